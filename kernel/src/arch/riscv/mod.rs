@@ -3,6 +3,9 @@ pub mod stdout;
 
 pub use page_control::*;
 
+use crate::constants::CPU_ID_LIMIT;
+use riscv::register::sie;
+
 core::arch::global_asm!(
     "   .section .data
         .align 12
@@ -24,9 +27,9 @@ core::arch::global_asm!(
 #[repr(C, align(4096))]
 struct KernelStack([u8; 256 * 1024]);
 
-/// 所有核的启动栈
+/// 所有核的启动栈。按 hartid 排列，所以数量是 CPU_ID_LIMIT 而不是实际使用的核数
 #[link_section = ".bss.stack"]
-static mut KERNEL_STACK: core::mem::MaybeUninit<[KernelStack; 4]> =
+static mut KERNEL_STACK: core::mem::MaybeUninit<[KernelStack; CPU_ID_LIMIT]> =
     core::mem::MaybeUninit::uninit();
 
 /// 获取启动栈地址
@@ -118,7 +121,7 @@ unsafe extern "C" fn set_boot_pt(hartid: usize) {
 }
 
 /// 需要在堆初始化之后，因为这里 STDOUT 打印需要用到 Mutex 锁，这需要堆分配
-/// 在硬件上 start_hart 需要调用这个函数来确认启动，但是在 qemu 中，start_hart 默认是被注释掉的
+/// 在硬件上 start_hart 需要调用这个函数来确认启动
 #[allow(dead_code)]
 pub fn cpu_init(cpu_id: usize) {
     println!("Hello, CPU [{}]", cpu_id);
@@ -159,7 +162,6 @@ pub fn set_timer(stime_value: u64) {
     sbi_rt::set_timer(stime_value);
 }
 
-#[allow(unused)]
 #[inline]
 pub fn start_hart(hartid: usize, start_addr: usize, a1: usize) {
     //print("start_hart");
@@ -167,13 +169,63 @@ pub fn start_hart(hartid: usize, start_addr: usize, a1: usize) {
     //print("\n");
     let ret = sbi_rt::hart_start(hartid, start_addr, a1);
     if ret.error != sbi_rt::RET_SUCCESS {
-        panic!("start hart{} failed: {:?}", hartid, ret);
+        // 设备树中的核可能已被禁用或启动失败，这时只用已启动的核即可
+        warn!("start hart{} failed: {:?}", hartid, ret);
     }
     //print("end_start_hart");
     //console_putchar(b'0' as usize +hartid);
     //print("\n");
 }
 
+/// 向另一个核发送核间中断(IPI)，一般用于唤醒正在 wait_for_ipi 的空闲核
+#[inline]
+pub fn send_ipi(hartid: usize) {
+    sbi_rt::send_ipi(1, hartid);
+}
+
+/// 清除当前核上未处理的核间中断
+#[inline]
+pub fn clear_ipi() {
+    // sip.SSIP 是第 1 位
+    unsafe { core::arch::asm!("csrc sip, {0}", in(reg) 1 << 1) };
+}
+
+/// 空闲时让当前核停下来，直到收到核间中断。
+///
+/// 内核态下 sstatus.SIE 是关闭的，所以中断到来时不会进入 trap，而是直接从 wfi 返回
+pub fn wait_for_ipi() {
+    unsafe {
+        sie::set_ssoft();
+        riscv::asm::wfi();
+        sie::clear_ssoft();
+    }
+    clear_ipi();
+}
+
+/// 同 wait_for_ipi，但最晚在时钟到达 stime_value 时也会返回
+pub fn wait_for_ipi_or_timer(stime_value: u64) {
+    set_timer(stime_value);
+    unsafe {
+        sie::set_stimer();
+        sie::set_ssoft();
+        riscv::asm::wfi();
+        sie::clear_ssoft();
+        sie::clear_stimer();
+    }
+    // 把下一次时钟设到无穷远，以清除 sip.STIP
+    set_timer(u64::MAX);
+    clear_ipi();
+}
+
+/// 刷新除当前核以外其他所有核的 TLB。
+/// 用于多个核可能同时使用同一个页表，而当前核修改了页表中已有的映射时
+pub fn remote_flush_tlb() {
+    let hart_mask = crate::fdt::hart_mask() & !(1 << get_cpu_id());
+    if hart_mask != 0 {
+        sbi_rt::remote_sfence_vma(hart_mask, 0, 0, usize::MAX);
+    }
+}
+
 #[inline]
 pub fn shutdown_failure() -> ! {
     use sbi_rt::*;
@@ -0,0 +1,167 @@
+//! 内核命令行
+//!
+//! 启动参数来自设备树 /chosen 中的 bootargs，即 qemu 的 `-append`。和 Linux 一样以空格分隔，
+//! 每项是 `key=value` 或单独的 `key`，值中有空格时可以用双引号括起来。目前支持：
+//!
+//! - `init=<程序>`：不运行测例，而是启动这个初始进程。`--` 之后的参数会作为它的 argv
+//! - `tests=<列表>[,<列表>...]`：依次运行这些测例列表，列表名见 `file::testcase_list`。
+//!   没有指定 `init=` 时默认为 `tests=default`
+//! - `root=builtin|preloaded`：文件系统镜像是编译在内核 .data 段中的，还是由 qemu 预先加载到 `DEVICE_START` 的
+//! - `loglevel=off|error|warn|info|debug|trace`：内核输出的等级
+//! - `nosmp`：单核运行，其他核只启动，不运行用户程序
+//! - `report_page_fault`：输出用户程序的访存报错信息
+//!
+//! 不认识的参数会被忽略。没有给出的参数使用下面各个变量的默认值
+
+use crate::{
+    console::LogLevel,
+    constants::{BUILTIN_FS_IMG_SIZE, DEVICE_START, ORIGIN_USER_PROC_NAME, PRELOADED_FS_IMG_SIZE},
+};
+use alloc::{string::String, vec::Vec};
+use core::sync::atomic::{AtomicBool, Ordering};
+use lock::Mutex;
+
+/// 是否是比赛评测。评测时要求OS像一个批处理系统一样工作，依次运行测例，而不是启动初始进程
+static IS_TEST_ENV: AtomicBool = AtomicBool::new(true);
+/// 文件系统镜像是否是由qemu预先加载的
+static IS_PRELOADED_FS_IMG: AtomicBool = AtomicBool::new(false);
+/// 是否单核运行
+static IS_SINGLE_CORE: AtomicBool = AtomicBool::new(false);
+/// 是否输出访存报错信息。这个信息会干扰到评测判定(换行问题)，但平时很有用
+static REPORT_PAGE_FAULT: AtomicBool = AtomicBool::new(false);
+/// 初始进程的 argv。解析命令行之前为空，此时使用 ORIGIN_USER_PROC_NAME
+static INIT_ARGV: Mutex<Vec<String>> = Mutex::new(Vec::new());
+/// 要运行的测例列表的名字，为空时使用 "default"
+static TEST_LISTS: Mutex<Vec<String>> = Mutex::new(Vec::new());
+
+/// 解析命令行。需由主核在解析设备树之后、页帧分配器初始化之前调用且仅调用一次
+pub fn init(bootargs: &str) {
+    let mut args = split_args(bootargs).into_iter();
+    let mut init_path = None;
+    let mut init_args = Vec::new();
+    let mut test_lists = Vec::new();
+    while let Some(arg) = args.next() {
+        let (key, value) = match arg.split_once('=') {
+            Some((key, value)) => (key, Some(value)),
+            None => (arg.as_str(), None),
+        };
+        match (key, value) {
+            ("--", None) => {
+                // 之后的都是初始进程的参数
+                init_args.extend(args.by_ref());
+            }
+            ("init", Some(path)) => {
+                init_path = Some(String::from(path));
+                IS_TEST_ENV.store(false, Ordering::Relaxed);
+            }
+            ("tests", Some(lists)) => {
+                test_lists.extend(lists.split(',').filter(|s| !s.is_empty()).map(String::from));
+                IS_TEST_ENV.store(true, Ordering::Relaxed);
+            }
+            ("root", Some("builtin")) => IS_PRELOADED_FS_IMG.store(false, Ordering::Relaxed),
+            ("root", Some("preloaded")) => IS_PRELOADED_FS_IMG.store(true, Ordering::Relaxed),
+            ("loglevel", Some(level)) => match level.parse::<LogLevel>() {
+                Ok(level) => log::set_max_level(level),
+                Err(_) => warn!("unknown loglevel {}", level),
+            },
+            ("nosmp", None) => IS_SINGLE_CORE.store(true, Ordering::Relaxed),
+            ("report_page_fault", None) => REPORT_PAGE_FAULT.store(true, Ordering::Relaxed),
+            _ => warn!("unknown kernel parameter {}", arg),
+        }
+    }
+    info!(
+        "cmdline \"{}\": test env {}, preloaded fs img {}, single core {}",
+        bootargs,
+        is_test_env(),
+        is_preloaded_fs_img(),
+        is_single_core()
+    );
+    let mut init_argv = vec![init_path.unwrap_or_else(|| ORIGIN_USER_PROC_NAME.into())];
+    init_argv.extend(init_args);
+    *INIT_ARGV.lock() = init_argv;
+    *TEST_LISTS.lock() = test_lists;
+}
+
+/// 是否是比赛评测，即依次运行测例而不是启动初始进程
+pub fn is_test_env() -> bool {
+    IS_TEST_ENV.load(Ordering::Relaxed)
+}
+
+/// 文件系统镜像是否是由qemu预先加载到 `DEVICE_START` 的
+pub fn is_preloaded_fs_img() -> bool {
+    IS_PRELOADED_FS_IMG.load(Ordering::Relaxed)
+}
+
+/// 是否单核运行。单核运行时，则其他核只启动，不运行用户程序
+pub fn is_single_core() -> bool {
+    IS_SINGLE_CORE.load(Ordering::Relaxed)
+}
+
+/// 是否输出访存报错信息
+pub fn report_page_fault() -> bool {
+    REPORT_PAGE_FAULT.load(Ordering::Relaxed)
+}
+
+/// 文件系统镜像的大小。注意内置镜像的大小和 fs-init 模块中 `/src/main.rs` 里生成镜像时的大小相同
+pub fn fs_img_size() -> usize {
+    if is_preloaded_fs_img() {
+        PRELOADED_FS_IMG_SIZE
+    } else {
+        BUILTIN_FS_IMG_SIZE
+    }
+}
+
+/// 文件系统镜像在物理内存中的最后位置。内置镜像实际在 .data 段中，但也会映射到这个范围对应的虚拟地址上
+pub fn device_end() -> usize {
+    DEVICE_START + fs_img_size()
+}
+
+/// 初始进程的 argv
+pub fn init_argv() -> Vec<String> {
+    let argv = INIT_ARGV.lock();
+    if argv.is_empty() {
+        vec![ORIGIN_USER_PROC_NAME.into()]
+    } else {
+        argv.clone()
+    }
+}
+
+/// 评测时要依次运行的测例列表的名字
+pub fn test_lists() -> Vec<String> {
+    let lists = TEST_LISTS.lock();
+    if lists.is_empty() {
+        vec!["default".into()]
+    } else {
+        lists.clone()
+    }
+}
+
+/// 按空格分隔命令行。双引号中的空格不分隔，双引号本身会被去掉
+fn split_args(s: &str) -> Vec<String> {
+    let mut args = Vec::new();
+    let mut current = String::new();
+    let mut in_quotation = false;
+    let mut has_arg = false;
+    for c in s.chars() {
+        match c {
+            '"' => {
+                in_quotation = !in_quotation;
+                has_arg = true;
+            }
+            c if c.is_whitespace() && !in_quotation => {
+                if has_arg {
+                    args.push(core::mem::take(&mut current));
+                    has_arg = false;
+                }
+            }
+            c => {
+                current.push(c);
+                has_arg = true;
+            }
+        }
+    }
+    if has_arg {
+        args.push(current);
+    }
+    args
+}
@@ -13,7 +13,7 @@
 //!
 //! /* ------------------------------ MMIO ------------------------------*/
 //! /// 用于设备 MMIO 的内存段。这些地址会在页表中做恒等映射
-//! pub const MMIO_REGIONS: &\[AddrArea\] = &\[AddrArea(0x10001000, 0x10002000)\];
+//! pub const MMIO_REGIONS: &\[AddrArea\] = &\[AddrArea(0x10001000, 0x10009000)\];
 //!
 //! /* ------------------------------ 内核 ------------------------------*/
 //! /// 内核中虚拟地址相对于物理地址的偏移
@@ -47,42 +47,62 @@
 pub const FIRST_CPU_ID: usize = if cfg!(feature = "sifive") { 1 } else { 0 };
 /// 指定一个特定的 cpu，用于执行启动过程中只能进行一次的初始化过程
 //pub const BOOTSTRAP_CPU_ID: usize = FIRST_CPU_ID;
-/// 最大的cpu_id再+1，可以认为是总的核数(无论是否使用)。目前在 virt 下是 4，在 sifive 下是 5
-pub const CPU_ID_LIMIT: usize = FIRST_CPU_ID + 4;
+/// 支持的最大的cpu_id再+1，决定了启动栈和内核栈的个数。目前在 virt 下是 8，在 sifive 下是 9。
+/// 实际可用的核在启动时从设备树中读取，见 `fdt::hart_mask`
+pub const CPU_ID_LIMIT: usize = FIRST_CPU_ID + 8;
 /// 最后一个 CPU 的编号
 pub const LAST_CPU_ID: usize = CPU_ID_LIMIT - 1;
-/// 是否单核运行。单核运行时，则其他核只启动，不运行用户程序
-pub const IS_SINGLE_CORE: bool = true;
 /// 是否在启动后暂停。如果为 true，则所有核都只启动，不进入用户程序
 pub const SPIN_LOOP_AFTER_BOOT: bool = false;
-/// 运行时有多少内核输出
-pub const LOG_LEVEL: crate::console::LogLevel = crate::console::LogLevel::Error;
-//pub const LOG_LEVEL: crate::console::LogLevel = crate::console::LogLevel::Off; // 评测时使用这个等级
+/// 运行时有多少内核输出。启动参数中的 `loglevel=` 会覆盖它，见 `cmdline`
+pub const DEFAULT_LOG_LEVEL: crate::console::LogLevel = crate::console::LogLevel::Error;
+//pub const DEFAULT_LOG_LEVEL: crate::console::LogLevel = crate::console::LogLevel::Off; // 评测时使用这个等级
 
-/// 是否输出访存报错信息。这个信息会干扰到评测判定(换行问题)，但平时很有用
-pub const REPORT_PAGE_FAULT: bool = false;
 /// 页表中每页的大小
 pub const PAGE_SIZE: usize = 0x1000; // 4 KB
 /// 即 log2(PAGE_SIZE)
 pub const PAGE_SIZE_BITS: usize = 0xc; // 4 KB = 2^12
 /// 内核栈大小
 pub const KERNEL_STACK_SIZE: usize = 0x80_000; // 8 MB -> 512 KB
-/// 内核堆的大小
+/// 内核堆的初始大小。之后堆中内存不够时会从页帧分配器中申请
 pub const KERNEL_HEAP_SIZE: usize = 0xc0_0000; // 32 MB -> 12 MB
-/// 用户栈大小
+/// 内核堆每次增长的大小
+pub const KERNEL_HEAP_GROW_SIZE: usize = 0x20_0000; // 2 MB
+/// 用户栈的初始大小。之后访问到栈下方时会自动向下增长
 pub const USER_STACK_SIZE: usize = 0x20_0000; // 15 MB -> 2 MB // `lmbench_all lat_fs /var/tmp` 会默认访问到 0x3ffdfb08
 /// 初始用户栈大小，用于存放 argc/argv/envs/auxv
 pub const USER_INIT_STACK_SIZE: usize = 0x4000; // 16 KB,
-/// 用户栈底位置。同时也是最开始的用户堆顶位置
+/// 用户栈默认最多可以增长到多大，即 RLIMIT_STACK 的初始值
+pub const USER_STACK_RLIMIT: usize = 0x80_0000; // 8 MB
+/// 用户栈和它下面的其他内存段之间至少要留出这么大的空隙。栈溢出时会先访问到空隙，从而触发 SIGSEGV
+pub const USER_STACK_GUARD_GAP: usize = 0x10_0000; // 1 MB
+/// 用户堆的起始位置在用户栈顶以下这么远的地方，中间留给栈向下和堆向上增长
+pub const USER_HEAP_STACK_DISTANCE: usize = 0x1000_0000; // 256 MB
+/// 用户栈底位置
+///
+/// 这是 Sv39 下的位置，实际使用时应通过 `memory::user_stack_offset()` 获取
 pub const USER_STACK_OFFSET: usize = 0x4000_0000 - USER_STACK_SIZE;
 /// 用户地址最大不能超过这个值
+///
+/// 这是 Sv39 下的值，实际使用时应通过 `memory::user_virt_addr_limit()` 获取
 pub const USER_VIRT_ADDR_LIMIT: usize = 0xFFFF_FFFF;
+/// 启动时是否尝试使用 Sv48 分页模式。如果硬件不支持，会自动退回 Sv39
+pub const USE_SV48: bool = true;
+/// Sv48 下的用户栈底位置，放在用户地址空间的顶部附近
+pub const USER_STACK_OFFSET_SV48: usize = 0x7FFF_F000_0000 - USER_STACK_SIZE;
+/// Sv48 下用户地址最大不能超过这个值，即低半部分的规范地址
+pub const USER_VIRT_ADDR_LIMIT_SV48: usize = 0x7FFF_FFFF_FFFF;
 /// 内核中虚拟地址相对于物理地址的偏移
 pub const PHYS_VIRT_OFFSET: usize = 0xFFFF_FFFF_0000_0000;
 /// 表示内存的地址段由此开始
 pub const PHYS_MEMORY_OFFSET: usize = 0x8000_0000;
-/// 表示内存的地址段到此为止
+/// 表示内存的地址段到此为止。只在启动时没有读到设备树的情况下使用
 pub const PHYS_MEMORY_END: usize = 0x8800_0000;
+/// 启动页表映射的物理内存到此为止。设备树需要在这之内，才能在切换到内核页表前读取
+pub const BOOT_PHYS_MEMORY_END: usize = 0xc000_0000;
+/// 内核的线性映射能覆盖的物理内存上限。虚拟地址为物理地址加上 PHYS_VIRT_OFFSET，
+/// 4GiB 以上的地址会溢出；最后一页也不用，否则映射区间的右端点会溢出
+pub const PHYS_LINEAR_MAP_END: usize = 0xffff_f000;
 
 /// 入口用户程序。OS启动后，只会启动以此为名的用户程序。
 /// 一般来说，这个程序会通过 fork / exec 启动终端和其他程序
@@ -94,34 +114,51 @@ pub const TID_LIMIT: usize = 4096;
 pub const FD_LIMIT_ORIGIN: usize = 256;
 /// sys_pipe创建的管道的大小，单位为字节
 pub const PIPE_SIZE_LIMIT: usize = 0x40_000; // 64 KB
-/// socket 使用的 buffer 大小
+/// socket 的收发缓冲区大小的上限，即 SO_SNDBUF / SO_RCVBUF 能设置的最大值
 pub const SOCKET_BUFFER_SIZE_LIMIT: usize = 0x200000; // 2 MB
+/// socket 默认的收发缓冲区大小
+pub const SOCKET_BUFFER_SIZE_DEFAULT: usize = 0x10000; // 64 KB
+/// UDP socket 的收发缓冲区中最多存放的数据报个数
+pub const UDP_PACKET_LIMIT: usize = 64;
+/// TCP socket 的 listen 最多同时等待的连接数
+pub const SOCKET_LISTEN_BACKLOG_LIMIT: usize = 16;
+/// 自动分配的本地端口的范围，与 Linux 默认的 ip_local_port_range 相同
+pub const EPHEMERAL_PORT_RANGE: core::ops::Range<u16> = 32768..61000;
+/// 网卡的 IPv4 地址和子网前缀长度。默认值与 qemu 用户态网络(`-netdev user`)分配给虚拟机的地址相同
+pub const NET_DEVICE_IP: ([u8; 4], u8) = ([10, 0, 2, 15], 24);
+/// 默认网关的地址，即 qemu 用户态网络中的宿主机
+pub const NET_GATEWAY_IP: [u8; 4] = [10, 0, 2, 2];
+/// 没有网卡时协议栈使用的 MAC 地址。它只用于本地回环
+pub const LOOPBACK_MAC: [u8; 6] = [0x02, 0, 0, 0, 0, 0x01];
+/// 以太网帧的最大长度(不含校验和)，即 MTU 1500 字节加上 14 字节的帧头
+pub const NET_FRAME_SIZE_LIMIT: usize = 1514;
+/// 调度器检查网卡收到的帧的最小间隔，单位为微秒
+pub const NET_POLL_INTERVAL_US: usize = 1000;
 
 /// 一段左闭右开的地址区间，.0 为左端点， .1 为右端点，
 pub struct AddrArea(pub usize, pub usize);
-/// 用于设备 MMIO 的内存段。这些地址会在页表中做恒等映射
-pub const MMIO_REGIONS: &[AddrArea] = &[AddrArea(0x10001000, 0x10002000)];
+/// 用于设备 MMIO 的内存段。这些地址会在页表中做恒等映射。
+/// 启动时如果读到了设备树，则改为使用其中的 PLIC、串口和 virtio-mmio 设备的地址
+pub const MMIO_REGIONS: &[AddrArea] = &[AddrArea(0x10001000, 0x10009000)];
+/// 第一个 virtio-mmio 设备的地址。qemu virt 上的 virtio-mmio 设备是连续排列的
+pub const VIRTIO_MMIO_START: usize = 0x10001000;
+/// 每个 virtio-mmio 设备占用的地址空间大小
+pub const VIRTIO_MMIO_SIZE: usize = 0x1000;
+/// virtio-mmio 设备的个数
+pub const VIRTIO_MMIO_SLOTS: usize = 8;
+/// 启动时是否在 virtio-mmio 设备中寻找网卡。在没有 virtio-mmio 设备的硬件(如 fu740)上需要关掉
+pub const PROBE_NET_DEVICE: bool = true;
+/// swapon 时是否在 virtio-mmio 设备中寻找块设备
+pub const PROBE_BLOCK_DEVICE: bool = true;
 
-/// 是否是比赛评测。线上评测时要求OS像一个批处理系统一样工作，这可能导致内核不会直接去拿初始进程并运行
-pub const IS_TEST_ENV: bool = true;
-/// 测试环境下，文件系统镜像是否是由qemu引入
-pub const IS_PRELOADED_FS_IMG: bool = false;
-/// 文件系统镜像的大小。注意这个量和 fs-init 模块中 `/src/main.rs` 里生成镜像时的大小相同。
+/// 内置的文件系统镜像的大小。注意这个量和 fs-init 模块中 `/src/main.rs` 里生成镜像时的大小相同。
 /// 启动时会从 .data 段加载加载
-const LOCAL_FS_IMG_SIZE: usize = 0x200_0000; // 32MB
-/// 测试时的文件系统镜像大小。
+pub const BUILTIN_FS_IMG_SIZE: usize = 0x200_0000; // 32MB
+/// 由 qemu 预先加载的文件系统镜像大小，用于评测(启动参数 `root=preloaded`)。
 /// 注意因为这个文件太大，默认是已经被qemu加载好了，启动时不会加载
-const TEST_FS_IMG_SIZE: usize = 0x4000_0000; // 1GB
-/// 文件系统镜像大小。只有这个常量可以被其他文件使用，而上面两个不能
-pub const FS_IMG_SIZE: usize = if IS_PRELOADED_FS_IMG {
-    TEST_FS_IMG_SIZE
-} else {
-    LOCAL_FS_IMG_SIZE
-};
-/// 设备(sdcard)映射到内存的起始位置
+pub const PRELOADED_FS_IMG_SIZE: usize = 0x4000_0000; // 1GB
+/// 设备(sdcard)映射到内存的起始位置。结束位置取决于镜像大小，见 `cmdline::device_end`
 pub const DEVICE_START: usize = 0x9000_0000;
-/// 设备映射到内存的最后位置
-pub const DEVICE_END: usize = DEVICE_START + FS_IMG_SIZE;
 
 /// 文件系统的根目录，注意斜杠方向
 pub const ROOT_DIR: &str = "./";
@@ -131,9 +168,29 @@ pub const AT_FDCWD: i32 = -100;
 pub const NO_PARENT: usize = usize::MAX;
 /// 临时文件的大小限制
 pub const TMP_SIZE_LIMIT: usize = 0x8_000; // 1 MB
+/// 共享内存的大小限制，包括 /dev/shm 下的文件和 System V 共享内存段
+pub const SHM_SIZE_LIMIT: usize = 0x4000_0000; // 1 GB
 
 /// 如果 elf 的 phdr 指示 base 是 0(如 libc-test 的 libc.so)，则需要找一个非0的位置放置
 pub const ELF_BASE_RELOCATE: usize = 0x400_0000;
+/// 地址随机化时，用户栈底最多向下偏移 2^ASLR_STACK_BITS 页
+pub const ASLR_STACK_BITS: usize = 12; // 16 MB
+/// Sv48 下用户栈底的随机位数
+pub const ASLR_STACK_BITS_SV48: usize = 22; // 16 GB
+/// 地址随机化时，mmap 从这个地址之后的随机位置开始找空闲区间
+pub const ASLR_MMAP_BASE: usize = 0x1000_0000;
+/// mmap 起始位置的随机位数
+pub const ASLR_MMAP_BITS: usize = 16; // 256 MB
+/// Sv48 下 mmap 起始位置的下限
+pub const ASLR_MMAP_BASE_SV48: usize = 0x10_0000_0000;
+/// Sv48 下 mmap 起始位置的随机位数
+pub const ASLR_MMAP_BITS_SV48: usize = 28; // 1 TB
+/// 地址随机化时，需要重定位的 elf 放在 ELF_BASE_RELOCATE 之后的 2^ASLR_ELF_BITS 页内
+pub const ASLR_ELF_BITS: usize = 12; // 16 MB
+/// Sv48 下需要重定位的 elf 的随机位数
+pub const ASLR_ELF_BITS_SV48: usize = 20; // 4 GB
+/// personality 中的标志位，表示关闭地址随机化
+pub const ADDR_NO_RANDOMIZE: u32 = 0x0040000;
 
 /// signal 中用到的 bitset 长度。
 pub const SIGSET_SIZE_IN_BYTE: usize = 8;
@@ -141,7 +198,7 @@ pub const SIGSET_SIZE_IN_BYTE: usize = 8;
 pub const SIGSET_SIZE_IN_BIT: usize = SIGSET_SIZE_IN_BYTE * 8; // =64
 /// SIGINFO 要求把一些信息存在用户栈上，从用户栈开辟一块空间来保存它们
 pub const USER_STACK_RED_ZONE: usize = 0x200; // 512 B
-/// 一个在 Sv39 页表里不合法的地址。
+/// 一个在 Sv39 和 Sv48 页表里都不合法的地址。
 ///
 /// 如果 sigaction 中没有设置 SA_RESTORER，那么需要内核来代替libc库实现"信号执行完成后通过sigreturn返回"的效果
 /// 但是mmap一块地址把"手动调用ecall执行 sigreturn"写进去又显得不够优雅，因为用户地址空间会多出来一块它并不知道的trampoline
@@ -152,6 +209,21 @@ pub const SIGNAL_RETURN_TRAP: usize = 0xffff_0000_8080_0000;
 
 /// sys_sendfile64 中使用 buffer 的大小
 pub const SENDFILE_BUFFER_SIZE: usize = 0x2000;
+/// 页缓存最多缓存的页数。被映射到用户地址空间的页不受这个限制
+pub const PAGE_CACHE_FRAMES_LIMIT: usize = 0x1000;
+/// 内存不足时每次最多回收的页数
+pub const SWAP_RECLAIM_BATCH: usize = 0x100;
 /// 标记是否使用 msync。不做实际的检查效率更高，
 /// 但如果它影响了用户程序的正确性，需要还是打开的
 pub const USE_MSYNC: bool = true;
+
+/// 是否使用按 nice 值加权的 CfsScheduler。为 false 时使用 RoundRobinScheduler
+pub const USE_CFS_SCHEDULER: bool = true;
+/// CfsScheduler 中，睡眠后被唤醒的任务最多比队列中最小的 vruntime 提前多少微秒。
+/// 取一个时钟中断的长度，即醒来的任务最多能"补回"一个时间片
+pub const SCHED_WAKEUP_GRANULARITY_US: usize = timer::USEC_PER_INTERRUPT;
+
+/// 等待队列超时用的时间轮的槽数
+pub const TIMER_WHEEL_SLOTS: usize = 64;
+/// 时间轮每个槽对应的时间，单位为微秒。到期时间落在同一个槽里的定时器会在同一次检查中被触发
+pub const TIMER_WHEEL_TICK_US: usize = 1000;
@@ -1,4 +1,8 @@
 use core::any::Any;
+
+/// 块设备中每个块的大小
+pub const BLOCK_SIZE: usize = 512;
+
 /// 读写块设备的规范
 pub trait BlockDevice: Send + Sync + Any {
     ///读一个块到buf
@@ -1,13 +1,41 @@
 use super::BlockDeviceImpl;
-use alloc::sync::Arc;
+use crate::{constants::PROBE_BLOCK_DEVICE, fdt::virtio_mmio_regions};
+use alloc::{sync::Arc, vec::Vec};
+use virtio_drivers::{DeviceType, VirtIOHeader};
 
 mod block_device;
 mod virtio_block;
-pub use block_device::BlockDevice;
+pub use block_device::{BlockDevice, BLOCK_SIZE};
 pub use virtio_block::VirtIOBlock;
 
 lazy_static::lazy_static! {
     pub static ref BLOCK_DEVICE: Arc<dyn BlockDevice> = Arc::new(BlockDeviceImpl::new());
+    /// 所有 virtio-blk 设备，按在 virtio-mmio 中的顺序排列，依次对应 /dev/vda、/dev/vdb ...
+    ///
+    /// 文件系统是直接映射到内存的，所以这些设备目前只用作交换区
+    pub static ref BLOCK_DEVICES: Vec<Arc<dyn BlockDevice>> = probe_block_devices();
+}
+
+/// 在所有 virtio-mmio 设备中找到块设备并初始化
+fn probe_block_devices() -> Vec<Arc<dyn BlockDevice>> {
+    let mut devices: Vec<Arc<dyn BlockDevice>> = Vec::new();
+    if !PROBE_BLOCK_DEVICE {
+        return devices;
+    }
+    for (slot, region) in virtio_mmio_regions().into_iter().enumerate() {
+        let header = unsafe { &mut *(region.start as *mut VirtIOHeader) };
+        if !header.verify() || header.device_type() != DeviceType::Block {
+            continue;
+        }
+        match VirtIOBlock::from_header(header) {
+            Some(device) => {
+                info!("virtio-blk found at slot {slot}");
+                devices.push(Arc::new(device));
+            }
+            None => warn!("failed to init virtio-blk at slot {slot}"),
+        }
+    }
+    devices
 }
 
 #[allow(unused)]
@@ -1,14 +1,14 @@
 use crate::drivers::block::BlockDevice;
+use crate::fdt::virtio_mmio_regions;
 use crate::memory::{phys_to_virt, virt_to_phys, Frame, PhysAddr, VirtAddr};
+use alloc::collections::BTreeMap;
 use lock::Mutex;
 use virtio_drivers::{VirtIOBlk, VirtIOHeader};
 
-#[allow(unused)]
-const VIRTIO0: usize = 0x10001000;
-
 pub struct VirtIOBlock(Mutex<VirtIOBlk<'static>>);
 
-static QUEUE_FRAMES: Mutex<Option<Frame>> = Mutex::new(None);
+/// virtio 设备的队列所占用的页帧，以起始物理地址为索引。块设备和网卡都从这里分配
+static QUEUE_FRAMES: Mutex<BTreeMap<PhysAddr, Frame>> = Mutex::new(BTreeMap::new());
 
 impl BlockDevice for VirtIOBlock {
     fn read_block(&self, block_id: usize, buf: &mut [u8]) {
@@ -26,14 +26,20 @@ impl BlockDevice for VirtIOBlock {
 }
 
 impl VirtIOBlock {
+    /// 用第一个 virtio-mmio 设备初始化块设备
     #[allow(unused)]
     pub fn new() -> Self {
+        let virtio0 = virtio_mmio_regions()[0].start;
         unsafe {
             Self(Mutex::new(
-                VirtIOBlk::new(&mut *(VIRTIO0 as *mut VirtIOHeader)).unwrap(),
+                VirtIOBlk::new(&mut *(virtio0 as *mut VirtIOHeader)).unwrap(),
             ))
         }
     }
+    /// 用 `header` 处的 virtio 设备初始化块设备。调用者需要保证这个设备是块设备
+    pub fn from_header(header: &'static mut VirtIOHeader) -> Option<Self> {
+        VirtIOBlk::new(header).map(|blk| Self(Mutex::new(blk))).ok()
+    }
 }
 
 #[no_mangle]
@@ -41,16 +47,22 @@ impl VirtIOBlock {
 /// 因为这个函数是面向 virtio-drivers 的接口，而且仅在内核启动时初始化，
 /// 所以这里默认可以拿到需要的空间，不处理分配失败导致的异常
 pub extern "C" fn virtio_dma_alloc(pages: usize) -> PhysAddr {
-    let frame = Frame::new_contiguous(pages, 0).unwrap();
+    // 设备的缓冲区不会马上被 CPU 访问，单个页时用冷页帧即可
+    let frame = if pages == 1 {
+        Frame::new_cold()
+    } else {
+        Frame::new_contiguous(pages, 0)
+    }
+    .unwrap();
     let paddr = frame.start_paddr();
-    *QUEUE_FRAMES.lock() = Some(frame);
+    QUEUE_FRAMES.lock().insert(paddr, frame);
     paddr
 }
 
 #[no_mangle]
 /// Frame 在 Drop 时会释放页帧，所以这里不用做其他处理
-pub extern "C" fn virtio_dma_dealloc(_pa: PhysAddr, _pages: usize) -> i32 {
-    *QUEUE_FRAMES.lock() = None;
+pub extern "C" fn virtio_dma_dealloc(pa: PhysAddr, _pages: usize) -> i32 {
+    QUEUE_FRAMES.lock().remove(&pa);
     0
 }
 
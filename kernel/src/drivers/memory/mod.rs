@@ -9,10 +9,7 @@
 mod device;
 mod wrapper;
 
-use crate::{
-    constants::{DEVICE_END, DEVICE_START},
-    memory::phys_to_virt,
-};
+use crate::{cmdline::device_end, constants::DEVICE_START, memory::phys_to_virt};
 use fatfs::{DefaultTimeProvider, FileSystem, FsOptions, LossyOemCpConverter};
 use fscommon::BufStream;
 
@@ -23,7 +20,7 @@ pub type IoType = IoWrapper<BufStream<MemoryMappedDevice>>;
 
 /// 创建文件系统实例
 pub fn new_memory_mapped_fs() -> FileSystem<IoType, DefaultTimeProvider, LossyOemCpConverter> {
-    let device = MemoryMappedDevice::new(phys_to_virt(DEVICE_START), phys_to_virt(DEVICE_END));
+    let device = MemoryMappedDevice::new(phys_to_virt(DEVICE_START), phys_to_virt(device_end()));
     let buf_stream = BufStream::new(device);
     let options = FsOptions::new().update_accessed_date(true);
     FileSystem::new(IoWrapper::new(buf_stream), options).unwrap()
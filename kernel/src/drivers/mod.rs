@@ -1,7 +1,9 @@
 mod block;
 mod memory;
-pub use block::BLOCK_DEVICE;
+mod net;
+pub use block::{BlockDevice, BLOCK_DEVICE, BLOCK_DEVICES, BLOCK_SIZE};
 pub use memory::new_memory_mapped_fs;
+pub use net::{MacAddress, NetDevice, NET_DEVICE};
 
 pub type BlockDeviceImpl = block::VirtIOBlock;
 pub type MemoryMappedFsIoType = memory::IoType;
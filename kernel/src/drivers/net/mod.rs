@@ -0,0 +1,40 @@
+//! 网卡驱动
+//!
+//! 目前只支持 virtio-net。启动时依次检查每个 virtio-mmio 设备，使用找到的第一个网卡。
+//! 网卡的中断没有打开，收包靠协议栈定期轮询，见 `crate::file::socket`
+
+use crate::{constants::PROBE_NET_DEVICE, fdt::virtio_mmio_regions};
+use alloc::sync::Arc;
+use virtio_drivers::{DeviceType, VirtIOHeader};
+
+mod net_device;
+mod virtio_net;
+pub use net_device::{MacAddress, NetDevice};
+pub use virtio_net::VirtIONetDevice;
+
+lazy_static::lazy_static! {
+    /// 网卡。没有找到网卡时为 None，此时只有本地回环可用
+    pub static ref NET_DEVICE: Option<Arc<dyn NetDevice>> = probe_net_device();
+}
+
+/// 在所有 virtio-mmio 设备中找到网卡并初始化
+fn probe_net_device() -> Option<Arc<dyn NetDevice>> {
+    if !PROBE_NET_DEVICE {
+        return None;
+    }
+    for (slot, region) in virtio_mmio_regions().into_iter().enumerate() {
+        let header = unsafe { &mut *(region.start as *mut VirtIOHeader) };
+        if !header.verify() || header.device_type() != DeviceType::Network {
+            continue;
+        }
+        match VirtIONetDevice::new(header) {
+            Some(device) => {
+                let mac = device.mac_address();
+                info!("virtio-net found at slot {slot}, mac {:02x?}", mac);
+                return Some(Arc::new(device));
+            }
+            None => warn!("failed to init virtio-net at slot {slot}"),
+        }
+    }
+    None
+}
@@ -0,0 +1,18 @@
+use core::any::Any;
+
+/// 网卡的 MAC 地址
+pub type MacAddress = [u8; 6];
+
+/// 收发以太网帧的网卡的规范
+pub trait NetDevice: Send + Sync + Any {
+    /// 网卡的 MAC 地址
+    fn mac_address(&self) -> MacAddress;
+    /// 发送队列是否还有空间
+    fn can_send(&self) -> bool;
+    /// 是否有已经收到、还没有取出的帧
+    fn can_recv(&self) -> bool;
+    /// 发送一个以太网帧，成功时返回 true
+    fn send(&self, buf: &[u8]) -> bool;
+    /// 取出一个收到的以太网帧放到 buf 中，返回帧的长度。没有收到帧时返回 None
+    fn recv(&self, buf: &mut [u8]) -> Option<usize>;
+}
@@ -0,0 +1,35 @@
+use super::{MacAddress, NetDevice};
+use lock::Mutex;
+use virtio_drivers::{VirtIOHeader, VirtIONet};
+
+/// virtio-net 网卡
+pub struct VirtIONetDevice(Mutex<VirtIONet<'static>>);
+
+impl NetDevice for VirtIONetDevice {
+    fn mac_address(&self) -> MacAddress {
+        self.0.lock().mac()
+    }
+    fn can_send(&self) -> bool {
+        self.0.lock().can_send()
+    }
+    fn can_recv(&self) -> bool {
+        self.0.lock().can_recv()
+    }
+    fn send(&self, buf: &[u8]) -> bool {
+        self.0.lock().send(buf).is_ok()
+    }
+    fn recv(&self, buf: &mut [u8]) -> Option<usize> {
+        let mut net = self.0.lock();
+        if !net.can_recv() {
+            return None;
+        }
+        net.recv(buf).ok()
+    }
+}
+
+impl VirtIONetDevice {
+    /// 用 `header` 处的 virtio 设备初始化网卡。调用者需要保证这个设备是网卡
+    pub fn new(header: &'static mut VirtIOHeader) -> Option<Self> {
+        VirtIONet::new(header).map(|net| Self(Mutex::new(net))).ok()
+    }
+}
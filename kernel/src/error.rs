@@ -17,6 +17,7 @@ pub enum OSError {
     PageTable_PageNotMapped,
     PageTable_UnknownErrorWhenUnmap,
     PageTable_RawAccessToPageTable,
+    PageTable_InvalidHugePage,
 
     VmArea_InvalidRange,
     VmArea_VmSizeNotEqualToPmSize,
@@ -37,6 +38,7 @@ pub enum OSError {
     PmArea_InvalidRange,
     PmArea_ShrinkFailed,
     PmArea_SplitFailed,
+    PmArea_GrowFailed,
     PmAreaLazy_ReleaseNotAllocatedPage,
 
     // 没有空的*物理*页
@@ -48,6 +50,8 @@ pub enum OSError {
     MemorySet_InvalidRange,
     MemorySet_UnmapAreaNotFound,
     MemorySet_AreaNotMapped,
+    // 映射之后地址空间会超过 RLIMIT_AS 或 RLIMIT_DATA
+    MemorySet_ExceedRlimit,
     Task_MmapLengthDisagree,
     // unmap 一段 VMA 可能会把分成两段
     // 本身不该算是错误，只是目前还没有实现
@@ -68,6 +72,22 @@ pub enum OSError {
     // cpu 找不到刚刚切换出来的任务
     CpuLocal_SwitchedFromEmptyTask,
 
+    // swapon 的设备或文件中没有 mkswap 写入的交换区头部
+    Swap_InvalidHeader,
+    // 交换区已经启用，或者 swapoff 时仍有页无法换回内存
+    Swap_AreaBusy,
+    // swapoff 时找不到对应的交换区
+    Swap_AreaNotFound,
+
+    // shmget 要求新建(IPC_EXCL)，但 key 对应的共享内存段已存在
+    Shm_KeyExists,
+    // 找不到 key 或 id 对应的共享内存段
+    Shm_NotFound,
+    // 共享内存段的大小为 0 或太大，或者比已有的段还大
+    Shm_InvalidSize,
+    // 写入的位置超过了共享内存的大小限制
+    Shm_TooLarge,
+
     // 文件描述符已满，无法再分配了
     FdManager_NoAvailableFd,
     // 找不到要求的文件描述符
@@ -0,0 +1,421 @@
+//! 解析 OpenSBI 通过 a1 传入的设备树(FDT)
+//!
+//! 主核在初始化堆分配器之后调用 `init`，从设备树中读出物理内存、可用的核、时钟频率、
+//! PLIC / 串口 / virtio-mmio 设备的地址和 /chosen 中的 bootargs，保存在 `BOOT_INFO` 中。
+//! 之后设备树本身就不再需要了，它所在的页会和其他空闲内存一起交给页帧分配器。
+//!
+//! 如果没有传入设备树或者解析失败，各个查询函数会返回 `constants.rs` 中 qemu virt 平台的默认值
+
+use crate::{
+    constants::{
+        BOOT_PHYS_MEMORY_END, CPU_ID_LIMIT, FIRST_CPU_ID, MMIO_REGIONS, PHYS_MEMORY_END,
+        PHYS_MEMORY_OFFSET, VIRTIO_MMIO_SIZE, VIRTIO_MMIO_SLOTS, VIRTIO_MMIO_START,
+    },
+    memory::{phys_to_virt, PhysAddr},
+};
+use alloc::{string::String, vec::Vec};
+use core::{
+    ops::Range,
+    slice,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+use lock::Mutex;
+
+/// 设备树头部的魔数
+const FDT_MAGIC: u32 = 0xd00d_feed;
+/// 设备树头部的长度。这里只用到 version 17 中定义的前 10 个字段
+const FDT_HEADER_SIZE: usize = 40;
+/// 结构块中的标记：节点开始，后面是以 0 结尾的节点名
+const FDT_BEGIN_NODE: u32 = 1;
+/// 结构块中的标记：节点结束
+const FDT_END_NODE: u32 = 2;
+/// 结构块中的标记：属性，后面是属性值的长度、属性名在字符串块中的偏移和属性值
+const FDT_PROP: u32 = 3;
+/// 结构块中的标记：空
+const FDT_NOP: u32 = 4;
+/// 结构块中的标记：结构块结束
+const FDT_END: u32 = 9;
+
+/// 从设备树中得到的启动信息
+struct BootInfo {
+    /// 是否成功解析了设备树。为 false 时下面的字段都为空，查询时使用默认值
+    from_fdt: bool,
+    /// 物理内存
+    memory: Vec<Range<usize>>,
+    /// 不能使用的物理内存，包括 memreserve 块和 /reserved-memory 下的节点
+    reserved: Vec<Range<usize>>,
+    /// PLIC、串口等需要映射的设备的 MMIO 区间，不包括 virtio-mmio
+    mmio: Vec<Range<usize>>,
+    /// virtio-mmio 设备的 MMIO 区间，按地址排序
+    virtio: Vec<Range<usize>>,
+    /// /chosen 中的 bootargs
+    bootargs: String,
+}
+
+static BOOT_INFO: Mutex<BootInfo> = Mutex::new(BootInfo {
+    from_fdt: false,
+    memory: Vec::new(),
+    reserved: Vec::new(),
+    mmio: Vec::new(),
+    virtio: Vec::new(),
+    bootargs: String::new(),
+});
+
+/// 可用的核，第 i 位表示 hartid 为 i 的核。
+/// 刷新其他核的 TLB 时需要用到，所以单独放在原子变量里，不用拿锁。
+/// 没有设备树时认为 FIRST_CPU_ID 到 CPU_ID_LIMIT 之间的核都可用
+static HART_MASK: AtomicUsize =
+    AtomicUsize::new(((1 << CPU_ID_LIMIT) - 1) & !((1 << FIRST_CPU_ID) - 1));
+
+/// 解析设备树。需由主核在堆分配器初始化之后、页帧分配器初始化之前调用且仅调用一次
+///
+/// 此时还在使用启动页表，所以设备树需要在 `BOOT_PHYS_MEMORY_END` 以内才能读到
+pub fn init(dtb: PhysAddr) {
+    let fdt = match unsafe { Fdt::from_paddr(dtb) } {
+        Some(fdt) => fdt,
+        None => {
+            warn!("no valid device tree at {:#x}, use default config", dtb);
+            return;
+        }
+    };
+    let mut info = BootInfo {
+        from_fdt: true,
+        memory: Vec::new(),
+        reserved: fdt.mem_reserved(),
+        mmio: Vec::new(),
+        virtio: Vec::new(),
+        bootargs: String::new(),
+    };
+    let mut hart_mask = 0;
+    let mut timebase = None;
+    if fdt
+        .walk(|node, parent| info.add_node(node, parent, &mut hart_mask, &mut timebase))
+        .is_none()
+    {
+        warn!("device tree at {:#x} is malformed, use default config", dtb);
+        return;
+    }
+    info.memory.sort_by_key(|region| region.start);
+    info.virtio.sort_by_key(|region| region.start);
+    info!("device tree at {:#x}", dtb);
+    for region in info.memory.iter() {
+        info!("memory {:#x}..{:#x}", region.start, region.end);
+    }
+    for region in info.reserved.iter() {
+        info!("reserved memory {:#x}..{:#x}", region.start, region.end);
+    }
+    info!("harts {:#b}, bootargs \"{}\"", hart_mask, info.bootargs);
+    if let Some(freq) = timebase {
+        info!("timebase frequency {}", freq);
+        timer::set_clock_freq(freq);
+    }
+    if hart_mask != 0 {
+        HART_MASK.store(hart_mask, Ordering::Relaxed);
+    }
+    *BOOT_INFO.lock() = info;
+}
+
+/// 物理内存的范围，其中包括内核本身和保留的内存
+pub fn memory_regions() -> Vec<Range<usize>> {
+    let info = BOOT_INFO.lock();
+    if info.from_fdt && !info.memory.is_empty() {
+        info.memory.clone()
+    } else {
+        vec![PHYS_MEMORY_OFFSET..PHYS_MEMORY_END]
+    }
+}
+
+/// 设备树中要求保留、不能分配的物理内存
+pub fn reserved_regions() -> Vec<Range<usize>> {
+    BOOT_INFO.lock().reserved.clone()
+}
+
+/// 需要在内核页表中恒等映射的设备 MMIO 区间，包括 virtio-mmio 设备
+pub fn mmio_regions() -> Vec<Range<usize>> {
+    let info = BOOT_INFO.lock();
+    if info.from_fdt {
+        info.mmio
+            .iter()
+            .chain(info.virtio.iter())
+            .cloned()
+            .collect()
+    } else {
+        MMIO_REGIONS
+            .iter()
+            .map(|region| region.0..region.1)
+            .collect()
+    }
+}
+
+/// 所有 virtio-mmio 设备的 MMIO 区间，按地址排序
+pub fn virtio_mmio_regions() -> Vec<Range<usize>> {
+    let info = BOOT_INFO.lock();
+    if info.from_fdt {
+        info.virtio.clone()
+    } else {
+        (0..VIRTIO_MMIO_SLOTS)
+            .map(|slot| VIRTIO_MMIO_START + slot * VIRTIO_MMIO_SIZE)
+            .map(|start| start..start + VIRTIO_MMIO_SIZE)
+            .collect()
+    }
+}
+
+/// 可用的核，第 i 位表示 hartid 为 i 的核
+pub fn hart_mask() -> usize {
+    HART_MASK.load(Ordering::Relaxed)
+}
+
+/// /chosen 中的 bootargs，即 qemu 的 -append 参数。没有时为空串
+pub fn bootargs() -> String {
+    BOOT_INFO.lock().bootargs.clone()
+}
+
+/// 遍历结构块时一个节点的信息。属性值都直接指向设备树中的数据
+struct Node<'a> {
+    /// 节点名，包括 @ 之后的单元地址
+    name: &'a [u8],
+    /// 子节点的 reg 中，地址占几个 u32
+    address_cells: usize,
+    /// 子节点的 reg 中，长度占几个 u32
+    size_cells: usize,
+    device_type: &'a [u8],
+    /// 以 0 分隔的字符串列表
+    compatible: &'a [u8],
+    reg: &'a [u8],
+    /// status 属性不存在或为 "okay"
+    enabled: bool,
+    timebase_frequency: Option<usize>,
+    bootargs: &'a [u8],
+}
+
+impl<'a> Node<'a> {
+    fn new(name: &'a [u8]) -> Self {
+        Self {
+            name,
+            // 规范中规定的默认值
+            address_cells: 2,
+            size_cells: 1,
+            device_type: &[],
+            compatible: &[],
+            reg: &[],
+            enabled: true,
+            timebase_frequency: None,
+            bootargs: &[],
+        }
+    }
+
+    /// 去掉 @ 之后的单元地址的节点名
+    fn base_name(&self) -> &'a [u8] {
+        self.name.split(|&c| c == b'@').next().unwrap_or(self.name)
+    }
+
+    /// compatible 中是否有给定的字符串
+    fn is_compatible(&self, names: &[&[u8]]) -> bool {
+        self.compatible
+            .split(|&c| c == 0)
+            .any(|compatible| names.contains(&compatible))
+    }
+
+    /// 按父节点给出的 #address-cells 和 #size-cells 解析 reg
+    fn reg_regions(&self, parent: &Node) -> Vec<Range<usize>> {
+        let (address_cells, size_cells) = (parent.address_cells, parent.size_cells);
+        let entry_size = (address_cells + size_cells) * 4;
+        if entry_size == 0 {
+            return Vec::new();
+        }
+        self.reg
+            .chunks_exact(entry_size)
+            .map(|entry| {
+                let start = read_cells(&entry[..address_cells * 4]);
+                let size = read_cells(&entry[address_cells * 4..]);
+                start..start.saturating_add(size)
+            })
+            .filter(|region| !region.is_empty())
+            .collect()
+    }
+}
+
+impl BootInfo {
+    /// 在节点结束时处理它。此时节点的所有属性都已经读到了
+    fn add_node(
+        &mut self,
+        node: &Node,
+        parent: Option<&Node>,
+        hart_mask: &mut usize,
+        timebase: &mut Option<usize>,
+    ) {
+        if node.name == b"chosen" {
+            let bootargs = node.bootargs.split(|&c| c == 0).next().unwrap_or(&[]);
+            self.bootargs = String::from_utf8_lossy(bootargs).into_owned();
+        }
+        // timebase-frequency 一般在 /cpus 中，也可能在每个 cpu 节点中
+        if timebase.is_none() {
+            *timebase = node.timebase_frequency;
+        }
+        let parent = match parent {
+            Some(parent) => parent,
+            None => return,
+        };
+        if !node.enabled {
+            return;
+        }
+        if node.device_type == b"memory\0" {
+            self.memory.extend(node.reg_regions(parent));
+        } else if parent.base_name() == b"reserved-memory" {
+            self.reserved.extend(node.reg_regions(parent));
+        } else if node.device_type == b"cpu\0" {
+            // /cpus 的 #size-cells 为 0，reg 中只有 hartid
+            if let Some(hartid) = node.reg.get(..parent.address_cells * 4).map(read_cells) {
+                if (FIRST_CPU_ID..CPU_ID_LIMIT).contains(&hartid) {
+                    *hart_mask |= 1 << hartid;
+                } else {
+                    warn!("hart {} is out of CPU_ID_LIMIT, ignored", hartid);
+                }
+            }
+        } else if node.is_compatible(&[b"virtio,mmio"]) {
+            self.virtio.extend(node.reg_regions(parent));
+        } else if node.is_compatible(&[
+            b"riscv,plic0",
+            b"sifive,plic-1.0.0",
+            b"ns16550a",
+            b"sifive,uart0",
+        ]) {
+            self.mmio.extend(node.reg_regions(parent));
+        }
+    }
+}
+
+/// 一个已经检查过头部的设备树
+struct Fdt {
+    /// 整个设备树的数据
+    data: &'static [u8],
+    /// 结构块的范围
+    structs: Range<usize>,
+    /// 字符串块的范围
+    strings: Range<usize>,
+    /// memreserve 块的起始位置
+    mem_rsvmap: usize,
+}
+
+impl Fdt {
+    /// 检查物理地址 dtb 处的设备树头部
+    ///
+    /// # Safety
+    ///
+    /// 需要在启动页表下调用，且 dtb 处的内存在解析完成前不会被修改
+    unsafe fn from_paddr(dtb: PhysAddr) -> Option<Self> {
+        if dtb < PHYS_MEMORY_OFFSET
+            || dtb % 8 != 0
+            || dtb.checked_add(FDT_HEADER_SIZE)? > BOOT_PHYS_MEMORY_END
+        {
+            return None;
+        }
+        let header = slice::from_raw_parts(phys_to_virt(dtb) as *const u8, FDT_HEADER_SIZE);
+        let field = |index: usize| read_u32(header, index * 4).map(|value| value as usize);
+        if field(0)? != FDT_MAGIC as usize {
+            return None;
+        }
+        let total_size = field(1)?;
+        if total_size < FDT_HEADER_SIZE || dtb.checked_add(total_size)? > BOOT_PHYS_MEMORY_END {
+            return None;
+        }
+        let data = slice::from_raw_parts(phys_to_virt(dtb) as *const u8, total_size);
+        let (off_struct, off_strings, off_rsvmap) = (field(2)?, field(3)?, field(4)?);
+        let (size_strings, size_struct) = (field(8)?, field(9)?);
+        let structs = off_struct..off_struct.checked_add(size_struct)?;
+        let strings = off_strings..off_strings.checked_add(size_strings)?;
+        if structs.end > total_size || strings.end > total_size || off_rsvmap > total_size {
+            return None;
+        }
+        Some(Self {
+            data,
+            structs,
+            strings,
+            mem_rsvmap: off_rsvmap,
+        })
+    }
+
+    /// 读取 memreserve 块。它由 (地址, 长度) 对组成，以两个 0 结尾
+    fn mem_reserved(&self) -> Vec<Range<usize>> {
+        let mut regions = Vec::new();
+        let mut pos = self.mem_rsvmap;
+        while let (Some(start), Some(size)) =
+            (read_u64(self.data, pos), read_u64(self.data, pos + 8))
+        {
+            if start == 0 && size == 0 {
+                break;
+            }
+            regions.push(start..start.saturating_add(size));
+            pos += 16;
+        }
+        regions
+    }
+
+    /// 遍历结构块，每个节点结束时以它和它的父节点调用 f。数据不合法时返回 None
+    fn walk(&self, mut f: impl FnMut(&Node, Option<&Node>)) -> Option<()> {
+        let structs = &self.data[self.structs.clone()];
+        let strings = &self.data[self.strings.clone()];
+        let mut stack: Vec<Node> = Vec::new();
+        let mut pos = 0;
+        loop {
+            let token = read_u32(structs, pos)?;
+            pos += 4;
+            match token {
+                FDT_BEGIN_NODE => {
+                    let len = structs.get(pos..)?.iter().position(|&c| c == 0)?;
+                    stack.push(Node::new(&structs[pos..pos + len]));
+                    pos = align4(pos + len + 1);
+                }
+                FDT_END_NODE => {
+                    let node = stack.pop()?;
+                    f(&node, stack.last());
+                }
+                FDT_PROP => {
+                    let len = read_u32(structs, pos)? as usize;
+                    let name_offset = read_u32(structs, pos + 4)? as usize;
+                    let value = structs.get(pos + 8..pos + 8 + len)?;
+                    pos = align4(pos + 8 + len);
+                    let name_len = strings.get(name_offset..)?.iter().position(|&c| c == 0)?;
+                    let name = &strings[name_offset..name_offset + name_len];
+                    let node = stack.last_mut()?;
+                    match name {
+                        b"#address-cells" => node.address_cells = read_u32(value, 0)? as usize,
+                        b"#size-cells" => node.size_cells = read_u32(value, 0)? as usize,
+                        b"device_type" => node.device_type = value,
+                        b"compatible" => node.compatible = value,
+                        b"reg" => node.reg = value,
+                        b"status" => node.enabled = value.starts_with(b"ok"),
+                        b"timebase-frequency" => node.timebase_frequency = Some(read_cells(value)),
+                        b"bootargs" => node.bootargs = value,
+                        _ => {}
+                    }
+                }
+                FDT_NOP => {}
+                FDT_END => return if stack.is_empty() { Some(()) } else { None },
+                _ => return None,
+            }
+        }
+    }
+}
+
+/// 向上对齐到 4 字节
+fn align4(pos: usize) -> usize {
+    (pos + 3) & !3
+}
+
+/// 读取大端序的 u32
+fn read_u32(data: &[u8], pos: usize) -> Option<u32> {
+    Some(u32::from_be_bytes(data.get(pos..pos + 4)?.try_into().ok()?))
+}
+
+/// 读取大端序的 u64
+fn read_u64(data: &[u8], pos: usize) -> Option<usize> {
+    Some(u64::from_be_bytes(data.get(pos..pos + 8)?.try_into().ok()?) as usize)
+}
+
+/// 把若干个大端序的 u32 拼成一个数，如 reg 中的地址和长度
+fn read_cells(data: &[u8]) -> usize {
+    data.chunks_exact(4).fold(0, |value, cell| {
+        (value << 32) | read_u32(cell, 0).unwrap() as usize
+    })
+}
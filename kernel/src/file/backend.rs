@@ -1,6 +1,9 @@
 //! 和某段内存同步的实际后端文件，带有一个偏移量，相当于原文件的某一段
 //! 可以根据需要和源文件同步
 
+use super::{get_cached_frame, is_cached_dirty, mark_cached_dirty, write_back_page, FatFile};
+use crate::constants::PAGE_SIZE;
+use crate::memory::Frame;
 use alloc::sync::Arc;
 use base_file::File;
 
@@ -19,6 +22,9 @@ pub enum SyncPolicy {
 pub struct BackEndFile {
     file: Arc<dyn File>,
     offset: usize,
+    /// 从 offset 开始只有 limit 字节属于这个后端文件，超出的部分读出 0，写入时丢弃。
+    /// 如 ELF 的段在文件中只有 file_size 字节，之后的部分是 BSS
+    limit: Option<usize>,
     policy: SyncPolicy,
 }
 
@@ -28,6 +34,21 @@ impl BackEndFile {
         Self {
             file: file,
             offset: offset,
+            limit: None,
+            policy: policy,
+        }
+    }
+    /// 创建只映射文件中 [offset, offset + limit) 的后端文件
+    pub fn new_limited(
+        file: Arc<dyn File>,
+        offset: usize,
+        limit: usize,
+        policy: SyncPolicy,
+    ) -> Self {
+        Self {
+            file: file,
+            offset: offset,
+            limit: Some(limit),
             policy: policy,
         }
     }
@@ -37,6 +58,7 @@ impl BackEndFile {
         Self {
             file: self.file.clone(),
             offset: self.offset + delta,
+            limit: self.limit.map(|limit| limit.saturating_sub(delta)),
             policy: self.policy,
         }
     }
@@ -47,6 +69,84 @@ impl BackEndFile {
     /// 改变这个后端文件所映射的文件的偏移量。通常是由于 mmap / munmap / mprotect 导致的区间改变
     pub fn modify_offset(&mut self, delta: usize) {
         self.offset += delta;
+        self.limit = self.limit.map(|limit| limit.saturating_sub(delta));
+    }
+    /// 是否是共享映射，即对映射的修改需要写回文件
+    pub fn is_shared(&self) -> bool {
+        self.policy != SyncPolicy::SyncRead
+    }
+    /// 尝试从页缓存中获取从 pos 开始的一页。
+    ///
+    /// 只有 FAT 中的文件、且这一页在文件中页对齐并完整属于这个后端文件时才能使用页缓存，否则返回 None
+    pub fn cached_frame(&self, pos: usize) -> Option<Arc<Frame>> {
+        let fat_file = self.cache_file(pos)?;
+        get_cached_frame(
+            &fat_file.cache_key(),
+            (self.offset + pos) / PAGE_SIZE,
+            &fat_file.cache_io(),
+        )
+        .ok()
+    }
+    /// 如果从 pos 开始的一页是页缓存中的页帧 frame，返回它是否是脏页。否则返回 None
+    pub fn is_cached_dirty(&self, pos: usize, frame: &Arc<Frame>) -> Option<bool> {
+        let fat_file = self.cache_file(pos)?;
+        is_cached_dirty(
+            &fat_file.cache_key(),
+            (self.offset + pos) / PAGE_SIZE,
+            frame,
+        )
+    }
+    /// 共享映射第一次写入从 pos 开始的一页时调用。如果它是页缓存中的页帧 frame，则标记为脏页
+    pub fn mark_dirty(&self, pos: usize, frame: &Arc<Frame>) {
+        if let Some(fat_file) = self.cache_file(pos) {
+            mark_cached_dirty(
+                &fat_file.cache_key(),
+                (self.offset + pos) / PAGE_SIZE,
+                frame,
+            );
+        }
+    }
+    /// 从 pos 开始的一页是否使用页缓存，条件见 `cached_frame`
+    pub fn is_cached(&self, pos: usize) -> bool {
+        self.cache_file(pos).is_some()
+    }
+    /// pos 在文件中的位置，返回打开的文件对象的地址和文件内的偏移。
+    ///
+    /// fork 出的地址段和原来的地址段使用同一个文件对象
+    pub fn location(&self, pos: usize) -> (usize, usize) {
+        (
+            Arc::as_ptr(&self.file) as *const u8 as usize,
+            self.offset + pos,
+        )
+    }
+    /// 如果从 pos 开始的一页可以使用页缓存，返回对应的 FAT 文件
+    fn cache_file(&self, pos: usize) -> Option<&FatFile> {
+        let fat_file = self.file.as_any().downcast_ref::<FatFile>()?;
+        let file_pos = self.offset + pos;
+        if file_pos % PAGE_SIZE != 0 || self.valid_len(pos, PAGE_SIZE) < PAGE_SIZE {
+            return None;
+        }
+        Some(fat_file)
+    }
+    /// 把从 pos 开始的一页 frame 同步到文件。
+    ///
+    /// 页缓存中的页帧只在它是脏页时写回；其他页帧按同步策略通过 write_to_offset 写回
+    pub fn sync_page(&self, pos: usize, frame: &Arc<Frame>) {
+        if let Some(fat_file) = self.file.as_any().downcast_ref::<FatFile>() {
+            let file_pos = self.offset + pos;
+            if file_pos % PAGE_SIZE == 0
+                && write_back_page(&fat_file.cache_key(), file_pos / PAGE_SIZE, frame)
+            {
+                return;
+            }
+        }
+        // 无法写回也无所谓，当前区域仍可使用
+        self.write_to_offset(pos, frame.as_slice()).unwrap_or(0);
+    }
+    /// 从 pos 开始的 len 字节中，有多少字节在 limit 以内
+    fn valid_len(&self, pos: usize, len: usize) -> usize {
+        self.limit
+            .map_or(len, |limit| limit.saturating_sub(pos).min(len))
     }
 }
 
@@ -59,11 +159,19 @@ impl File for BackEndFile {
     fn write(&self, _buf: &[u8]) -> Option<usize> {
         None
     }
-    /// 转移读操作
+    /// 转移读操作。超出 limit 或者文件结尾的部分读出 0，所以成功时总是填满 buf
     fn read_from_offset(&self, pos: usize, buf: &mut [u8]) -> Option<usize> {
         if self.policy == SyncPolicy::SyncRead || self.policy == SyncPolicy::SyncReadWrite {
             //println!("backend read self.offset {:x} pos {:x}", self.offset, pos);
-            self.file.read_from_offset(self.offset + pos, buf)
+            let valid_len = self.valid_len(pos, buf.len());
+            let read_len = if valid_len > 0 {
+                self.file
+                    .read_from_offset(self.offset + pos, &mut buf[..valid_len])?
+            } else {
+                0
+            };
+            buf[read_len..].fill(0);
+            Some(buf.len())
         } else {
             None
         }
@@ -72,7 +180,12 @@ impl File for BackEndFile {
     fn write_to_offset(&self, pos: usize, buf: &[u8]) -> Option<usize> {
         if self.policy == SyncPolicy::SyncWrite || self.policy == SyncPolicy::SyncReadWrite {
             //println!("backend write self.offset {:x} pos {:x}", self.offset, pos);
-            self.file.write_to_offset(self.offset + pos, buf)
+            let valid_len = self.valid_len(pos, buf.len());
+            if valid_len == 0 {
+                return Some(0);
+            }
+            self.file
+                .write_to_offset(self.offset + pos, &buf[..valid_len])
         } else {
             None
         }
@@ -4,7 +4,11 @@
 //#![deny(missing_docs)]
 
 use super::{get_link_count, FsFile};
-use crate::constants::FS_IMG_SIZE;
+use crate::cmdline::fs_img_size;
+use crate::constants::PAGE_SIZE;
+use crate::file::{
+    invalidate_cached_file, read_cached, update_cached, write_back_file, PageCacheIo,
+};
 use alloc::{string::String, sync::Arc, vec::Vec};
 use base_file::{normal_file_mode, File, Kstat, OpenFlags, StMode};
 use fatfs::{Read, Seek, SeekFrom, Write};
@@ -67,97 +71,181 @@ impl FatFile {
             }),
         }
     }
+    /// 文件在页缓存中的 key，即经过链接转换后的完整路径
+    pub fn cache_key(&self) -> String {
+        [self.dir.as_str(), self.name.as_str()].concat()
+    }
+    /// 页缓存读写这个文件的方式
+    pub fn cache_io(&self) -> Arc<dyn PageCacheIo> {
+        self.file.clone()
+    }
+    /// 把文件的所有脏页写回，即 fsync
+    pub fn sync(&self) {
+        write_back_file(&self.cache_key());
+    }
+    /// 通过页缓存读取从 pos 开始的数据，不超过文件结尾
+    fn read_through_cache(&self, pos: usize, buf: &mut [u8]) -> Option<usize> {
+        let len = file_len(&mut self.file.lock());
+        let read_len = len.saturating_sub(pos).min(buf.len());
+        read_cached(
+            &self.cache_key(),
+            pos,
+            &mut buf[..read_len],
+            &self.cache_io(),
+        )
+        .ok()
+    }
+}
+
+/// 获取文件大小，不改变文件指针
+fn file_len(file: &mut FsFile) -> usize {
+    let pre_pos = file.seek(SeekFrom::Current(0)).unwrap();
+    let len = file.seek(SeekFrom::End(0)).unwrap() as usize;
+    file.seek(SeekFrom::Start(pre_pos)).unwrap();
+    len
+}
+
+/// 从文件当前位置开始读，直到读满 buf 或者读到文件结尾
+fn read_loop(file: &mut FsFile, buf: &mut [u8]) -> Option<usize> {
+    let len = buf.len();
+    let mut pos = 0;
+    while pos < len {
+        match file.read(&mut buf[pos..]) {
+            Ok(read_len) => {
+                if read_len == 0 {
+                    break;
+                } else {
+                    pos += read_len;
+                }
+            }
+            Err(_) => {
+                if pos == 0 {
+                    // 如果什么都没读到，则报错
+                    return None;
+                } else {
+                    //否则说明还是读了一些的
+                    return Some(pos);
+                }
+            }
+        }
+    }
+    Some(pos)
+}
+
+/// 从文件当前位置开始写入 buf
+fn write_loop(file: &mut FsFile, buf: &[u8]) -> Option<usize> {
+    let len = buf.len();
+    let mut pos = 0;
+    while pos < len {
+        match file.write(&buf[pos..]) {
+            Ok(write_len) => {
+                if write_len == 0 {
+                    break;
+                } else {
+                    pos += write_len;
+                }
+            }
+            Err(_) => {
+                if pos == 0 {
+                    return None;
+                } else {
+                    return Some(pos);
+                }
+            }
+        }
+    }
+    Some(pos)
+}
+
+/// 页缓存直接通过 FsFile 读写文件，读写完成后恢复原来的文件指针
+impl PageCacheIo for Mutex<FsFile> {
+    fn read_page(&self, idx: usize, buf: &mut [u8]) {
+        let mut file = self.lock();
+        let pre_pos = file.seek(SeekFrom::Current(0)).unwrap();
+        let read_len = match file.seek(SeekFrom::Start((idx * PAGE_SIZE) as u64)) {
+            Ok(_) => read_loop(&mut file, buf).unwrap_or(0),
+            Err(_) => 0,
+        };
+        buf[read_len..].fill(0);
+        file.seek(SeekFrom::Start(pre_pos)).unwrap();
+    }
+    fn write_page(&self, idx: usize, buf: &[u8]) {
+        let mut file = self.lock();
+        let start = idx * PAGE_SIZE;
+        let write_len = file_len(&mut file).saturating_sub(start).min(buf.len());
+        if write_len > 0 {
+            let pre_pos = file.seek(SeekFrom::Current(0)).unwrap();
+            file.seek(SeekFrom::Start(start as u64)).unwrap();
+            write_loop(&mut file, &buf[..write_len]);
+            file.seek(SeekFrom::Start(pre_pos)).unwrap();
+        }
+    }
 }
 
 impl File for FatFile {
-    /// 读取文件
+    /// 读取文件。数据从页缓存中读出
     fn read(&self, buf: &mut [u8]) -> Option<usize> {
         if !self.readable {
             return None;
         }
+        // 不能在持有文件锁的时候访问页缓存，所以先取出文件指针，读完再移动它
+        let pos = self.file.lock().seek(SeekFrom::Current(0)).unwrap() as usize;
+        let read_len = self.read_through_cache(pos, buf)?;
+        self.file
+            .lock()
+            .seek(SeekFrom::Start((pos + read_len) as u64))
+            .unwrap();
+        Some(read_len)
+    }
+    /// 写入文件。数据直接写入文件，同时更新页缓存中已有的页
+    fn write(&self, buf: &[u8]) -> Option<usize> {
+        if !self.writable {
+            return None;
+        }
         let mut file = self.file.lock();
-        let len = buf.len();
-        let mut pos = 0;
-        while pos < len {
-            match file.read(&mut buf[pos..]) {
-                Ok(read_len) => {
-                    if read_len == 0 {
-                        break;
-                    } else {
-                        pos += read_len;
-                    }
-                }
-                Err(_) => {
-                    if pos == 0 {
-                        // 如果什么都没读到，则报错
-                        return None;
-                    } else {
-                        //否则说明还是读了一些的
-                        return Some(pos);
-                    }
-                }
-            }
+        let pos = file.seek(SeekFrom::Current(0)).unwrap() as usize;
+        let write_len = write_loop(&mut file, buf)?;
+        drop(file);
+        update_cached(&self.cache_key(), pos, &buf[..write_len]);
+        Some(write_len)
+    }
+    /// 从 pos 开始读文件，不改变文件指针
+    fn read_from_offset(&self, pos: usize, buf: &mut [u8]) -> Option<usize> {
+        if !self.readable {
+            return None;
         }
-        Some(pos)
+        self.read_through_cache(pos, buf)
     }
-    /// 写入文件
-    fn write(&self, buf: &[u8]) -> Option<usize> {
+    /// 从 pos 开始写文件，不改变文件指针。pos 超过文件结尾时，中间的部分填 0
+    fn write_to_offset(&self, pos: usize, buf: &[u8]) -> Option<usize> {
         if !self.writable {
             return None;
         }
         let mut file = self.file.lock();
-        let len = buf.len();
-        let mut pos = 0;
-        while pos < len {
-            match file.write(&buf[pos..]) {
-                Ok(write_len) => {
-                    if write_len == 0 {
-                        break;
-                    } else {
-                        pos += write_len;
-                    }
-                }
-                Err(_) => {
-                    if pos == 0 {
-                        return None;
-                    } else {
-                        return Some(pos);
-                    }
-                }
-            }
+        let pre_pos = file.seek(SeekFrom::Current(0)).unwrap();
+        let len = file.seek(SeekFrom::End(0)).unwrap() as usize;
+        if len < pos {
+            let mut zeros: Vec<u8> = Vec::new();
+            zeros.resize(pos - len, 0);
+            write_loop(&mut file, zeros.as_slice());
+        }
+        file.seek(SeekFrom::Start(pos as u64)).unwrap();
+        let write_len = write_loop(&mut file, buf);
+        file.seek(SeekFrom::Start(pre_pos)).unwrap();
+        drop(file);
+        if let Some(write_len) = write_len {
+            update_cached(&self.cache_key(), pos, &buf[..write_len]);
         }
-        Some(pos)
+        write_len
     }
-    /// 读取所有数据
+    /// 读取所有数据。和 read 一样经过页缓存，所以能读到共享映射中还没有写回的修改
     unsafe fn read_all(&self) -> Vec<u8> {
-        let mut file = self.file.lock();
         // 获取文件大小
-        let len = file.seek(SeekFrom::End(0)).unwrap() as usize;
-        file.seek(SeekFrom::Start(0)).unwrap();
+        let len = file_len(&mut self.file.lock());
         let mut temp: Vec<u8> = Vec::new();
         info!("file len {}=0x{:x}", len, len);
         temp.resize(len, 0);
-        let mut pos = 0;
-        while pos < len {
-            let read_len = file.read(&mut temp[pos..]).unwrap();
-            //println!("read {} bytes", read_len);
-            pos += read_len;
-        }
-        /*
-        // println!("{} {} {} {}", temp[0], temp[1], temp[2], temp[3]); // elf
-        println!("-------------------- test elf --------------------");
-        let mut i: usize = 0x1000;
-        while i < len && temp[i] == 0 {
-            i += 1;
-        }
-        print!("i = {} , temp[i] = {}", i, temp[i]);
-
-        //for i in 0x1000..0x1010 {
-        //    print!("{} ", temp[i]);
-        //}
-
-        println!("");
-        */
+        read_cached(&self.cache_key(), 0, temp.as_mut_slice(), &self.cache_io()).unwrap();
         temp
     }
     /// 文件属性
@@ -193,7 +281,7 @@ impl File for FatFile {
             .map(|pos| match seekfrom {
                 SeekFrom::Start(origin) => {
                     let len = file.seek(SeekFrom::End(0)).unwrap();
-                    if len < origin && origin - len <= FS_IMG_SIZE as u64 {
+                    if len < origin && origin - len <= fs_img_size() as u64 {
                         let mut buf: Vec<u8> = Vec::new();
                         buf.resize(origin as usize - len as usize, 0);
                         file.write(buf.as_slice()).unwrap();
@@ -250,8 +338,9 @@ impl File for FatFile {
     fn get_status(&self) -> OpenFlags {
         self.inner.lock().flags
     }
-    /// 清空文件
+    /// 清空文件，同时丢弃它在页缓存中的内容
     fn clear(&self) {
+        invalidate_cached_file(&self.cache_key());
         let mut file = self.file.lock();
         file.seek(SeekFrom::Start(0)).unwrap();
         file.truncate().unwrap();
@@ -12,7 +12,7 @@ mod test;
 
 use super::{
     check_virt_dir_exists, check_virt_file_exists, get_virt_dir_if_possible,
-    get_virt_file_if_possible, try_make_virt_dir, try_remove_virt_file,
+    get_virt_file_if_possible, invalidate_cached_file, try_make_virt_dir, try_remove_virt_file,
 };
 use crate::{
     constants::ROOT_DIR,
@@ -46,6 +46,7 @@ pub use test::{
     //load_testcases,
     load_next_testcase,
     show_testcase_result,
+    testcase_list,
 };
 
 lazy_static::lazy_static! {
@@ -341,8 +342,7 @@ pub fn open_file(dir_name: &str, file_path: &str, flags: OpenFlags) -> Option<Ar
                     );
                     if flags.contains(OpenFlags::CREATE) {
                         // 清空这个文件
-                        fat_file.seek(fatfs::SeekFrom::Start(0)).unwrap();
-                        fat_file.file.lock().truncate().unwrap();
+                        fat_file.clear();
                     };
                     debug!("opened file {}", file_name);
                     Some(Arc::new(fat_file))
@@ -415,6 +415,7 @@ fn remove_file(path: &str, name: &str) {
     }
     let dir = inner_open_dir(root, path).unwrap();
     dir.remove(name).unwrap();
+    invalidate_cached_file(&[path, name].concat());
     /*
     dir.remove(name).unwrap_or_else(|_| {
         println!("path [{}] name [{}]", path, name);
@@ -461,6 +462,9 @@ pub fn rename_or_move(
     new_file: &str,
     replace: bool,
 ) -> Result<(), ErrorNo> {
+    // 页缓存以路径区分文件，所以移动前后的文件都不能再使用原来的缓存
+    invalidate_cached_file(&[old_dir, old_file].concat());
+    invalidate_cached_file(&[new_dir, new_file].concat());
     if let Some(old_dir) = inner_open_dir(MEMORY_FS.root_dir(), old_dir) {
         if let Some(new_dir) = inner_open_dir(MEMORY_FS.root_dir(), new_dir) {
             return match old_dir.rename(old_file, &new_dir, new_file) {
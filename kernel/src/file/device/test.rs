@@ -1,10 +1,11 @@
 //! 运行比赛测试
 
 use crate::{
+    cmdline::test_lists,
     constants::{NO_PARENT, ROOT_DIR},
     task::TaskControlBlock,
 };
-use alloc::{boxed::Box, string::String, sync::Arc, vec::Vec};
+use alloc::{string::String, sync::Arc, vec::Vec};
 use lock::Mutex;
 
 /// 分隔 argv 参数，处理带引号的情况
@@ -37,7 +38,7 @@ pub fn load_next_testcase() -> Option<Arc<TaskControlBlock>> {
             TEST_STATUS.lock().final_info();
             None
         },
-        |&user_command| {
+        |user_command| {
             //let mut argv: Vec<String> = user_command.split(' ').map(|s| s.into()).collect();
             //let argv = argv.drain_filter(|s| s != "").collect();
             let argv = split_argv(user_command.as_bytes());
@@ -123,18 +124,48 @@ impl TestStatus {
         for info in SYS_INFO.lock().iter() {
             info!("{info}");
         }
+        info!("frames: {:?}", crate::memory::frame_stat());
+        info!("kernel heap: {:#x?}", crate::memory::heap_stat());
+        for stat in crate::memory::slab_stats() {
+            info!("slab {:#x?}", stat);
+        }
         panic!("all test end.");
     }
 }
 
-#[allow(dead_code)]
-const CASES: &[&str] = crate::testcases::TESTCASES;
+/// 按名字查找测例列表，即启动参数 `tests=` 中可以使用的名字
+pub fn testcase_list(name: &str) -> Option<&'static [&'static str]> {
+    match name {
+        "default" => Some(crate::testcases::TESTCASES),
+        "sample" => Some(SAMPLE),
+        "busybox" => Some(BUSYBOX_TESTCASES),
+        "lua" => Some(LUA_TESTCASES),
+        "libc-dynamic" => Some(LIBC_DYNAMIC_TESTCASES),
+        "libc-static" => Some(LIBX_STATIC_TESTCASES),
+        "preliminary" => Some(PRELIMINARY_TESTCASES),
+        "format-libc-static" => Some(FORMAT_LIBC_STATIC),
+        "format-libc-dynamic" => Some(FORMAT_LIBC_DYNAMIC),
+        _ => None,
+    }
+}
+
+/// 启动参数中选择的所有测例，按列表的顺序拼接
+fn selected_testcases() -> Vec<&'static str> {
+    let mut cases = Vec::new();
+    for name in test_lists() {
+        match testcase_list(&name) {
+            Some(list) => cases.extend_from_slice(list),
+            None => warn!("unknown testcase list {}", name),
+        }
+    }
+    cases
+}
 
 lazy_static::lazy_static! {
     //static ref TESTCASES_ITER: Mutex<Box<dyn Iterator<Item = &'static &'static str> + Send>> = Mutex::new(Box::new(FORMAT_LIBC_STATIC.into_iter().chain(FORMAT_LIBC_DYNAMIC.into_iter())));
     //static ref TEST_STATUS: Mutex<TestStatus> = Mutex::new(TestStatus::new(&[FORMAT_LIBC_STATIC, FORMAT_LIBC_DYNAMIC].concat()));
-    static ref TESTCASES_ITER: Mutex<Box<dyn Iterator<Item = &'static &'static str> + Send>> = Mutex::new(Box::new(CASES.into_iter()));
-    static ref TEST_STATUS: Mutex<TestStatus> = Mutex::new(TestStatus::new(CASES));
+    static ref TESTCASES_ITER: Mutex<alloc::vec::IntoIter<&'static str>> = Mutex::new(selected_testcases().into_iter());
+    static ref TEST_STATUS: Mutex<TestStatus> = Mutex::new(TestStatus::new(&selected_testcases()));
     static ref SYS_INFO: Mutex<Vec<String>> = Mutex::new(Vec::new());
 }
 
@@ -0,0 +1,24 @@
+//! 文件读写状态变化的通知
+//!
+//! epoll_wait 和 socket 的阻塞读写需要等待"某个文件变得可读/可写"，但事先不知道会是哪个任务来改变它。
+//! 所以所有可能改变文件读写状态的操作(如管道和 socket 的读写、关闭)都会唤醒这个全局的等待队列，
+//! 被唤醒的任务再自己检查它关心的文件
+
+use crate::task::WaitQueue;
+
+/// 等待文件读写状态变化的任务
+static FILE_EVENT_QUEUE: WaitQueue = WaitQueue::new();
+
+/// 通知所有等待文件状态变化的任务
+pub fn notify_file_event() {
+    FILE_EVENT_QUEUE.notify_all();
+}
+
+/// 阻塞当前任务，直到 `cond` 返回 Some，或者系统时间到达 expire_us(单位为微秒，None 表示不会超时)。
+/// 每次有文件状态变化时都会重新检查 `cond`
+pub fn wait_for_file_event<T>(
+    expire_us: Option<usize>,
+    cond: impl FnMut() -> Option<T>,
+) -> Option<T> {
+    FILE_EVENT_QUEUE.wait_until(expire_us, cond)
+}
@@ -3,6 +3,7 @@
 
 //#![deny(missing_docs)]
 
+use alloc::collections::BTreeSet;
 use alloc::sync::Arc;
 use alloc::vec::Vec;
 use base_file::{File, OpenFlags};
@@ -18,6 +19,9 @@ use super::stdio::{Stderr, Stdin, Stdout};
 pub struct FdManager {
     /// 内部包含的文件
     files: Vec<Option<Arc<dyn File>>>,
+    /// 带有 CLOEXEC 标记的 fd。
+    /// 它只属于 fd 表项，与文件状态中的 CLOEXEC 不同，不会影响其他进程中指向同一个文件的 fd
+    cloexec_fds: BTreeSet<usize>,
     /// 描述符和分配器
     fd_allocator: FdAllocator,
     /// 最大 fd 限制
@@ -33,6 +37,7 @@ impl FdManager {
         let limit = FD_LIMIT_ORIGIN;
         let mut fd_manager = Self {
             files: Vec::new(),
+            cloexec_fds: BTreeSet::new(),
             fd_allocator: FdAllocator::new(limit),
             limit: limit,
             umask: umask,
@@ -51,6 +56,7 @@ impl FdManager {
     pub fn copy_all(&self) -> Self {
         let mut new_manager = Self {
             files: Vec::new(),
+            cloexec_fds: self.cloexec_fds.clone(),
             fd_allocator: FdAllocator::new(self.limit),
             limit: self.limit,
             umask: self.umask,
@@ -99,6 +105,7 @@ impl FdManager {
                 }
                 // 这里可能会删除该处原有的fd，不过这是符合语义的
                 self.files[new_fd].replace(file);
+                self.cloexec_fds.remove(&new_fd);
             })
             .is_ok()
     }
@@ -115,6 +122,38 @@ impl FdManager {
             Err(OSError::FdManager_NoAvailableFd)
         }
     }
+    /// 预留最多 count 个 fd，但暂时不放入文件。返回预留到的 fd，fd 不够时会少于 count
+    pub fn reserve(&mut self, count: usize) -> Vec<usize> {
+        (0..count)
+            .map_while(|_| self.fd_allocator.alloc())
+            .collect()
+    }
+    /// 把文件放进一个预留的 fd。cloexec 为 true 时给这个 fd 加上 CLOEXEC 标记
+    pub fn install_reserved(&mut self, fd: usize, file: Arc<dyn File>, cloexec: bool) {
+        if self.files.len() <= fd {
+            self.files.resize(fd + 1, None);
+        }
+        self.files[fd] = Some(file);
+        if cloexec {
+            self.cloexec_fds.insert(fd);
+        }
+    }
+    /// 取消预留一个还没有放入文件的 fd
+    pub fn unreserve(&mut self, fd: usize) {
+        self.fd_allocator.dealloc(fd);
+    }
+    /// fd 表项是否带有 CLOEXEC 标记
+    pub fn is_fd_cloexec(&self, fd: usize) -> bool {
+        self.cloexec_fds.contains(&fd)
+    }
+    /// 设置或清除 fd 表项的 CLOEXEC 标记
+    pub fn set_fd_cloexec(&mut self, fd: usize, is_set: bool) {
+        if is_set {
+            self.cloexec_fds.insert(fd);
+        } else {
+            self.cloexec_fds.remove(&fd);
+        }
+    }
     /// 拿到一个文件的 Arc 指针(clone 语义)
     pub fn get_file(&self, fd: usize) -> OSResult<Arc<dyn File>> {
         if fd >= self.files.len() || self.files[fd].is_none() {
@@ -144,6 +183,7 @@ impl FdManager {
             return Err(OSError::FdManager_FdNotFound);
         } else {
             self.fd_allocator.dealloc(fd);
+            self.cloexec_fds.remove(&fd);
             Ok(self.files[fd].take().unwrap())
         }
     }
@@ -171,20 +211,22 @@ impl FdManager {
         }
         self.limit = new_limit;
     }
-    /// 删除所有带有 CLOEXEC 标记的文件。在 exec 时使用
+    /// 删除所有带有 CLOEXEC 标记的文件，包括文件状态和 fd 表项上的标记。在 exec 时使用
     pub fn close_cloexec_files(&mut self) {
         // 这里希望删除文件后其他文件顺序不变，所以用枚举 fd 而不是迭代器之类的方法
         for fd in 0..self.files.len() {
             if self.files[fd].is_some()
-                && self.files[fd]
-                    .as_ref()
-                    .unwrap()
-                    .get_status()
-                    .contains(OpenFlags::CLOEXEC)
+                && (self.cloexec_fds.contains(&fd)
+                    || self.files[fd]
+                        .as_ref()
+                        .unwrap()
+                        .get_status()
+                        .contains(OpenFlags::CLOEXEC))
             {
                 self.files[fd].take();
             }
         }
+        self.cloexec_fds.clear();
     }
     /// 获取 umask
     pub fn get_umask(&self) -> i32 {
@@ -2,8 +2,10 @@
 
 mod backend;
 mod device;
+mod event;
 mod fd_manager;
 mod fs_stat;
+mod page_cache;
 mod pipe;
 pub mod socket;
 mod stdio;
@@ -27,18 +29,25 @@ pub use device::{
     read_link,
     rename_or_move,
     show_testcase_result,
+    testcase_list,
     try_add_link,
     try_remove_link,
     umount_fat_fs,
 };
 
 pub use backend::{BackEndFile, SyncPolicy};
+pub use event::{notify_file_event, wait_for_file_event};
 pub use device::{FatFile, FileDisc};
 pub use fd_manager::FdManager;
 pub use fs_stat::FsStat;
+pub use page_cache::{
+    get_cached_frame, invalidate_cached_file, is_cached_dirty, mark_cached_dirty, read_cached,
+    shrink_page_cache, update_cached, write_back_all, write_back_file, write_back_page,
+    PageCacheIo,
+};
 pub use pipe::{Pipe, RingBuffer};
-pub use socket::Socket;
+pub use socket::{poll_nic, Socket};
 pub use vfs::{
     check_virt_dir_exists, check_virt_file_exists, get_virt_dir_if_possible,
-    get_virt_file_if_possible, try_make_virt_dir, try_remove_virt_file, BufferFile,
+    get_virt_file_if_possible, try_make_virt_dir, try_remove_virt_file, BufferFile, ShmFile,
 };
@@ -0,0 +1,276 @@
+//! 全局的页缓存
+//!
+//! 文件系统中的文件按 (文件的完整路径, 页号) 缓存在这里。文件的 read / write 和 mmap 出的页都使用同一份页帧：
+//! - 只读映射和私有映射(包括 ELF 的段)直接共享缓存的页帧，写入时再写时复制；
+//! - 共享映射(MAP_SHARED)的写入直接改动缓存的页帧。页一开始以只读方式映射，第一次写入时触发 page fault，
+//! 这时才被标记为脏页，在 msync / fsync / sync、或者缓存满了需要换出时写回文件。
+//! 仍然被映射着的脏页写回后还是脏页，因为之后通过映射的写入不会再触发 page fault；
+//! - write 同时写入文件和缓存，所以不会产生脏页。
+//!
+//! 缓存的页数超过 `PAGE_CACHE_FRAMES_LIMIT` 时，会换出最久没有访问、且没有被映射到任何地址空间的页。
+//!
+//! 缓存的锁在文件本身的锁之前获取，即持有缓存的锁时可以调用 `PageCacheIo` 读写文件，反之则不行
+
+use crate::constants::{PAGE_CACHE_FRAMES_LIMIT, PAGE_SIZE};
+use crate::error::{OSError, OSResult};
+use crate::memory::Frame;
+use crate::task::request_oom_kill;
+use alloc::{collections::BTreeMap, string::String, sync::Arc, vec::Vec};
+use core::slice;
+use lock::Mutex;
+
+/// 页缓存读写文件内容的方式
+pub trait PageCacheIo: Send + Sync {
+    /// 把文件的第 idx 页读到 buf 中，文件结尾之后的部分填 0
+    fn read_page(&self, idx: usize, buf: &mut [u8]);
+    /// 把 buf 写回文件的第 idx 页，文件结尾之后的部分不写
+    fn write_page(&self, idx: usize, buf: &[u8]);
+}
+
+/// 缓存中的一页
+struct CachedPage {
+    frame: Arc<Frame>,
+    /// 是否被共享映射修改过，还没有写回文件
+    dirty: bool,
+    /// 最后一次访问的时间戳，换出时使用
+    last_access: usize,
+}
+
+/// 一个文件缓存的所有页
+struct CachedFile {
+    /// 读写文件的方式。每次访问时更新为最新打开的文件，换出脏页时使用
+    io: Arc<dyn PageCacheIo>,
+    pages: BTreeMap<usize, CachedPage>,
+}
+
+struct PageCache {
+    files: BTreeMap<String, CachedFile>,
+    /// 缓存的总页数
+    page_count: usize,
+    /// 访问计数，作为每页的访问时间戳
+    clock: usize,
+}
+
+static PAGE_CACHE: Mutex<PageCache> = Mutex::new(PageCache {
+    files: BTreeMap::new(),
+    page_count: 0,
+    clock: 0,
+});
+
+impl PageCache {
+    /// 获取文件 key 的第 idx 页，不在缓存中时从 io 读入
+    fn get_page(
+        &mut self,
+        key: &str,
+        idx: usize,
+        io: &Arc<dyn PageCacheIo>,
+    ) -> OSResult<&mut CachedPage> {
+        self.clock += 1;
+        let clock = self.clock;
+        let cached = self
+            .files
+            .get(key)
+            .map_or(false, |file| file.pages.contains_key(&idx));
+        if !cached {
+            if self.page_count >= PAGE_CACHE_FRAMES_LIMIT {
+                self.evict(PAGE_CACHE_FRAMES_LIMIT / 8);
+            }
+            let mut frame = match Frame::new() {
+                Some(frame) => frame,
+                None => {
+                    // 内存不足时先尽量换出缓存再试一次，还是不够就请求 OOM killer 释放内存
+                    self.evict(self.page_count);
+                    Frame::new().ok_or_else(|| {
+                        request_oom_kill();
+                        OSError::Memory_RunOutOfMemory
+                    })?
+                }
+            };
+            io.read_page(idx, frame.as_slice_mut());
+            self.page_count += 1;
+            self.files
+                .entry(String::from(key))
+                .or_insert_with(|| CachedFile {
+                    io: io.clone(),
+                    pages: BTreeMap::new(),
+                })
+                .pages
+                .insert(
+                    idx,
+                    CachedPage {
+                        frame: Arc::new(frame),
+                        dirty: false,
+                        last_access: clock,
+                    },
+                );
+        }
+        let file = self.files.get_mut(key).unwrap();
+        file.io = io.clone();
+        let page = file.pages.get_mut(&idx).unwrap();
+        page.last_access = clock;
+        Ok(page)
+    }
+    /// 换出至多 count 个没有被映射的页，脏页先写回。返回换出的页数
+    fn evict(&mut self, count: usize) -> usize {
+        let mut candidates: Vec<(usize, String, usize)> = Vec::new();
+        for (key, file) in self.files.iter() {
+            for (&idx, page) in file.pages.iter() {
+                // 只有缓存自己持有的页帧可以换出
+                if Arc::strong_count(&page.frame) == 1 {
+                    candidates.push((page.last_access, key.clone(), idx));
+                }
+            }
+        }
+        candidates.sort_unstable_by_key(|&(last_access, _, _)| last_access);
+        candidates.truncate(count);
+        let evicted = candidates.len();
+        for (_, key, idx) in candidates {
+            let file = self.files.get_mut(&key).unwrap();
+            let page = file.pages.remove(&idx).unwrap();
+            if page.dirty {
+                file.io.write_page(idx, page.frame.as_slice());
+            }
+            if file.pages.is_empty() {
+                self.files.remove(&key);
+            }
+        }
+        self.page_count -= evicted;
+        evicted
+    }
+    /// 文件 key 第 idx 页在缓存中的页帧如果是 frame，返回这一页
+    fn find_page(&mut self, key: &str, idx: usize, frame: &Arc<Frame>) -> Option<&mut CachedPage> {
+        self.files
+            .get_mut(key)?
+            .pages
+            .get_mut(&idx)
+            .filter(|page| Arc::ptr_eq(&page.frame, frame))
+    }
+    /// 删除文件 key 的所有缓存页，不写回
+    fn remove_file(&mut self, key: &str) {
+        if let Some(file) = self.files.remove(key) {
+            self.page_count -= file.pages.len();
+        }
+    }
+}
+
+/// 获取文件 key 第 idx 页的页帧，用于把它映射到用户地址空间。
+///
+/// 映射本身不会产生脏页，共享映射第一次写入这一页时需要调用 `mark_cached_dirty`
+pub fn get_cached_frame(key: &str, idx: usize, io: &Arc<dyn PageCacheIo>) -> OSResult<Arc<Frame>> {
+    let mut cache = PAGE_CACHE.lock();
+    let page = cache.get_page(key, idx, io)?;
+    Ok(page.frame.clone())
+}
+
+/// 如果 frame 是文件 key 第 idx 页在缓存中的页帧，返回它是否是脏页。否则返回 None
+pub fn is_cached_dirty(key: &str, idx: usize, frame: &Arc<Frame>) -> Option<bool> {
+    PAGE_CACHE
+        .lock()
+        .find_page(key, idx, frame)
+        .map(|page| page.dirty)
+}
+
+/// 如果 frame 是文件 key 第 idx 页在缓存中的页帧，把它标记为脏页。一般是共享映射第一次写入这一页时要求的
+pub fn mark_cached_dirty(key: &str, idx: usize, frame: &Arc<Frame>) {
+    if let Some(page) = PAGE_CACHE.lock().find_page(key, idx, frame) {
+        page.dirty = true;
+    }
+}
+
+/// 从缓存中读取文件 key 从 pos 开始的数据到 buf。调用者需要保证读取的范围不超过文件结尾
+pub fn read_cached(
+    key: &str,
+    pos: usize,
+    buf: &mut [u8],
+    io: &Arc<dyn PageCacheIo>,
+) -> OSResult<usize> {
+    let mut cache = PAGE_CACHE.lock();
+    let mut done = 0;
+    while done < buf.len() {
+        let offset = (pos + done) % PAGE_SIZE;
+        let len = (PAGE_SIZE - offset).min(buf.len() - done);
+        let page = cache.get_page(key, (pos + done) / PAGE_SIZE, io)?;
+        buf[done..done + len].copy_from_slice(&page.frame.as_slice()[offset..offset + len]);
+        done += len;
+    }
+    Ok(done)
+}
+
+/// 文件 key 从 pos 开始的部分被写入了 buf，更新已经缓存的页。不在缓存中的页不需要处理
+pub fn update_cached(key: &str, pos: usize, buf: &[u8]) {
+    let mut cache = PAGE_CACHE.lock();
+    let file = match cache.files.get_mut(key) {
+        Some(file) => file,
+        None => return,
+    };
+    let mut done = 0;
+    while done < buf.len() {
+        let offset = (pos + done) % PAGE_SIZE;
+        let len = (PAGE_SIZE - offset).min(buf.len() - done);
+        if let Some(page) = file.pages.get(&((pos + done) / PAGE_SIZE)) {
+            // 页帧可能同时映射在用户地址空间中，拿不到 &mut Frame，所以直接从地址构造 slice
+            let data =
+                unsafe { slice::from_raw_parts_mut(page.frame.as_mut_ptr().add(offset), len) };
+            data.copy_from_slice(&buf[done..done + len]);
+        }
+        done += len;
+    }
+}
+
+/// 页帧是否还映射在某个地址空间中。这样的页写回后仍然保持为脏页，见模块的说明
+fn is_mapped(page: &CachedPage) -> bool {
+    Arc::strong_count(&page.frame) > 1
+}
+
+/// 把文件 key 的第 idx 页写回文件，如果它是脏页的话。
+///
+/// 返回 frame 是否是缓存中的页帧。如果不是则不做任何处理，由调用者自己写回
+pub fn write_back_page(key: &str, idx: usize, frame: &Arc<Frame>) -> bool {
+    let mut cache = PAGE_CACHE.lock();
+    if let Some(file) = cache.files.get_mut(key) {
+        if let Some(page) = file.pages.get_mut(&idx) {
+            if Arc::ptr_eq(&page.frame, frame) {
+                if page.dirty {
+                    file.io.write_page(idx, page.frame.as_slice());
+                    page.dirty = is_mapped(page);
+                }
+                return true;
+            }
+        }
+    }
+    false
+}
+
+/// 把文件 key 的所有脏页写回文件，即 fsync
+pub fn write_back_file(key: &str) {
+    let mut cache = PAGE_CACHE.lock();
+    if let Some(file) = cache.files.get_mut(key) {
+        for (&idx, page) in file.pages.iter_mut().filter(|(_, page)| page.dirty) {
+            file.io.write_page(idx, page.frame.as_slice());
+            page.dirty = is_mapped(page);
+        }
+    }
+}
+
+/// 把所有文件的脏页写回，即 sync
+pub fn write_back_all() {
+    let mut cache = PAGE_CACHE.lock();
+    for file in cache.files.values_mut() {
+        for (&idx, page) in file.pages.iter_mut().filter(|(_, page)| page.dirty) {
+            file.io.write_page(idx, page.frame.as_slice());
+            page.dirty = is_mapped(page);
+        }
+    }
+}
+
+/// 内存不足时换出至多 count 个没有被映射的页，返回实际换出的页数
+pub fn shrink_page_cache(count: usize) -> usize {
+    PAGE_CACHE.lock().evict(count)
+}
+
+/// 文件 key 被截断、删除或者移动后，丢弃它的所有缓存页。
+///
+/// 已经映射到用户地址空间的页帧仍由对应的地址段持有，不会被释放
+pub fn invalidate_cached_file(key: &str) {
+    PAGE_CACHE.lock().remove_file(key);
+}
@@ -1,11 +1,11 @@
 //! 管道实现
 //!
 //! 相当于两个文件，其中一个只读，一个只可写，但指向同一片内存。
-//! Pipe 的读写可能会触发进程切换。
+//! Pipe 的读写可能会阻塞，此时任务在两端共享的等待队列上等待另一端读写或者关闭。
 //! 目前的实现中，Pipe会请求并获取页帧，不占用内核堆/栈
 
-use super::BufferFile;
-use crate::{constants::PIPE_SIZE_LIMIT, task::suspend_current_task};
+use super::{notify_file_event, BufferFile};
+use crate::{constants::PIPE_SIZE_LIMIT, task::WaitQueue};
 use alloc::sync::Arc;
 use base_file::{File, OpenFlags};
 use lock::Mutex;
@@ -105,57 +105,87 @@ pub struct Pipe {
     /// 管道内保存的数据
     /// 只有所有持有管道的 Arc 被 Drop 时，才会释放其中的 PipeBuffer 的空间
     data: Arc<Mutex<RingBuffer>>,
+    /// 在管道上阻塞的任务。
+    /// 注意它必须放在 data 之后，这样在 Drop 时，它唤醒另一端之前 data 的引用计数就已经减少了
+    waiters: PipeWaiters,
+}
+
+/// 两端共享的等待队列，每次读写或者关闭一端时都会唤醒在另一端等待的任务
+struct PipeWaiters(Arc<WaitQueue>);
+
+impl PipeWaiters {
+    /// 唤醒所有在管道上等待的任务
+    fn notify(&self) {
+        self.0.notify_all();
+        // 可能有任务在 epoll_wait 里等这个管道
+        notify_file_event();
+    }
+}
+
+impl Drop for PipeWaiters {
+    /// 一端被关闭时，另一端需要知道这件事
+    fn drop(&mut self) {
+        self.notify();
+    }
 }
 
 impl Pipe {
     /// 新建一个管道，返回两端
     pub fn new_pipe() -> (Self, Self) {
         let buf = Arc::new(Mutex::new(RingBuffer::new(PIPE_SIZE_LIMIT)));
+        let waiters = Arc::new(WaitQueue::new());
         (
             Self {
                 is_read: true,
                 data: buf.clone(),
+                waiters: PipeWaiters(waiters.clone()),
             },
             Self {
                 is_read: false,
                 data: buf,
+                waiters: PipeWaiters(waiters),
             },
         )
     }
+    /// 另一端是否还没有关闭。
+    /// 注意这里 self.data 的引用一定是自己持有一个，另一端持有一个
+    /// 就算 fd 被复制，也只是复制 Pipe 外包着的 Arc，内部 self.data 的 Arc 不会复制
+    fn other_end_alive(&self) -> bool {
+        Arc::strong_count(&self.data) == 2
+    }
 }
 
 impl File for Pipe {
-    /// 读管道中数据
+    /// 读管道中数据。
+    /// 如果管道是空的，则阻塞直到读到数据，或者写端被关闭
     fn read(&self, buf: &mut [u8]) -> Option<usize> {
         if self.is_read {
-            let mut read_len = 0;
             // 先读一次，如果一次完成就不用切换进程了
-            read_len += self.data.lock().read(&mut buf[read_len..]);
+            let mut read_len = self.data.lock().read(buf);
             info!("read pipe len {}, require {}", read_len, buf.len());
-            //let tid = crate::task::get_current_task().unwrap().get_tid_num();
-            //if buf.len() != 4 { println!("tid {} read pipe len {}, require {}", tid, read_len, buf.len()); }
-            // if buf.len() != read_len { println!("tid {} read {} got {}", tid, buf.len(), read_len); }
-
-            // 如果读够了或者写端的 fd 已经被释放了，则退出
-            // 注意这里 self.data 的引用一定是自己持有一个，写端持有一个
-            // 就算 fd 被复制，也只是复制 Pipe 外包着的 Arc，内部 self.data 的 Arc 不会复制
-            let mut cnt = 0;
-            while read_len < buf.len() && Arc::strong_count(&self.data) == 2 {
-                cnt += 1;
-                if cnt > 2 {
-                    break;
-                }
-                suspend_current_task();
-                read_len += self.data.lock().read(&mut buf[read_len..]);
+            if read_len == 0 && !buf.is_empty() {
+                // 被信号打断时返回 None，由 syscall 返回 EINTR
+                read_len = self.waiters.0.wait_until(None, || {
+                    let read_len = self.data.lock().read(buf);
+                    // 如果读到了数据或者写端的 fd 已经被释放了，则退出
+                    if read_len > 0 || !self.other_end_alive() {
+                        Some(read_len)
+                    } else {
+                        None
+                    }
+                })?;
+            }
+            if read_len > 0 {
+                // 腾出了空间，唤醒等待的写端
+                self.waiters.notify();
             }
-            //if buf.len() != read_len { println!("tid {} read {} final got {}", tid, buf.len(), read_len); }
-            //else { println!("tid {} read {} final got {}", tid, buf.len(), read_len); }
             Some(read_len)
         } else {
             None
         }
     }
-    /// 写入管道
+    /// 写入管道。
+    /// 如果管道满了，则阻塞直到全部写完，或者读端被关闭
     fn write(&self, buf: &[u8]) -> Option<usize> {
         if self.is_read {
             None
@@ -163,36 +193,30 @@ impl File for Pipe {
             let mut write_len = 0;
             // 同上，如果一次完成就不用切换进程了
             write_len += self.data.lock().write(&buf[write_len..]);
-            /*
-            unsafe {
-                static mut TIMES: usize = 0;
-                TIMES += 1;
-                if TIMES % 1000 == 0 {
-                    println!("write pipe {} times", TIMES);
-                } else if write_len > 4 {
-                    println!("write end {} times", TIMES);
-                }
-            }
-            */
             info!("write pipe len {}", write_len);
-
-            //let tid = crate::task::get_current_task().unwrap().get_tid_num();
-            //if buf.len() != 4 { println!("tid {} write pipe len {}, require {}", tid, write_len, buf.len()); }
-            //if buf.len() != write_len { println!("tid {} write {} got {}", tid, buf.len(), write_len); }
-            //else { println!("tid {} write {} got {}", tid, buf.len(), write_len); }
-
-            // 同上，参见 read 函数
-            let mut cnt = 0;
-            while write_len < buf.len() && Arc::strong_count(&self.data) == 2 {
-                cnt += 1;
-                if cnt > 2 {
-                    break;
+            while write_len < buf.len() {
+                // 唤醒读端来腾出空间
+                self.waiters.notify();
+                let len = self.waiters.0.wait_until(None, || {
+                    let len = self.data.lock().write(&buf[write_len..]);
+                    if len > 0 || !self.other_end_alive() {
+                        Some(len)
+                    } else {
+                        None
+                    }
+                });
+                match len {
+                    // 读端已关闭
+                    Some(0) => break,
+                    Some(len) => write_len += len,
+                    // 被信号打断。已经写了一部分时返回写入的长度，否则由 syscall 返回 EINTR
+                    None if write_len > 0 => break,
+                    None => return None,
                 }
-                suspend_current_task();
-                write_len += self.data.lock().write(&buf[write_len..]);
             }
-            //if buf.len() != write_len { println!("tid {} write {} final got {}", tid, buf.len(), write_len); }
-            //else { println!("tid {} write {} final got {}", tid, buf.len(), write_len); }
+            if write_len > 0 {
+                self.waiters.notify();
+            }
             Some(write_len)
         }
     }
@@ -207,9 +231,9 @@ impl File for Pipe {
     /// 是否已经终止。对于 pipe 来说，这意味着另一端已关闭
     fn is_hang_up(&self) -> bool {
         if self.is_read {
-            self.data.lock().is_empty() && Arc::strong_count(&self.data) < 2
+            self.data.lock().is_empty() && !self.other_end_alive()
         } else {
-            Arc::strong_count(&self.data) < 2
+            !self.other_end_alive()
         }
     }
 }
@@ -0,0 +1,125 @@
+//! 协议栈使用的网络设备
+//!
+//! 本地回环和网卡共用同一个以太网接口：发给本机地址(127.0.0.0/8 或者网卡的地址)的帧，
+//! 包括询问这些地址的 ARP 请求，会被直接放回接收队列；其余的帧交给网卡发送。
+//! 没有网卡时，只有本地回环可用
+
+use crate::{constants::NET_FRAME_SIZE_LIMIT, drivers::NetDevice};
+use alloc::{collections::VecDeque, sync::Arc, vec, vec::Vec};
+use smoltcp::{
+    phy::{self, Device, DeviceCapabilities, Medium},
+    time::Instant,
+    wire::{ArpPacket, EthernetFrame, EthernetProtocol, Ipv4Address, Ipv4Packet},
+};
+
+/// 协议栈使用的网络设备，包括本地回环和网卡(如果有的话)
+pub struct InterfaceDevice {
+    /// 网卡
+    nic: Option<Arc<dyn NetDevice>>,
+    /// 网卡的 IPv4 地址。发给这些地址的帧也走本地回环
+    local_ips: Vec<Ipv4Address>,
+    /// 本地回环中等待接收的帧
+    loopback: VecDeque<Vec<u8>>,
+}
+
+impl InterfaceDevice {
+    /// 创建网络设备。nic 为 None 时只有本地回环
+    pub fn new(nic: Option<Arc<dyn NetDevice>>, local_ips: Vec<Ipv4Address>) -> Self {
+        Self {
+            nic,
+            local_ips,
+            loopback: VecDeque::new(),
+        }
+    }
+    /// 网卡上是否有还没有取出的帧
+    pub fn nic_has_frame(&self) -> bool {
+        self.nic.as_ref().map_or(false, |nic| nic.can_recv())
+    }
+    /// 这个帧是否是发给本机的
+    fn is_local_frame(&self, frame: &[u8]) -> bool {
+        let is_local = |addr: Ipv4Address| addr.is_loopback() || self.local_ips.contains(&addr);
+        let frame = match EthernetFrame::new_checked(frame) {
+            Ok(frame) => frame,
+            Err(_) => return false,
+        };
+        match frame.ethertype() {
+            EthernetProtocol::Arp => ArpPacket::new_checked(frame.payload()).map_or(false, |arp| {
+                is_local(Ipv4Address::from_bytes(arp.target_protocol_addr()))
+            }),
+            EthernetProtocol::Ipv4 => Ipv4Packet::new_checked(frame.payload())
+                .map_or(false, |packet| is_local(packet.dst_addr())),
+            _ => false,
+        }
+    }
+}
+
+impl Device for InterfaceDevice {
+    type RxToken<'a> = RxToken;
+    type TxToken<'a> = TxToken<'a>;
+
+    fn capabilities(&self) -> DeviceCapabilities {
+        let mut caps = DeviceCapabilities::default();
+        caps.medium = Medium::Ethernet;
+        caps.max_transmission_unit = NET_FRAME_SIZE_LIMIT;
+        caps
+    }
+
+    fn receive(&mut self, _timestamp: Instant) -> Option<(Self::RxToken<'_>, Self::TxToken<'_>)> {
+        let buffer = match self.loopback.pop_front() {
+            Some(buffer) => buffer,
+            None => {
+                let nic = self.nic.as_ref()?;
+                let mut buffer = vec![0u8; NET_FRAME_SIZE_LIMIT];
+                let len = nic.recv(&mut buffer)?;
+                buffer.truncate(len);
+                buffer
+            }
+        };
+        Some((RxToken { buffer }, TxToken { device: self }))
+    }
+
+    fn transmit(&mut self, _timestamp: Instant) -> Option<Self::TxToken<'_>> {
+        // 网卡的发送队列满了时，本地回环的帧也要等一等。这种情况很少见，且很快就会恢复
+        if self.nic.as_ref().map_or(false, |nic| !nic.can_send()) {
+            return None;
+        }
+        Some(TxToken { device: self })
+    }
+}
+
+/// 收到的一个帧
+pub struct RxToken {
+    buffer: Vec<u8>,
+}
+
+impl phy::RxToken for RxToken {
+    fn consume<R, F>(mut self, f: F) -> R
+    where
+        F: FnOnce(&mut [u8]) -> R,
+    {
+        f(&mut self.buffer)
+    }
+}
+
+/// 发送一个帧的许可
+pub struct TxToken<'a> {
+    device: &'a mut InterfaceDevice,
+}
+
+impl<'a> phy::TxToken for TxToken<'a> {
+    fn consume<R, F>(self, len: usize, f: F) -> R
+    where
+        F: FnOnce(&mut [u8]) -> R,
+    {
+        let mut buffer = vec![0u8; len];
+        let ret = f(&mut buffer);
+        if self.device.is_local_frame(&buffer) {
+            self.device.loopback.push_back(buffer);
+        } else if let Some(nic) = self.device.nic.as_ref() {
+            if !nic.send(&buffer) {
+                warn!("net device failed to send a frame of {len} bytes");
+            }
+        }
+        ret
+    }
+}
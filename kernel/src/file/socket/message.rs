@@ -0,0 +1,87 @@
+//! 收发消息时的参数和附加信息，即 send / recv 系列 syscall 的 flags 以及控制信息(cmsg)在内核中的表示
+
+use super::resolution::SocketAddr;
+use crate::task::get_current_task;
+use alloc::{sync::Arc, vec::Vec};
+use base_file::File;
+use bitflags::*;
+
+bitflags! {
+    /// send / recv 系列 syscall 的 flags 参数，以及 recvmsg 返回的 msg_flags
+    pub struct MsgFlags: u32 {
+        /// 只查看数据，不把它从接收队列中取走
+        const PEEK = 0x2;
+        /// (recvmsg 返回)控制信息缓冲区放不下，部分控制信息被丢弃
+        const CTRUNC = 0x8;
+        /// 作为参数时，返回数据报的实际长度而不是读到的长度；recvmsg 返回时表示数据报被截断
+        const TRUNC = 0x20;
+        /// 只有这一次操作是非阻塞的
+        const DONTWAIT = 0x40;
+        /// 流式 socket 一直阻塞到读满缓冲区、读到 EOF 或者出错为止
+        const WAITALL = 0x100;
+        /// 对方关闭连接时不发送 SIGPIPE。内核目前不会发送 SIGPIPE，所以只是接受这个参数
+        const NOSIGNAL = 0x4000;
+        /// 通过 SCM_RIGHTS 收到的 fd 带上 CLOEXEC
+        const CMSG_CLOEXEC = 0x4000_0000;
+    }
+}
+
+/// 发送方的身份，即 `struct ucred`
+#[repr(C)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct UCred {
+    pub pid: i32,
+    pub uid: u32,
+    pub gid: u32,
+}
+
+impl UCred {
+    /// 当前任务的身份。内核还没有用户的概念，uid 和 gid 总是 0
+    pub fn current() -> Self {
+        Self {
+            pid: get_current_task().unwrap().get_pid_num() as i32,
+            uid: 0,
+            gid: 0,
+        }
+    }
+}
+
+/// 随消息传递的控制信息。只有 unix 域 socket 支持
+#[derive(Default)]
+pub struct Ancillary {
+    /// SCM_RIGHTS 传递的文件
+    pub rights: Vec<Arc<dyn File>>,
+    /// SCM_CREDENTIALS 传递的发送方身份
+    pub creds: Option<UCred>,
+}
+
+impl Ancillary {
+    /// 是否没有任何控制信息
+    pub fn is_empty(&self) -> bool {
+        self.rights.is_empty() && self.creds.is_none()
+    }
+}
+
+/// 收到的一条消息
+pub struct RecvMeta {
+    /// 写入缓冲区的长度
+    pub len: usize,
+    /// 消息的实际长度。数据报被截断时大于 len，流式 socket 总是等于 len
+    pub msg_len: usize,
+    /// 消息的来源
+    pub src: Option<SocketAddr>,
+    /// 随消息传递的控制信息
+    pub ancillary: Ancillary,
+}
+
+impl RecvMeta {
+    /// 只有数据、没有控制信息的消息
+    pub fn new(len: usize, msg_len: usize, src: Option<SocketAddr>) -> Self {
+        Self {
+            len,
+            msg_len,
+            src,
+            ancillary: Ancillary::default(),
+        }
+    }
+}
@@ -1,14 +1,35 @@
-//! 暂时的 socket 实现
+//! socket 的实现
+//!
+//! `Socket` 是用户通过 fd 访问的文件，具体的协议由 `tcp.rs`、`udp.rs` 和 `unix.rs` 实现。
+//! 其中 TCP 和 UDP 建立在 `stack.rs` 中基于 smoltcp 的协议栈之上，unix 域 socket 则直接在内核中传递消息
 
-mod loopback;
+mod device;
+mod message;
+mod options;
 mod resolution;
+mod stack;
+mod tcp;
+mod udp;
+mod unix;
 
 use base_file::{File, OpenFlags};
-use core::mem::size_of;
+use core::{cmp::min, mem::size_of};
 use lock::RwLock;
-use loopback::{can_read, can_write, read_from_port, write_to_port, LOCAL_LOOPBACK_ADDR};
-pub use resolution::IpAddr;
-use resolution::{addr_resolution, get_ephemeral_port, AddrType};
+use options::*;
+use smoltcp::wire::IpListenEndpoint;
+use stack::lock_net_stack;
+use syscall::ErrorNo;
+use tcp::TcpSocket;
+use timer::TimeVal;
+use udp::UdpSocket;
+use unix::UnixSocket;
+
+pub use message::{Ancillary, MsgFlags, RecvMeta, UCred};
+pub use resolution::{
+    addr_resolution, addr_to_user, endpoint_to_user, remote_endpoint, IpAddr, SocketAddr, UnixAddr,
+};
+pub use stack::{poll_interfaces, poll_nic};
+pub use unix::{absolute_unix_path, unlink_unix_path};
 
 /// 一个套接字
 pub struct Socket {
@@ -20,126 +41,457 @@ pub struct Socket {
     protocol: usize,
     /// SocketInner struct to modify socket
     inner: RwLock<SocketInner>,
+    /// 具体的协议实现
+    transport: Transport,
 }
+
 pub struct SocketInner {
     flags: OpenFlags,
-    local_endpoint: Option<AddrType>,
-    remote_endpoint: Option<AddrType>,
-    is_listening: bool,
+    options: SocketOptions,
+}
+
+/// socket 使用的传输层协议
+enum Transport {
+    Tcp(TcpSocket),
+    Udp(UdpSocket),
+    Unix(UnixSocket),
 }
 
+/// shutdown 的参数：关闭读端
+const SHUT_RD: usize = 0;
+/// shutdown 的参数：关闭写端
+const SHUT_WR: usize = 1;
+/// shutdown 的参数：同时关闭读端和写端
+const SHUT_RDWR: usize = 2;
+
+/// IPPROTO_TCP，即 socket 的 protocol 参数为 TCP
+const PROTOCOL_TCP: usize = 6;
+/// IPPROTO_UDP，即 socket 的 protocol 参数为 UDP
+const PROTOCOL_UDP: usize = 17;
+
 impl Socket {
-    pub fn new(domain: Domain, s_type: SocketType, protocol: usize) -> Self {
-        Self {
-            domain: domain,
-            stype: s_type,
-            protocol: protocol,
-            inner: RwLock::new(SocketInner {
-                flags: OpenFlags::RDWR | OpenFlags::CLOEXEC | OpenFlags::NON_BLOCK,
-                local_endpoint: None,
-                remote_endpoint: None,
-                is_listening: false,
-            }),
+    /// 新建一个 socket。支持 ipv4 的 TCP 和 UDP，以及 unix 域的流式、数据报和有序包 socket
+    pub fn new(
+        domain: Domain,
+        stype: SocketType,
+        protocol: usize,
+        flags: OpenFlags,
+    ) -> Result<Self, ErrorNo> {
+        let options = SocketOptions::new();
+        let transport = match (domain, stype, protocol) {
+            (Domain::AF_INET, SocketType::SOCK_STREAM, 0 | PROTOCOL_TCP) => {
+                Transport::Tcp(TcpSocket::new())
+            }
+            (Domain::AF_INET, SocketType::SOCK_DGRAM, 0 | PROTOCOL_UDP) => {
+                Transport::Udp(UdpSocket::new())
+            }
+            (
+                Domain::AF_UNIX,
+                SocketType::SOCK_STREAM | SocketType::SOCK_DGRAM | SocketType::SOCK_SEQPACKET,
+                0,
+            ) => Transport::Unix(UnixSocket::new(stype, &options)),
+            _ => return Err(ErrorNo::EPROTONOSUPPORT),
+        };
+        Ok(Self::with_transport(
+            domain, stype, protocol, flags, options, transport,
+        ))
+    }
+    /// 新建一对互相连接的 socket，即 socketpair。只有 unix 域 socket 可用
+    pub fn new_pair(
+        domain: Domain,
+        stype: SocketType,
+        protocol: usize,
+        flags: OpenFlags,
+    ) -> Result<(Self, Self), ErrorNo> {
+        if domain != Domain::AF_UNIX {
+            return Err(ErrorNo::EOPNOTSUPP);
+        }
+        if protocol != 0 {
+            return Err(ErrorNo::EPROTONOSUPPORT);
         }
+        let options = SocketOptions::new();
+        let (first, second) = match stype {
+            SocketType::SOCK_STREAM | SocketType::SOCK_DGRAM | SocketType::SOCK_SEQPACKET => {
+                UnixSocket::pair(stype, &options)
+            }
+            _ => return Err(ErrorNo::EPROTONOSUPPORT),
+        };
+        let new_socket =
+            |transport| Self::with_transport(domain, stype, protocol, flags, options, transport);
+        Ok((
+            new_socket(Transport::Unix(first)),
+            new_socket(Transport::Unix(second)),
+        ))
     }
-    pub fn clonew(&self) -> Self {
+    fn with_transport(
+        domain: Domain,
+        stype: SocketType,
+        protocol: usize,
+        flags: OpenFlags,
+        options: SocketOptions,
+        transport: Transport,
+    ) -> Self {
         Self {
-            domain: self.domain,
-            stype: self.stype,
-            protocol: self.protocol,
+            domain,
+            stype,
+            protocol,
             inner: RwLock::new(SocketInner {
-                flags: OpenFlags::RDWR,
-                local_endpoint: self.inner.read().local_endpoint,
-                remote_endpoint: self.inner.read().remote_endpoint,
-                is_listening: false,
+                flags: flags | OpenFlags::RDWR,
+                options,
             }),
+            transport,
         }
     }
-    pub fn set_endpoint(&self, addr: *const u8, is_remote: bool) -> Option<u16> {
-        match addr_resolution(addr as *const u16) {
-            AddrType::Ip(ip, mut port) => {
-                if is_remote {
-                    self.inner.write().remote_endpoint = Some(AddrType::Ip(ip, port));
-                } else {
-                    if port == 0 {
-                        port = get_ephemeral_port();
+    /// 是否是非阻塞的
+    fn is_nonblock(&self) -> bool {
+        self.inner.read().flags.contains(OpenFlags::NON_BLOCK)
+    }
+    /// 当前的选项
+    fn options(&self) -> SocketOptions {
+        self.inner.read().options
+    }
+    /// 绑定本地地址
+    pub fn bind(&self, addr: SocketAddr) -> Result<(), ErrorNo> {
+        info!("socket bind {:?}", addr);
+        let options = self.options();
+        match &self.transport {
+            Transport::Tcp(tcp) => tcp.bind(addr.into_inet()?, &options),
+            Transport::Udp(udp) => udp.bind(addr.into_inet()?, &options),
+            Transport::Unix(unix) => unix.bind(addr.into_unix()?),
+        }
+    }
+    /// 开始监听，只有 TCP socket 和面向连接的 unix 域 socket 可用
+    pub fn listen(&self, backlog: usize) -> Result<(), ErrorNo> {
+        match &self.transport {
+            Transport::Tcp(tcp) => tcp.listen(backlog, &self.options()),
+            Transport::Udp(_) => Err(ErrorNo::EOPNOTSUPP),
+            Transport::Unix(unix) => unix.listen(backlog),
+        }
+    }
+    /// 连接到远程地址。对数据报 socket 来说只是设置默认的目的地址
+    pub fn connect(&self, addr: SocketAddr) -> Result<(), ErrorNo> {
+        info!("socket connect {:?}", addr);
+        let options = self.options();
+        let nonblock = self.is_nonblock();
+        match &self.transport {
+            Transport::Tcp(tcp) => {
+                tcp.connect(remote_endpoint(addr.into_inet()?), nonblock, &options)
+            }
+            Transport::Udp(udp) => udp.connect(remote_endpoint(addr.into_inet()?), &options),
+            Transport::Unix(unix) => unix.connect(addr.into_unix()?, nonblock, &options),
+        }
+    }
+    /// 接受一个连接，返回新的 socket 和对方的地址。新的 socket 的 fd 选项由 flags 决定，其他选项与当前 socket 相同
+    pub fn accept(&self, flags: OpenFlags) -> Result<(Socket, SocketAddr), ErrorNo> {
+        let options = self.options();
+        let nonblock = self.is_nonblock();
+        let (transport, remote) = match &self.transport {
+            Transport::Tcp(tcp) => {
+                let (socket, remote) = tcp.accept(nonblock, &options)?;
+                (Transport::Tcp(socket), SocketAddr::from(remote))
+            }
+            Transport::Udp(_) => return Err(ErrorNo::EOPNOTSUPP),
+            Transport::Unix(unix) => {
+                let (socket, remote) = unix.accept(nonblock, &options)?;
+                (Transport::Unix(socket), SocketAddr::Unix(remote))
+            }
+        };
+        let socket = Self::with_transport(
+            self.domain,
+            self.stype,
+            self.protocol,
+            flags,
+            options,
+            transport,
+        );
+        Ok((socket, remote))
+    }
+    /// 发送消息。dest 为 None 时发送到已连接的地址。面向连接的 socket 会忽略 dest
+    pub fn send(&self, buf: &[u8], dest: Option<SocketAddr>) -> Result<usize, ErrorNo> {
+        self.send_msg(buf, dest, Ancillary::default(), MsgFlags::empty())
+    }
+    /// 发送消息，同时传递控制信息 ancillary。只有 unix 域 socket 可以传递控制信息
+    pub fn send_msg(
+        &self,
+        buf: &[u8],
+        dest: Option<SocketAddr>,
+        ancillary: Ancillary,
+        flags: MsgFlags,
+    ) -> Result<usize, ErrorNo> {
+        let options = self.options();
+        let nonblock = self.is_nonblock() || flags.contains(MsgFlags::DONTWAIT);
+        if !ancillary.is_empty() && !matches!(self.transport, Transport::Unix(_)) {
+            return Err(ErrorNo::EINVAL);
+        }
+        match &self.transport {
+            Transport::Tcp(tcp) => tcp.send(buf, nonblock, &options),
+            Transport::Udp(udp) => {
+                let dest = dest.map(SocketAddr::into_inet).transpose()?;
+                udp.send(buf, dest.map(remote_endpoint), nonblock, &options)
+            }
+            Transport::Unix(unix) => {
+                let dest = dest.map(SocketAddr::into_unix).transpose()?;
+                unix.send(buf, dest, ancillary, nonblock, &options)
+            }
+        }
+    }
+    /// 收取消息，返回消息长度和来源
+    pub fn recv(&self, buf: &mut [u8]) -> Result<(usize, Option<SocketAddr>), ErrorNo> {
+        self.recv_msg(buf, MsgFlags::empty())
+            .map(|meta| (meta.len, meta.src))
+    }
+    /// 按 flags 收取消息，返回消息长度、来源和控制信息。
+    ///
+    /// 只有打开了 SO_PASSCRED 时才返回发送方的身份
+    pub fn recv_msg(&self, buf: &mut [u8], flags: MsgFlags) -> Result<RecvMeta, ErrorNo> {
+        let options = self.options();
+        let nonblock = self.is_nonblock() || flags.contains(MsgFlags::DONTWAIT);
+        let peek = flags.contains(MsgFlags::PEEK);
+        let mut meta = self.recv_once(buf, peek, nonblock, &options)?;
+        // MSG_WAITALL 只对流式 socket 有效。遇到 EOF、错误或者超时时，返回已经读到的部分
+        if flags.contains(MsgFlags::WAITALL) && !peek && self.stype == SocketType::SOCK_STREAM {
+            while meta.len > 0 && meta.len < buf.len() {
+                match self.recv_once(&mut buf[meta.len..], false, nonblock, &options) {
+                    Ok(more) if more.len > 0 => {
+                        meta.len += more.len;
+                        meta.msg_len = meta.len;
+                        meta.ancillary.rights.extend(more.ancillary.rights);
                     }
-                    self.inner.write().local_endpoint = Some(AddrType::Ip(ip, port));
+                    _ => break,
                 }
-                info!("set endpoint: ip {:x}, port {}", ip, port);
-                Some(port)
+            }
+        }
+        if !options.pass_cred {
+            meta.ancillary.creds = None;
+        }
+        Ok(meta)
+    }
+    /// 从具体的协议收取一次消息
+    fn recv_once(
+        &self,
+        buf: &mut [u8],
+        peek: bool,
+        nonblock: bool,
+        options: &SocketOptions,
+    ) -> Result<RecvMeta, ErrorNo> {
+        match &self.transport {
+            Transport::Tcp(tcp) => {
+                let len = tcp.recv(buf, peek, nonblock, options)?;
+                let src = tcp.remote_endpoint().ok().map(SocketAddr::from);
+                Ok(RecvMeta::new(len, len, src))
+            }
+            Transport::Udp(udp) => {
+                let (len, msg_len, src) = udp.recv(buf, peek, nonblock, options)?;
+                Ok(RecvMeta::new(len, msg_len, src.map(SocketAddr::from)))
+            }
+            Transport::Unix(unix) => unix.recv(buf, peek, nonblock, options),
+        }
+    }
+    /// 关闭连接的读端和/或写端
+    pub fn shutdown(&self, how: usize) -> Result<(), ErrorNo> {
+        let (read, write) = match how {
+            SHUT_RD => (true, false),
+            SHUT_WR => (false, true),
+            SHUT_RDWR => (true, true),
+            _ => return Err(ErrorNo::EINVAL),
+        };
+        match &self.transport {
+            Transport::Tcp(tcp) => tcp.shutdown(read, write),
+            Transport::Udp(udp) => udp.shutdown(read, write),
+            Transport::Unix(unix) => unix.shutdown(read, write),
+        }
+    }
+    /// 本地地址。ipv4 socket 还没有绑定时为 0.0.0.0:0
+    pub fn local_addr(&self) -> SocketAddr {
+        let endpoint = match &self.transport {
+            Transport::Tcp(tcp) => tcp.local_endpoint(),
+            Transport::Udp(udp) => udp.local_endpoint(),
+            Transport::Unix(unix) => return SocketAddr::Unix(unix.local_addr()),
+        };
+        SocketAddr::Inet(endpoint.unwrap_or(IpListenEndpoint::from(0u16)))
+    }
+    /// 对方的地址
+    pub fn peer_addr(&self) -> Result<SocketAddr, ErrorNo> {
+        match &self.transport {
+            Transport::Tcp(tcp) => tcp.remote_endpoint().map(SocketAddr::from),
+            Transport::Udp(udp) => udp.remote_endpoint().map(SocketAddr::from),
+            Transport::Unix(unix) => unix.peer_addr().map(SocketAddr::Unix),
+        }
+    }
+    /// 设置选项(setsockopt)，选项的值在 optval 中
+    pub fn set_option(&self, level: usize, name: usize, optval: &[u8]) -> Result<(), ErrorNo> {
+        info!("setsockopt level {} name {}", level, name);
+        let mut inner = self.inner.write();
+        let options = &mut inner.options;
+        match (level, name) {
+            (SOL_SOCKET, SO_REUSEADDR) => options.reuse_addr = read_i32(optval)? != 0,
+            (SOL_SOCKET, SO_BROADCAST) => options.broadcast = read_i32(optval)? != 0,
+            (SOL_SOCKET, SO_KEEPALIVE) => options.keep_alive = read_i32(optval)? != 0,
+            (SOL_SOCKET, SO_SNDBUF) => {
+                options.send_buf_size = clamp_buffer_size(read_i32(optval)? as usize)
+            }
+            (SOL_SOCKET, SO_RCVBUF) => {
+                options.recv_buf_size = clamp_buffer_size(read_i32(optval)? as usize)
+            }
+            (SOL_SOCKET, SO_SNDTIMEO) => options.send_timeout_us = read_timeout(optval)?,
+            (SOL_SOCKET, SO_RCVTIMEO) => options.recv_timeout_us = read_timeout(optval)?,
+            (SOL_SOCKET, SO_PASSCRED) if self.domain == Domain::AF_UNIX => {
+                options.pass_cred = read_i32(optval)? != 0
+            }
+            (IPPROTO_TCP, TCP_NODELAY) if self.stype == SocketType::SOCK_STREAM => {
+                options.tcp_nodelay = read_i32(optval)? != 0
+            }
+            // 报文段长度由协议栈决定，这里只检查参数
+            (IPPROTO_TCP, TCP_MAXSEG) if self.stype == SocketType::SOCK_STREAM => {
+                read_i32(optval)?;
             }
             _ => {
-                warn!("set endpoint failed !");
-                None
+                warn!("unsupported socket option: level {} name {}", level, name);
+                return Err(ErrorNo::ENOPROTOOPT);
             }
         }
+        let options = inner.options;
+        drop(inner);
+        match &self.transport {
+            Transport::Tcp(tcp) => tcp.set_options(&options),
+            Transport::Udp(_) => {}
+            Transport::Unix(unix) => unix.set_options(&options),
+        }
+        Ok(())
     }
-    pub fn set_listening(&self, listen: bool) {
-        self.inner.write().is_listening = listen;
+    /// 获取选项(getsockopt)，选项的值写入 optval，返回写入的长度
+    pub fn get_option(
+        &self,
+        level: usize,
+        name: usize,
+        optval: &mut [u8],
+    ) -> Result<usize, ErrorNo> {
+        info!("getsockopt level {} name {}", level, name);
+        let options = self.options();
+        let val = match (level, name) {
+            (SOL_SOCKET, SO_TYPE) => self.stype as i32,
+            (SOL_SOCKET, SO_ERROR) => match &self.transport {
+                Transport::Tcp(tcp) => tcp.error().map_or(0, |err| -(err as i32)),
+                _ => 0,
+            },
+            (SOL_SOCKET, SO_ACCEPTCONN) => match &self.transport {
+                Transport::Tcp(tcp) => tcp.is_listening() as i32,
+                Transport::Udp(_) => 0,
+                Transport::Unix(unix) => unix.is_listening() as i32,
+            },
+            (SOL_SOCKET, SO_REUSEADDR) => options.reuse_addr as i32,
+            (SOL_SOCKET, SO_BROADCAST) => options.broadcast as i32,
+            (SOL_SOCKET, SO_KEEPALIVE) => options.keep_alive as i32,
+            (SOL_SOCKET, SO_SNDBUF) => options.send_buf_size as i32,
+            (SOL_SOCKET, SO_RCVBUF) => options.recv_buf_size as i32,
+            (SOL_SOCKET, SO_SNDTIMEO) => return Ok(write_timeout(optval, options.send_timeout_us)),
+            (SOL_SOCKET, SO_RCVTIMEO) => return Ok(write_timeout(optval, options.recv_timeout_us)),
+            (SOL_SOCKET, SO_PASSCRED) if self.domain == Domain::AF_UNIX => options.pass_cred as i32,
+            (SOL_SOCKET, SO_PEERCRED) => match &self.transport {
+                Transport::Unix(unix) => {
+                    let cred = unix.peer_cred()?;
+                    let bytes = unsafe {
+                        core::slice::from_raw_parts(
+                            &cred as *const UCred as *const u8,
+                            size_of::<UCred>(),
+                        )
+                    };
+                    return Ok(write_bytes(optval, bytes));
+                }
+                _ => return Err(ErrorNo::ENOPROTOOPT),
+            },
+            (IPPROTO_TCP, TCP_NODELAY) if self.stype == SocketType::SOCK_STREAM => {
+                options.tcp_nodelay as i32
+            }
+            (IPPROTO_TCP, TCP_MAXSEG) if self.stype == SocketType::SOCK_STREAM => {
+                TCP_DEFAULT_MSS as i32
+            }
+            _ => {
+                warn!("unsupported socket option: level {} name {}", level, name);
+                return Err(ErrorNo::ENOPROTOOPT);
+            }
+        };
+        Ok(write_bytes(optval, &val.to_ne_bytes()))
     }
 }
 
+/// 从选项的值中读取一个 i32
+fn read_i32(optval: &[u8]) -> Result<i32, ErrorNo> {
+    let bytes = optval.get(..size_of::<i32>()).ok_or(ErrorNo::EINVAL)?;
+    Ok(i32::from_ne_bytes(bytes.try_into().unwrap()))
+}
+
+/// 从选项的值中读取超时时间(TimeVal)，单位为微秒。0 表示不会超时
+fn read_timeout(optval: &[u8]) -> Result<Option<usize>, ErrorNo> {
+    if optval.len() < size_of::<TimeVal>() {
+        return Err(ErrorNo::EINVAL);
+    }
+    let time_val = unsafe { (optval.as_ptr() as *const TimeVal).read_unaligned() };
+    let time_us: usize = time_val.into();
+    Ok((time_us != 0).then_some(time_us))
+}
+
+/// 把超时时间以 TimeVal 的格式写入选项的值，返回写入的长度
+fn write_timeout(optval: &mut [u8], timeout_us: Option<usize>) -> usize {
+    let time_val = TimeVal::from(timeout_us.unwrap_or(0));
+    let bytes = unsafe {
+        core::slice::from_raw_parts(
+            &time_val as *const TimeVal as *const u8,
+            size_of::<TimeVal>(),
+        )
+    };
+    write_bytes(optval, bytes)
+}
+
+/// 把 bytes 写入选项的值，放不下的部分会被截断。返回写入的长度
+fn write_bytes(optval: &mut [u8], bytes: &[u8]) -> usize {
+    let len = min(optval.len(), bytes.len());
+    optval[..len].copy_from_slice(&bytes[..len]);
+    len
+}
+
 impl File for Socket {
-    /// Socket 不适用普通读写
+    /// 对已连接的 socket 读，相当于不带地址的 recvfrom
     fn read(&self, buf: &mut [u8]) -> Option<usize> {
-        self.recvfrom(buf, 0, 0, &mut 0)
+        self.recv(buf).ok().map(|(len, _)| len)
     }
-    /// Socket 不适用普通读写
+    /// 对已连接的 socket 写，相当于不带地址的 sendto
     fn write(&self, buf: &[u8]) -> Option<usize> {
-        self.sendto(buf, 0, 0)
+        self.send(buf, None).ok()
     }
-    /// socket的buffer内有值则可读
+    /// 有数据或者有新连接时可读
     fn ready_to_read(&self) -> bool {
-        if let Some(ep) = self.inner.read().local_endpoint {
-            match ep {
-                AddrType::Ip(_ip, port) => {
-                    if self.inner.read().is_listening {
-                        //Fixme, 检测新连接
-                        let port = port + 100;
-                        if let Some(len) = can_read(port) {
-                            info!("Port {} accept: {}", port, len);
-                            return true;
-                        }
-                    } else if let Some(len) = can_read(port) {
-                        info!("Port {} can read: {}", port, len);
-                        return true;
-                    }
-                }
-                _ => {
-                    warn!("local endpoint Unknown");
-                }
-            }
-        } else {
-            warn!(
-                "local endpoint is invalid {:?}",
-                self.inner.read().local_endpoint
-            );
+        if let Transport::Unix(unix) = &self.transport {
+            return unix.ready_to_read();
+        }
+        poll_interfaces();
+        let mut stack = lock_net_stack();
+        match &self.transport {
+            Transport::Tcp(tcp) => tcp.ready_to_read(&mut stack),
+            Transport::Udp(udp) => udp.ready_to_read(&mut stack),
+            Transport::Unix(_) => unreachable!(),
         }
-
-        false
     }
-    /// socket的buffer未满则可写
+    /// 发送缓冲区未满时可写
     fn ready_to_write(&self) -> bool {
-        if let Some(ep) = self.inner.read().remote_endpoint {
-            match ep {
-                AddrType::Ip(_ip, port) => {
-                    if let Some(len) = can_write(port) {
-                        info!("Port {} can write: {}", port, len);
-                        return true;
-                    } else {
-                        info!("Buffer to write is full");
-                        return false;
-                    }
-                }
-                _ => {}
-            }
+        if let Transport::Unix(unix) = &self.transport {
+            return unix.ready_to_write();
+        }
+        poll_interfaces();
+        let mut stack = lock_net_stack();
+        match &self.transport {
+            Transport::Tcp(tcp) => tcp.ready_to_write(&mut stack),
+            Transport::Udp(udp) => udp.ready_to_write(&mut stack),
+            Transport::Unix(_) => unreachable!(),
+        }
+    }
+    /// 面向连接的 socket 的对方关闭了连接
+    fn is_hang_up(&self) -> bool {
+        match &self.transport {
+            Transport::Tcp(tcp) => tcp.is_hang_up(&mut lock_net_stack()),
+            Transport::Udp(_) => false,
+            Transport::Unix(unix) => unix.is_hang_up(),
         }
-        info!(
-            "local endpoint is invalid now {:?}",
-            self.inner.read().remote_endpoint
-        );
-        true
     }
     /// 获取文件状态信息
     fn get_status(&self) -> OpenFlags {
@@ -153,81 +505,6 @@ impl File for Socket {
         fl.set(OpenFlags::CLOEXEC, flags.contains(OpenFlags::CLOEXEC));
         true
     }
-    /// 发送消息，当且仅当这个文件是 socket 时可用
-    fn sendto(&self, buf: &[u8], flags: i32, dest_addr: usize) -> Option<usize> {
-        let endpoint = if dest_addr == 0 {
-            self.inner
-                .read()
-                .remote_endpoint
-                .unwrap_or(AddrType::Unknown)
-        } else {
-            addr_resolution(dest_addr as *const u16)
-        };
-        match endpoint {
-            AddrType::Ip(ip, port) => {
-                info!("send to ip {:x} port {}", ip, port);
-                if (ip == 0) || (ip == LOCAL_LOOPBACK_ADDR) {
-                    //Fixme, 用于建立TCP连接
-                    let port = if flags == 100 {
-                        if port == 0 {
-                            get_ephemeral_port() + 100 - 2
-                        } else {
-                            port + 100
-                        }
-                    } else {
-                        if port == 0 {
-                            get_ephemeral_port() - 1
-                        } else {
-                            port
-                        }
-                    };
-                    write_to_port(port, buf)
-                } else {
-                    warn!("Unknown IP: {:#x}", ip);
-                    None
-                }
-            }
-            AddrType::Unknown => {
-                warn!("recvfrom AddrType::Unknown");
-                None
-            }
-        }
-    }
-    /// 收取消息，当且仅当这个文件是 socket 时可用
-    fn recvfrom(
-        &self,
-        buf: &mut [u8],
-        flags: i32,
-        src_addr: usize,
-        src_len: &mut u32,
-    ) -> Option<usize> {
-        let endpoint = if src_addr == 0 {
-            self.inner
-                .read()
-                .local_endpoint
-                .unwrap_or(AddrType::Unknown)
-        } else {
-            addr_resolution(src_addr as *const u16)
-        };
-        match endpoint {
-            AddrType::Ip(ip, port) => {
-                // 按 syscall 描述，这里需要把地址信息的长度写到用户给的 src_len 的位置
-                *src_len = size_of::<IpAddr>() as u32;
-                if (ip == 0) || (ip == LOCAL_LOOPBACK_ADDR) {
-                    //Fixme, 用于建立TCP连接
-                    let port = if flags == 100 { port + 100 } else { port };
-                    read_from_port(port, buf)
-                } else {
-                    warn!("Unknown IP: {:#x}", ip);
-                    None
-                }
-            }
-            AddrType::Unknown => {
-                warn!("recvfrom AddrType::Unknown");
-                None
-            }
-        }
-    }
 }
 
 use numeric_enum_macro::numeric_enum;
@@ -0,0 +1,86 @@
+//! socket 选项，即 setsockopt / getsockopt 的参数
+//!
+//! 详见 `https://man7.org/linux/man-pages/man7/socket.7.html` 和 `https://man7.org/linux/man-pages/man7/tcp.7.html`
+
+use crate::constants::{SOCKET_BUFFER_SIZE_DEFAULT, SOCKET_BUFFER_SIZE_LIMIT};
+
+/// 选项的 level：socket 本身的选项
+pub const SOL_SOCKET: usize = 1;
+/// 选项的 level：TCP 协议的选项
+pub const IPPROTO_TCP: usize = 6;
+
+/// 允许绑定已被占用的地址
+pub const SO_REUSEADDR: usize = 2;
+/// 获取 socket 的类型(只读)
+pub const SO_TYPE: usize = 3;
+/// 获取并清除 socket 上未处理的错误(只读)
+pub const SO_ERROR: usize = 4;
+/// 允许发送广播
+pub const SO_BROADCAST: usize = 6;
+/// 发送缓冲区大小
+pub const SO_SNDBUF: usize = 7;
+/// 接收缓冲区大小
+pub const SO_RCVBUF: usize = 8;
+/// TCP 连接空闲时定期发送探测包
+pub const SO_KEEPALIVE: usize = 9;
+/// 接收消息时附带发送方的身份(SCM_CREDENTIALS)
+pub const SO_PASSCRED: usize = 16;
+/// 获取建立连接时对方的身份(只读)
+pub const SO_PEERCRED: usize = 17;
+/// 接收超时时间
+pub const SO_RCVTIMEO: usize = 20;
+/// 发送超时时间
+pub const SO_SNDTIMEO: usize = 21;
+/// 是否在 listen 状态(只读)
+pub const SO_ACCEPTCONN: usize = 30;
+
+/// 关闭 Nagle 算法，数据尽快发出
+pub const TCP_NODELAY: usize = 1;
+/// 最大报文段长度
+pub const TCP_MAXSEG: usize = 2;
+
+/// TCP_MAXSEG 的默认值
+pub const TCP_DEFAULT_MSS: usize = 536;
+/// SO_KEEPALIVE 打开时，发送探测包的间隔，单位为微秒
+pub const TCP_KEEPALIVE_INTERVAL_US: usize = 75_000_000;
+
+/// 一个 socket 的选项
+#[derive(Clone, Copy)]
+pub struct SocketOptions {
+    pub reuse_addr: bool,
+    pub broadcast: bool,
+    pub keep_alive: bool,
+    pub tcp_nodelay: bool,
+    /// unix 域 socket 收到消息时是否返回发送方的身份
+    pub pass_cred: bool,
+    /// 发送缓冲区大小。只在创建协议栈中的 socket 时生效
+    pub send_buf_size: usize,
+    /// 接收缓冲区大小。只在创建协议栈中的 socket 时生效
+    pub recv_buf_size: usize,
+    /// 阻塞发送的超时时间，单位为微秒。None 表示不会超时
+    pub send_timeout_us: Option<usize>,
+    /// 阻塞接收的超时时间，单位为微秒。None 表示不会超时
+    pub recv_timeout_us: Option<usize>,
+}
+
+impl SocketOptions {
+    /// 默认的选项
+    pub fn new() -> Self {
+        Self {
+            reuse_addr: false,
+            broadcast: false,
+            keep_alive: false,
+            tcp_nodelay: false,
+            pass_cred: false,
+            send_buf_size: SOCKET_BUFFER_SIZE_DEFAULT,
+            recv_buf_size: SOCKET_BUFFER_SIZE_DEFAULT,
+            send_timeout_us: None,
+            recv_timeout_us: None,
+        }
+    }
+}
+
+/// 用户设置的缓冲区大小，截断到合理的范围内
+pub fn clamp_buffer_size(size: usize) -> usize {
+    size.clamp(1024, SOCKET_BUFFER_SIZE_LIMIT)
+}
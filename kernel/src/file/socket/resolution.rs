@@ -1,46 +1,163 @@
-//! 地址解析。目前只有 ip 地址的解析
+//! 地址解析，即用户态的 sockaddr 和内核中的地址之间的转换。支持 ipv4 地址和 unix 域地址
 
-#[derive(Debug, Clone, Copy)]
-pub enum AddrType {
-    /// ip 地址和端口
-    Ip(u32, u16),
-    /// 未知
-    Unknown,
-}
+use alloc::{string::String, vec::Vec};
+use core::mem::size_of;
+use smoltcp::wire::{IpAddress, IpEndpoint, IpListenEndpoint, Ipv4Address};
+use syscall::ErrorNo;
 
+/// 用户态的 ipv4 地址，即 `struct sockaddr_in`。端口和地址都是大端序
 #[repr(C)]
+#[derive(Clone, Copy)]
 pub struct IpAddr {
     pub family: u16,
     pub port: u16,
     pub addr: u32,
+    pub zero: [u8; 8],
 }
 
-//const FAMILY_UNIX: u16 = 1;
+const FAMILY_UNIX: u16 = 1;
 const FAMILY_INTERNET: u16 = 2;
 
-pub fn addr_resolution(family_user_addr: *const u16) -> AddrType {
-    let family = unsafe { *family_user_addr };
+/// `struct sockaddr_un` 中 sun_path 的长度
+pub const UNIX_PATH_MAX: usize = 108;
+
+/// unix 域 socket 的地址
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum UnixAddr {
+    /// 没有绑定地址
+    Unnamed,
+    /// 文件系统中的路径。内核中保存的总是绝对路径
+    Path(String),
+    /// 抽象地址，即 sun_path 以 '\0' 开头的地址。它不出现在文件系统中，内容可以是任意字节
+    Abstract(Vec<u8>),
+}
+
+/// socket 的地址
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum SocketAddr {
+    /// ipv4 地址。addr 为 None 表示 0.0.0.0
+    Inet(IpListenEndpoint),
+    /// unix 域地址
+    Unix(UnixAddr),
+}
+
+impl From<IpEndpoint> for SocketAddr {
+    fn from(endpoint: IpEndpoint) -> Self {
+        Self::Inet(endpoint.into())
+    }
+}
+
+impl SocketAddr {
+    /// 取出 ipv4 地址。如果不是 ipv4 地址，则返回 EAFNOSUPPORT
+    pub fn into_inet(self) -> Result<IpListenEndpoint, ErrorNo> {
+        match self {
+            Self::Inet(endpoint) => Ok(endpoint),
+            Self::Unix(_) => Err(ErrorNo::EAFNOSUPPORT),
+        }
+    }
+    /// 取出 unix 域地址。如果不是 unix 域地址，则返回 EINVAL
+    pub fn into_unix(self) -> Result<UnixAddr, ErrorNo> {
+        match self {
+            Self::Unix(addr) => Ok(addr),
+            Self::Inet(_) => Err(ErrorNo::EINVAL),
+        }
+    }
+}
+
+/// 解析用户传入的地址。调用者需要保证 [addr, addr + addr_len) 在用户地址空间中。
+///
+/// - ipv4 地址为 0.0.0.0 时，返回的 `IpListenEndpoint` 中的 addr 为 None；
+/// - unix 域的路径地址原样返回，如果是相对路径，需要调用者再转换为绝对路径
+pub fn addr_resolution(addr: *const u8, addr_len: usize) -> Result<SocketAddr, ErrorNo> {
+    if addr_len < size_of::<u16>() {
+        return Err(ErrorNo::EINVAL);
+    }
+    let family = unsafe { *(addr as *const u16) };
     match family {
         FAMILY_INTERNET => {
-            let ip_addr = unsafe { &*(family_user_addr as *const IpAddr) };
-            AddrType::Ip(u32::from_be(ip_addr.addr), u16::from_be(ip_addr.port))
+            if addr_len < size_of::<IpAddr>() {
+                return Err(ErrorNo::EINVAL);
+            }
+            let ip_addr = unsafe { *(addr as *const IpAddr) };
+            let ip = Ipv4Address::from_bytes(&ip_addr.addr.to_ne_bytes());
+            Ok(SocketAddr::Inet(IpListenEndpoint {
+                addr: (!ip.is_unspecified()).then_some(IpAddress::Ipv4(ip)),
+                port: u16::from_be(ip_addr.port),
+            }))
+        }
+        FAMILY_UNIX => {
+            let path_len = addr_len - size_of::<u16>();
+            if path_len > UNIX_PATH_MAX {
+                return Err(ErrorNo::EINVAL);
+            }
+            let path = unsafe { core::slice::from_raw_parts(addr.add(size_of::<u16>()), path_len) };
+            let unix_addr = match path.first() {
+                None => UnixAddr::Unnamed,
+                Some(0) => UnixAddr::Abstract(path[1..].to_vec()),
+                Some(_) => {
+                    // 路径以第一个 '\0' 结尾，也可能没有 '\0'
+                    let end = path.iter().position(|&c| c == 0).unwrap_or(path.len());
+                    let path = core::str::from_utf8(&path[..end]).map_err(|_| ErrorNo::EINVAL)?;
+                    UnixAddr::Path(String::from(path))
+                }
+            };
+            Ok(SocketAddr::Unix(unix_addr))
         }
-        _ => AddrType::Unknown,
+        _ => Err(ErrorNo::EAFNOSUPPORT),
     }
 }
 
-pub fn get_ephemeral_port() -> u16 {
-    // TODO selects non-conflict high port
-    static mut EPHEMERAL_PORT: u16 = 0;
-    unsafe {
-        if EPHEMERAL_PORT == 0 {
-            EPHEMERAL_PORT = (49152 + 0 % (65536 - 49152)) as u16;
+/// 把地址转换为用户态的格式，即 `struct sockaddr_in` 或者 `struct sockaddr_un` 的字节。
+///
+/// unix 域地址的长度与 Linux 相同：路径地址包含结尾的 '\0'，抽象地址和未绑定的地址则不包含
+pub fn addr_to_user(addr: &SocketAddr) -> Vec<u8> {
+    match addr {
+        SocketAddr::Inet(endpoint) => {
+            let user_addr = endpoint_to_user(*endpoint);
+            let bytes = unsafe {
+                core::slice::from_raw_parts(
+                    &user_addr as *const IpAddr as *const u8,
+                    size_of::<IpAddr>(),
+                )
+            };
+            bytes.to_vec()
         }
-        if EPHEMERAL_PORT == 65535 {
-            EPHEMERAL_PORT = 49152;
-        } else {
-            EPHEMERAL_PORT = EPHEMERAL_PORT + 1;
+        SocketAddr::Unix(unix_addr) => {
+            let mut bytes = FAMILY_UNIX.to_ne_bytes().to_vec();
+            match unix_addr {
+                UnixAddr::Unnamed => {}
+                UnixAddr::Path(path) => {
+                    bytes.extend_from_slice(path.as_bytes());
+                    bytes.push(0);
+                }
+                UnixAddr::Abstract(name) => {
+                    bytes.push(0);
+                    bytes.extend_from_slice(name);
+                }
+            }
+            bytes
         }
-        EPHEMERAL_PORT
+    }
+}
+
+/// 把协议栈中的地址转换为用户态的格式
+pub fn endpoint_to_user(endpoint: IpListenEndpoint) -> IpAddr {
+    let addr = match endpoint.addr {
+        Some(IpAddress::Ipv4(ip)) => u32::from_ne_bytes(ip.0),
+        None => 0,
+    };
+    IpAddr {
+        family: FAMILY_INTERNET,
+        port: endpoint.port.to_be(),
+        addr,
+        zero: [0; 8],
+    }
+}
+
+/// 如果地址是 0.0.0.0，则连接到本机，即 127.0.0.1
+pub fn remote_endpoint(endpoint: IpListenEndpoint) -> IpEndpoint {
+    IpEndpoint {
+        addr: endpoint.addr.unwrap_or(IpAddress::v4(127, 0, 0, 1)),
+        port: endpoint.port,
     }
 }
@@ -0,0 +1,304 @@
+//! 基于 smoltcp 的 TCP/IP 协议栈
+//!
+//! 所有 TCP/UDP socket 都放在全局的 `NetStack` 的同一个 `SocketSet` 里。协议栈只有一个以太网接口，
+//! 本地回环(127.0.0.1/8)和网卡共用它，见 `super::device`。找到网卡时，接口还有网卡的地址和经过网关的默认路由。
+//!
+//! smoltcp 不会自己收发包，需要调用 `NetStack::poll` 推进协议栈。每次 socket 操作前后都会推进一次；
+//! 阻塞等待时，会以协议栈下一次需要处理的时间(如重传、延迟 ACK)作为超时时间，到期后再推进一次。
+//! 推进后如果有 socket 的状态可能发生了变化，则通过 `notify_file_event` 唤醒等待文件事件的任务。
+//!
+//! 网卡的中断没有打开，所以有网卡时调度器选择任务前也会调用 `poll_nic`，把网卡收到的帧交给协议栈。
+//! 它每隔 `NET_POLL_INTERVAL_US` 最多推进一次，并且只在一个核上进行
+
+use super::device::InterfaceDevice;
+use crate::{
+    constants::{
+        EPHEMERAL_PORT_RANGE, LOOPBACK_MAC, NET_DEVICE_IP, NET_GATEWAY_IP, NET_POLL_INTERVAL_US,
+    },
+    drivers::NET_DEVICE,
+    file::{notify_file_event, wait_for_file_event},
+    task::signal_pending,
+};
+use alloc::{collections::BTreeMap, vec, vec::Vec};
+use core::sync::atomic::{AtomicUsize, Ordering};
+use lock::{Mutex, MutexGuard};
+use smoltcp::{
+    iface::{Config, Interface, SocketHandle, SocketSet},
+    socket::{tcp, udp, AnySocket},
+    time::Instant,
+    wire::{
+        EthernetAddress, HardwareAddress, IpAddress, IpCidr, IpEndpoint, IpListenEndpoint,
+        Ipv4Address,
+    },
+};
+use syscall::ErrorNo;
+use timer::get_time_us;
+
+/// 端口所属的协议。TCP 和 UDP 的端口是分开分配的
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug)]
+pub enum PortSpace {
+    Tcp,
+    Udp,
+}
+
+/// 协议栈，包括网卡、其上的所有 socket 以及端口的分配情况
+pub struct NetStack {
+    /// smoltcp 的网络接口
+    iface: Interface,
+    /// 网络设备，包括本地回环和网卡
+    device: InterfaceDevice,
+    /// 所有 socket
+    sockets: SocketSet<'static>,
+    /// 用户已经关闭、但还在等待 TCP 挥手结束的 socket
+    closing: Vec<SocketHandle>,
+    /// 已被占用的端口，以及占用它的 socket 数。设置了 SO_REUSEADDR 时一个端口可以被多个 socket 占用
+    ports: BTreeMap<(PortSpace, u16), usize>,
+    /// 下一次自动分配端口时从这里开始找
+    next_ephemeral_port: u16,
+}
+
+lazy_static::lazy_static! {
+    /// 全局的协议栈
+    static ref NET_STACK: Mutex<NetStack> = Mutex::new(NetStack::new());
+}
+
+/// 调度器下一次推进协议栈的时间，单位为微秒
+static NEXT_POLL_US: AtomicUsize = AtomicUsize::new(0);
+
+/// 获取协议栈的锁
+pub fn lock_net_stack() -> MutexGuard<'static, NetStack> {
+    NET_STACK.lock()
+}
+
+/// 当前时间，以 smoltcp 的格式表示
+fn now() -> Instant {
+    Instant::from_micros(get_time_us() as i64)
+}
+
+impl NetStack {
+    /// 初始化网络接口。如果有网卡，则配置网卡的地址和默认路由
+    fn new() -> Self {
+        let nic = NET_DEVICE.clone();
+        let (device_ip, prefix_len) = NET_DEVICE_IP;
+        let device_ip = Ipv4Address(device_ip);
+        let local_ips = if nic.is_some() {
+            vec![device_ip]
+        } else {
+            Vec::new()
+        };
+        let mac = nic.as_ref().map_or(LOOPBACK_MAC, |nic| nic.mac_address());
+        let mut device = InterfaceDevice::new(nic, local_ips.clone());
+        let mut config = Config::new(HardwareAddress::Ethernet(EthernetAddress(mac)));
+        config.random_seed = get_time_us() as u64;
+        let mut iface = Interface::new(config, &mut device, now());
+        iface.update_ip_addrs(|addrs| {
+            addrs
+                .push(IpCidr::new(IpAddress::v4(127, 0, 0, 1), 8))
+                .unwrap();
+            for &ip in local_ips.iter() {
+                addrs
+                    .push(IpCidr::new(IpAddress::Ipv4(ip), prefix_len))
+                    .unwrap();
+            }
+        });
+        if !local_ips.is_empty() {
+            iface
+                .routes_mut()
+                .add_default_ipv4_route(Ipv4Address(NET_GATEWAY_IP))
+                .unwrap();
+        }
+        Self {
+            iface,
+            device,
+            sockets: SocketSet::new(Vec::new()),
+            closing: Vec::new(),
+            ports: BTreeMap::new(),
+            next_ephemeral_port: EPHEMERAL_PORT_RANGE.start,
+        }
+    }
+    /// 推进协议栈，收发所有可以处理的包。返回是否有 socket 的状态可能发生了变化
+    pub fn poll(&mut self) -> bool {
+        let mut changed = false;
+        // 本地回环发出的包要在下一次 poll 时才会被收到，所以一直推进到没有新的包为止
+        while self.iface.poll(now(), &mut self.device, &mut self.sockets) {
+            changed = true;
+        }
+        // 回收已经完成挥手的 socket
+        let sockets = &mut self.sockets;
+        self.closing.retain(|&handle| {
+            if sockets.get::<tcp::Socket>(handle).state() == tcp::State::Closed {
+                sockets.remove(handle);
+                false
+            } else {
+                true
+            }
+        });
+        changed
+    }
+    /// 协议栈下一次需要推进的时间，单位为微秒。None 表示在有新的包或者 socket 操作之前都不需要推进
+    pub fn poll_at_us(&mut self) -> Option<usize> {
+        self.iface
+            .poll_at(now(), &self.sockets)
+            .map(|instant| instant.total_micros().max(0) as usize)
+    }
+    /// 添加一个 socket
+    pub fn add_socket<T: AnySocket<'static>>(&mut self, socket: T) -> SocketHandle {
+        self.sockets.add(socket)
+    }
+    /// 删除一个 socket
+    pub fn remove_socket(&mut self, handle: SocketHandle) {
+        self.sockets.remove(handle);
+    }
+    /// 获取 TCP socket
+    pub fn tcp(&mut self, handle: SocketHandle) -> &mut tcp::Socket<'static> {
+        self.sockets.get_mut::<tcp::Socket>(handle)
+    }
+    /// 获取 UDP socket
+    pub fn udp(&mut self, handle: SocketHandle) -> &mut udp::Socket<'static> {
+        self.sockets.get_mut::<udp::Socket>(handle)
+    }
+    /// 发起 TCP 连接
+    pub fn tcp_connect(
+        &mut self,
+        handle: SocketHandle,
+        remote: IpEndpoint,
+        local: IpListenEndpoint,
+    ) -> Result<(), ErrorNo> {
+        let socket = self.sockets.get_mut::<tcp::Socket>(handle);
+        socket
+            .connect(self.iface.context(), remote, local)
+            .map_err(|err| match err {
+                tcp::ConnectError::InvalidState => ErrorNo::EISCONN,
+                tcp::ConnectError::Unaddressable => ErrorNo::EINVAL,
+            })
+    }
+    /// 关闭 TCP socket。它会在挥手结束后被自动回收
+    pub fn tcp_close(&mut self, handle: SocketHandle) {
+        let socket = self.tcp(handle);
+        socket.close();
+        if socket.state() == tcp::State::Closed {
+            self.sockets.remove(handle);
+        } else {
+            self.closing.push(handle);
+        }
+    }
+    /// 占用一个端口。port 为 0 时自动分配一个空闲的端口。
+    ///
+    /// 如果端口已被占用，且 reuse 为 false，则返回 EADDRINUSE。
+    /// 每次成功的调用都要对应一次 `release_port`，端口在所有占用者都释放后才会空闲
+    pub fn alloc_port(&mut self, space: PortSpace, port: u16, reuse: bool) -> Result<u16, ErrorNo> {
+        if port != 0 {
+            let users = self.ports.entry((space, port)).or_insert(0);
+            if *users > 0 && !reuse {
+                return Err(ErrorNo::EADDRINUSE);
+            }
+            *users += 1;
+            return Ok(port);
+        }
+        for _ in EPHEMERAL_PORT_RANGE {
+            let port = self.next_ephemeral_port;
+            self.next_ephemeral_port = if port + 1 >= EPHEMERAL_PORT_RANGE.end {
+                EPHEMERAL_PORT_RANGE.start
+            } else {
+                port + 1
+            };
+            if !self.ports.contains_key(&(space, port)) {
+                self.ports.insert((space, port), 1);
+                return Ok(port);
+            }
+        }
+        Err(ErrorNo::EADDRINUSE)
+    }
+    /// 释放一个端口，即减少一个占用者
+    pub fn release_port(&mut self, space: PortSpace, port: u16) {
+        if let Some(users) = self.ports.get_mut(&(space, port)) {
+            *users -= 1;
+            if *users == 0 {
+                self.ports.remove(&(space, port));
+            }
+        }
+    }
+}
+
+/// 推进协议栈，如果有 socket 的状态可能发生了变化，则唤醒等待文件事件的任务
+pub fn poll_interfaces() {
+    let changed = lock_net_stack().poll();
+    if changed {
+        notify_file_event();
+    }
+}
+
+/// 由调度器调用，把网卡收到的帧交给协议栈。如果有 socket 的状态可能发生了变化，则唤醒等待文件事件的任务
+///
+/// 没有网卡时不需要推进，本地回环的包在 socket 操作时就会被处理。
+/// 距离上次推进不到 `NET_POLL_INTERVAL_US`，或者其他核正在使用协议栈时直接返回
+pub fn poll_nic() {
+    if NET_DEVICE.is_none() {
+        return;
+    }
+    let now_us = get_time_us();
+    let next_us = NEXT_POLL_US.load(Ordering::Relaxed);
+    // 同一时间只有一个核能抢到这一次推进
+    if now_us < next_us
+        || NEXT_POLL_US
+            .compare_exchange(
+                next_us,
+                now_us + NET_POLL_INTERVAL_US,
+                Ordering::Relaxed,
+                Ordering::Relaxed,
+            )
+            .is_err()
+    {
+        return;
+    }
+    let changed = match NET_STACK.try_lock() {
+        Some(mut stack) => stack.poll(),
+        None => return,
+    };
+    if changed {
+        notify_file_event();
+    }
+}
+
+/// 反复推进协议栈并调用 `f`，直到它返回的不是 EAGAIN。
+///
+/// - 如果 nonblock 为 true，则只尝试一次，直接返回 `f` 的结果；
+/// - 否则阻塞等待，直到 `f` 成功或出错，或者经过 timeout_us 微秒后返回 EAGAIN(None 表示不会超时)
+pub fn block_on<T>(
+    nonblock: bool,
+    timeout_us: Option<usize>,
+    mut f: impl FnMut(&mut NetStack) -> Result<T, ErrorNo>,
+) -> Result<T, ErrorNo> {
+    let deadline = timeout_us.map(|timeout_us| get_time_us() + timeout_us);
+    let mut try_once = || {
+        let mut stack = lock_net_stack();
+        let mut changed = stack.poll();
+        let ret = f(&mut stack);
+        // f 可能发送了数据，再推进一次让它们尽快被处理
+        changed |= stack.poll();
+        drop(stack);
+        if changed {
+            notify_file_event();
+        }
+        match ret {
+            Err(ErrorNo::EAGAIN) if !nonblock => None,
+            ret => Some(ret),
+        }
+    };
+    loop {
+        let poll_at = lock_net_stack().poll_at_us();
+        let expire_us = match (poll_at, deadline) {
+            (Some(poll_at), Some(deadline)) => Some(poll_at.min(deadline)),
+            (poll_at, deadline) => poll_at.or(deadline),
+        };
+        if let Some(ret) = wait_for_file_event(expire_us, &mut try_once) {
+            return ret;
+        }
+        if signal_pending() {
+            return Err(ErrorNo::EINTR);
+        }
+        if deadline.map_or(false, |deadline| get_time_us() >= deadline) {
+            return Err(ErrorNo::EAGAIN);
+        }
+    }
+}
@@ -0,0 +1,458 @@
+//! TCP socket
+//!
+//! 连接的状态机由 smoltcp 维护，这里只记录用户看到的状态：
+//! - `Closed`：刚创建，或者只调用了 bind；
+//! - `Listening`：调用了 listen。smoltcp 的一个 socket 只能接受一个连接，
+//! 所以 listen 时会在同一个端口上创建 backlog 个处于 LISTEN 状态的 socket，
+//! 其中的一个建立连接后，accept 把它取走，再补上一个新的；
+//! - `Connected`：调用了 connect，或者是 accept 得到的 socket。此时连接可能还在建立中，或者已经关闭
+//!
+//! 需要同时持有协议栈和 socket 的锁时，总是先获取协议栈的锁
+
+use super::{
+    options::{SocketOptions, TCP_KEEPALIVE_INTERVAL_US},
+    stack::{block_on, lock_net_stack, poll_interfaces, NetStack, PortSpace},
+};
+use crate::constants::SOCKET_LISTEN_BACKLOG_LIMIT;
+use alloc::{vec, vec::Vec};
+use lock::Mutex;
+use smoltcp::{
+    iface::SocketHandle,
+    socket::tcp::{self, State},
+    time::Duration,
+    wire::{IpEndpoint, IpListenEndpoint},
+};
+use syscall::ErrorNo;
+
+/// 用户看到的 TCP socket 状态
+enum TcpState {
+    /// 刚创建，或者只调用了 bind
+    Closed,
+    /// 正在监听，其中是协议栈中所有等待连接的 socket
+    Listening(Vec<SocketHandle>),
+    /// 正在连接或者已经连接
+    Connected(SocketHandle),
+}
+
+struct TcpInner {
+    state: TcpState,
+    /// 本地地址。bind、listen 或 connect 之后才有
+    local: Option<IpListenEndpoint>,
+    /// 本地端口是否由这个 socket 占用。accept 得到的 socket 和监听的 socket 共用端口，不需要释放
+    owns_port: bool,
+    /// 是否调用了 shutdown(SHUT_RD)
+    read_shutdown: bool,
+}
+
+/// TCP socket
+pub struct TcpSocket {
+    inner: Mutex<TcpInner>,
+}
+
+/// 连接失败时删除协议栈中的 socket，回到 connect 之前的状态。
+///
+/// 如果本地端口是 connect 时分配的(alloc_port)，则一并释放
+fn abort_connect(
+    stack: &mut NetStack,
+    inner: &mut TcpInner,
+    handle: SocketHandle,
+    alloc_port: bool,
+) {
+    stack.remove_socket(handle);
+    inner.state = TcpState::Closed;
+    if alloc_port {
+        if let Some(local) = inner.local.take() {
+            stack.release_port(PortSpace::Tcp, local.port);
+        }
+        inner.owns_port = false;
+    }
+}
+
+/// 在协议栈中新建一个 TCP socket
+fn new_stack_socket(stack: &mut NetStack, options: &SocketOptions) -> SocketHandle {
+    let mut socket = tcp::Socket::new(
+        tcp::SocketBuffer::new(vec![0u8; options.recv_buf_size]),
+        tcp::SocketBuffer::new(vec![0u8; options.send_buf_size]),
+    );
+    apply_options(&mut socket, options);
+    stack.add_socket(socket)
+}
+
+/// 把 TCP 相关的选项设置到协议栈中的 socket 上
+fn apply_options(socket: &mut tcp::Socket, options: &SocketOptions) {
+    socket.set_nagle_enabled(!options.tcp_nodelay);
+    socket.set_keep_alive(
+        options
+            .keep_alive
+            .then_some(Duration::from_micros(TCP_KEEPALIVE_INTERVAL_US as u64)),
+    );
+}
+
+impl TcpSocket {
+    /// 新建一个 TCP socket
+    pub fn new() -> Self {
+        Self::with_state(TcpState::Closed, None, false)
+    }
+    fn with_state(state: TcpState, local: Option<IpListenEndpoint>, owns_port: bool) -> Self {
+        Self {
+            inner: Mutex::new(TcpInner {
+                state,
+                local,
+                owns_port,
+                read_shutdown: false,
+            }),
+        }
+    }
+    /// 绑定本地地址
+    pub fn bind(&self, endpoint: IpListenEndpoint, options: &SocketOptions) -> Result<(), ErrorNo> {
+        let mut stack = lock_net_stack();
+        let mut inner = self.inner.lock();
+        if inner.local.is_some() {
+            return Err(ErrorNo::EINVAL);
+        }
+        let port = stack.alloc_port(PortSpace::Tcp, endpoint.port, options.reuse_addr)?;
+        inner.local = Some(IpListenEndpoint {
+            addr: endpoint.addr,
+            port,
+        });
+        inner.owns_port = true;
+        Ok(())
+    }
+    /// 开始监听，最多同时等待 backlog 个连接
+    pub fn listen(&self, backlog: usize, options: &SocketOptions) -> Result<(), ErrorNo> {
+        let mut stack = lock_net_stack();
+        let mut inner = self.inner.lock();
+        match inner.state {
+            TcpState::Closed => {}
+            TcpState::Listening(_) => return Ok(()),
+            TcpState::Connected(_) => return Err(ErrorNo::EISCONN),
+        }
+        // 没有 bind 过的 socket 监听一个自动分配的端口
+        let (local, new_port) = match inner.local {
+            Some(local) => (local, false),
+            None => {
+                let port = stack.alloc_port(PortSpace::Tcp, 0, false)?;
+                (IpListenEndpoint { addr: None, port }, true)
+            }
+        };
+        let mut handles = Vec::new();
+        for _ in 0..backlog.clamp(1, SOCKET_LISTEN_BACKLOG_LIMIT) {
+            let handle = new_stack_socket(&mut stack, options);
+            handles.push(handle);
+            if stack.tcp(handle).listen(local).is_err() {
+                // 撤销已经做的所有修改，socket 仍然处于 listen 之前的状态
+                for handle in handles {
+                    stack.remove_socket(handle);
+                }
+                if new_port {
+                    stack.release_port(PortSpace::Tcp, local.port);
+                }
+                return Err(ErrorNo::EINVAL);
+            }
+        }
+        if new_port {
+            inner.local = Some(local);
+            inner.owns_port = true;
+        }
+        inner.state = TcpState::Listening(handles);
+        Ok(())
+    }
+    /// 连接到远程地址。非阻塞时，如果连接还没有建立，则返回 EINPROGRESS
+    pub fn connect(
+        &self,
+        remote: IpEndpoint,
+        nonblock: bool,
+        options: &SocketOptions,
+    ) -> Result<(), ErrorNo> {
+        let mut stack = lock_net_stack();
+        let mut inner = self.inner.lock();
+        match inner.state {
+            TcpState::Closed => {}
+            TcpState::Listening(_) => return Err(ErrorNo::EISCONN),
+            TcpState::Connected(handle) => {
+                return match stack.tcp(handle).state() {
+                    State::SynSent => Err(ErrorNo::EALREADY),
+                    _ => Err(ErrorNo::EISCONN),
+                }
+            }
+        }
+        // 没有 bind 过时由 connect 分配端口，连接失败时要还回去
+        let alloc_port = inner.local.is_none();
+        let local = match inner.local {
+            Some(local) => local,
+            None => {
+                let port = stack.alloc_port(PortSpace::Tcp, 0, false)?;
+                inner.owns_port = true;
+                IpListenEndpoint { addr: None, port }
+            }
+        };
+        inner.local = Some(local);
+        let handle = new_stack_socket(&mut stack, options);
+        if let Err(err) = stack.tcp_connect(handle, remote, local) {
+            abort_connect(&mut stack, &mut inner, handle, alloc_port);
+            return Err(err);
+        }
+        inner.state = TcpState::Connected(handle);
+        drop(inner);
+        drop(stack);
+        // 等待三次握手完成
+        let ret = block_on(nonblock, options.send_timeout_us, |stack| {
+            match stack.tcp(handle).state() {
+                State::SynSent => Err(ErrorNo::EAGAIN),
+                State::Closed => Err(ErrorNo::ECONNREFUSED),
+                _ => Ok(()),
+            }
+        });
+        match ret {
+            Err(ErrorNo::EAGAIN) if nonblock => Err(ErrorNo::EINPROGRESS),
+            // 被信号打断时连接仍在后台继续建立
+            Err(ErrorNo::EINTR) => Err(ErrorNo::EINTR),
+            Err(err) => {
+                // 连接被拒绝或超时，回到 connect 之前的状态，之后还可以再次 connect
+                let mut stack = lock_net_stack();
+                let mut inner = self.inner.lock();
+                if matches!(inner.state, TcpState::Connected(current) if current == handle) {
+                    abort_connect(&mut stack, &mut inner, handle, alloc_port);
+                }
+                Err(err)
+            }
+            ret => ret,
+        }
+    }
+    /// 接受一个连接，返回新的 socket 和对方的地址
+    pub fn accept(
+        &self,
+        nonblock: bool,
+        options: &SocketOptions,
+    ) -> Result<(TcpSocket, IpEndpoint), ErrorNo> {
+        block_on(nonblock, options.recv_timeout_us, |stack| {
+            let mut inner = self.inner.lock();
+            let listen_endpoint = inner.local.unwrap_or(IpListenEndpoint::from(0u16));
+            let handles = match &mut inner.state {
+                TcpState::Listening(handles) => handles,
+                _ => return Err(ErrorNo::EINVAL),
+            };
+            for handle in handles.iter_mut() {
+                let socket = stack.tcp(*handle);
+                match socket.state() {
+                    State::Listen | State::SynReceived => continue,
+                    // 握手过程中被对方重置了，重新开始监听
+                    State::Closed => {
+                        socket
+                            .listen(listen_endpoint)
+                            .map_err(|_| ErrorNo::EINVAL)?;
+                        continue;
+                    }
+                    _ => {}
+                }
+                let remote = socket.remote_endpoint().unwrap();
+                let local = socket.local_endpoint().map(IpListenEndpoint::from);
+                // 补上一个新的监听 socket
+                let new_handle = new_stack_socket(stack, options);
+                stack
+                    .tcp(new_handle)
+                    .listen(listen_endpoint)
+                    .map_err(|_| ErrorNo::EINVAL)?;
+                let accepted = core::mem::replace(handle, new_handle);
+                let socket = TcpSocket::with_state(TcpState::Connected(accepted), local, false);
+                return Ok((socket, remote));
+            }
+            Err(ErrorNo::EAGAIN)
+        })
+    }
+    /// 发送数据，返回实际发送的长度
+    pub fn send(
+        &self,
+        buf: &[u8],
+        nonblock: bool,
+        options: &SocketOptions,
+    ) -> Result<usize, ErrorNo> {
+        let handle = self.connected_handle()?;
+        block_on(nonblock, options.send_timeout_us, |stack| {
+            let socket = stack.tcp(handle);
+            match socket.state() {
+                State::SynSent | State::SynReceived => return Err(ErrorNo::EAGAIN),
+                State::Closed if socket.remote_endpoint().is_none() => {
+                    return Err(ErrorNo::ECONNRESET)
+                }
+                _ => {}
+            }
+            if !socket.may_send() {
+                return Err(ErrorNo::EPIPE);
+            }
+            if !socket.can_send() {
+                return Err(ErrorNo::EAGAIN);
+            }
+            socket.send_slice(buf).map_err(|_| ErrorNo::EPIPE)
+        })
+    }
+    /// 接收数据，返回实际接收的长度。如果对方已经关闭连接，则返回 0。
+    ///
+    /// peek 为 true 时数据留在接收缓冲区中
+    pub fn recv(
+        &self,
+        buf: &mut [u8],
+        peek: bool,
+        nonblock: bool,
+        options: &SocketOptions,
+    ) -> Result<usize, ErrorNo> {
+        let handle = self.connected_handle()?;
+        if self.inner.lock().read_shutdown {
+            return Ok(0);
+        }
+        block_on(nonblock, options.recv_timeout_us, |stack| {
+            let socket = stack.tcp(handle);
+            if socket.can_recv() {
+                let ret = if peek {
+                    socket.peek_slice(buf)
+                } else {
+                    socket.recv_slice(buf)
+                };
+                return ret.map_err(|_| ErrorNo::ENOTCONN);
+            }
+            match socket.state() {
+                State::SynSent | State::SynReceived => Err(ErrorNo::EAGAIN),
+                _ if socket.may_recv() => Err(ErrorNo::EAGAIN),
+                _ => Ok(0),
+            }
+        })
+    }
+    /// 关闭连接的读端和/或写端
+    pub fn shutdown(&self, read: bool, write: bool) -> Result<(), ErrorNo> {
+        let mut stack = lock_net_stack();
+        let mut inner = self.inner.lock();
+        let handle = match inner.state {
+            TcpState::Connected(handle) => handle,
+            _ => return Err(ErrorNo::ENOTCONN),
+        };
+        if read {
+            inner.read_shutdown = true;
+        }
+        if write {
+            // 发送 FIN，但 socket 仍然可以接收数据
+            stack.tcp(handle).close();
+        }
+        drop(inner);
+        drop(stack);
+        poll_interfaces();
+        Ok(())
+    }
+    /// 设置 TCP 相关的选项
+    pub fn set_options(&self, options: &SocketOptions) {
+        let mut stack = lock_net_stack();
+        let inner = self.inner.lock();
+        match &inner.state {
+            TcpState::Closed => {}
+            TcpState::Listening(handles) => {
+                for &handle in handles {
+                    apply_options(stack.tcp(handle), options);
+                }
+            }
+            TcpState::Connected(handle) => apply_options(stack.tcp(*handle), options),
+        }
+    }
+    /// 获取已连接的 socket 在协议栈中的句柄
+    fn connected_handle(&self) -> Result<SocketHandle, ErrorNo> {
+        match self.inner.lock().state {
+            TcpState::Connected(handle) => Ok(handle),
+            _ => Err(ErrorNo::ENOTCONN),
+        }
+    }
+    /// 本地地址。如果还没有绑定，则返回 None
+    pub fn local_endpoint(&self) -> Option<IpListenEndpoint> {
+        let mut stack = lock_net_stack();
+        let inner = self.inner.lock();
+        if let TcpState::Connected(handle) = inner.state {
+            if let Some(local) = stack.tcp(handle).local_endpoint() {
+                return Some(local.into());
+            }
+        }
+        inner.local
+    }
+    /// 对方的地址。没有连接时返回 ENOTCONN
+    pub fn remote_endpoint(&self) -> Result<IpEndpoint, ErrorNo> {
+        let handle = self.connected_handle()?;
+        lock_net_stack()
+            .tcp(handle)
+            .remote_endpoint()
+            .ok_or(ErrorNo::ENOTCONN)
+    }
+    /// 是否在监听
+    pub fn is_listening(&self) -> bool {
+        matches!(self.inner.lock().state, TcpState::Listening(_))
+    }
+    /// 获取 socket 上的错误(SO_ERROR)。目前只有连接被拒绝这一种
+    pub fn error(&self) -> Option<ErrorNo> {
+        let handle = self.connected_handle().ok()?;
+        let mut stack = lock_net_stack();
+        let socket = stack.tcp(handle);
+        (socket.state() == State::Closed && socket.remote_endpoint().is_none())
+            .then_some(ErrorNo::ECONNREFUSED)
+    }
+    /// 是否可读：有数据、对方已关闭连接，或者(监听时)有新的连接
+    pub fn ready_to_read(&self, stack: &mut NetStack) -> bool {
+        let inner = self.inner.lock();
+        match &inner.state {
+            TcpState::Closed => false,
+            TcpState::Listening(handles) => handles.iter().any(|&handle| {
+                !matches!(
+                    stack.tcp(handle).state(),
+                    State::Listen | State::SynReceived | State::Closed
+                )
+            }),
+            TcpState::Connected(handle) => {
+                let socket = stack.tcp(*handle);
+                inner.read_shutdown
+                    || socket.can_recv()
+                    || !(socket.may_recv()
+                        || matches!(socket.state(), State::SynSent | State::SynReceived))
+            }
+        }
+    }
+    /// 是否可写：连接已建立且发送缓冲区未满，或者连接已经失败(此时写会立即返回错误)
+    pub fn ready_to_write(&self, stack: &mut NetStack) -> bool {
+        match self.inner.lock().state {
+            TcpState::Connected(handle) => {
+                let socket = stack.tcp(handle);
+                match socket.state() {
+                    State::SynSent | State::SynReceived => false,
+                    _ => socket.can_send() || !socket.may_send(),
+                }
+            }
+            _ => false,
+        }
+    }
+    /// 对方是否已经关闭了连接
+    pub fn is_hang_up(&self, stack: &mut NetStack) -> bool {
+        match self.inner.lock().state {
+            TcpState::Connected(handle) => {
+                let socket = stack.tcp(handle);
+                !matches!(socket.state(), State::SynSent | State::SynReceived) && !socket.may_recv()
+            }
+            _ => false,
+        }
+    }
+}
+
+impl Drop for TcpSocket {
+    fn drop(&mut self) {
+        let inner = self.inner.get_mut();
+        let mut stack = lock_net_stack();
+        match &inner.state {
+            TcpState::Closed => {}
+            TcpState::Listening(handles) => {
+                for &handle in handles {
+                    stack.remove_socket(handle);
+                }
+            }
+            TcpState::Connected(handle) => stack.tcp_close(*handle),
+        }
+        if inner.owns_port {
+            if let Some(local) = inner.local {
+                stack.release_port(PortSpace::Tcp, local.port);
+            }
+        }
+        drop(stack);
+        poll_interfaces();
+    }
+}
@@ -0,0 +1,227 @@
+//! UDP socket
+//!
+//! 协议栈中的 socket 在第一次 bind、connect 或者发送时才创建并绑定端口。
+//! connect 只是记录默认的目的地址，之后只接收来自这个地址的数据报。
+//!
+//! 需要同时持有协议栈和 socket 的锁时，总是先获取协议栈的锁
+
+use super::{
+    options::SocketOptions,
+    stack::{block_on, lock_net_stack, NetStack, PortSpace},
+};
+use crate::constants::UDP_PACKET_LIMIT;
+use alloc::vec;
+use core::cmp::min;
+use lock::Mutex;
+use smoltcp::{
+    iface::SocketHandle,
+    socket::udp::{self, PacketBuffer, PacketMetadata},
+    wire::{IpEndpoint, IpListenEndpoint},
+};
+use syscall::ErrorNo;
+
+struct UdpInner {
+    /// 协议栈中的 socket，绑定端口后才有
+    handle: Option<SocketHandle>,
+    /// 本地地址，绑定端口后才有
+    local: Option<IpListenEndpoint>,
+    /// connect 设置的默认目的地址
+    remote: Option<IpEndpoint>,
+    /// 是否调用了 shutdown(SHUT_RD)
+    read_shutdown: bool,
+    /// 是否调用了 shutdown(SHUT_WR)
+    write_shutdown: bool,
+}
+
+/// UDP socket
+pub struct UdpSocket {
+    inner: Mutex<UdpInner>,
+}
+
+impl UdpSocket {
+    /// 新建一个 UDP socket
+    pub fn new() -> Self {
+        Self {
+            inner: Mutex::new(UdpInner {
+                handle: None,
+                local: None,
+                remote: None,
+                read_shutdown: false,
+                write_shutdown: false,
+            }),
+        }
+    }
+    /// 绑定本地地址。port 为 0 时自动分配端口
+    pub fn bind(&self, endpoint: IpListenEndpoint, options: &SocketOptions) -> Result<(), ErrorNo> {
+        let mut stack = lock_net_stack();
+        let mut inner = self.inner.lock();
+        if inner.handle.is_some() {
+            return Err(ErrorNo::EINVAL);
+        }
+        Self::bind_locked(&mut stack, &mut inner, endpoint, options).map(|_| ())
+    }
+    /// 在已经持有锁的情况下绑定本地地址，返回协议栈中的 socket
+    fn bind_locked(
+        stack: &mut NetStack,
+        inner: &mut UdpInner,
+        endpoint: IpListenEndpoint,
+        options: &SocketOptions,
+    ) -> Result<SocketHandle, ErrorNo> {
+        if let Some(handle) = inner.handle {
+            return Ok(handle);
+        }
+        let port = stack.alloc_port(PortSpace::Udp, endpoint.port, options.reuse_addr)?;
+        let local = IpListenEndpoint {
+            addr: endpoint.addr,
+            port,
+        };
+        let mut socket = udp::Socket::new(
+            PacketBuffer::new(
+                vec![PacketMetadata::EMPTY; UDP_PACKET_LIMIT],
+                vec![0u8; options.recv_buf_size],
+            ),
+            PacketBuffer::new(
+                vec![PacketMetadata::EMPTY; UDP_PACKET_LIMIT],
+                vec![0u8; options.send_buf_size],
+            ),
+        );
+        if socket.bind(local).is_err() {
+            stack.release_port(PortSpace::Udp, port);
+            return Err(ErrorNo::EINVAL);
+        }
+        let handle = stack.add_socket(socket);
+        inner.handle = Some(handle);
+        inner.local = Some(local);
+        Ok(handle)
+    }
+    /// 设置默认的目的地址。如果还没有绑定端口，则自动分配一个
+    pub fn connect(&self, remote: IpEndpoint, options: &SocketOptions) -> Result<(), ErrorNo> {
+        let mut stack = lock_net_stack();
+        let mut inner = self.inner.lock();
+        Self::bind_locked(
+            &mut stack,
+            &mut inner,
+            IpListenEndpoint::from(0u16),
+            options,
+        )?;
+        inner.remote = Some(remote);
+        Ok(())
+    }
+    /// 发送一个数据报到 dest，如果 dest 为 None 则发送到 connect 设置的地址。返回发送的长度
+    pub fn send(
+        &self,
+        buf: &[u8],
+        dest: Option<IpEndpoint>,
+        nonblock: bool,
+        options: &SocketOptions,
+    ) -> Result<usize, ErrorNo> {
+        let (handle, dest) = {
+            let mut stack = lock_net_stack();
+            let mut inner = self.inner.lock();
+            if inner.write_shutdown {
+                return Err(ErrorNo::EPIPE);
+            }
+            let dest = dest.or(inner.remote).ok_or(ErrorNo::EDESTADDRREQ)?;
+            let handle = Self::bind_locked(
+                &mut stack,
+                &mut inner,
+                IpListenEndpoint::from(0u16),
+                options,
+            )?;
+            if buf.len() > stack.udp(handle).payload_send_capacity() {
+                return Err(ErrorNo::EMSGSIZE);
+            }
+            (handle, dest)
+        };
+        block_on(nonblock, options.send_timeout_us, |stack| {
+            match stack.udp(handle).send_slice(buf, dest) {
+                Ok(()) => Ok(buf.len()),
+                Err(udp::SendError::BufferFull) => Err(ErrorNo::EAGAIN),
+                Err(udp::SendError::Unaddressable) => Err(ErrorNo::EINVAL),
+            }
+        })
+    }
+    /// 接收一个数据报，返回读到的长度、数据报的实际长度和来源。关闭读端后返回 0，且没有来源。
+    ///
+    /// 每次只接收一个数据报，如果 buf 放不下，则多出的部分会被丢弃。peek 为 true 时数据报留在接收队列中
+    pub fn recv(
+        &self,
+        buf: &mut [u8],
+        peek: bool,
+        nonblock: bool,
+        options: &SocketOptions,
+    ) -> Result<(usize, usize, Option<IpEndpoint>), ErrorNo> {
+        block_on(nonblock, options.recv_timeout_us, |stack| {
+            let inner = self.inner.lock();
+            if inner.read_shutdown {
+                return Ok((0, 0, None));
+            }
+            // 还没有绑定端口的 socket 收不到任何数据，一直阻塞
+            let handle = inner.handle.ok_or(ErrorNo::EAGAIN)?;
+            let remote = inner.remote;
+            drop(inner);
+            let socket = stack.udp(handle);
+            loop {
+                let (data, meta) = socket.peek().map_err(|_| ErrorNo::EAGAIN)?;
+                let endpoint = meta.endpoint;
+                // 连接后，丢弃来自其他地址的数据报
+                if remote.map_or(false, |remote| remote != endpoint) {
+                    socket.recv().ok();
+                    continue;
+                }
+                let len = min(buf.len(), data.len());
+                buf[..len].copy_from_slice(&data[..len]);
+                let msg_len = data.len();
+                if !peek {
+                    socket.recv().ok();
+                }
+                return Ok((len, msg_len, Some(endpoint)));
+            }
+        })
+    }
+    /// 关闭读端和/或写端
+    pub fn shutdown(&self, read: bool, write: bool) -> Result<(), ErrorNo> {
+        let mut inner = self.inner.lock();
+        if inner.remote.is_none() {
+            return Err(ErrorNo::ENOTCONN);
+        }
+        inner.read_shutdown |= read;
+        inner.write_shutdown |= write;
+        Ok(())
+    }
+    /// 本地地址。如果还没有绑定，则返回 None
+    pub fn local_endpoint(&self) -> Option<IpListenEndpoint> {
+        self.inner.lock().local
+    }
+    /// connect 设置的地址。没有连接时返回 ENOTCONN
+    pub fn remote_endpoint(&self) -> Result<IpEndpoint, ErrorNo> {
+        self.inner.lock().remote.ok_or(ErrorNo::ENOTCONN)
+    }
+    /// 接收缓冲区中是否有数据报
+    pub fn ready_to_read(&self, stack: &mut NetStack) -> bool {
+        let inner = self.inner.lock();
+        inner.read_shutdown
+            || inner
+                .handle
+                .map_or(false, |handle| stack.udp(handle).can_recv())
+    }
+    /// 发送缓冲区是否有空间。还没有绑定端口的 socket 总是可写
+    pub fn ready_to_write(&self, stack: &mut NetStack) -> bool {
+        let inner = self.inner.lock();
+        inner.write_shutdown
+            || inner
+                .handle
+                .map_or(true, |handle| stack.udp(handle).can_send())
+    }
+}
+
+impl Drop for UdpSocket {
+    fn drop(&mut self) {
+        let inner = self.inner.get_mut();
+        if let (Some(handle), Some(local)) = (inner.handle, inner.local) {
+            let mut stack = lock_net_stack();
+            stack.remove_socket(handle);
+            stack.release_port(PortSpace::Udp, local.port);
+        }
+    }
+}
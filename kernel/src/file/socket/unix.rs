@@ -0,0 +1,681 @@
+//! unix 域 socket
+//!
+//! 每个 unix socket 有一个 `UnixEndpoint`，其中是它的接收队列。发送消息就是把消息放进对方的接收队列：
+//! 流式(SOCK_STREAM)和有序包(SOCK_SEQPACKET) socket 在连接时记下对方的 endpoint，
+//! 数据报(SOCK_DGRAM) socket 则在每次发送时按地址查找对方。
+//!
+//! 绑定的地址记录在全局的名字表中。抽象地址在 socket 关闭时自动删除；路径地址和 Linux 一样，
+//! 要等到 unlink 这个路径时才删除，在此之前再次绑定同一个路径会返回 EADDRINUSE。
+//!
+//! 为了避免死锁，同一时间最多只持有一个 endpoint 的锁。名字表的锁总是在 endpoint 的锁之前获取
+//!
+//! 内核没有回收引用环的垃圾回收，所以通过 SCM_RIGHTS 发送 unix socket 时，如果从它出发，
+//! 经过接收队列中的 socket 能够回到接收端(包括发给它自己)，就拒绝发送，否则环上的 socket 永远不会被释放。
+//! 检查和放进队列之间没有加锁，两个任务同时互相发送时仍可能形成环
+
+use super::{
+    message::{Ancillary, RecvMeta, UCred},
+    options::SocketOptions,
+    resolution::{SocketAddr, UnixAddr},
+    Socket, SocketType, Transport,
+};
+use crate::file::{notify_file_event, wait_for_file_event};
+use crate::task::signal_pending;
+use alloc::{
+    collections::{BTreeMap, BTreeSet, VecDeque},
+    format,
+    string::String,
+    sync::{Arc, Weak},
+    vec::Vec,
+};
+use base_file::File;
+use core::{
+    cmp::min,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+use lock::Mutex;
+use syscall::ErrorNo;
+use timer::get_time_us;
+
+/// 已绑定的地址
+static UNIX_NAMES: Mutex<BTreeMap<UnixAddr, Weak<UnixEndpoint>>> = Mutex::new(BTreeMap::new());
+/// 自动分配地址时使用的编号
+static NEXT_AUTOBIND_ID: AtomicUsize = AtomicUsize::new(0);
+
+/// 如果 file 是 unix socket，返回它的接收端
+fn endpoint_of(file: &Arc<dyn File>) -> Option<Arc<UnixEndpoint>> {
+    match file
+        .as_any()
+        .downcast_ref::<Socket>()
+        .map(|socket| &socket.transport)
+    {
+        Some(Transport::Unix(unix)) => Some(unix.endpoint.clone()),
+        _ => None,
+    }
+}
+
+/// 把 files 放进 target 的接收队列是否会形成引用环，
+/// 即从其中的 unix socket 出发，沿着接收队列中传递的 socket 能否到达 target
+fn forms_cycle(files: &[Arc<dyn File>], target: &Arc<UnixEndpoint>) -> bool {
+    let mut pending: Vec<Arc<UnixEndpoint>> = files.iter().filter_map(endpoint_of).collect();
+    let mut visited = BTreeSet::new();
+    while let Some(endpoint) = pending.pop() {
+        if Arc::ptr_eq(&endpoint, target) {
+            return true;
+        }
+        if visited.insert(Arc::as_ptr(&endpoint) as usize) {
+            pending.extend(endpoint.held_endpoints());
+        }
+    }
+    false
+}
+
+/// 接收队列中的一条消息
+struct UnixMessage {
+    /// 消息内容。流式 socket 只读了一部分时，剩下的部分仍留在这里
+    data: Vec<u8>,
+    /// 发送方的地址
+    from: UnixAddr,
+    /// 随消息传递的文件(SCM_RIGHTS)
+    rights: Vec<Arc<dyn File>>,
+    /// 发送方的身份(SCM_CREDENTIALS)
+    cred: UCred,
+}
+
+/// 连接状态
+enum UnixState {
+    /// 未连接
+    Unconnected,
+    /// 正在监听。pending 中是已经连上、但还没有被 accept 的 endpoint
+    Listening {
+        backlog: usize,
+        pending: VecDeque<Arc<UnixEndpoint>>,
+    },
+    /// 已连接。对数据报 socket 来说只是设置了默认的目的地址
+    Connected {
+        peer: Weak<UnixEndpoint>,
+        peer_addr: UnixAddr,
+        /// 建立连接时对方的身份，即 SO_PEERCRED
+        peer_cred: UCred,
+    },
+}
+
+struct EndpointInner {
+    /// 本地地址
+    local: UnixAddr,
+    /// 创建这一端的任务的身份
+    cred: UCred,
+    /// 连接状态
+    state: UnixState,
+    /// 接收队列
+    queue: VecDeque<UnixMessage>,
+    /// 接收队列中的字节数
+    queued_bytes: usize,
+    /// 接收队列的大小上限，即 SO_RCVBUF
+    recv_limit: usize,
+    /// 本端调用了 shutdown(SHUT_RD)
+    read_shutdown: bool,
+    /// 本端调用了 shutdown(SHUT_WR)
+    write_shutdown: bool,
+    /// 对方调用了 shutdown(SHUT_WR)
+    peer_write_shutdown: bool,
+}
+
+/// unix socket 的一端，即它的接收队列和连接状态
+pub struct UnixEndpoint {
+    inner: Mutex<EndpointInner>,
+}
+
+impl EndpointInner {
+    /// 接收队列是否还能放下 len 字节。
+    ///
+    /// 队列为空时总能放下，否则一个比缓冲区还大的消息永远发不出去
+    fn has_space(&self, len: usize) -> bool {
+        self.queue.is_empty() || self.queued_bytes + len <= self.recv_limit
+    }
+}
+
+impl UnixEndpoint {
+    fn new(local: UnixAddr, cred: UCred, recv_limit: usize) -> Arc<Self> {
+        Arc::new(Self {
+            inner: Mutex::new(EndpointInner {
+                local,
+                cred,
+                state: UnixState::Unconnected,
+                queue: VecDeque::new(),
+                queued_bytes: 0,
+                recv_limit,
+                read_shutdown: false,
+                write_shutdown: false,
+                peer_write_shutdown: false,
+            }),
+        })
+    }
+    /// 把消息放进接收队列，成功时会取走 message。队列已满时返回 EAGAIN，对方已关闭读端时返回 EPIPE
+    fn push(&self, message: &mut Option<UnixMessage>) -> Result<(), ErrorNo> {
+        let mut inner = self.inner.lock();
+        if inner.read_shutdown {
+            return Err(ErrorNo::EPIPE);
+        }
+        let len = message.as_ref().map_or(0, |message| message.data.len());
+        if !inner.has_space(len) {
+            return Err(ErrorNo::EAGAIN);
+        }
+        inner.queued_bytes += len;
+        inner.queue.extend(message.take());
+        Ok(())
+    }
+    /// 接收队列是否还能放下 len 字节
+    fn has_space(&self, len: usize) -> bool {
+        let inner = self.inner.lock();
+        inner.read_shutdown || inner.has_space(len)
+    }
+    /// 这一端持有的其他 endpoint：接收队列中传递的 unix socket 的接收端，以及监听时还没有被 accept 的连接
+    fn held_endpoints(&self) -> Vec<Arc<UnixEndpoint>> {
+        let inner = self.inner.lock();
+        let mut endpoints: Vec<Arc<UnixEndpoint>> = inner
+            .queue
+            .iter()
+            .flat_map(|message| message.rights.iter().filter_map(endpoint_of))
+            .collect();
+        if let UnixState::Listening { pending, .. } = &inner.state {
+            endpoints.extend(pending.iter().cloned());
+        }
+        endpoints
+    }
+}
+
+/// 一个 unix 域 socket
+pub struct UnixSocket {
+    stype: SocketType,
+    endpoint: Arc<UnixEndpoint>,
+}
+
+/// 把路径转换为绝对路径。cwd 是任务的当前目录，可能以 '.' 开头(见 `sys_getcwd`)
+pub fn absolute_unix_path(cwd: &str, path: &str) -> String {
+    let mut components: Vec<&str> = Vec::new();
+    let base = if path.starts_with('/') {
+        ""
+    } else {
+        cwd.trim_start_matches('.')
+    };
+    for component in base.split('/').chain(path.split('/')) {
+        match component {
+            "" | "." => {}
+            ".." => {
+                components.pop();
+            }
+            _ => components.push(component),
+        }
+    }
+    format!("/{}", components.join("/"))
+}
+
+/// 删除绑定在路径上的 unix socket 地址，即 unlink 一个 socket 文件。如果没有这个地址，返回 false
+pub fn unlink_unix_path(path: &str) -> bool {
+    UNIX_NAMES
+        .lock()
+        .remove(&UnixAddr::Path(String::from(path)))
+        .is_some()
+}
+
+/// 反复调用 `f`，直到它返回的不是 EAGAIN。
+///
+/// 如果 nonblock 为 true，则只尝试一次；否则等待文件状态变化后重试，超过 timeout_us 微秒后返回 EAGAIN
+fn block_on<T>(
+    nonblock: bool,
+    timeout_us: Option<usize>,
+    mut f: impl FnMut() -> Result<T, ErrorNo>,
+) -> Result<T, ErrorNo> {
+    if nonblock {
+        return f();
+    }
+    let expire_us = timeout_us.map(|timeout_us| get_time_us() + timeout_us);
+    wait_for_file_event(expire_us, || match f() {
+        Err(ErrorNo::EAGAIN) => None,
+        ret => Some(ret),
+    })
+    .unwrap_or(Err(if signal_pending() {
+        ErrorNo::EINTR
+    } else {
+        ErrorNo::EAGAIN
+    }))
+}
+
+impl UnixSocket {
+    /// 新建一个 unix socket
+    pub fn new(stype: SocketType, options: &SocketOptions) -> Self {
+        Self {
+            stype,
+            endpoint: UnixEndpoint::new(UnixAddr::Unnamed, UCred::current(), options.recv_buf_size),
+        }
+    }
+    /// 新建一对互相连接的 unix socket，即 socketpair
+    pub fn pair(stype: SocketType, options: &SocketOptions) -> (Self, Self) {
+        let first = Self::new(stype, options);
+        let second = Self::new(stype, options);
+        let cred = UCred::current();
+        first.endpoint.inner.lock().state = UnixState::Connected {
+            peer: Arc::downgrade(&second.endpoint),
+            peer_addr: UnixAddr::Unnamed,
+            peer_cred: cred,
+        };
+        second.endpoint.inner.lock().state = UnixState::Connected {
+            peer: Arc::downgrade(&first.endpoint),
+            peer_addr: UnixAddr::Unnamed,
+            peer_cred: cred,
+        };
+        (first, second)
+    }
+    /// 是否是面向连接的 socket
+    fn is_connection_based(&self) -> bool {
+        self.stype != SocketType::SOCK_DGRAM
+    }
+    /// 绑定地址。地址为空时自动分配一个抽象地址
+    pub fn bind(&self, addr: UnixAddr) -> Result<(), ErrorNo> {
+        let mut names = UNIX_NAMES.lock();
+        let mut inner = self.endpoint.inner.lock();
+        if inner.local != UnixAddr::Unnamed {
+            return Err(ErrorNo::EINVAL);
+        }
+        let addr = match addr {
+            UnixAddr::Unnamed => loop {
+                // 和 Linux 一样，自动分配的地址是 5 位十六进制数
+                let id = NEXT_AUTOBIND_ID.fetch_add(1, Ordering::Relaxed) & 0xfffff;
+                let addr = UnixAddr::Abstract(format!("{:05x}", id).into_bytes());
+                if !names.contains_key(&addr) {
+                    break addr;
+                }
+            },
+            addr => addr,
+        };
+        if names.contains_key(&addr) {
+            return Err(ErrorNo::EADDRINUSE);
+        }
+        names.insert(addr.clone(), Arc::downgrade(&self.endpoint));
+        inner.local = addr;
+        Ok(())
+    }
+    /// 开始监听，只有面向连接的 socket 可用
+    pub fn listen(&self, backlog: usize) -> Result<(), ErrorNo> {
+        if !self.is_connection_based() {
+            return Err(ErrorNo::EOPNOTSUPP);
+        }
+        let mut inner = self.endpoint.inner.lock();
+        if inner.local == UnixAddr::Unnamed {
+            return Err(ErrorNo::EINVAL);
+        }
+        match &mut inner.state {
+            state @ UnixState::Unconnected => {
+                *state = UnixState::Listening {
+                    backlog,
+                    pending: VecDeque::new(),
+                }
+            }
+            // 重复 listen 只修改 backlog
+            UnixState::Listening { backlog: old, .. } => *old = backlog,
+            UnixState::Connected { .. } => return Err(ErrorNo::EINVAL),
+        }
+        Ok(())
+    }
+    /// 按地址找到对方
+    fn lookup(addr: &UnixAddr) -> Result<Arc<UnixEndpoint>, ErrorNo> {
+        UNIX_NAMES
+            .lock()
+            .get(addr)
+            .and_then(|endpoint| endpoint.upgrade())
+            .ok_or(ErrorNo::ECONNREFUSED)
+    }
+    /// 连接到 addr。
+    ///
+    /// 面向连接的 socket 会在对方的等待队列中放一个新的 endpoint，由对方 accept 取走；
+    /// 数据报 socket 只是设置默认的目的地址
+    pub fn connect(
+        &self,
+        addr: UnixAddr,
+        nonblock: bool,
+        options: &SocketOptions,
+    ) -> Result<(), ErrorNo> {
+        let target = Self::lookup(&addr)?;
+        if !self.is_connection_based() {
+            let peer_cred = target.inner.lock().cred;
+            self.endpoint.inner.lock().state = UnixState::Connected {
+                peer: Arc::downgrade(&target),
+                peer_addr: addr,
+                peer_cred,
+            };
+            return Ok(());
+        }
+        let (local, cred) = {
+            let inner = self.endpoint.inner.lock();
+            match inner.state {
+                UnixState::Unconnected => (inner.local.clone(), inner.cred),
+                UnixState::Listening { .. } => return Err(ErrorNo::EINVAL),
+                UnixState::Connected { .. } => return Err(ErrorNo::EISCONN),
+            }
+        };
+        let (server, peer_cred) = block_on(nonblock, options.send_timeout_us, || {
+            let mut target_inner = target.inner.lock();
+            let target_inner = &mut *target_inner;
+            match &mut target_inner.state {
+                UnixState::Listening { backlog, pending } => {
+                    if pending.len() > *backlog {
+                        return Err(ErrorNo::EAGAIN);
+                    }
+                    // 新的 endpoint 在放进 pending 之前别人拿不到，所以这里可以同时持有两个锁
+                    let server = UnixEndpoint::new(
+                        target_inner.local.clone(),
+                        target_inner.cred,
+                        target_inner.recv_limit,
+                    );
+                    server.inner.lock().state = UnixState::Connected {
+                        peer: Arc::downgrade(&self.endpoint),
+                        peer_addr: local.clone(),
+                        peer_cred: cred,
+                    };
+                    pending.push_back(server.clone());
+                    Ok((server, target_inner.cred))
+                }
+                _ => Err(ErrorNo::ECONNREFUSED),
+            }
+        })?;
+        self.endpoint.inner.lock().state = UnixState::Connected {
+            peer: Arc::downgrade(&server),
+            peer_addr: addr,
+            peer_cred,
+        };
+        notify_file_event();
+        Ok(())
+    }
+    /// 接受一个连接，返回新的 socket 和对方的地址
+    pub fn accept(
+        &self,
+        nonblock: bool,
+        options: &SocketOptions,
+    ) -> Result<(UnixSocket, UnixAddr), ErrorNo> {
+        let endpoint = block_on(nonblock, options.recv_timeout_us, || {
+            match &mut self.endpoint.inner.lock().state {
+                UnixState::Listening { pending, .. } => pending.pop_front().ok_or(ErrorNo::EAGAIN),
+                _ => Err(ErrorNo::EINVAL),
+            }
+        })?;
+        let peer_addr = match &endpoint.inner.lock().state {
+            UnixState::Connected { peer_addr, .. } => peer_addr.clone(),
+            _ => UnixAddr::Unnamed,
+        };
+        let socket = UnixSocket {
+            stype: self.stype,
+            endpoint,
+        };
+        Ok((socket, peer_addr))
+    }
+    /// 已连接的对方。对方已经关闭时返回 None
+    fn peer(&self) -> Result<Option<Arc<UnixEndpoint>>, ErrorNo> {
+        match &self.endpoint.inner.lock().state {
+            UnixState::Connected { peer, .. } => Ok(peer.upgrade()),
+            _ => Err(ErrorNo::ENOTCONN),
+        }
+    }
+    /// 发送消息，ancillary 为随消息传递的控制信息。返回发送的长度。
+    ///
+    /// - 面向连接的 socket 忽略 dest，发送到已连接的对方；
+    /// - 数据报 socket 在 dest 为 None 时发送到 connect 设置的地址；
+    /// - 没有指定发送方身份时，使用当前任务的身份。所有任务都是 root，所以不检查用户指定的身份
+    pub fn send(
+        &self,
+        buf: &[u8],
+        dest: Option<UnixAddr>,
+        ancillary: Ancillary,
+        nonblock: bool,
+        options: &SocketOptions,
+    ) -> Result<usize, ErrorNo> {
+        let from = {
+            let inner = self.endpoint.inner.lock();
+            if inner.write_shutdown {
+                return Err(ErrorNo::EPIPE);
+            }
+            inner.local.clone()
+        };
+        let target = match dest {
+            Some(dest) if !self.is_connection_based() => Self::lookup(&dest)?,
+            _ => match self.peer() {
+                Ok(Some(peer)) => peer,
+                Ok(None) if self.is_connection_based() => return Err(ErrorNo::EPIPE),
+                Ok(None) => return Err(ErrorNo::ECONNREFUSED),
+                Err(_) if self.is_connection_based() => return Err(ErrorNo::ENOTCONN),
+                Err(_) => return Err(ErrorNo::EDESTADDRREQ),
+            },
+        };
+        // 发送的 socket 能经过接收队列回到接收端时，会形成引用环
+        if forms_cycle(&ancillary.rights, &target) {
+            return Err(ErrorNo::EINVAL);
+        }
+        if self.stype == SocketType::SOCK_STREAM {
+            // 流式 socket 的空消息会让对方以为读到了 EOF
+            if buf.is_empty() && ancillary.rights.is_empty() {
+                return Ok(0);
+            }
+        } else if buf.len() > options.send_buf_size {
+            return Err(ErrorNo::EMSGSIZE);
+        }
+        let mut message = Some(UnixMessage {
+            data: buf.to_vec(),
+            from,
+            rights: ancillary.rights,
+            cred: ancillary.creds.unwrap_or_else(UCred::current),
+        });
+        block_on(nonblock, options.send_timeout_us, || {
+            // 等待时对方可能已经关闭了
+            if self.is_connection_based() && !matches!(self.peer(), Ok(Some(_))) {
+                return Err(ErrorNo::EPIPE);
+            }
+            target.push(&mut message)
+        })?;
+        notify_file_event();
+        Ok(buf.len())
+    }
+    /// 接收消息，返回读到的长度、发送方的地址和控制信息。peek 为 true 时消息留在接收队列中。
+    ///
+    /// 流式 socket 可以一次读到多条消息的内容，但不会越过带有文件或者发送方身份不同的消息；
+    /// 其他 socket 每次只读一条消息，buf 放不下的部分会被丢弃。
+    /// 对方关闭连接或者本端关闭读端后，返回长度 0
+    pub fn recv(
+        &self,
+        buf: &mut [u8],
+        peek: bool,
+        nonblock: bool,
+        options: &SocketOptions,
+    ) -> Result<RecvMeta, ErrorNo> {
+        let ret = block_on(nonblock, options.recv_timeout_us, || {
+            let mut inner = self.endpoint.inner.lock();
+            if inner.queue.is_empty() {
+                let eof = inner.read_shutdown
+                    || inner.peer_write_shutdown
+                    || match &inner.state {
+                        UnixState::Connected { peer, .. } => {
+                            self.is_connection_based() && peer.strong_count() == 0
+                        }
+                        UnixState::Listening { .. } => return Err(ErrorNo::EINVAL),
+                        UnixState::Unconnected if self.is_connection_based() => {
+                            return Err(ErrorNo::ENOTCONN)
+                        }
+                        UnixState::Unconnected => false,
+                    };
+                return if eof {
+                    Ok(RecvMeta::new(0, 0, None))
+                } else {
+                    Err(ErrorNo::EAGAIN)
+                };
+            }
+            if self.stype != SocketType::SOCK_STREAM {
+                let message = inner.queue.front().unwrap();
+                let len = min(buf.len(), message.data.len());
+                buf[..len].copy_from_slice(&message.data[..len]);
+                let meta = RecvMeta {
+                    len,
+                    msg_len: message.data.len(),
+                    src: Some(SocketAddr::Unix(message.from.clone())),
+                    ancillary: Ancillary {
+                        rights: message.rights.clone(),
+                        creds: Some(message.cred),
+                    },
+                };
+                if !peek {
+                    let message = inner.queue.pop_front().unwrap();
+                    inner.queued_bytes -= message.data.len();
+                }
+                return Ok(meta);
+            }
+            let mut read_len = 0;
+            let mut rights = Vec::new();
+            let mut first: Option<(UnixAddr, UCred)> = None;
+            // 完整读完的消息数，以及最后一条没有读完的消息读了多少字节
+            let mut full_count = 0;
+            let mut partial_len = 0;
+            for message in inner.queue.iter() {
+                if let Some((_, cred)) = &first {
+                    // 带有文件或者发送方身份不同的消息要单独读出来
+                    if read_len == buf.len() || !message.rights.is_empty() || message.cred != *cred
+                    {
+                        break;
+                    }
+                }
+                first.get_or_insert_with(|| (message.from.clone(), message.cred));
+                let len = min(buf.len() - read_len, message.data.len());
+                buf[read_len..read_len + len].copy_from_slice(&message.data[..len]);
+                rights.extend(message.rights.iter().cloned());
+                read_len += len;
+                if len < message.data.len() {
+                    partial_len = len;
+                    break;
+                }
+                full_count += 1;
+            }
+            if !peek {
+                for _ in 0..full_count {
+                    let message = inner.queue.pop_front().unwrap();
+                    inner.queued_bytes -= message.data.len();
+                }
+                if let Some(message) = inner.queue.front_mut() {
+                    if partial_len > 0 {
+                        message.data.drain(..partial_len);
+                        // 文件已经随这次读取交出去了
+                        message.rights.clear();
+                        inner.queued_bytes -= partial_len;
+                    }
+                }
+            }
+            let (from, cred) = first.unzip();
+            Ok(RecvMeta {
+                len: read_len,
+                msg_len: read_len,
+                src: from.map(SocketAddr::Unix),
+                ancillary: Ancillary {
+                    rights,
+                    creds: cred,
+                },
+            })
+        })?;
+        if !peek {
+            notify_file_event();
+        }
+        Ok(ret)
+    }
+    /// 关闭读端和/或写端。关闭写端后，对方读完已有的数据后会读到 EOF
+    pub fn shutdown(&self, read: bool, write: bool) -> Result<(), ErrorNo> {
+        let peer = {
+            let mut inner = self.endpoint.inner.lock();
+            let peer = match &inner.state {
+                UnixState::Connected { peer, .. } => peer.upgrade(),
+                _ => return Err(ErrorNo::ENOTCONN),
+            };
+            inner.read_shutdown |= read;
+            inner.write_shutdown |= write;
+            peer
+        };
+        if let (true, Some(peer)) = (write, peer) {
+            peer.inner.lock().peer_write_shutdown = true;
+        }
+        notify_file_event();
+        Ok(())
+    }
+    /// 本地地址
+    pub fn local_addr(&self) -> UnixAddr {
+        self.endpoint.inner.lock().local.clone()
+    }
+    /// 对方的地址
+    pub fn peer_addr(&self) -> Result<UnixAddr, ErrorNo> {
+        match &self.endpoint.inner.lock().state {
+            UnixState::Connected { peer_addr, .. } => Ok(peer_addr.clone()),
+            _ => Err(ErrorNo::ENOTCONN),
+        }
+    }
+    /// 建立连接时对方的身份，即 SO_PEERCRED
+    pub fn peer_cred(&self) -> Result<UCred, ErrorNo> {
+        match &self.endpoint.inner.lock().state {
+            UnixState::Connected { peer_cred, .. } => Ok(*peer_cred),
+            _ => Err(ErrorNo::ENOTCONN),
+        }
+    }
+    /// 修改选项。目前只有接收缓冲区大小会生效
+    pub fn set_options(&self, options: &SocketOptions) {
+        self.endpoint.inner.lock().recv_limit = options.recv_buf_size;
+    }
+    /// 是否在监听
+    pub fn is_listening(&self) -> bool {
+        matches!(
+            self.endpoint.inner.lock().state,
+            UnixState::Listening { .. }
+        )
+    }
+    /// 有数据、有新连接或者连接已关闭时可读
+    pub fn ready_to_read(&self) -> bool {
+        let inner = self.endpoint.inner.lock();
+        !inner.queue.is_empty()
+            || inner.read_shutdown
+            || inner.peer_write_shutdown
+            || match &inner.state {
+                UnixState::Listening { pending, .. } => !pending.is_empty(),
+                UnixState::Connected { peer, .. } => {
+                    self.is_connection_based() && peer.strong_count() == 0
+                }
+                UnixState::Unconnected => false,
+            }
+    }
+    /// 对方的接收队列有空间时可写。未连接的数据报 socket 总是可写
+    pub fn ready_to_write(&self) -> bool {
+        match self.peer() {
+            Ok(Some(peer)) => peer.has_space(1),
+            Ok(None) => self.is_connection_based(),
+            Err(_) => !self.is_connection_based(),
+        }
+    }
+    /// 面向连接的 socket 的对方已经关闭
+    pub fn is_hang_up(&self) -> bool {
+        self.is_connection_based() && matches!(self.peer(), Ok(None))
+    }
+}
+
+impl Drop for UnixSocket {
+    fn drop(&mut self) {
+        // 抽象地址随 socket 一起删除，路径地址则要等到 unlink。
+        // accept 得到的 socket 的地址和监听的 socket 相同，但名字表里记录的不是它，这时不能删除
+        let (local, cred) = {
+            let inner = self.endpoint.inner.lock();
+            (inner.local.clone(), inner.cred)
+        };
+        if let UnixAddr::Abstract(_) = local {
+            let mut names = UNIX_NAMES.lock();
+            let weak = Arc::downgrade(&self.endpoint);
+            if names.get(&local).map_or(false, |owner| owner.ptr_eq(&weak)) {
+                names.remove(&local);
+            }
+        }
+        // 先释放 endpoint，让对方能看到连接已断开，再唤醒可能在等待这个 socket 的对方
+        drop(core::mem::replace(
+            &mut self.endpoint,
+            UnixEndpoint::new(UnixAddr::Unnamed, cred, 0),
+        ));
+        notify_file_event();
+    }
+}
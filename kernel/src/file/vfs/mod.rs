@@ -2,6 +2,7 @@
 //! 用于对一些特殊目录和文件的访问，如 /dev/zero 或 /tmp
 
 mod null;
+mod shm;
 mod temp;
 mod virt_dir;
 mod virt_file;
@@ -15,6 +16,7 @@ use lock::Mutex;
 use alloc::collections::BTreeMap;
 use base_file::{File, OpenFlags};
 use null::NullFile;
+pub use shm::{ShmFile, SHM_DIR};
 use virt_dir::VirtDir;
 use virt_file::{VirtFile, VirtFileInner};
 pub type BufferFile = VirtFileInner;
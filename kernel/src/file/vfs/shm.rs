@@ -0,0 +1,111 @@
+//! /dev/shm 下的文件，即 shm_open 打开的共享内存
+//!
+//! 文件内容保存在一段 `SharedMemory` 中。用 MAP_SHARED 映射这个文件时，地址段直接使用共享内存的页帧，
+//! 所以不同进程的映射和文件读写看到的都是同一份数据
+
+use crate::constants::SHM_SIZE_LIMIT;
+use crate::file::SeekFrom;
+use crate::memory::SharedMemory;
+use alloc::sync::Arc;
+use base_file::{normal_file_mode, File, Kstat, StMode};
+use lock::Mutex;
+use syscall::ErrorNo;
+
+/// 共享内存文件所在的目录
+pub const SHM_DIR: &str = "dev/shm";
+
+/// 共享内存文件
+pub struct ShmFile {
+    memory: Arc<SharedMemory>,
+    /// 当前文件指针位置
+    pos: Mutex<usize>,
+}
+
+impl ShmFile {
+    pub fn new() -> Self {
+        Self {
+            memory: Arc::new(SharedMemory::new(0)),
+            pos: Mutex::new(0),
+        }
+    }
+    /// 文件内容所在的共享内存
+    pub fn memory(&self) -> Arc<SharedMemory> {
+        self.memory.clone()
+    }
+    /// 修改文件大小，一般是 ftruncate 要求的
+    pub fn truncate(&self, size: usize) {
+        self.memory.set_size(size);
+    }
+    /// 写入失败的原因：文件指针已经到了共享内存的大小限制，或者没有内存了
+    pub fn write_error(&self) -> ErrorNo {
+        if *self.pos.lock() >= SHM_SIZE_LIMIT {
+            ErrorNo::EFBIG
+        } else {
+            ErrorNo::ENOSPC
+        }
+    }
+}
+
+impl File for ShmFile {
+    /// 读取文件
+    fn read(&self, buf: &mut [u8]) -> Option<usize> {
+        let mut pos = self.pos.lock();
+        let read_len = self.memory.read(*pos, buf);
+        *pos += read_len;
+        Some(read_len)
+    }
+    /// 写入文件
+    fn write(&self, buf: &[u8]) -> Option<usize> {
+        let mut pos = self.pos.lock();
+        let write_len = self.memory.write(*pos, buf).ok()?;
+        *pos += write_len;
+        Some(write_len)
+    }
+    /// 从某个位置读文件内容到 buf 中，返回读到的字节数，但不改变指针位置
+    fn read_from_offset(&self, pos: usize, buf: &mut [u8]) -> Option<usize> {
+        Some(self.memory.read(pos, buf))
+    }
+    /// 将 buf 写入文件中的某个位置，返回读到的字节数，但不改变指针位置
+    fn write_to_offset(&self, pos: usize, buf: &[u8]) -> Option<usize> {
+        self.memory.write(pos, buf).ok()
+    }
+    /// 文件属性
+    fn get_stat(&self, stat: *mut Kstat) -> bool {
+        unsafe {
+            (*stat).st_dev = 0;
+            (*stat).st_ino = 0;
+            (*stat).st_nlink = 1;
+            (*stat).st_mode = normal_file_mode(StMode::S_IFREG).bits();
+            (*stat).st_size = self.memory.size() as u64;
+            (*stat).st_uid = 0;
+            (*stat).st_gid = 0;
+            (*stat).st_atime_sec = 0;
+            (*stat).st_atime_nsec = 0;
+            (*stat).st_mtime_sec = 0;
+            (*stat).st_mtime_nsec = 0;
+            (*stat).st_ctime_sec = 0;
+            (*stat).st_ctime_nsec = 0;
+        }
+        true
+    }
+    /// 切换文件指针位置
+    fn seek(&self, seekfrom: SeekFrom) -> Option<usize> {
+        let mut pos = self.pos.lock();
+        let new_pos = match seekfrom {
+            SeekFrom::Start(off) => off as i64,
+            SeekFrom::Current(off) => *pos as i64 + off,
+            SeekFrom::End(off) => self.memory.size() as i64 + off,
+        };
+        // 不能移动到文件前
+        if new_pos < 0 {
+            return None;
+        }
+        *pos = new_pos as usize;
+        Some(*pos)
+    }
+    /// 清空文件
+    fn clear(&self) {
+        *self.pos.lock() = 0;
+        self.memory.set_size(0);
+    }
+}
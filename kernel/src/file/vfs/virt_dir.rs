@@ -1,7 +1,7 @@
 //! 虚拟文件系统的目录。不需要考虑把数据塞进页里
 //!
 
-use super::VirtFile;
+use super::{ShmFile, VirtFile, SHM_DIR};
 use alloc::{string::String, sync::Arc, vec::Vec};
 use base_file::{normal_file_mode, File, Kstat, OpenFlags, StMode};
 use lock::Mutex;
@@ -96,7 +96,8 @@ impl VirtDir {
                         //要求必须要创建文件
                         None
                     } else {
-                        if flags.contains(OpenFlags::CREATE) {
+                        // /dev/shm 下的文件会被多个进程用 O_CREAT 打开，不能清空
+                        if flags.contains(OpenFlags::CREATE) && self.name != SHM_DIR {
                             // 清空这个文件
                             f.clear();
                         };
@@ -104,9 +105,13 @@ impl VirtDir {
                     }
                 }
                 None => {
-                    // 找不到且要求创建，则默认创建 VirtFile
+                    // 找不到且要求创建，则默认创建 VirtFile。/dev/shm 下则创建共享内存文件
                     if flags.contains(OpenFlags::CREATE) {
-                        let file: Arc<dyn File> = Arc::new(VirtFile::new(flags));
+                        let file: Arc<dyn File> = if self.name == SHM_DIR {
+                            Arc::new(ShmFile::new())
+                        } else {
+                            Arc::new(VirtFile::new(flags))
+                        };
                         let ret = file.clone();
                         self_entry.push(DirEntry::new(file_name.clone(), file));
                         Some(ret)
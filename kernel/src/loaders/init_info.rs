@@ -10,6 +10,7 @@ use core::ptr::null;
 
 use super::flags::*;
 use super::InitStack;
+use crate::random::random_u64;
 
 /// 初始化信息
 #[derive(Debug)]
@@ -29,8 +30,8 @@ impl InitInfo {
         let mut writer = InitStack::new(stack_top);
         // 程序名
         writer.push_str(&self.args[0]);
-        // "随机"串。想要真正做到随机需要硬件，但目前实现暂不影响程序运行
-        let random_str = &[3703830112808742751usize, 7081108068768079778usize];
+        // 16 字节的随机串，libc 用它初始化栈保护等
+        let random_str = &[random_u64() as usize, random_u64() as usize];
         writer.push_slice(random_str.as_slice());
         let random_pos = writer.sp;
         // 环境变量
@@ -10,12 +10,12 @@ use alloc::{
     sync::Arc,
     vec::Vec,
 };
-use base_file::OpenFlags;
+use base_file::{File, OpenFlags};
 use core::convert::From;
 use lock::Mutex;
 use xmas_elf::{
     header,
-    program::{Flags, SegmentData, Type},
+    program::{Flags, Type},
     sections::SectionData,
     symbol_table::Entry,
     ElfFile,
@@ -25,21 +25,26 @@ use crate::constants::{
     //LIBC_SO_NAME,
     //LIBC_SO_FILE,
     //LIBC_SO_DIR,
-    ELF_BASE_RELOCATE,
     PAGE_SIZE,
     ROOT_DIR,
-    USER_STACK_OFFSET,
     USER_STACK_SIZE,
 };
 use crate::error::{OSError, OSResult};
-use crate::file::open_file;
+use crate::file::{open_file, BackEndFile, SyncPolicy};
 use crate::memory::addr::{page_count, page_offset, VirtAddr};
 use crate::memory::{MemorySet, PTEFlags};
-use crate::memory::{PmArea, PmAreaLazy, VmArea};
+use crate::memory::{PmArea, PmAreaLazy, VmArea, USER_STACK_AREA_NAME};
 use crate::utils::raw_ptr_to_ref_str;
 
+/// ELF 加载器。
+///
+/// 加载器只读取 ELF 头和程序头表，PT_LOAD 段通过 `BackEndFile` 映射到文件上，
+/// 发生 page fault 时才从文件中读取对应的页
 pub struct ElfLoader<'a> {
+    /// 只包含 ELF 头和程序头表，不能用来读取段或者节的内容
     elf: ElfFile<'a>,
+    /// ELF 文件本身
+    file: Arc<dyn File>,
 }
 
 impl From<&str> for OSError {
@@ -50,8 +55,9 @@ impl From<&str> for OSError {
 }
 
 impl<'a> ElfLoader<'a> {
-    pub fn new(elf_data: &'a [u8]) -> OSResult<Self> {
-        let elf = ElfFile::new(elf_data).unwrap();
+    /// elf_header 是文件开头包含 ELF 头和程序头表的部分，见 `read_elf_header`
+    pub fn new(elf_header: &'a [u8], file: Arc<dyn File>) -> OSResult<Self> {
+        let elf = ElfFile::new(elf_header)?;
         // 检查类型
         if elf.header.pt1.class() != header::Class::SixtyFour {
             return Err("32-bit ELF is not supported on the riscv64".into());
@@ -66,7 +72,7 @@ impl<'a> ElfLoader<'a> {
             header::Machine::Other(0xF3) => {}
             _ => return Err("invalid ELF arch".into()),
         };
-        Ok(Self { elf })
+        Ok(Self { elf, file })
     }
     /// 解析 elf 文件并初始化一个用户程序，其中 args 为用户程序执行时的参数。
     ///
@@ -89,10 +95,12 @@ impl<'a> ElfLoader<'a> {
             .program_iter()
             .find(|ph| ph.get_type() == Ok(Type::Interp))
         {
-            let data = match interp_header.get_data(&self.elf).unwrap() {
-                SegmentData::Undefined(data) => data,
-                _ => return Err(OSError::Loader_InvalidSegment),
-            };
+            let mut data = vec![0u8; interp_header.file_size() as usize + 1];
+            self.file
+                .read_from_offset(interp_header.offset() as usize, &mut data)
+                .ok_or(OSError::Loader_InvalidSegment)?;
+            // 多读的一个字节保证路径以 '\0' 结尾
+            *data.last_mut().unwrap() = 0;
             let path = unsafe { raw_ptr_to_ref_str(data.as_ptr()) };
             info!("path: {:?}", path);
             let mut new_args = vec![String::from(path)];
@@ -119,12 +127,13 @@ impl<'a> ElfLoader<'a> {
             info!("phdr = {:x}", phdr);
             // 如果是 0，如 libc.so，则需要放到一个非零的合法地址。此处规定从某个特定位置开始往后找。
             // 这样设置是因为，动态库运行时可能会mmap实际的用户程序且指定 MAP_FIXED，
-            // 而用户程序的地址一般较低。为了让它们直接尽可能不冲突，所以会放到稍高的地址
+            // 而用户程序的地址一般较低。为了让它们直接尽可能不冲突，所以会放到稍高的地址。
+            // 开启地址随机化时，这个位置还会加上一个随机偏移，见 `UserLayout`
             if phdr != 0 {
                 phdr
             } else {
-                dyn_base = ELF_BASE_RELOCATE;
-                ELF_BASE_RELOCATE
+                dyn_base = vm.layout.elf_base;
+                dyn_base
             }
         } else {
             //return Err(OSError::Loader_PhdrNotFound);
@@ -140,14 +149,18 @@ impl<'a> ElfLoader<'a> {
 
             let pgoff = page_offset(ph.virtual_addr() as usize);
             let page_count = page_count(ph.mem_size() as usize + pgoff);
-            let mut pma = PmAreaLazy::new(page_count, None)?;
-            //let data = &self.elf.input[ph.offset() as usize..(ph.offset() + ph.file_size()) as usize];
-            //let d0 = &self.elf.input;
-            let data = match ph.get_data(&self.elf).unwrap() {
-                SegmentData::Undefined(data) => data,
-                _ => return Err(OSError::Loader_InvalidSegment),
-            };
-            pma.write(pgoff, data)?;
+            // 段在文件中的偏移和虚拟地址模页大小同余，所以第一页对应文件中 offset - pgoff 处。
+            // 最后一页中超出 file_size 的部分和 BSS 都在 limit 之外，读出来是 0
+            let file_start = (ph.offset() as usize)
+                .checked_sub(pgoff)
+                .ok_or(OSError::Loader_InvalidSegment)?;
+            let backend = BackEndFile::new_limited(
+                self.file.clone(),
+                file_start,
+                pgoff + ph.file_size() as usize,
+                SyncPolicy::SyncRead,
+            );
+            let pma = PmAreaLazy::new(page_count, Some(backend))?;
             let seg = VmArea::new(
                 ph.virtual_addr() as VirtAddr + dyn_base,
                 (ph.virtual_addr() + ph.mem_size()) as VirtAddr + dyn_base,
@@ -158,99 +171,15 @@ impl<'a> ElfLoader<'a> {
             //info!("{:#?}", seg);
             vm.push(seg)?;
         }
-        // 如果需要重定位，即这是动态执行程序
-        if let Some(rela_header) = self.elf.find_section_by_name(".rela.dyn") {
-            let data = match rela_header.get_data(&self.elf).unwrap() {
-                SectionData::Rela64(data) => data,
-                _ => return Err(OSError::Loader_InvalidSection),
-            };
-
-            // 再检查是否有 .dynsym，如果没有说明应该是静态编译的，那么不处理 .rela.dyn
-            if let Some(dynsym_header) = self.elf.find_section_by_name(".dynsym") {
-                let dynamic_symbols = match dynsym_header.get_data(&self.elf).unwrap() {
-                    SectionData::DynSymbolTable64(dsym) => dsym,
-                    _ => return Err(OSError::Loader_InvalidSection),
-                };
-                for entry in data.iter() {
-                    match entry.get_type() {
-                        REL_GOT | REL_PLT | R_RISCV_64 => {
-                            let dynsym = &dynamic_symbols[entry.get_symbol_table_index() as usize];
-                            let symval = if dynsym.shndx() == 0 {
-                                let name = dynsym.get_name(&self.elf)?;
-                                panic!("symbol not found: {:?}", name);
-                            } else {
-                                dyn_base + dynsym.value() as usize
-                            };
-                            let value = symval + entry.get_addend() as usize;
-                            let addr = dyn_base + entry.get_offset() as usize;
-                            //info!("write: {:#x} @ {:#x} type = {}", value, addr, entry.get_type() as usize);
-                            vm.write(
-                                addr,
-                                core::mem::size_of::<usize>(),
-                                &value.to_ne_bytes(),
-                                PTEFlags::empty(),
-                            )?;
-                            //vmar.write_memory(addr, &value.to_ne_bytes()).map_err(|_| "Invalid Vmar")?;
-                        }
-                        REL_RELATIVE | R_RISCV_RELATIVE => {
-                            let value = dyn_base + entry.get_addend() as usize;
-                            let addr = dyn_base + entry.get_offset() as usize;
-                            //info!("write: {:#x} @ {:#x} type = {}", value, addr, entry.get_type() as usize);
-                            vm.write(
-                                addr,
-                                core::mem::size_of::<usize>(),
-                                &value.to_ne_bytes(),
-                                PTEFlags::empty(),
-                            )?;
-                        }
-                        t => panic!("[kernel] unknown entry, type = {}", t),
-                    }
-                }
-            }
-        }
-
-        if let Some(rela_header) = self.elf.find_section_by_name(".rela.plt") {
-            let data = match rela_header.get_data(&self.elf).unwrap() {
-                SectionData::Rela64(data) => data,
-                _ => return Err(OSError::Loader_InvalidSection),
-            };
-            let dynamic_symbols = match self
-                .elf
-                .find_section_by_name(".dynsym")
-                .ok_or(OSError::Loader_InvalidSection)?
-                .get_data(&self.elf)
-                .unwrap()
-            {
-                SectionData::DynSymbolTable64(dsym) => dsym,
-                _ => return Err(OSError::Loader_InvalidSection),
-            };
-            for entry in data.iter() {
-                match entry.get_type() {
-                    5 => {
-                        let dynsym = &dynamic_symbols[entry.get_symbol_table_index() as usize];
-                        let symval = if dynsym.shndx() == 0 {
-                            let name = dynsym.get_name(&self.elf)?;
-                            panic!("symbol not found: {:?}", name);
-                        } else {
-                            dynsym.value() as usize
-                        };
-                        let value = dyn_base + symval;
-                        let addr = dyn_base + entry.get_offset() as usize;
-                        //info!("write: {:#x} @ {:#x} type = {}", value, addr, entry.get_type() as usize);
-                        vm.write(
-                            addr,
-                            core::mem::size_of::<usize>(),
-                            &value.to_ne_bytes(),
-                            PTEFlags::empty(),
-                        )?;
-                        //vmar.write_memory(addr, &value.to_ne_bytes()).map_err(|_| "Invalid Vmar")?;
-                    }
-                    t => panic!("[kernel] unknown entry, type = {}", t),
-                }
-            }
+        // 如果需要重定位，即这是动态执行程序。
+        // 重定位需要读取节的内容，这时才读取整个文件。需要重定位的一般只有作为解释器的动态库，不会很大
+        if dyn_base != 0 {
+            let data = unsafe { self.file.read_all() };
+            let elf = ElfFile::new(data.as_slice())?;
+            relocate(&elf, vm, dyn_base)?;
         }
         let user_entry = self.elf.header.pt2.entry_point() as usize;
-        let stack_bottom = USER_STACK_OFFSET;
+        let stack_bottom = vm.layout.stack_bottom;
         let mut stack_top = stack_bottom + USER_STACK_SIZE;
         let mut stack_pma = PmAreaLazy::new(page_count(USER_STACK_SIZE), None)?;
 
@@ -304,7 +233,7 @@ impl<'a> ElfLoader<'a> {
             stack_top,
             PTEFlags::READ | PTEFlags::WRITE | PTEFlags::USER,
             Arc::new(Mutex::new(stack_pma)),
-            "user_stack",
+            USER_STACK_AREA_NAME,
         )?;
         vm.push(stack_vma)?;
         // println!("{:#x?}", vm);
@@ -312,6 +241,100 @@ impl<'a> ElfLoader<'a> {
     }
 }
 
+/// 按 .rela.dyn 和 .rela.plt 重定位已经加载到 vm 中的 ELF，dyn_base 为加载的基地址
+fn relocate(elf: &ElfFile, vm: &mut MemorySet, dyn_base: usize) -> OSResult {
+    if let Some(rela_header) = elf.find_section_by_name(".rela.dyn") {
+        let data = match rela_header.get_data(elf).unwrap() {
+            SectionData::Rela64(data) => data,
+            _ => return Err(OSError::Loader_InvalidSection),
+        };
+
+        // 再检查是否有 .dynsym，如果没有说明应该是静态编译的，那么不处理 .rela.dyn
+        if let Some(dynsym_header) = elf.find_section_by_name(".dynsym") {
+            let dynamic_symbols = match dynsym_header.get_data(elf).unwrap() {
+                SectionData::DynSymbolTable64(dsym) => dsym,
+                _ => return Err(OSError::Loader_InvalidSection),
+            };
+            for entry in data.iter() {
+                match entry.get_type() {
+                    REL_GOT | REL_PLT | R_RISCV_64 => {
+                        let dynsym = &dynamic_symbols[entry.get_symbol_table_index() as usize];
+                        let symval = if dynsym.shndx() == 0 {
+                            let name = dynsym.get_name(elf)?;
+                            panic!("symbol not found: {:?}", name);
+                        } else {
+                            dyn_base + dynsym.value() as usize
+                        };
+                        let value = symval + entry.get_addend() as usize;
+                        let addr = dyn_base + entry.get_offset() as usize;
+                        //info!("write: {:#x} @ {:#x} type = {}", value, addr, entry.get_type() as usize);
+                        vm.write(
+                            addr,
+                            core::mem::size_of::<usize>(),
+                            &value.to_ne_bytes(),
+                            PTEFlags::empty(),
+                        )?;
+                        //vmar.write_memory(addr, &value.to_ne_bytes()).map_err(|_| "Invalid Vmar")?;
+                    }
+                    REL_RELATIVE | R_RISCV_RELATIVE => {
+                        let value = dyn_base + entry.get_addend() as usize;
+                        let addr = dyn_base + entry.get_offset() as usize;
+                        //info!("write: {:#x} @ {:#x} type = {}", value, addr, entry.get_type() as usize);
+                        vm.write(
+                            addr,
+                            core::mem::size_of::<usize>(),
+                            &value.to_ne_bytes(),
+                            PTEFlags::empty(),
+                        )?;
+                    }
+                    t => panic!("[kernel] unknown entry, type = {}", t),
+                }
+            }
+        }
+    }
+
+    if let Some(rela_header) = elf.find_section_by_name(".rela.plt") {
+        let data = match rela_header.get_data(elf).unwrap() {
+            SectionData::Rela64(data) => data,
+            _ => return Err(OSError::Loader_InvalidSection),
+        };
+        let dynamic_symbols = match elf
+            .find_section_by_name(".dynsym")
+            .ok_or(OSError::Loader_InvalidSection)?
+            .get_data(elf)
+            .unwrap()
+        {
+            SectionData::DynSymbolTable64(dsym) => dsym,
+            _ => return Err(OSError::Loader_InvalidSection),
+        };
+        for entry in data.iter() {
+            match entry.get_type() {
+                5 => {
+                    let dynsym = &dynamic_symbols[entry.get_symbol_table_index() as usize];
+                    let symval = if dynsym.shndx() == 0 {
+                        let name = dynsym.get_name(elf)?;
+                        panic!("symbol not found: {:?}", name);
+                    } else {
+                        dynsym.value() as usize
+                    };
+                    let value = dyn_base + symval;
+                    let addr = dyn_base + entry.get_offset() as usize;
+                    //info!("write: {:#x} @ {:#x} type = {}", value, addr, entry.get_type() as usize);
+                    vm.write(
+                        addr,
+                        core::mem::size_of::<usize>(),
+                        &value.to_ne_bytes(),
+                        PTEFlags::empty(),
+                    )?;
+                    //vmar.write_memory(addr, &value.to_ne_bytes()).map_err(|_| "Invalid Vmar")?;
+                }
+                t => panic!("[kernel] unknown entry, type = {}", t),
+            }
+        }
+    }
+    Ok(())
+}
+
 impl From<Flags> for PTEFlags {
     fn from(f: Flags) -> Self {
         let mut ret = PTEFlags::USER;
@@ -358,16 +381,33 @@ pub fn parse_user_app(
     } else {
         (app_dir, app_name, args)
     };
-    open_file(app_dir, app_name, OpenFlags::RDONLY)
-        .map(|node| unsafe { node.read_all() })
-        .map(|data| {
-            /*
-            for i in 0..20 {
-                print!("{} ", data[i]);
-            }
-            */
-            let mut loader = ElfLoader::new(data.as_slice())?;
-            loader.init_vm(&mut vm, args)
-        })
-        .unwrap_or(Err(OSError::Loader_AppNotFound))
+    let file =
+        open_file(app_dir, app_name, OpenFlags::RDONLY).ok_or(OSError::Loader_AppNotFound)?;
+    let header = read_elf_header(&file)?;
+    let mut loader = ElfLoader::new(header.as_slice(), file)?;
+    loader.init_vm(&mut vm, args)
+}
+
+/// 读取 ELF 文件开头的 ELF 头和程序头表
+fn read_elf_header(file: &Arc<dyn File>) -> OSResult<Vec<u8>> {
+    let mut data = vec![0u8; PAGE_SIZE];
+    let len = file
+        .read_from_offset(0, &mut data)
+        .ok_or(OSError::Loader_ParseElfFailed)?;
+    data.truncate(len);
+    let ph_end = {
+        let pt2 = ElfFile::new(data.as_slice())?.header.pt2;
+        pt2.ph_offset() as usize + pt2.ph_count() as usize * pt2.ph_entry_size() as usize
+    };
+    // 程序头表一般紧跟在 ELF 头之后，很少会超出第一页
+    if ph_end > data.len() {
+        data.resize(ph_end, 0);
+        let len = file
+            .read_from_offset(0, &mut data)
+            .ok_or(OSError::Loader_ParseElfFailed)?;
+        if len < ph_end {
+            return Err(OSError::Loader_ParseElfFailed);
+        }
+    }
+    Ok(data)
 }
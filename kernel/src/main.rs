@@ -16,13 +16,16 @@ extern crate log;
 
 #[macro_use]
 pub mod console;
+pub mod cmdline;
 pub mod constants;
 pub mod drivers;
 pub mod error;
+pub mod fdt;
 pub mod file;
 pub mod lang;
 pub mod loaders;
 pub mod memory;
+pub mod random;
 pub mod signal;
 pub mod syscall;
 pub mod task;
@@ -113,15 +116,32 @@ impl task_trampoline::TaskTrampoline for TaskTrampoline {
         let mut time = task.time.lock();
         time.set_raw_timer(timer_interval_us, timer_remained_us, timer_type)
     }
+
+    fn sleep_until(&self, expire_us: usize) -> bool {
+        task::sleep_current_task_until(expire_us)
+    }
+
+    fn wait_for_file_event(
+        &self,
+        expire_us: Option<usize>,
+        ready: &mut dyn FnMut() -> bool,
+    ) -> bool {
+        file::wait_for_file_event(expire_us, || ready().then_some(())).is_some()
+    }
 }
 
 #[no_mangle]
 /// 主核启动OS
-pub extern "C" fn start_kernel(_arg0: usize, _arg1: usize) -> ! {
+pub extern "C" fn start_kernel(_arg0: usize, dtb_paddr: usize) -> ! {
     arch::clear_bss(); // 清空 bss 段
-    console::init_logger(crate::constants::LOG_LEVEL).unwrap();
+    console::init_logger(crate::constants::DEFAULT_LOG_LEVEL).unwrap();
     task_trampoline::init_task_trampoline(&TaskTrampoline);
-    memory::allocator_init(); // 初始化堆分配器和页帧分配器
+    memory::heap_init(); // 初始化堆分配器
+    fdt::init(dtb_paddr); // 解析 OpenSBI 传入的设备树，获取物理内存、可用的核、时钟频率和设备地址
+    cmdline::init(&fdt::bootargs()); // 解析设备树中的启动参数
+    memory::allocator_init(); // 初始化页帧分配器
+    memory::init_paging_mode(); // 选择 Sv39 或 Sv48 分页模式
+    random::init(); // 初始化熵源，之后地址随机化会用到
     memory::enable_kernel_page_table(); // 构造并切换到内核态页表与 MemorySet
     trap::init(); // 设置异常/中断的入口，即 stvec
     arch::allow_sum_access(); // 内核可以读写 USER 页表项中的数据
@@ -134,11 +154,12 @@ pub extern "C" fn start_kernel(_arg0: usize, _arg1: usize) -> ! {
     file::fs_init(); // 初始化一些不是实际文件本身但是 OS 约定需要的"文件"
     let cpu_id = arch::get_cpu_id();
     info!("CPU [{cpu_id}] bootstrap");
+    let hart_mask = fdt::hart_mask();
     for other_cpu in constants::FIRST_CPU_ID..=constants::LAST_CPU_ID {
-        if other_cpu != cpu_id {
-            let _entry = arch::secondary_entry as usize;
+        if other_cpu != cpu_id && hart_mask & (1 << other_cpu) != 0 {
+            let entry = arch::secondary_entry as usize;
             // println!("other_cpu {}", other_cpu);
-            // arch::start_hart(other_cpu, memory::virt_to_phys(_entry), 0);
+            arch::start_hart(other_cpu, memory::virt_to_phys(entry), 0);
         }
     }
 
@@ -167,7 +188,7 @@ pub extern "C" fn start_kernel_secondary(_arg0: usize, _arg1: usize) -> ! {
     info!("I'm CPU [{cpu_id}]");
 
     // 全局初始化结束
-    if constants::SPIN_LOOP_AFTER_BOOT || constants::IS_SINGLE_CORE {
+    if constants::SPIN_LOOP_AFTER_BOOT || cmdline::is_single_core() {
         loop {}
     } else {
         task::run_tasks();
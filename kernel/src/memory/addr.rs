@@ -53,11 +53,10 @@ pub fn page_offset(addr: usize) -> usize {
     addr & (PAGE_SIZE - 1)
 }
 
-/// 虚拟地址所对应的Sv39的三级页表项，即第 \[38:30\],\[29:21\],\[20:12\] 位
-pub fn pte_idx_of_virt_addr(vaddr: VirtAddr) -> (usize, usize, usize) {
-    (
-        (vaddr >> 30) & 0x1ff,
-        (vaddr >> 21) & 0x1ff,
-        (vaddr >> 12) & 0x1ff,
-    )
+/// 虚拟地址在第 level 级页表中对应的页表项下标。
+///
+/// 最后一级页表为第 0 级，对应第 \[20:12\] 位；往上依次是 \[29:21\],\[38:30\],\[47:39\] 位。
+/// Sv39 的根页表是第 2 级，Sv48 的根页表是第 3 级
+pub fn pte_idx_of_virt_addr(vaddr: VirtAddr, level: usize) -> usize {
+    (vaddr >> (12 + 9 * level)) & 0x1ff
 }
@@ -2,9 +2,12 @@
 
 extern crate maturin_page_frame;
 
-
-use crate::memory::get_phys_memory_regions;
+use crate::arch::get_cpu_id;
 use crate::constants::{PAGE_SIZE, PHYS_MEMORY_OFFSET};
+use crate::memory::get_phys_memory_regions;
+use maturin_page_frame::PageFrameConfig as _;
+
+pub use maturin_page_frame::FrameStat;
 
 pub struct PageFrameConfig;
 impl maturin_page_frame::PageFrameConfig for PageFrameConfig {
@@ -17,10 +20,30 @@ impl maturin_page_frame::PageFrameConfig for PageFrameConfig {
     fn frame_idx_to_phys_addr(idx: usize) -> usize {
         idx * PAGE_SIZE + PHYS_MEMORY_OFFSET
     }
+
+    /// 每个核使用自己的页帧缓存
+    fn cpu_id() -> usize {
+        get_cpu_id()
+    }
+
+    fn use_cpu_cache() -> bool {
+        true
+    }
 }
 
 pub type Frame = maturin_page_frame::Frame<PageFrameConfig>;
 
 pub fn init() {
-    unsafe { Frame::init(get_phys_memory_regions()) };
-}
\ No newline at end of file
+    let ignored = unsafe { Frame::init(get_phys_memory_regions()) };
+    if ignored > 0 {
+        warn!(
+            "{:#x} bytes of physical memory exceed the frame allocator and are ignored",
+            ignored * PAGE_SIZE
+        );
+    }
+}
+
+/// 页帧分配器的使用情况
+pub fn frame_stat() -> FrameStat {
+    PageFrameConfig::stat()
+}
@@ -1,15 +1,62 @@
 //! 堆分配器
 //!
-//! 使用 buddy_system_allocator::LockedHeap ，堆大小在 constants 中定义
+//! 使用 buddy_system_allocator::Heap 。启动时堆中只有一段大小为 `KERNEL_HEAP_SIZE` 的静态数组，
+//! 之后堆中内存不够时，会从页帧分配器中申请一段连续的页帧加入堆中，所以堆可以一直增长到物理内存用完。
+//! 加入堆中的页帧不会再还给页帧分配器。
+//!
+//! 大小和对齐与某个 slab 缓存相同的请求会交给 slab 缓存处理，见 `slab.rs`。
+//!
+//! 页帧分配器中的内存也用完时，堆会先用掉预留的一段页帧，同时请求 OOM killer 杀掉一个进程。
+//! 预留的页帧之后在调度循环中补上
 
 //#![deny(missing_docs)]
 
-use buddy_system_allocator::LockedHeap;
+use super::{frame::Frame, slab::cache_for};
+use crate::constants::{KERNEL_HEAP_GROW_SIZE, KERNEL_HEAP_SIZE, PAGE_SIZE};
+use crate::task::request_oom_kill;
+use buddy_system_allocator::Heap;
+use core::{
+    alloc::{GlobalAlloc, Layout},
+    mem::forget,
+    ops::Range,
+    ptr::{null_mut, NonNull},
+    sync::atomic::{AtomicUsize, Ordering},
+};
+use lock::Mutex;
 
-use crate::constants::KERNEL_HEAP_SIZE;
+/// 内核的全局分配器
+struct KernelHeap {
+    heap: Mutex<Heap>,
+    /// 从页帧分配器中拿到的内存总大小
+    grown: AtomicUsize,
+}
 
 #[global_allocator]
-static HEAP_ALLOCATOR: LockedHeap = LockedHeap::new();
+static HEAP_ALLOCATOR: KernelHeap = KernelHeap {
+    heap: Mutex::new(Heap::new()),
+    grown: AtomicUsize::new(0),
+};
+
+/// 留给堆在内存耗尽时使用的页帧。
+///
+/// 被 OOM killer 杀掉的进程退出之前，内核自身的分配(包括 OOM killer 本身)仍然可以从这里得到内存
+static HEAP_RESERVE: Mutex<Option<Frame>> = Mutex::new(None);
+
+unsafe impl GlobalAlloc for KernelHeap {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        match cache_for(layout) {
+            Some(cache) => cache.alloc(),
+            None => heap_alloc(layout),
+        }
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        match cache_for(layout) {
+            Some(cache) => cache.dealloc(ptr),
+            None => heap_dealloc(ptr, layout),
+        }
+    }
+}
 
 /// Initialize the global heap alloactor.
 pub fn init() {
@@ -18,7 +65,126 @@ pub fn init() {
     static mut HEAP: [usize; HEAP_BLOCK] = [0; HEAP_BLOCK];
     unsafe {
         HEAP_ALLOCATOR
+            .heap
             .lock()
             .init(HEAP.as_ptr() as usize, HEAP_BLOCK * MACHINE_ALIGN);
     };
 }
+
+/// 直接从堆中分配，不经过 slab 缓存。内存不够时尝试从页帧分配器中拿一段内存加入堆中
+pub(super) fn heap_alloc(layout: Layout) -> *mut u8 {
+    loop {
+        let mut heap = HEAP_ALLOCATOR.heap.lock();
+        if let Ok(ptr) = heap.alloc(layout) {
+            return ptr.as_ptr();
+        }
+        // 输出信息时可能也要分配内存，所以要先释放堆的锁
+        match grow(&mut heap, layout) {
+            Some(range) => {
+                drop(heap);
+                info!(
+                    "kernel heap grows by {:#x} bytes at {:#x?}",
+                    range.len(),
+                    range
+                );
+            }
+            None => {
+                // 输出信息和 OOM killer 都可能再分配内存，所以不能持有预留页帧的锁
+                let reserve = HEAP_RESERVE.lock().take();
+                if let Some(frame) = reserve {
+                    let range = add_to_heap(&mut heap, frame);
+                    drop(heap);
+                    request_oom_kill();
+                    warn!(
+                        "kernel heap uses the reserved {:#x} bytes at {:#x?}",
+                        range.len(),
+                        range
+                    );
+                    continue;
+                }
+                let stat = stat(&heap);
+                drop(heap);
+                error!(
+                    "kernel heap exhausted when allocating {:?}: {:#x?}",
+                    layout, stat
+                );
+                return null_mut();
+            }
+        }
+    }
+}
+
+/// 直接还给堆，不经过 slab 缓存
+///
+/// # Safety
+///
+/// ptr 必须是 heap_alloc 以同样的 layout 返回的
+pub(super) unsafe fn heap_dealloc(ptr: *mut u8, layout: Layout) {
+    HEAP_ALLOCATOR
+        .heap
+        .lock()
+        .dealloc(NonNull::new_unchecked(ptr), layout);
+}
+
+/// 从页帧分配器中申请一段内存加入堆中，使堆能满足 layout 的请求。返回加入的内存范围
+///
+/// 一般每次申请 `KERNEL_HEAP_GROW_SIZE`，如果请求更大，就申请足够大的一段。
+/// 页帧分配器中找不到这么大的连续内存时，会退而申请恰好够用的大小
+fn grow(heap: &mut Heap, layout: Layout) -> Option<Range<usize>> {
+    // 伙伴分配器中的块按自身大小对齐，所以申请到的内存也按大小对齐，才能保证切出完整的块
+    let need = layout.size().max(layout.align()).next_power_of_two();
+    let min_pages = (need + PAGE_SIZE - 1) / PAGE_SIZE;
+    let pages = min_pages.max(KERNEL_HEAP_GROW_SIZE / PAGE_SIZE);
+    let frame = Frame::new_contiguous(pages, pages.trailing_zeros() as usize)
+        .or_else(|| Frame::new_contiguous(min_pages, min_pages.trailing_zeros() as usize))?;
+    Some(add_to_heap(heap, frame))
+}
+
+/// 把一段页帧加入堆中，返回加入的内存范围。这段内存之后归堆所有，不再还给页帧分配器
+fn add_to_heap(heap: &mut Heap, frame: Frame) -> Range<usize> {
+    let start = frame.as_mut_ptr() as usize;
+    let end = start + frame.size();
+    forget(frame);
+    unsafe { heap.add_to_heap(start, end) };
+    HEAP_ALLOCATOR
+        .grown
+        .fetch_add(end - start, Ordering::Relaxed);
+    start..end
+}
+
+/// 如果预留给堆的页帧已经被用掉了，则尝试重新申请一段。
+/// 在页帧分配器初始化后调用一次，之后由调度循环调用
+pub fn refill_heap_reserve() {
+    let mut reserve = HEAP_RESERVE.lock();
+    if reserve.is_none() {
+        let pages = KERNEL_HEAP_GROW_SIZE / PAGE_SIZE;
+        *reserve = Frame::new_contiguous(pages, pages.trailing_zeros() as usize);
+    }
+}
+
+/// 堆的使用情况
+#[derive(Debug, Clone, Copy)]
+pub struct HeapStat {
+    /// 堆的总大小，包括启动时的静态数组和之后从页帧分配器中拿到的内存
+    pub total: usize,
+    /// 从页帧分配器中拿到的内存大小
+    pub grown: usize,
+    /// 已分配的大小，即所有请求的大小之和
+    pub user: usize,
+    /// 实际分配出去的大小，包括伙伴分配器向上取整浪费的部分
+    pub actual: usize,
+}
+
+fn stat(heap: &Heap) -> HeapStat {
+    HeapStat {
+        total: heap.stats_total_bytes(),
+        grown: HEAP_ALLOCATOR.grown.load(Ordering::Relaxed),
+        user: heap.stats_alloc_user(),
+        actual: heap.stats_alloc_actual(),
+    }
+}
+
+/// 堆的使用情况。slab 缓存中的 slab 也算在已分配的内存中
+pub fn heap_stat() -> HeapStat {
+    stat(&HEAP_ALLOCATOR.heap.lock())
+}
@@ -1,6 +1,7 @@
 //! 各种分配器
 //!
-//! - 使用 buddy_system_allocator::LockedHeap 作为堆分配器；
+//! - 使用 buddy_system_allocator::Heap 作为堆分配器，内存不够时从页帧分配器中申请页帧来增长；
+//! - 使用 slab 缓存分配任务控制块、管道、页表页等频繁创建和释放的对象；
 //! - 使用 bitmap_allocator 作为其他编号的分配器，这个类型里的实现是用 bitset 做 radix tree
 
 //#![deny(missing_docs)]
@@ -8,19 +9,31 @@
 mod fd;
 mod frame;
 mod heap;
+mod slab;
 mod tid;
 
 pub use fd::FdAllocator;
-pub use frame::Frame;
+pub use frame::{frame_stat, Frame, FrameStat};
+pub use heap::{heap_stat, refill_heap_reserve, HeapStat};
+pub use slab::{slab_stats, SlabStat};
 pub use tid::Tid;
 
-/// 初始化堆分配器、页帧分配器和 TID 分配器。需由其中一个核调用且仅调用一次
-pub fn allocator_init() {
+/// 初始化堆分配器。需由其中一个核调用且仅调用一次
+///
+/// 解析设备树的结果保存在堆上，所以它需要在解析设备树之前调用
+pub fn heap_init() {
     // println 中调用的 STDOUT 有 Mutex 锁，需要在堆上分配
     // 所以在 heap::init() 前请不要输出任何语句
     heap::init();
     info!("heap allocator inited.");
+}
+
+/// 初始化页帧分配器和 TID 分配器。需由其中一个核调用且仅调用一次
+///
+/// 页帧分配器管理的物理内存来自设备树，所以它需要在解析设备树之后调用
+pub fn allocator_init() {
     frame::init();
+    heap::refill_heap_reserve();
     info!("frame allocator inited.");
     tid::init();
     info!("tid allocator inited.");
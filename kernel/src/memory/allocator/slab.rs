@@ -0,0 +1,294 @@
+//! slab 缓存
+//!
+//! 内核中有一些对象会被频繁地创建和释放，如任务控制块、管道、每个 `VmArea` 对应的 `PmAreaLazy` 和页表页。
+//! 每种对象有一个 `SlabCache`，它从堆中申请一块按自身大小对齐的内存作为 slab，切成等大的对象，
+//! 空闲的对象用链表串起来。释放对象时把地址向下对齐到 slab 大小，就能找到它所在 slab 的头部。
+//!
+//! 全局分配器收到的请求如果大小和对齐恰好与某个缓存的对象相同，就交给这个缓存处理，
+//! 所以使用这些类型的代码不需要改动，`Arc::new` 时就会从对应的缓存中分配。
+//!
+//! 每个缓存最多保留一个空的 slab，再有空的 slab 就还给堆
+
+use super::heap::{heap_alloc, heap_dealloc};
+use crate::{constants::PAGE_SIZE, file::Pipe, memory::PmAreaLazy, task::TaskControlBlock};
+use alloc::vec::Vec;
+use core::{alloc::Layout, mem::size_of, ptr::null_mut};
+use lock::Mutex;
+
+/// 每个 slab 中至少能放下的对象数
+const MIN_OBJECTS_PER_SLAB: usize = 8;
+
+/// 和标准库中 `Arc<T>` 的 `ArcInner<T>` 布局相同的结构，用于计算 `Arc::new` 时申请的内存布局
+#[repr(C)]
+struct ArcInner<T> {
+    strong: usize,
+    weak: usize,
+    data: T,
+}
+
+/// `Arc<T>` 中存放 T 的那块内存的布局
+const fn arc_layout<T>() -> Layout {
+    Layout::new::<ArcInner<T>>()
+}
+
+/// 任务控制块，每个线程一个
+pub static TASK_CONTROL_BLOCK_CACHE: SlabCache =
+    SlabCache::new("task_control_block", arc_layout::<TaskControlBlock>());
+/// 管道的一端，每次 sys_pipe 创建两个
+pub static PIPE_CACHE: SlabCache = SlabCache::new("pipe", arc_layout::<Pipe>());
+/// 每个 VmArea 对应的 PmAreaLazy。VmArea 本身直接存在地址空间的区间树中，不单独分配
+pub static VM_AREA_CACHE: SlabCache = SlabCache::new("vm_area", arc_layout::<Mutex<PmAreaLazy>>());
+/// 页表页，见 `PageTablePage`
+pub static PAGE_TABLE_CACHE: SlabCache = SlabCache::new("page_table", unsafe {
+    Layout::from_size_align_unchecked(PAGE_SIZE, PAGE_SIZE)
+});
+
+/// 所有的缓存。全局分配器按顺序查找第一个布局相同的缓存
+static CACHES: [&SlabCache; 4] = [
+    &TASK_CONTROL_BLOCK_CACHE,
+    &PIPE_CACHE,
+    &VM_AREA_CACHE,
+    &PAGE_TABLE_CACHE,
+];
+
+/// 找到负责这个内存布局的缓存
+pub fn cache_for(layout: Layout) -> Option<&'static SlabCache> {
+    CACHES.iter().find(|cache| cache.layout == layout).copied()
+}
+
+/// 一个缓存的使用情况
+#[derive(Debug, Clone, Copy)]
+pub struct SlabStat {
+    /// 缓存名
+    pub name: &'static str,
+    /// 每个对象的大小
+    pub object_size: usize,
+    /// 正在使用的对象数
+    pub active_objects: usize,
+    /// 所有 slab 中的对象总数，包括空闲的
+    pub total_objects: usize,
+    /// slab 的个数
+    pub slabs: usize,
+    /// 每个 slab 的大小
+    pub slab_size: usize,
+    /// 从启动到现在总共分配过多少次
+    pub allocs: usize,
+}
+
+/// 所有缓存的使用情况
+pub fn slab_stats() -> Vec<SlabStat> {
+    CACHES.iter().map(|cache| cache.stat()).collect()
+}
+
+/// 空闲对象的开头存着下一个空闲对象的地址
+struct FreeObject {
+    next: *mut FreeObject,
+}
+
+/// 每个 slab 开头的结构
+struct SlabHeader {
+    /// slab 中的空闲对象
+    free: *mut FreeObject,
+    /// slab 中正在使用的对象数
+    in_use: usize,
+    /// 在缓存的 partial 链表中的前一个 slab
+    prev: *mut SlabHeader,
+    /// 在缓存的 partial 链表中的后一个 slab
+    next: *mut SlabHeader,
+}
+
+/// 缓存中需要加锁的部分
+struct CacheInner {
+    /// 还有空闲对象的 slab 组成的双向链表，包括空的 slab。满的 slab 不在链表中，释放对象时才会加回来
+    partial: *mut SlabHeader,
+    /// 空的 slab 数，最多为 1
+    empty_slabs: usize,
+    /// slab 总数
+    slabs: usize,
+    /// 正在使用的对象数
+    active_objects: usize,
+    /// 总共分配过多少次
+    allocs: usize,
+}
+
+// 里面的指针都指向由缓存自己管理的 slab，只在持有锁时访问
+unsafe impl Send for CacheInner {}
+
+impl CacheInner {
+    /// 把 slab 插入 partial 链表头部
+    unsafe fn push(&mut self, slab: *mut SlabHeader) {
+        (*slab).prev = null_mut();
+        (*slab).next = self.partial;
+        if !self.partial.is_null() {
+            (*self.partial).prev = slab;
+        }
+        self.partial = slab;
+    }
+
+    /// 把 slab 从 partial 链表中删除
+    unsafe fn remove(&mut self, slab: *mut SlabHeader) {
+        if (*slab).prev.is_null() {
+            self.partial = (*slab).next;
+        } else {
+            (*(*slab).prev).next = (*slab).next;
+        }
+        if !(*slab).next.is_null() {
+            (*(*slab).next).prev = (*slab).prev;
+        }
+        (*slab).prev = null_mut();
+        (*slab).next = null_mut();
+    }
+}
+
+/// 一种对象的 slab 缓存
+pub struct SlabCache {
+    /// 缓存名，只用于输出统计信息
+    name: &'static str,
+    /// 由这个缓存负责的内存布局
+    layout: Layout,
+    /// 相邻两个对象的间隔
+    stride: usize,
+    /// 第一个对象在 slab 中的偏移，它之前是 SlabHeader
+    first_offset: usize,
+    /// 每个 slab 的大小，是 2 的幂，slab 也按这个大小对齐
+    slab_size: usize,
+    /// 每个 slab 中的对象数
+    objects_per_slab: usize,
+    inner: Mutex<CacheInner>,
+}
+
+impl SlabCache {
+    /// 创建一个空的缓存，此时还没有申请任何内存
+    pub const fn new(name: &'static str, layout: Layout) -> Self {
+        let align = if layout.align() > size_of::<usize>() {
+            layout.align()
+        } else {
+            size_of::<usize>()
+        };
+        // 空闲对象里要能放下一个指针
+        let size = if layout.size() > size_of::<FreeObject>() {
+            layout.size()
+        } else {
+            size_of::<FreeObject>()
+        };
+        let stride = (size + align - 1) / align * align;
+        let first_offset = (size_of::<SlabHeader>() + align - 1) / align * align;
+        let mut slab_size = (first_offset + stride * MIN_OBJECTS_PER_SLAB).next_power_of_two();
+        if slab_size < PAGE_SIZE {
+            slab_size = PAGE_SIZE;
+        }
+        Self {
+            name,
+            layout,
+            stride,
+            first_offset,
+            slab_size,
+            objects_per_slab: (slab_size - first_offset) / stride,
+            inner: Mutex::new(CacheInner {
+                partial: null_mut(),
+                empty_slabs: 0,
+                slabs: 0,
+                active_objects: 0,
+                allocs: 0,
+            }),
+        }
+    }
+
+    /// slab 本身的内存布局
+    fn slab_layout(&self) -> Layout {
+        unsafe { Layout::from_size_align_unchecked(self.slab_size, self.slab_size) }
+    }
+
+    /// 分配一个对象。堆中没有内存时返回空指针
+    pub fn alloc(&self) -> *mut u8 {
+        let mut inner = self.inner.lock();
+        unsafe {
+            if inner.partial.is_null() {
+                let slab = self.new_slab();
+                if slab.is_null() {
+                    return null_mut();
+                }
+                inner.push(slab);
+                inner.slabs += 1;
+                inner.empty_slabs += 1;
+            }
+            let slab = inner.partial;
+            let object = (*slab).free;
+            (*slab).free = (*object).next;
+            if (*slab).in_use == 0 {
+                inner.empty_slabs -= 1;
+            }
+            (*slab).in_use += 1;
+            if (*slab).free.is_null() {
+                inner.remove(slab);
+            }
+            inner.active_objects += 1;
+            inner.allocs += 1;
+            object as *mut u8
+        }
+    }
+
+    /// 释放一个对象
+    ///
+    /// # Safety
+    ///
+    /// ptr 必须是这个缓存的 alloc 返回的，且还没有被释放过
+    pub unsafe fn dealloc(&self, ptr: *mut u8) {
+        let mut inner = self.inner.lock();
+        let slab = (ptr as usize & !(self.slab_size - 1)) as *mut SlabHeader;
+        let object = ptr as *mut FreeObject;
+        let was_full = (*slab).free.is_null();
+        (*object).next = (*slab).free;
+        (*slab).free = object;
+        (*slab).in_use -= 1;
+        inner.active_objects -= 1;
+        if was_full {
+            inner.push(slab);
+        }
+        if (*slab).in_use == 0 {
+            if inner.empty_slabs > 0 {
+                inner.remove(slab);
+                inner.slabs -= 1;
+                heap_dealloc(slab as *mut u8, self.slab_layout());
+            } else {
+                inner.empty_slabs += 1;
+            }
+        }
+    }
+
+    /// 从堆中申请一个新的 slab，并把其中的对象都串到空闲链表中
+    unsafe fn new_slab(&self) -> *mut SlabHeader {
+        let start = heap_alloc(self.slab_layout());
+        if start.is_null() {
+            return null_mut();
+        }
+        let slab = start as *mut SlabHeader;
+        let mut free = null_mut();
+        for idx in (0..self.objects_per_slab).rev() {
+            let object = start.add(self.first_offset + idx * self.stride) as *mut FreeObject;
+            (*object).next = free;
+            free = object;
+        }
+        slab.write(SlabHeader {
+            free,
+            in_use: 0,
+            prev: null_mut(),
+            next: null_mut(),
+        });
+        slab
+    }
+
+    /// 缓存的使用情况
+    pub fn stat(&self) -> SlabStat {
+        let inner = self.inner.lock();
+        SlabStat {
+            name: self.name,
+            object_size: self.layout.size(),
+            active_objects: inner.active_objects,
+            total_objects: inner.slabs * self.objects_per_slab,
+            slabs: inner.slabs,
+            slab_size: self.slab_size,
+            allocs: inner.allocs,
+        }
+    }
+}
@@ -33,18 +33,53 @@ impl PmArea for PmAreaFixed {
         })))
     }
 
+    fn clone_as_cow(&self) -> OSResult<Arc<Mutex<dyn PmArea>>> {
+        // 直接映射的物理地址本来就是共享的，不需要写时复制
+        self.clone_as_fork()
+    }
+
+    fn is_shared_frame(&self, _idx: usize) -> bool {
+        false
+    }
+
+    fn unshare_frame(&mut self, idx: usize) -> OSResult<Option<PhysAddr>> {
+        self.get_frame(idx, false)
+    }
+
     fn get_frame(&mut self, idx: usize, _need_alloc: bool) -> OSResult<Option<PhysAddr>> {
         let paddr = self.start + idx * PAGE_SIZE;
         debug_assert!(paddr < self.end);
         Ok(Some(paddr))
     }
 
+    fn get_huge_frame(
+        &mut self,
+        idx: usize,
+        count: usize,
+        _need_alloc: bool,
+    ) -> OSResult<Option<PhysAddr>> {
+        let paddr = self.start + idx * PAGE_SIZE;
+        if paddr % (count * PAGE_SIZE) == 0 && paddr + count * PAGE_SIZE <= self.end {
+            Ok(Some(paddr))
+        } else {
+            Ok(None)
+        }
+    }
+
     fn sync_frame_with_file(&mut self, _idx: usize) {}
 
     fn release_frame(&mut self, _idx: usize) -> OSResult {
         Ok(())
     }
 
+    fn swap_out_frame(&mut self, _idx: usize) -> bool {
+        false
+    }
+
+    fn swap_in_area(&mut self, _area: usize) -> OSResult {
+        Ok(())
+    }
+
     fn read(&mut self, offset: usize, dst: &mut [u8]) -> OSResult<usize> {
         if offset >= self.size() {
             error!(
@@ -91,6 +126,16 @@ impl PmArea for PmAreaFixed {
         }
     }
 
+    fn grow_right(&mut self, _new_end: usize) -> OSResult {
+        // 后面的物理地址不一定属于这一段，不能扩展
+        Err(OSError::PmArea_GrowFailed)
+    }
+
+    fn grow_left(&mut self, _size: usize) -> OSResult {
+        // 同理，前面的物理地址也不能扩展
+        Err(OSError::PmArea_GrowFailed)
+    }
+
     fn split(&mut self, left_end: usize, right_start: usize) -> OSResult<Arc<Mutex<dyn PmArea>>> {
         if left_end <= right_start && right_start < self.end - self.start {
             let old_end = self.end;
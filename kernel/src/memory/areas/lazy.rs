@@ -2,23 +2,32 @@
 
 //#![deny(missing_docs)]
 
-use alloc::{sync::Arc, vec::Vec};
+use alloc::{collections::BTreeMap, sync::Arc, vec::Vec};
 use base_file::File;
 use core::fmt::{Debug, Formatter, Result};
+use core::slice;
 
 use lock::Mutex;
 
-use super::{PmArea, VmArea};
+use super::{PmArea, SharedLocation, VmArea};
 use crate::error::{OSError, OSResult};
 use crate::file::BackEndFile;
 use crate::memory::{
     addr::{self, addr_to_page_id, align_down},
-    Frame, PTEFlags, PhysAddr, VirtAddr, PAGE_SIZE, USER_VIRT_ADDR_LIMIT,
+    user_virt_addr_limit, Frame, PTEFlags, PageSize, PhysAddr, SwapSlot, VirtAddr, PAGE_SIZE,
 };
 
 /// lazy 分配的物理地址段。当 page fault 发生时会由 VmArea 负责调用这段 PmAreaLazy 进行实际分配
+///
+/// 页帧用 Arc 保存，fork 时父子进程的地址段可以共享同一个页帧(写时复制)。
+/// 引用计数大于 1 的页帧是共享的，写入前需要先通过 `unshare_frame` 复制一份。
+/// 文件的共享映射例外，它的页帧来自页缓存，写入时直接修改共享的页帧。
+/// 其中还不是脏页的页缓存页帧仍然以只读方式映射，第一次写入时由 `unshare_frame` 把它标记为脏页
+///
+/// 被换出到交换区的页在 frames 中为 None，它在交换区中的位置记录在 swapped 里
 pub struct PmAreaLazy {
-    frames: Vec<Option<Frame>>,
+    frames: Vec<Option<Arc<Frame>>>,
+    swapped: BTreeMap<usize, Arc<SwapSlot>>,
     backend: Option<BackEndFile>,
 }
 
@@ -35,9 +44,66 @@ impl PmArea for PmAreaLazy {
         )?)))
     }
 
+    fn clone_as_cow(&self) -> OSResult<Arc<Mutex<dyn PmArea>>> {
+        let new_backend = self.backend.as_ref().map(|b| b.clone_as_fork());
+        // 只复制 Arc，两边共享已分配的页帧，以及已经换出的交换页
+        let mut new_area = Self::new_from_frames(self.frames.clone(), new_backend);
+        new_area.swapped = self.swapped.clone();
+        Ok(Arc::new(Mutex::new(new_area)))
+    }
+
+    fn is_shared_frame(&self, idx: usize) -> bool {
+        let frame = match &self.frames[idx] {
+            Some(frame) => frame,
+            None => return false,
+        };
+        match &self.backend {
+            // 共享映射的页帧本来就应该和其他地址段、页缓存共享，写入时不复制。
+            // 但还不是脏页的页缓存页帧要映射为只读，这样第一次写入时才会触发 page fault 把它标记为脏页
+            Some(backend) if backend.is_shared() => {
+                backend.is_cached_dirty(idx * PAGE_SIZE, frame) == Some(false)
+            }
+            _ => Arc::strong_count(frame) > 1,
+        }
+    }
+
+    fn unshare_frame(&mut self, idx: usize) -> OSResult<Option<PhysAddr>> {
+        if let Some(frame) = &mut self.frames[idx] {
+            match &self.backend {
+                // 共享映射不复制页帧，只是把页缓存中的页标记为脏页
+                Some(backend) if backend.is_shared() => backend.mark_dirty(idx * PAGE_SIZE, frame),
+                _ if Arc::strong_count(frame) > 1 => {
+                    let mut new_frame = Frame::new().ok_or(OSError::Memory_RunOutOfMemory)?;
+                    new_frame.as_slice_mut().copy_from_slice(frame.as_slice());
+                    // 原页帧的引用计数减一，当其他地址段也都不再使用时自动释放
+                    *frame = Arc::new(new_frame);
+                }
+                _ => {}
+            }
+            Ok(Some(frame.start_paddr()))
+        } else {
+            Ok(None)
+        }
+    }
+
     fn get_frame(&mut self, idx: usize, need_alloc: bool) -> OSResult<Option<PhysAddr>> {
         if need_alloc && self.frames[idx].is_none() {
-            if let Some(mut frame) = Frame::new() {
+            if let Some(slot) = self.swapped.get(&idx) {
+                // 换出的页从交换区读回
+                let mut frame = Frame::new().ok_or(OSError::Memory_RunOutOfMemory)?;
+                slot.swap_in(&mut frame);
+                self.swapped.remove(&idx);
+                self.frames[idx] = Some(Arc::new(frame));
+                return Ok(self.frames[idx].as_ref().map(|f| f.start_paddr()));
+            }
+            // 优先使用页缓存中的页帧，这样映射同一个文件的地址段和文件读写都使用同一份数据
+            if let Some(frame) = self
+                .backend
+                .as_ref()
+                .and_then(|backend| backend.cached_frame(idx * PAGE_SIZE))
+            {
+                self.frames[idx] = Some(frame);
+            } else if let Some(mut frame) = Frame::new() {
                 if let Some(backend) = &self.backend {
                     // 无法读取则直接置零
                     if backend
@@ -50,7 +116,7 @@ impl PmArea for PmAreaLazy {
                 } else {
                     frame.zero();
                 }
-                self.frames[idx] = Some(frame);
+                self.frames[idx] = Some(Arc::new(frame));
             } else {
                 return Err(OSError::Memory_RunOutOfMemory);
             }
@@ -58,44 +124,112 @@ impl PmArea for PmAreaLazy {
         Ok(self.frames[idx].as_ref().map(|f| f.start_paddr()))
     }
 
+    fn get_huge_frame(
+        &mut self,
+        idx: usize,
+        count: usize,
+        need_alloc: bool,
+    ) -> OSResult<Option<PhysAddr>> {
+        // 只给匿名映射分配 2MiB 大页。1GiB 的页帧很难分配，清零的代价也太大
+        if !need_alloc || self.backend.is_some() || count != PageSize::Size2M.page_count() {
+            return Ok(None);
+        }
+        if self.frames[idx..idx + count]
+            .iter()
+            .any(|frame| frame.is_some())
+            || self.swapped.range(idx..idx + count).next().is_some()
+        {
+            return Ok(None);
+        }
+        if let Some(mut frame) = Frame::new_huge(count) {
+            frame.zero();
+            let paddr = frame.start_paddr();
+            // 拆成单独的页帧保存，这样之后写时复制、换出或者部分 munmap 时仍然可以按页处理
+            for (i, frame) in frame.split().into_iter().enumerate() {
+                self.frames[idx + i] = Some(Arc::new(frame));
+            }
+            Ok(Some(paddr))
+        } else {
+            // 分配不到大页时退回到普通的页
+            Ok(None)
+        }
+    }
+
     fn sync_frame_with_file(&mut self, idx: usize) {
-        // 有后端文件就同步，即使没有也不报错
-        if let Some(backend) = &self.backend {
-            // 无法写回也无所谓，当前区域仍可使用
-            backend
-                .write_to_offset(
-                    idx * PAGE_SIZE,
-                    self.frames[idx].as_ref().unwrap().as_slice(),
-                )
-                .unwrap_or(0);
+        // 有后端文件且页已分配就同步，否则什么都不做
+        if let (Some(backend), Some(frame)) = (&self.backend, &self.frames[idx]) {
+            backend.sync_page(idx * PAGE_SIZE, frame);
         }
     }
 
     fn release_frame(&mut self, idx: usize) -> OSResult {
+        // 换出的页在页表中本来就是无效的，和未分配的页一样处理
+        self.swapped.remove(&idx);
         let frame = self.frames[idx]
             .take()
             .ok_or(OSError::PmAreaLazy_ReleaseNotAllocatedPage)?;
         if let Some(backend) = &self.backend {
-            // 无法写回也无所谓，当前区域仍可使用
-            backend
-                .write_to_offset(idx * PAGE_SIZE, frame.as_slice())
-                .unwrap_or(0);
+            backend.sync_page(idx * PAGE_SIZE, &frame);
+        }
+        Ok(())
+    }
+
+    fn can_swap_out(&self, idx: usize) -> bool {
+        // 共享映射的页帧属于页缓存，由页缓存负责写回和换出
+        !self.is_shared_mapping()
+            && self.frames[idx]
+                .as_ref()
+                .map_or(false, |frame| Arc::strong_count(frame) == 1)
+    }
+
+    fn swap_out_frame(&mut self, idx: usize) -> bool {
+        if !self.can_swap_out(idx) {
+            return false;
+        }
+        if let Some(slot) = SwapSlot::swap_out(self.frames[idx].as_ref().unwrap()) {
+            self.frames[idx] = None;
+            self.swapped.insert(idx, Arc::new(slot));
+            true
+        } else {
+            false
+        }
+    }
+
+    fn swap_in_area(&mut self, area: usize) -> OSResult {
+        let pages: Vec<usize> = self
+            .swapped
+            .iter()
+            .filter(|(_, slot)| slot.area() == area)
+            .map(|(&idx, _)| idx)
+            .collect();
+        for idx in pages {
+            self.get_frame(idx, true)?;
         }
         Ok(())
     }
     /// 复制从 offset 位置开始的一段数据到 dst
     fn read(&mut self, offset: usize, dst: &mut [u8]) -> OSResult<usize> {
         //info!("pma read");
-        self.for_each_frame(offset, dst.len(), |processed: usize, frame: &mut [u8]| {
-            dst[processed..processed + frame.len()].copy_from_slice(frame);
-        })
+        self.for_each_frame(
+            offset,
+            dst.len(),
+            false,
+            |processed: usize, frame: &mut [u8]| {
+                dst[processed..processed + frame.len()].copy_from_slice(frame);
+            },
+        )
     }
     /// 复制 src ，放到从 offset 位置开始的物理页
     fn write(&mut self, offset: usize, src: &[u8]) -> OSResult<usize> {
         //info!("pma write");
-        self.for_each_frame(offset, src.len(), |processed: usize, frame: &mut [u8]| {
-            frame.copy_from_slice(&src[processed..processed + frame.len()]);
-        })
+        self.for_each_frame(
+            offset,
+            src.len(),
+            true,
+            |processed: usize, frame: &mut [u8]| {
+                frame.copy_from_slice(&src[processed..processed + frame.len()]);
+            },
+        )
     }
 
     fn shrink_left(&mut self, new_start: usize) -> OSResult {
@@ -105,6 +239,7 @@ impl PmArea for PmAreaLazy {
             }
             // 被删除的页帧会在 drop 时自动释放
             self.frames.drain(..addr_to_page_id(new_start));
+            self.swapped = shift_swapped(&mut self.swapped, addr_to_page_id(new_start));
             if let Some(backend) = &mut self.backend {
                 backend.modify_offset(new_start);
             }
@@ -121,28 +256,115 @@ impl PmArea for PmAreaLazy {
             }
             // 被删除的页帧会在 drop 时自动释放
             self.frames.drain(addr_to_page_id(new_end)..);
+            self.swapped.split_off(&addr_to_page_id(new_end));
             Ok(())
         } else {
             Err(OSError::PmArea_ShrinkFailed)
         }
     }
 
+    fn grow_right(&mut self, new_end: usize) -> OSResult {
+        let page_count = addr_to_page_id(new_end);
+        if page_count > addr::page_count(user_virt_addr_limit()) {
+            return Err(OSError::Memory_RunOutOfMemory);
+        }
+        if new_end > self.size() {
+            // 新增的页和 lazy 分配的页一样，有后端文件时之后从文件的对应位置读取
+            self.frames.resize(page_count, None);
+            Ok(())
+        } else {
+            Err(OSError::PmArea_GrowFailed)
+        }
+    }
+
+    fn grow_left(&mut self, size: usize) -> OSResult {
+        let count = addr_to_page_id(size);
+        // 有后端文件时，文件中的偏移不能向前移动
+        if self.backend.is_some()
+            || self.frames.len() + count > addr::page_count(user_virt_addr_limit())
+        {
+            return Err(OSError::PmArea_GrowFailed);
+        }
+        self.frames.splice(0..0, (0..count).map(|_| None));
+        self.swapped = core::mem::take(&mut self.swapped)
+            .into_iter()
+            .map(|(idx, slot)| (idx + count, slot))
+            .collect();
+        Ok(())
+    }
+
     fn split(&mut self, left_end: usize, right_start: usize) -> OSResult<Arc<Mutex<dyn PmArea>>> {
         if left_end <= right_start && right_start < self.size() {
             let new_frames = self.frames.drain(addr_to_page_id(right_start)..).collect();
+            let new_swapped = shift_swapped(&mut self.swapped, addr_to_page_id(right_start));
             for idx in addr_to_page_id(left_end)..addr_to_page_id(right_start) {
                 self.release_frame(idx).unwrap_or(());
             }
             // 被删除的页帧会在 drop 时自动释放
             self.frames.drain(addr_to_page_id(left_end)..);
-            Ok(Arc::new(Mutex::new(PmAreaLazy::new_from_frames(
+            let mut new_area = PmAreaLazy::new_from_frames(
                 new_frames,
                 self.backend.as_ref().map(|file| file.split(right_start)),
-            ))))
+            );
+            new_area.swapped = new_swapped;
+            Ok(Arc::new(Mutex::new(new_area)))
         } else {
             Err(OSError::PmArea_SplitFailed)
         }
     }
+
+    /// 是否是文件的共享映射
+    fn is_shared_mapping(&self) -> bool {
+        self.backend
+            .as_ref()
+            .map_or(false, |backend| backend.is_shared())
+    }
+
+    fn shared_location(&self, offset: usize) -> Option<SharedLocation> {
+        let backend = self
+            .backend
+            .as_ref()
+            .filter(|backend| backend.is_shared())?;
+        let frame = self.frames[offset / PAGE_SIZE].as_ref()?;
+        if backend.is_cached(align_down(offset)) {
+            // 所有映射这一页的地址段都直接使用页缓存中的页帧
+            Some(SharedLocation::Physical {
+                paddr: frame.start_paddr() + addr::page_offset(offset),
+            })
+        } else {
+            let (file, offset) = backend.location(offset);
+            Some(SharedLocation::File { file, offset })
+        }
+    }
+
+    fn merge_right(&mut self, right: &mut dyn PmArea) -> bool {
+        if self.backend.is_some() {
+            return false;
+        }
+        match right.take_anonymous() {
+            Some(mut right) => {
+                let offset = self.frames.len();
+                self.swapped.extend(
+                    core::mem::take(&mut right.swapped)
+                        .into_iter()
+                        .map(|(idx, slot)| (idx + offset, slot)),
+                );
+                self.frames.append(&mut right.frames);
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn take_anonymous(&mut self) -> Option<PmAreaLazy> {
+        if self.backend.is_some() {
+            return None;
+        }
+        Some(core::mem::replace(
+            self,
+            PmAreaLazy::new_from_frames(Vec::new(), None),
+        ))
+    }
 }
 
 impl PmAreaLazy {
@@ -152,7 +374,7 @@ impl PmAreaLazy {
             error!("page_count is 0 in PmAreaLazy");
             return Err(OSError::PmArea_InvalidRange);
         }
-        if page_count > addr::page_count(USER_VIRT_ADDR_LIMIT) {
+        if page_count > addr::page_count(user_virt_addr_limit()) {
             error!("page_count {:x} is too large in PmAreaLazy: ", page_count);
             return Err(OSError::Memory_RunOutOfMemory);
         }
@@ -162,21 +384,26 @@ impl PmAreaLazy {
         }
         Ok(Self {
             frames: frames,
+            swapped: BTreeMap::new(),
             backend: backend,
         })
     }
     /// 用给定页帧生成pma
-    pub fn new_from_frames(frames: Vec<Option<Frame>>, backend: Option<BackEndFile>) -> Self {
+    pub fn new_from_frames(frames: Vec<Option<Arc<Frame>>>, backend: Option<BackEndFile>) -> Self {
         Self {
             frames: frames,
+            swapped: BTreeMap::new(),
             backend: backend,
         }
     }
-    /// 对整体区间读写
+    /// 对整体区间读写。
+    ///
+    /// 如果 need_write，则会先复制共享的页帧，避免修改到其他地址段的数据
     fn for_each_frame(
         &mut self,
         offset: usize,
         len: usize,
+        need_write: bool,
         mut op: impl FnMut(usize, &mut [u8]),
     ) -> OSResult<usize> {
         if offset >= self.size() || offset + len > self.size() {
@@ -195,22 +422,15 @@ impl PmAreaLazy {
             let n = (PAGE_SIZE - pgoff).min(len);
 
             let idx = start_align / PAGE_SIZE;
-            if self.frames[idx].is_none() {
-                if let Some(mut frame) = Frame::new() {
-                    //info!("new frame vstart {:x} len {:x} (self_size {:x})pstart {:x}", start, len, self.size(), frame.start_paddr());
-                    frame.zero();
-                    self.frames[idx] = Some(frame);
-                } else {
-                    return Err(OSError::Memory_RunOutOfMemory);
-                }
-                /*
-                let mut frame = Frame::new()?;
-                frame.zero();
-                self.frames[idx] = Some(frame);
-                */
+            // 未分配的页和 page fault 时一样分配，有后端文件时从文件中读出内容
+            self.get_frame(idx, true)?;
+            if need_write {
+                self.unshare_frame(idx)?;
             }
-            let frame = self.frames[idx].as_mut().unwrap();
-            op(processed, &mut frame.as_slice_mut()[pgoff..pgoff + n]);
+            let frame = self.frames[idx].as_ref().unwrap();
+            // 只读时页帧可能是共享的，无法拿到 &mut Frame，所以直接从地址构造 slice
+            let data = unsafe { slice::from_raw_parts_mut(frame.as_mut_ptr().add(pgoff), n) };
+            op(processed, data);
             start += n;
             processed += n;
             len -= n;
@@ -219,6 +439,18 @@ impl PmAreaLazy {
     }
 }
 
+/// 取出 swapped 中第 start 页及之后的部分，并把页号改为相对于 start 的
+fn shift_swapped(
+    swapped: &mut BTreeMap<usize, Arc<SwapSlot>>,
+    start: usize,
+) -> BTreeMap<usize, Arc<SwapSlot>> {
+    swapped
+        .split_off(&start)
+        .into_iter()
+        .map(|(idx, slot)| (idx - start, slot))
+        .collect()
+}
+
 impl Debug for PmAreaLazy {
     fn fmt(&self, f: &mut Formatter) -> Result {
         f.debug_struct("PmAreaLazy")
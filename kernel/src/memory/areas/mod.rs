@@ -4,22 +4,20 @@
 
 mod fixed;
 mod lazy;
+mod shared;
 
 use super::{
-    addr::{align_down, align_up, PhysAddr, VirtAddr},
-    PTEFlags, PageTable, PAGE_SIZE,
+    addr::{align_down, align_up, page_offset, PhysAddr, VirtAddr},
+    PTEFlags, PageSize, PageTable, PAGE_SIZE,
 };
-use crate::{
-    error::{OSError, OSResult},
-    memory::phys_to_virt,
-};
-use alloc::sync::Arc;
-use core::slice;
+use crate::error::{OSError, OSResult};
+use alloc::{sync::Arc, vec::Vec};
 use lock::Mutex;
 
 pub use fixed::PmAreaFixed;
 pub use lazy::PmAreaLazy;
 use range_action_map::{ArgsType as PageTableRoot, IdentType as Flags, Segment};
+pub use shared::PmAreaShared;
 
 /// 一段访问权限相同的物理地址。注意物理地址本身不一定连续，只是拥有对应长度的空间
 ///
@@ -29,14 +27,42 @@ pub trait PmArea: core::fmt::Debug + Send + Sync {
     fn size(&self) -> usize;
     /// 复制一份区间，新区间结构暂不分配任何实际页帧。一般是 fork 要求的
     fn clone_as_fork(&self) -> OSResult<Arc<Mutex<dyn PmArea>>>;
+    /// 复制一份区间，新区间和原区间共享所有已分配的页帧，等到写入时再复制(Copy on write)。一般是 fork 要求的
+    fn clone_as_cow(&self) -> OSResult<Arc<Mutex<dyn PmArea>>>;
+    /// idx 所在页的页帧是否同时被其他区间使用。共享的页在页表中必须是只读的
+    fn is_shared_frame(&self, idx: usize) -> bool;
+    /// 如果 idx 所在页的页帧是共享的，则复制一份只属于当前区间的页帧替换它。
+    ///
+    /// 返回替换后的物理地址。如果这一页还未分配，则返回 None
+    fn unshare_frame(&mut self, idx: usize) -> OSResult<Option<PhysAddr>>;
     /// 获取 idx 所在页的页帧。
     ///
     /// 如果有 need_alloc，则会在 idx 所在页未分配时尝试分配
     fn get_frame(&mut self, idx: usize, need_alloc: bool) -> OSResult<Option<PhysAddr>>;
+    /// 获取从 idx 开始的 count 页作为一个大页时的起始物理地址，count 是 2 的幂。
+    ///
+    /// 只有这些页在物理地址上连续且按 count 页对齐时才会返回地址。
+    /// 如果有 need_alloc，则会在这些页全都未分配时尝试一起分配
+    fn get_huge_frame(
+        &mut self,
+        idx: usize,
+        count: usize,
+        need_alloc: bool,
+    ) -> OSResult<Option<PhysAddr>>;
     /// 同步页的信息到后端文件中
     fn sync_frame_with_file(&mut self, idx: usize);
     /// 释放 idx 地址对应的物理页
     fn release_frame(&mut self, idx: usize) -> OSResult;
+    /// idx 所在页是否可以换出。只有不和其他区间共享的页帧可以换出
+    fn can_swap_out(&self, _idx: usize) -> bool {
+        false
+    }
+    /// 把 idx 所在页换出到交换区并释放页帧，成功时返回 true。
+    ///
+    /// 调用者需要先删除这一页在页表中的映射并刷新所有核的 TLB，否则换出之后的写入会丢失
+    fn swap_out_frame(&mut self, idx: usize) -> bool;
+    /// 把存放在编号为 area 的交换区中的页都换回内存。一般是 swapoff 要求的
+    fn swap_in_area(&mut self, area: usize) -> OSResult;
     /// 读从 offset 开头的一段数据，成功时返回读取长度
     fn read(&mut self, offset: usize, dst: &mut [u8]) -> OSResult<usize>;
     /// 把数据写到从 offset 开头的地址，成功时返回写入长度
@@ -45,9 +71,44 @@ pub trait PmArea: core::fmt::Debug + Send + Sync {
     fn shrink_left(&mut self, new_start: usize) -> OSResult;
     /// 从右侧缩短一段(new_end是相对于地址段开头的偏移)
     fn shrink_right(&mut self, new_end: usize) -> OSResult;
+    /// 从右侧扩展到 new_end(相对于地址段开头的偏移)。新增的部分暂不分配页帧
+    fn grow_right(&mut self, new_end: usize) -> OSResult;
+    /// 从左侧扩展 size 字节，原来的偏移都会增加 size。新增的部分暂不分配页帧
+    fn grow_left(&mut self, size: usize) -> OSResult;
     /// 分成三段区间(输入参数都是相对于地址段开头的偏移)
     /// 自己保留[start, left_end), 删除 [left_end, right_start)，返回 [right_start, end)
     fn split(&mut self, left_end: usize, right_start: usize) -> OSResult<Arc<Mutex<dyn PmArea>>>;
+    /// 是否是共享映射(MAP_SHARED)，即写入对映射同一个对象的其他地址段可见。默认不是
+    fn is_shared_mapping(&self) -> bool {
+        false
+    }
+    /// 共享映射中第 offset 字节在被映射的对象中的位置，用于区分跨进程的 futex。
+    ///
+    /// 调用前需要保证这一页已经分配。不是共享映射时返回 None
+    fn shared_location(&self, _offset: usize) -> Option<SharedLocation> {
+        None
+    }
+    /// 把紧接在后面的区间 right 接到自己末尾，成功时 right 中的页都转移到自己这里。
+    ///
+    /// 不能合并时返回 false，且不修改两边。默认不合并
+    fn merge_right(&mut self, _right: &mut dyn PmArea) -> bool {
+        false
+    }
+    /// 如果是没有后端文件的匿名区间，取出其中所有的页，自己变为空的区间。否则返回 None。用于合并区间
+    fn take_anonymous(&mut self) -> Option<PmAreaLazy> {
+        None
+    }
+}
+
+/// 共享映射中的一个位置。映射同一个对象的地址段，不论在哪个地址空间、映射到哪个地址，得到的位置都相同
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug)]
+pub enum SharedLocation {
+    /// 共享内存中的偏移，memory 是 `SharedMemory` 的地址
+    Memory { memory: usize, offset: usize },
+    /// 文件的页缓存中的页帧。它被映射时不会被换出，所以可以直接用物理地址区分
+    Physical { paddr: PhysAddr },
+    /// 不经过页缓存的文件中的偏移，file 是打开的文件对象的地址
+    File { file: usize, offset: usize },
 }
 
 /// 一段访问权限相同的虚拟地址
@@ -122,32 +183,156 @@ impl VmArea {
         }
     }
 
-    /// 修改这段区间的访问权限。一般由 mprotect 触发
+    /// 换出这一段中至多 count 个最近没有被访问的页，返回换出的页数。
+    ///
+    /// 页表中有 ACCESS 位的页只清掉 ACCESS 位，等下次扫描时还没有被访问过才换出。
+    /// 共享映射的地址段，或者正在被其他核使用的地址段会被跳过。
+    /// 大页按整体判断是否被访问过，需要换出时先拆成 4KiB 页。
+    ///
+    /// 要换出的页会先从页表中删除，刷新所有核的 TLB 之后才写到交换区并释放页帧
+    pub fn reclaim(&self, pt: &mut PageTable, count: usize) -> usize {
+        if !self.is_user() {
+            return 0;
+        }
+        let mut pma = match self.pma.try_lock() {
+            Some(pma) if !pma.is_shared_mapping() => pma,
+            _ => return 0,
+        };
+        let mut victims = Vec::new();
+        let mut modified = false;
+        let mut vaddr = self.start;
+        while vaddr < self.end && victims.len() < count {
+            let size = pt.page_size(vaddr).unwrap_or(PageSize::Size4K);
+            if let Some(entry) = pt.get_entry(vaddr) {
+                unsafe {
+                    if !(*entry).is_valid() {
+                        vaddr += PAGE_SIZE;
+                        continue;
+                    }
+                    let flags = (*entry).flags();
+                    if flags.contains(PTEFlags::ACCESS) {
+                        (*entry).set_flags(flags - PTEFlags::ACCESS);
+                        modified = true;
+                        vaddr += size.bytes();
+                        continue;
+                    } else if size != PageSize::Size4K {
+                        if pt.split_huge_page(vaddr).is_err() {
+                            break;
+                        }
+                        continue;
+                    } else if pma.can_swap_out((vaddr - self.start) / PAGE_SIZE) {
+                        // 和 lazy 分配时未分配的页一样，再次访问时会触发 page fault
+                        (*entry).clear();
+                        modified = true;
+                        victims.push((vaddr - self.start) / PAGE_SIZE);
+                    }
+                }
+            }
+            vaddr += PAGE_SIZE;
+        }
+        if !modified {
+            return 0;
+        }
+        // 其他核上的线程可能还在通过旧的 TLB 项读写这些页。
+        // 清掉 ACCESS 位之后也需要刷新，否则之后的访问不会重新设置它
+        pt.flush_tlb_all_harts();
+        // 交换区满了时页帧仍留在 pma 中，下次访问时的 page fault 会把它重新映射回来
+        victims
+            .into_iter()
+            .filter(|&idx| pma.swap_out_frame(idx))
+            .count()
+    }
+
+    /// 把这一段中存放在编号为 area 的交换区中的页都换回内存
+    pub fn swap_in_area(&self, area: usize) -> OSResult {
+        self.pma.lock().swap_in_area(area)
+    }
+
+    /// 获取第 idx 页在页表中实际应使用的权限。
+    ///
+    /// 和其他区间共享的页帧需要去掉 WRITE 权限，这样写入时才会触发 Page Fault 进行写时复制
+    fn page_flags(&self, pma: &dyn PmArea, idx: usize) -> PTEFlags {
+        if pma.is_shared_frame(idx) {
+            self.flags - PTEFlags::WRITE
+        } else {
+            self.flags
+        }
+    }
+
+    /// 修改这段区间的访问权限。一般由 mprotect 或者 fork 触发
+    ///
+    /// 大页整体修改权限。但其中的页帧如果是共享的，说明是 fork 出来的写时复制页，需要先拆成 4KiB 页，
+    /// 这样之后写时复制时才能按页替换
     fn modify_area_flags(&self, pt: &mut PageTable) -> OSResult {
         let mut pma = self.pma.lock();
-        for vaddr in (self.start..self.end).step_by(PAGE_SIZE) {
-            if pma
-                .get_frame((vaddr - self.start) / PAGE_SIZE, false)?
-                .is_some()
-            {
+        let mut vaddr = self.start;
+        while vaddr < self.end {
+            let idx = (vaddr - self.start) / PAGE_SIZE;
+            let size = pt.page_size(vaddr).unwrap_or(PageSize::Size4K);
+            if size != PageSize::Size4K && pma.is_shared_frame(idx) {
+                pt.split_huge_page(vaddr)?;
+                continue;
+            }
+            if pma.get_frame(idx, false)?.is_some() {
                 // 因为 pma 中拿到了页帧，所以这里一定是会成功的，可以 unwrap
                 // 不成功说明 OS 有问题
-                pt.set_flags(vaddr, self.flags).unwrap();
+                pt.set_flags(vaddr, self.page_flags(&*pma, idx)).unwrap();
             }
+            vaddr += size.bytes();
+        }
+        Ok(())
+    }
+
+    /// 写时复制：如果第 idx 页的页帧是共享的，则换成独占的页帧，并恢复页表中的权限。
+    ///
+    /// 如果这一页在页表中无效，或者本来就可写，则什么也不做
+    fn copy_on_write(&self, pma: &mut dyn PmArea, idx: usize, pt: &PageTable) -> OSResult {
+        let vaddr = self.start + idx * PAGE_SIZE;
+        if let Some(entry) = pt.get_entry(vaddr) {
+            unsafe {
+                if (*entry).is_valid() && !(*entry).writable() {
+                    if let Some(paddr) = pma.unshare_frame(idx)? {
+                        (*entry).set_all(
+                            paddr,
+                            self.flags | PTEFlags::VALID | PTEFlags::ACCESS | PTEFlags::DIRTY,
+                        );
+                        pt.flush_tlb(Some(vaddr));
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// 对从 offset 开始、长为 len 的一段地址做写时复制。一般在内核直接修改这段数据之前调用
+    pub fn copy_on_write_range(&self, offset: usize, len: usize, pt: &PageTable) -> OSResult {
+        if len == 0 {
+            return Ok(());
+        }
+        let mut pma = self.pma.lock();
+        for idx in offset / PAGE_SIZE..=(offset + len - 1) / PAGE_SIZE {
+            self.copy_on_write(&mut *pma, idx, pt)?;
         }
         Ok(())
     }
 
     /// 把虚拟地址段和对应的物理地址段的映射写入页表。
     ///
-    /// 如果是 lazy 分配的，或者说还没有对应页帧时，则不分配，等到 page fault 时再分配
+    /// 如果是 lazy 分配的，或者说还没有对应页帧时，则不分配，等到 page fault 时再分配。
+    /// 物理地址连续且对齐的部分(如内核的物理内存映射)会尽量用大页映射
     pub fn map_area(&self, pt: &mut PageTable) -> OSResult {
         let mut pma = self.pma.lock();
-        for vaddr in (self.start..self.end).step_by(PAGE_SIZE) {
-            let page = pma.get_frame((vaddr - self.start) / PAGE_SIZE, false)?;
+        let mut vaddr = self.start;
+        while vaddr < self.end {
+            if let Some(size) = self.map_huge_page(&mut *pma, vaddr, pt, false)? {
+                vaddr += size.bytes();
+                continue;
+            }
+            let idx = (vaddr - self.start) / PAGE_SIZE;
+            let page = pma.get_frame(idx, false)?;
             let res = if let Some(paddr) = page {
                 // if vaddr < 0x9000_0000 { println!("create mapping {:x}->{:x} at {:x}", vaddr, paddr, pt.get_root_paddr()); }
-                pt.map(vaddr, paddr, self.flags)
+                pt.map(vaddr, paddr, self.page_flags(&*pma, idx))
             } else {
                 pt.map(vaddr, 0, PTEFlags::empty())
             };
@@ -158,32 +343,206 @@ impl VmArea {
                 );
                 e
             })?;
+            vaddr += PAGE_SIZE;
         }
         Ok(())
     }
 
+    /// 尝试用大页映射从 vaddr 开始的一段，成功时返回大页的大小。
+    ///
+    /// 只有这一段完整地落在地址段中，且 pma 能给出对齐的连续页帧时才会映射
+    fn map_huge_page(
+        &self,
+        pma: &mut dyn PmArea,
+        vaddr: VirtAddr,
+        pt: &mut PageTable,
+        need_alloc: bool,
+    ) -> OSResult<Option<PageSize>> {
+        for size in [PageSize::Size1G, PageSize::Size2M] {
+            if vaddr % size.bytes() != 0 || vaddr + size.bytes() > self.end {
+                continue;
+            }
+            let idx = (vaddr - self.start) / PAGE_SIZE;
+            if let Some(paddr) = pma.get_huge_frame(idx, size.page_count(), need_alloc)? {
+                if pt.map_huge(vaddr, paddr, size, self.flags).is_ok() {
+                    return Ok(Some(size));
+                }
+                // 页表中已经有小页的映射了，退回到按页映射。刚分配的页帧也要还回去，保持页表和 pma 一致
+                if need_alloc {
+                    for i in idx..idx + size.page_count() {
+                        pma.release_frame(i)?;
+                    }
+                }
+            }
+        }
+        Ok(None)
+    }
+
     /// 删除部分虚拟地址映射
+    ///
+    /// 先清掉页表项并刷新所有核的 TLB，再释放页帧。
+    /// 否则其他核上的线程可能还在通过旧的 TLB 项访问已经被释放、甚至已经分给别人的页帧
     fn unmap_area_partial(&self, pt: &mut PageTable, start: VirtAddr, end: VirtAddr) -> OSResult {
         let mut pma = self.pma.lock();
-        for vaddr in (start..end).step_by(PAGE_SIZE) {
-            let res = pma.release_frame((vaddr - self.start) / PAGE_SIZE);
-            //if vaddr == 0x3fff_f000 { println!("page {:#x?} at {:x}", res, pt.get_root_paddr()); }
-            // 如果触发 OSError::PmAreaLazy_ReleaseNotAllocatedPage，
-            // 说明这段 area 是 Lazy 分配的，且这一页还没被用到
-            // 这种情况下不需要报错，也不需要修改页表
-            if res != Err(OSError::PmAreaLazy_ReleaseNotAllocatedPage) {
-                if res.is_err() {
-                    return res;
-                }
+        let mut vaddr = start;
+        let mut unmapped = false;
+        while vaddr < end {
+            let size = pt.page_size(vaddr).unwrap_or(PageSize::Size4K);
+            if size != PageSize::Size4K && (vaddr % size.bytes() != 0 || vaddr + size.bytes() > end)
+            {
+                // 只删除大页的一部分时，先拆成 4KiB 页
+                pt.split_huge_page(vaddr)?;
+                continue;
+            }
+            // 如果这一段是 Lazy 分配的，且这些页还没被用到，就不需要修改页表
+            let mapped = (vaddr..vaddr + size.bytes())
+                .step_by(PAGE_SIZE)
+                .any(|page| {
+                    matches!(
+                        pma.get_frame((page - self.start) / PAGE_SIZE, false),
+                        Ok(Some(_))
+                    )
+                });
+            if mapped {
                 pt.unmap(vaddr).map_err(|e| {
                     error!("failed to unmap VA: {:#x?}, {:?}", vaddr, e);
                     e
                 })?;
+                unmapped = true;
+            }
+            vaddr += size.bytes();
+        }
+        if unmapped {
+            pt.flush_tlb_all_harts();
+        }
+        for page in (start..end).step_by(PAGE_SIZE) {
+            let res = pma.release_frame((page - self.start) / PAGE_SIZE);
+            // 如果触发 OSError::PmAreaLazy_ReleaseNotAllocatedPage，
+            // 说明这段 area 是 Lazy 分配的，且这一页还没被用到，不需要报错
+            if res != Err(OSError::PmAreaLazy_ReleaseNotAllocatedPage) {
+                res?;
             }
         }
         Ok(())
     }
 
+    /// 丢弃 [start, end) 中已分配的页帧，并删除它们在页表中的映射。一般由 madvise 触发
+    ///
+    /// 之后再访问时和 lazy 分配一样重新获取页帧：匿名映射读出的是全 0，文件映射则重新从文件中读取。
+    /// 大页会先拆成 4KiB 页，这样页表中还留着最后一级页表，page fault 时可以直接填入
+    pub fn discard(&self, pt: &mut PageTable, start: VirtAddr, end: VirtAddr) -> OSResult {
+        let start = align_down(start).max(self.start);
+        let end = align_up(end).min(self.end);
+        let mut vaddr = start;
+        while vaddr < end {
+            let size = pt.page_size(vaddr).unwrap_or(PageSize::Size4K);
+            if size != PageSize::Size4K {
+                pt.split_huge_page(vaddr)?;
+            }
+            vaddr += PAGE_SIZE;
+        }
+        self.unmap_area_partial(pt, start, end)
+    }
+
+    /// 预先分配 [start, end) 中还没有分配的页帧，并填入页表。一般由 madvise 触发
+    ///
+    /// 和 page fault 时一样，文件映射会从文件中读出数据。内存不足时直接停下，不报错
+    pub fn prefetch(&self, pt: &mut PageTable, start: VirtAddr, end: VirtAddr) -> OSResult {
+        let mut pma = self.pma.lock();
+        let start = align_down(start).max(self.start);
+        let end = align_up(end).min(self.end);
+        for vaddr in (start..end).step_by(PAGE_SIZE) {
+            let idx = (vaddr - self.start) / PAGE_SIZE;
+            if let Some(entry) = pt.get_entry(vaddr) {
+                unsafe {
+                    if (*entry).is_valid() {
+                        continue;
+                    }
+                    match pma.get_frame(idx, true) {
+                        Ok(Some(paddr)) => (*entry).set_all(
+                            paddr,
+                            self.page_flags(&*pma, idx)
+                                | PTEFlags::VALID
+                                | PTEFlags::ACCESS
+                                | PTEFlags::DIRTY,
+                        ),
+                        Ok(None) => {}
+                        Err(OSError::Memory_RunOutOfMemory) => break,
+                        Err(e) => return Err(e),
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// vaddr 所在的页当前是否在内存中。未分配或者已经换出的页都不算
+    pub fn is_resident(&self, vaddr: VirtAddr) -> bool {
+        let idx = (vaddr - self.start) / PAGE_SIZE;
+        matches!(self.pma.lock().get_frame(idx, false), Ok(Some(_)))
+    }
+
+    /// 这一段中当前在内存中的页数，即这一段占用的 RSS
+    pub fn resident_pages(&self) -> usize {
+        let mut pma = self.pma.lock();
+        (0..(self.end - self.start) / PAGE_SIZE)
+            .filter(|&idx| matches!(pma.get_frame(idx, false), Ok(Some(_))))
+            .count()
+    }
+
+    /// 把这一段整体移动到 new_start，并把长度调整为 new_len。一般由 mremap 触发
+    ///
+    /// 已分配的页帧保持不变，只删除原来在页表中的映射并刷新所有核的 TLB，之后需要重新 `map_area`。
+    /// 调用前这一段需要已经从区间树中取出。
+    /// 如果 pma 无法增长到新的长度，则返回 error，此时页表和这一段都没有被修改
+    pub fn remap(&mut self, new_start: VirtAddr, new_len: usize, pt: &mut PageTable) -> OSResult {
+        let old_len = self.end - self.start;
+        if new_len > old_len {
+            self.pma.lock().grow_right(new_len)?;
+        } else if new_len < old_len {
+            // 缩短时被删掉的部分要先清掉页表项、刷新 TLB，之后才能释放页帧
+            self.unmap_area_partial(pt, self.start + new_len, self.end)?;
+            self.pma.lock().shrink_right(new_len)?;
+        }
+        let mut vaddr = self.start;
+        while vaddr < self.end {
+            let size = pt.page_size(vaddr).unwrap_or(PageSize::Size4K);
+            if let Some(entry) = pt.get_entry(vaddr) {
+                unsafe { (*entry).clear() };
+            }
+            vaddr += size.bytes();
+        }
+        // 其他核上的线程可能还在通过旧地址访问这些页帧
+        pt.flush_tlb_all_harts();
+        self.start = new_start;
+        self.end = new_start + new_len;
+        Ok(())
+    }
+
+    /// 把这一段的结尾向上扩展到 new_end。一般是用户堆向上增长时要求的
+    ///
+    /// 原有的页在页表中的映射不变。调用前这一段需要已经从区间树中取出
+    pub fn grow_up(&mut self, new_end: VirtAddr) -> OSResult {
+        if new_end <= self.end || page_offset(new_end) != 0 {
+            return Err(OSError::VmArea_InvalidRange);
+        }
+        self.pma.lock().grow_right(new_end - self.start)?;
+        self.end = new_end;
+        Ok(())
+    }
+
+    /// 把这一段的开头向下扩展到 new_start。一般是用户栈向下增长时要求的
+    ///
+    /// 原有的页在页表中的映射不变。调用前这一段需要已经从区间树中取出
+    pub fn grow_down(&mut self, new_start: VirtAddr) -> OSResult {
+        if new_start >= self.start || page_offset(new_start) != 0 {
+            return Err(OSError::VmArea_InvalidRange);
+        }
+        self.pma.lock().grow_left(self.start - new_start)?;
+        self.start = new_start;
+        Ok(())
+    }
+
     /// 把虚拟地址段和对应的物理地址段的映射从页表中删除。
     ///
     /// 如果页表中的描述和 VmArea 的描述不符，则返回 error
@@ -192,11 +551,26 @@ impl VmArea {
         self.unmap_area_partial(pt, self.start, self.end)
     }
 
+    /// 地址段的名字，即创建时给出的用途
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+
     /// 这一段是否是用户态可见的
     pub fn is_user(&self) -> bool {
         self.flags.contains(PTEFlags::USER)
     }
 
+    /// 是否是共享映射(MAP_SHARED)，包括共享内存和文件的共享映射
+    pub fn is_shared(&self) -> bool {
+        self.pma.lock().is_shared_mapping()
+    }
+
+    /// vaddr 在被映射的对象中的位置，见 `PmArea::shared_location`
+    pub fn shared_location(&self, vaddr: VirtAddr) -> Option<SharedLocation> {
+        self.pma.lock().shared_location(vaddr - self.start)
+    }
+
     /// 从已有 VmArea 复制一个新的 VmArea ，其中虚拟地址段和权限相同，但没有实际分配物理页
     pub fn copy_to_new_area_empty(&self) -> OSResult<VmArea> {
         Ok(VmArea {
@@ -208,41 +582,25 @@ impl VmArea {
         })
     }
 
-    /// 从已有 VmArea 复制一个新的 VmArea ，复制所有的数据，但是用不同的物理地址
+    /// 从已有 VmArea 复制一个新的 VmArea ，两者的数据相同，且以写时复制(Copy on write)的方式共享页帧。
     ///
-    /// Todo: 可以改成 Copy on write 的方式
-    /// 需要把 WRITE 权限关掉，然后等到写这段内存发生 Page Fault 再实际写入数据。
-    /// 但是这需要建立一种映射关系，帮助在之后找到应该映射到同一块数据的所有 VmArea。
+    /// 已分配的页帧不会立即复制，而是在两边的页表中都关掉 WRITE 权限，
+    /// 等到任意一方写这段内存发生 Page Fault 时，再复制出一个独占的页帧。
     ///
-    /// 而且不同进程中进行 mmap / munmap 等操作时也可能会修改这样的对应关系，
-    /// 不是只有写这段内存才需要考虑 Copy on write，所以真正实现可能比想象的要复杂。
-    pub fn copy_to_new_area_with_data(&self) -> OSResult<VmArea> {
-        let new_area = self.copy_to_new_area_empty()?;
-        let mut new_pma = new_area.pma.lock();
-        let mut old_pma = self.pma.lock();
-        for vaddr in (self.start..self.end).step_by(PAGE_SIZE) {
-            // 获取当前 VmArea 的所有页
-            let old_page = old_pma.get_frame((vaddr - self.start) / PAGE_SIZE, false)?;
-            if let Some(old_paddr) = old_page {
-                // 如果这个页已被分配
-                // 在新 VmArea 中分配一个新页
-                // 这里不会出现 Ok(None) 的情况，因为 new_area 是刚生成的，所以 new_pma 里面为空。
-                // PmAreaLazy::get_frame 里的实现在这种情况下要么返回内存溢出错误，要么返回新获取的帧的物理地址
-                let new_paddr = new_pma
-                    .get_frame((vaddr - self.start) / PAGE_SIZE, true)?
-                    .unwrap();
-                // 手动复制这个页的内存。
-                // 其实可以利用 trait 的 write/read 接口，但是那样会需要两次内存复制操作
-                let src = unsafe {
-                    slice::from_raw_parts(phys_to_virt(old_paddr) as *const u8, PAGE_SIZE)
-                };
-                let dst = unsafe {
-                    slice::from_raw_parts_mut(phys_to_virt(new_paddr) as *mut u8, PAGE_SIZE)
-                };
-                dst.copy_from_slice(src);
-            }
+    /// 页帧本身带引用计数，所以之后 mmap / munmap / mprotect 拆分区间时不需要额外维护各个 VmArea 的对应关系。
+    /// 传入的 pt 是当前 VmArea 所在的页表，调用者需要在之后刷新 TLB
+    pub fn copy_to_new_area_cow(&self, pt: &mut PageTable) -> OSResult<VmArea> {
+        let new_area = VmArea {
+            start: self.start,
+            end: self.end,
+            flags: self.flags,
+            pma: self.pma.lock().clone_as_cow()?,
+            name: self.name,
+        };
+        // 当前地址段中已分配的页现在都是共享的了，需要改为只读
+        if self.flags.contains(PTEFlags::WRITE) {
+            self.modify_area_flags(pt)?;
         }
-        drop(new_pma);
         Ok(new_area)
     }
 
@@ -263,19 +621,50 @@ impl VmArea {
         }
         let offset = align_down(offset);
         let vaddr = self.start + offset;
-        let paddr = pma
-            .get_frame(offset / PAGE_SIZE, true)?
-            .ok_or(OSError::Memory_RunOutOfMemory)?;
-        // println!("paddr {:x}", paddr);
+        let idx = offset / PAGE_SIZE;
         if let Some(entry) = pt.get_entry(vaddr) {
             unsafe {
                 if (*entry).is_valid() {
                     // println!("entry flags {:x}", entry.bits);
-                    Err(OSError::PageFaultHandler_TrapAtValidPage)
+                    if access_flags.contains(PTEFlags::WRITE) && !(*entry).writable() {
+                        // 写一个有效但只读的页，而 VmArea 本身是可写的，说明是写时复制的页
+                        self.copy_on_write(&mut *pma, idx, pt)
+                    } else {
+                        // 换出页面时会清掉 ACCESS 位，硬件不自动设置 ACCESS / DIRTY 位时，
+                        // 访问这样的页会触发 page fault，此时补上对应的位即可
+                        let flags = (*entry).flags();
+                        let mut needed = PTEFlags::ACCESS;
+                        if access_flags.contains(PTEFlags::WRITE) {
+                            needed |= PTEFlags::DIRTY;
+                        }
+                        if flags.contains(needed) {
+                            return Err(OSError::PageFaultHandler_TrapAtValidPage);
+                        }
+                        (*entry).set_flags(flags | needed);
+                        pt.flush_tlb(Some(vaddr));
+                        Ok(())
+                    }
                 } else {
+                    // 对齐的匿名映射优先整块分配大页
+                    let huge_start = vaddr & !(PageSize::Size2M.bytes() - 1);
+                    if huge_start >= self.start
+                        && self
+                            .map_huge_page(&mut *pma, huge_start, pt, true)?
+                            .is_some()
+                    {
+                        pt.flush_tlb(Some(vaddr));
+                        return Ok(());
+                    }
+                    let paddr = pma
+                        .get_frame(idx, true)?
+                        .ok_or(OSError::Memory_RunOutOfMemory)?;
+                    // println!("paddr {:x}", paddr);
                     (*entry).set_all(
                         paddr,
-                        self.flags | PTEFlags::VALID | PTEFlags::ACCESS | PTEFlags::DIRTY,
+                        self.page_flags(&*pma, idx)
+                            | PTEFlags::VALID
+                            | PTEFlags::ACCESS
+                            | PTEFlags::DIRTY,
                     );
                     pt.flush_tlb(Some(vaddr));
                     //info!("[Handler] Lazy alloc a page for user.");
@@ -287,13 +676,16 @@ impl VmArea {
         }
     }
 
-    /// 检查一个地址是否分配，如果未分配则强制分配它
+    /// 检查一个地址是否分配，如果未分配则强制分配它。
+    ///
+    /// 内核之后可能直接写这个地址，所以如果这一页可写但正处于写时复制状态，也会在这里复制
     pub fn manually_alloc_page(&self, offset: usize, pt: &mut PageTable) -> OSResult {
         let mut pma = self.pma.lock();
         let offset = align_down(offset);
         let vaddr = self.start + offset;
+        let idx = offset / PAGE_SIZE;
         let paddr = pma
-            .get_frame(offset / PAGE_SIZE, true)?
+            .get_frame(idx, true)?
             .ok_or(OSError::Memory_RunOutOfMemory)?;
         // println!("paddr {:x}", paddr);
         if let Some(entry) = pt.get_entry(vaddr) {
@@ -301,12 +693,25 @@ impl VmArea {
                 if !(*entry).is_valid() {
                     (*entry).set_all(
                         paddr,
-                        self.flags | PTEFlags::VALID | PTEFlags::ACCESS | PTEFlags::DIRTY,
+                        self.page_flags(&*pma, idx)
+                            | PTEFlags::VALID
+                            | PTEFlags::ACCESS
+                            | PTEFlags::DIRTY,
                     );
                     pt.flush_tlb(Some(vaddr));
+                } else if !(*entry)
+                    .flags()
+                    .contains(PTEFlags::ACCESS | PTEFlags::DIRTY)
+                {
+                    // 换出扫描时可能清掉了 ACCESS 位，内核直接访问前先补上
+                    (*entry).set_flags((*entry).flags() | PTEFlags::ACCESS | PTEFlags::DIRTY);
+                    pt.flush_tlb(Some(vaddr));
                 }
-                Ok(())
             }
+            if self.flags.contains(PTEFlags::WRITE) {
+                self.copy_on_write(&mut *pma, idx, pt)?;
+            }
+            Ok(())
         } else {
             Err(OSError::PageTable_PageNotMapped)
         }
@@ -326,7 +731,33 @@ impl Segment for VmArea {
     fn remove(&mut self, args: PageTableRoot) {
         self.unmap_area(get_page_table(args)).unwrap();
     }
-    fn split(&mut self, pos: usize, _args: PageTableRoot) -> Self {
+    /// 只合并权限和名字都相同、且物理地址段没有和其他地址段共享的匿名映射。
+    /// 两段原本的页表项都不需要修改
+    fn merge(&mut self, right: Self, _args: PageTableRoot) -> Result<(), Self> {
+        if self.end != right.start
+            || self.flags != right.flags
+            || self.name != right.name
+            || self.is_shared()
+            || right.is_shared()
+        {
+            return Err(right);
+        }
+        if self.pma.lock().merge_right(&mut *right.pma.lock()) {
+            self.end = right.end;
+            Ok(())
+        } else {
+            Err(right)
+        }
+    }
+    fn split(&mut self, pos: usize, args: PageTableRoot) -> Self {
+        // 切分点落在大页中间时，先把大页拆开，这样两边可以各自修改或删除
+        let pt = get_page_table(args);
+        if pt
+            .page_size(pos)
+            .map_or(false, |size| pos % size.bytes() != 0)
+        {
+            pt.split_huge_page(pos).unwrap();
+        }
         let old_end = self.end;
         self.end = pos;
         let right_pma = self
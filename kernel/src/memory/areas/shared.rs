@@ -0,0 +1,233 @@
+//! 映射一段共享内存的物理地址段
+
+//#![deny(missing_docs)]
+
+use alloc::{sync::Arc, vec::Vec};
+use core::fmt::{Debug, Formatter, Result};
+
+use lock::Mutex;
+
+use super::{PmArea, SharedLocation};
+use crate::error::{OSError, OSResult};
+use crate::memory::{
+    addr::{self, addr_to_page_id},
+    user_virt_addr_limit, Frame, PhysAddr, SharedMemory, PAGE_SIZE,
+};
+
+/// 映射一段 `SharedMemory` 的物理地址段，从共享内存的第 offset 页开始。
+///
+/// 页帧都来自共享内存，fork 时新的地址段映射同一段共享内存，所以写入总是直接修改共享的页帧，不需要写时复制。
+/// frames 只记录这个地址段已经映射了哪些页，释放其中的页不会影响共享内存中的数据
+pub struct PmAreaShared {
+    memory: Arc<SharedMemory>,
+    offset: usize,
+    frames: Vec<Option<Arc<Frame>>>,
+    /// 是否由 shmat 创建。这样的地址段和从它复制、拆分出的地址段都计入共享内存的 nattch
+    attached: bool,
+}
+
+impl PmArea for PmAreaShared {
+    fn size(&self) -> usize {
+        self.frames.len() * PAGE_SIZE
+    }
+
+    fn clone_as_fork(&self) -> OSResult<Arc<Mutex<dyn PmArea>>> {
+        // 共享内存在 fork 后仍然是共享的，所以和 clone_as_cow 一样
+        self.clone_as_cow()
+    }
+
+    fn clone_as_cow(&self) -> OSResult<Arc<Mutex<dyn PmArea>>> {
+        Ok(Arc::new(Mutex::new(
+            self.with_frames(self.offset, self.frames.clone()),
+        )))
+    }
+
+    fn is_shared_frame(&self, _idx: usize) -> bool {
+        // 页帧虽然被多个地址段使用，但写入时不复制
+        false
+    }
+
+    fn unshare_frame(&mut self, idx: usize) -> OSResult<Option<PhysAddr>> {
+        self.get_frame(idx, false)
+    }
+
+    fn get_frame(&mut self, idx: usize, need_alloc: bool) -> OSResult<Option<PhysAddr>> {
+        if need_alloc && self.frames[idx].is_none() {
+            self.frames[idx] = Some(self.memory.get_frame(self.offset + idx)?);
+        }
+        Ok(self.frames[idx].as_ref().map(|f| f.start_paddr()))
+    }
+
+    fn get_huge_frame(
+        &mut self,
+        _idx: usize,
+        _count: usize,
+        _need_alloc: bool,
+    ) -> OSResult<Option<PhysAddr>> {
+        // 共享内存的页帧是逐页分配的，不保证连续
+        Ok(None)
+    }
+
+    fn sync_frame_with_file(&mut self, _idx: usize) {}
+
+    fn release_frame(&mut self, idx: usize) -> OSResult {
+        // 只是不再映射这一页，数据仍保存在共享内存中
+        self.frames[idx]
+            .take()
+            .map(|_| ())
+            .ok_or(OSError::PmAreaLazy_ReleaseNotAllocatedPage)
+    }
+
+    fn swap_out_frame(&mut self, _idx: usize) -> bool {
+        false
+    }
+
+    fn swap_in_area(&mut self, _area: usize) -> OSResult {
+        Ok(())
+    }
+
+    fn read(&mut self, offset: usize, dst: &mut [u8]) -> OSResult<usize> {
+        if offset + dst.len() > self.size() {
+            return Err(OSError::PmArea_OutOfRange);
+        }
+        // 超过共享内存结尾的部分读出来是 0
+        dst.fill(0);
+        self.memory.read(self.offset * PAGE_SIZE + offset, dst);
+        Ok(dst.len())
+    }
+
+    fn write(&mut self, offset: usize, src: &[u8]) -> OSResult<usize> {
+        if offset + src.len() > self.size() {
+            return Err(OSError::PmArea_OutOfRange);
+        }
+        self.memory.write(self.offset * PAGE_SIZE + offset, src)
+    }
+
+    fn shrink_left(&mut self, new_start: usize) -> OSResult {
+        if new_start < self.size() {
+            self.frames.drain(..addr_to_page_id(new_start));
+            self.offset += addr_to_page_id(new_start);
+            Ok(())
+        } else {
+            Err(OSError::PmArea_ShrinkFailed)
+        }
+    }
+
+    fn shrink_right(&mut self, new_end: usize) -> OSResult {
+        if new_end < self.size() {
+            self.frames.truncate(addr_to_page_id(new_end));
+            Ok(())
+        } else {
+            Err(OSError::PmArea_ShrinkFailed)
+        }
+    }
+
+    fn grow_right(&mut self, new_end: usize) -> OSResult {
+        let page_count = addr_to_page_id(new_end);
+        if page_count > addr::page_count(user_virt_addr_limit()) {
+            return Err(OSError::Memory_RunOutOfMemory);
+        }
+        if new_end > self.size() {
+            // 新增的部分同样映射到共享内存中，访问时再从共享内存中取页帧
+            self.frames.resize(page_count, None);
+            Ok(())
+        } else {
+            Err(OSError::PmArea_GrowFailed)
+        }
+    }
+
+    fn grow_left(&mut self, size: usize) -> OSResult {
+        let count = addr_to_page_id(size);
+        // 不能映射到共享内存开头之前
+        if count > self.offset {
+            return Err(OSError::PmArea_GrowFailed);
+        }
+        self.frames.splice(0..0, (0..count).map(|_| None));
+        self.offset -= count;
+        Ok(())
+    }
+
+    fn split(&mut self, left_end: usize, right_start: usize) -> OSResult<Arc<Mutex<dyn PmArea>>> {
+        if left_end <= right_start && right_start < self.size() {
+            let new_frames = self.frames.drain(addr_to_page_id(right_start)..).collect();
+            self.frames.truncate(addr_to_page_id(left_end));
+            Ok(Arc::new(Mutex::new(self.with_frames(
+                self.offset + addr_to_page_id(right_start),
+                new_frames,
+            ))))
+        } else {
+            Err(OSError::PmArea_SplitFailed)
+        }
+    }
+
+    fn is_shared_mapping(&self) -> bool {
+        true
+    }
+
+    fn shared_location(&self, offset: usize) -> Option<SharedLocation> {
+        Some(SharedLocation::Memory {
+            memory: Arc::as_ptr(&self.memory) as usize,
+            offset: self.offset * PAGE_SIZE + offset,
+        })
+    }
+}
+
+impl PmAreaShared {
+    /// 生成新的pma，映射共享内存中从第 offset 页开始的 page_count 页
+    pub fn new(memory: Arc<SharedMemory>, offset: usize, page_count: usize) -> OSResult<Self> {
+        if page_count == 0 {
+            error!("page_count is 0 in PmAreaShared");
+            return Err(OSError::PmArea_InvalidRange);
+        }
+        if page_count > addr::page_count(user_virt_addr_limit()) {
+            error!("page_count {:x} is too large in PmAreaShared: ", page_count);
+            return Err(OSError::Memory_RunOutOfMemory);
+        }
+        Ok(Self {
+            memory,
+            offset,
+            frames: vec![None; page_count],
+            attached: false,
+        })
+    }
+    /// 和 `new` 相同，但这个地址段是 shmat 创建的，会计入共享内存的 nattch
+    pub fn new_attached(
+        memory: Arc<SharedMemory>,
+        offset: usize,
+        page_count: usize,
+    ) -> OSResult<Self> {
+        let mut pma = Self::new(memory, offset, page_count)?;
+        pma.memory.attach();
+        pma.attached = true;
+        Ok(pma)
+    }
+    /// 映射同一段共享内存的新的 pma，是否计入 nattch 和自己相同
+    fn with_frames(&self, offset: usize, frames: Vec<Option<Arc<Frame>>>) -> Self {
+        if self.attached {
+            self.memory.attach();
+        }
+        Self {
+            memory: self.memory.clone(),
+            offset,
+            frames,
+            attached: self.attached,
+        }
+    }
+}
+
+impl Drop for PmAreaShared {
+    fn drop(&mut self) {
+        if self.attached {
+            self.memory.detach();
+        }
+    }
+}
+
+impl Debug for PmAreaShared {
+    fn fmt(&self, f: &mut Formatter) -> Result {
+        f.debug_struct("PmAreaShared")
+            .field("offset", &self.offset)
+            .field("size", &self.size())
+            .finish()
+    }
+}
@@ -0,0 +1,76 @@
+//! 用户地址空间的布局
+//!
+//! 开启地址随机化时，用户栈和用户堆、mmap 的起始位置和需要重定位的 elf 的基地址
+//! 都会加上一个按页对齐的随机偏移。偏移的范围取决于启动时选定的分页模式
+//!
+//! 用户栈在 `USER_STACK_SIZE` 之外可以向下增长，用户堆从 `heap_start` 开始向上增长，两者之间至少隔开 `USER_STACK_GUARD_GAP`
+
+use super::{user_stack_offset, PagingMode};
+use crate::{
+    constants::{
+        ASLR_ELF_BITS, ASLR_ELF_BITS_SV48, ASLR_MMAP_BASE, ASLR_MMAP_BASE_SV48, ASLR_MMAP_BITS,
+        ASLR_MMAP_BITS_SV48, ASLR_STACK_BITS, ASLR_STACK_BITS_SV48, ELF_BASE_RELOCATE, PAGE_SIZE,
+        USER_HEAP_STACK_DISTANCE, USER_STACK_SIZE,
+    },
+    random::random_u64,
+};
+
+/// 用户栈所在内存段的名字。访问到它下方未映射的地址时，这个内存段会向下增长
+pub const USER_STACK_AREA_NAME: &str = "user_stack";
+/// 用户堆所在内存段的名字，由 brk 调整大小
+pub const USER_HEAP_AREA_NAME: &str = "user_heap";
+
+/// 一个用户地址空间中各部分的起始位置。在加载用户程序前确定，fork 时随地址空间一起复制
+#[derive(Debug, Clone, Copy)]
+pub struct UserLayout {
+    /// 用户栈的初始栈底
+    pub stack_bottom: usize,
+    /// 用户堆的起始位置，从这里开始往上增长
+    pub heap_start: usize,
+    /// mmap 不指定地址时，从这里开始往后找空闲区间
+    pub mmap_base: usize,
+    /// elf 的第一段地址为 0 时(如动态库)，把它放在这个位置
+    pub elf_base: usize,
+}
+
+impl UserLayout {
+    /// 生成一个新的布局。如 randomize 为 false，则每次都得到相同的布局
+    pub fn new(randomize: bool) -> Self {
+        if !randomize {
+            return Self::with_stack_bottom(user_stack_offset(), 0, ELF_BASE_RELOCATE);
+        }
+        let (stack_bits, mmap_base, mmap_bits, elf_bits) = match PagingMode::current() {
+            PagingMode::Sv39 => (
+                ASLR_STACK_BITS,
+                ASLR_MMAP_BASE,
+                ASLR_MMAP_BITS,
+                ASLR_ELF_BITS,
+            ),
+            PagingMode::Sv48 => (
+                ASLR_STACK_BITS_SV48,
+                ASLR_MMAP_BASE_SV48,
+                ASLR_MMAP_BITS_SV48,
+                ASLR_ELF_BITS_SV48,
+            ),
+        };
+        Self::with_stack_bottom(
+            user_stack_offset() - random_pages(stack_bits),
+            mmap_base + random_pages(mmap_bits),
+            ELF_BASE_RELOCATE + random_pages(elf_bits),
+        )
+    }
+    /// 用户堆放在用户栈顶以下 `USER_HEAP_STACK_DISTANCE` 的位置，随栈一起随机化
+    fn with_stack_bottom(stack_bottom: usize, mmap_base: usize, elf_base: usize) -> Self {
+        Self {
+            stack_bottom,
+            heap_start: stack_bottom + USER_STACK_SIZE - USER_HEAP_STACK_DISTANCE,
+            mmap_base,
+            elf_base,
+        }
+    }
+}
+
+/// 随机取 [0, 2^bits) 页，返回对应的字节数
+fn random_pages(bits: usize) -> usize {
+    (random_u64() as usize & ((1 << bits) - 1)) * PAGE_SIZE
+}
@@ -3,23 +3,31 @@
 pub mod addr;
 mod allocator;
 mod areas;
+mod layout;
 mod page_table;
+mod shm;
+mod swap;
 mod user;
 mod vmm;
 
 use crate::{
+    cmdline::device_end,
     constants::{
-        DEVICE_END, DEVICE_START, PAGE_SIZE, PHYS_MEMORY_END, PHYS_VIRT_OFFSET,
-        USER_VIRT_ADDR_LIMIT,
+        DEVICE_START, PAGE_SIZE, PHYS_LINEAR_MAP_END, PHYS_VIRT_OFFSET, USER_STACK_OFFSET,
+        USER_STACK_OFFSET_SV48, USER_VIRT_ADDR_LIMIT, USER_VIRT_ADDR_LIMIT_SV48,
     },
     error::OSResult,
+    fdt,
 };
 use alloc::vec::Vec;
 use core::ops::Range;
 
 pub use addr::*;
-pub use allocator::{allocator_init, FdAllocator, Frame, Tid};
-pub use page_table::{PTEFlags, PageTable, PageTableEntry};
+pub use allocator::{
+    allocator_init, frame_stat, heap_init, heap_stat, refill_heap_reserve, slab_stats, FdAllocator,
+    Frame, FrameStat, HeapStat, SlabStat, Tid,
+};
+pub use page_table::{init_paging_mode, PTEFlags, PageSize, PageTable, PageTableEntry, PagingMode};
 
 /*
 #[cfg(target_arch = "riscv64")]
@@ -29,29 +37,78 @@ pub use page_table_impl_rv64_sv39::{
 };
 */
 
-pub use areas::{PmArea, PmAreaFixed, PmAreaLazy, VmArea};
+pub use areas::{PmArea, PmAreaFixed, PmAreaLazy, PmAreaShared, SharedLocation, VmArea};
+
+pub use layout::{UserLayout, USER_HEAP_AREA_NAME, USER_STACK_AREA_NAME};
+
+pub use shm::{
+    shm_attach, shm_get, shm_remove, shm_stat, SharedMemory, ShmSegmentStat, SHM_AREA_NAME,
+};
 
 pub use vmm::{
-    enable_kernel_page_table, handle_kernel_page_fault, new_memory_set_for_task, MemorySet,
+    enable_kernel_page_table, handle_kernel_page_fault, memory_set_merge_test,
+    new_memory_set_for_task, MemorySet,
+};
+
+pub use swap::{
+    reclaim_pages, register_memory_set, swap_off, swap_on, swap_stat, SwapSlot, SwapStorage,
 };
 
 pub use user::{UserPtr, UserPtrUnchecked};
 
-/// 获取从kernel_end的下一页起，至物理内存最后一页的物理页号
+/// 获取可以交给页帧分配器的物理内存，即设备树中 kernel_end 之后的物理内存，
+/// 去掉其中保留的部分和文件系统镜像所在的部分。每段都按页对齐
 pub fn get_phys_memory_regions() -> Vec<Range<usize>> {
     extern "C" {
         fn kernel_end();
     }
-    let start = align_up(virt_to_phys(kernel_end as usize));
-    let end = PHYS_MEMORY_END;
-    vec![start..end, 0xa000_0000..0xbe00_0000]
+    let kernel_end = align_up(virt_to_phys(kernel_end as usize));
+    let mut reserved = fdt::reserved_regions();
+    // 内置的镜像不在这里，但它被映射到了这段物理地址在线性映射中对应的虚拟地址上，所以也不能使用
+    reserved.push(DEVICE_START..device_end());
+    let mut regions: Vec<Range<usize>> = fdt::memory_regions()
+        .into_iter()
+        .map(|region| region.start.max(kernel_end)..region.end.min(PHYS_LINEAR_MAP_END))
+        .collect();
+    for hole in reserved {
+        regions = regions
+            .into_iter()
+            .flat_map(|region| {
+                [
+                    region.start..region.end.min(hole.start),
+                    region.start.max(hole.end)..region.end,
+                ]
+            })
+            .collect();
+    }
+    regions
+        .into_iter()
+        .map(|region| align_up(region.start)..align_down(region.end))
+        .filter(|region| region.start < region.end)
+        .collect()
+}
+
+/// 用户地址最大不能超过这个值。取决于启动时选定的分页模式
+pub fn user_virt_addr_limit() -> usize {
+    match PagingMode::current() {
+        PagingMode::Sv39 => USER_VIRT_ADDR_LIMIT,
+        PagingMode::Sv48 => USER_VIRT_ADDR_LIMIT_SV48,
+    }
+}
+
+/// 用户栈底位置，同时也是最开始的用户堆顶位置。取决于启动时选定的分页模式
+pub fn user_stack_offset() -> usize {
+    match PagingMode::current() {
+        PagingMode::Sv39 => USER_STACK_OFFSET,
+        PagingMode::Sv48 => USER_STACK_OFFSET_SV48,
+    }
 }
 
 #[allow(dead_code)]
 pub fn create_mapping(ms: &mut MemorySet) -> OSResult {
     ms.push(VmArea::from_fixed_pma(
         DEVICE_START,
-        DEVICE_END,
+        device_end(),
         PHYS_VIRT_OFFSET,
         PTEFlags::READ | PTEFlags::WRITE,
         "ramdisk",
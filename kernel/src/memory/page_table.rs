@@ -2,16 +2,27 @@
 
 //#![deny(missing_docs)]
 
-use super::{align_down, align_up, phys_to_virt, pte_idx_of_virt_addr, Frame, PhysAddr, VirtAddr};
+use super::{
+    align_down, align_up, phys_to_virt, pte_idx_of_virt_addr, virt_to_phys, Frame, PhysAddr,
+    VirtAddr, PAGE_SIZE,
+};
+use crate::arch;
+use crate::cmdline::is_single_core;
+use crate::constants::{PHYS_MEMORY_OFFSET, PHYS_VIRT_OFFSET, USE_SV48};
 use crate::error::{OSError, OSResult};
-use alloc::vec::Vec;
+use alloc::{
+    alloc::{alloc_zeroed, dealloc},
+    vec::Vec,
+};
+use core::sync::atomic::{AtomicUsize, Ordering};
+use core::{alloc::Layout, ptr::NonNull};
 use riscv::{
     asm::{sfence_vma, sfence_vma_all},
     register::satp,
 };
 
 bitflags! {
-    /// 页表项各位的定义。目前对应 riscv64 的 Sv39 模式
+    /// 页表项各位的定义。Sv39 和 Sv48 模式中是相同的
     pub struct PTEFlags: u8 {
         const VALID = 1 << 0;
         const READ = 1 << 1;
@@ -24,6 +35,124 @@ bitflags! {
     }
 }
 
+/// 每个页表页中的页表项个数
+const PTE_COUNT_PER_TABLE: usize = 512;
+
+/// 分页模式，值即为 satp 寄存器中 MODE 域的值
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PagingMode {
+    /// 三级页表，39 位虚拟地址
+    Sv39 = 8,
+    /// 四级页表，48 位虚拟地址
+    Sv48 = 9,
+}
+
+/// 启动时选定的分页模式。所有核和所有页表都使用同一种模式
+static PAGING_MODE: AtomicUsize = AtomicUsize::new(PagingMode::Sv39 as usize);
+
+impl PagingMode {
+    /// 获取当前使用的分页模式
+    pub fn current() -> Self {
+        if PAGING_MODE.load(Ordering::Relaxed) == Self::Sv48 as usize {
+            Self::Sv48
+        } else {
+            Self::Sv39
+        }
+    }
+    /// 页表的级数
+    pub const fn levels(self) -> usize {
+        match self {
+            Self::Sv39 => 3,
+            Self::Sv48 => 4,
+        }
+    }
+    /// 对应 riscv crate 中的模式
+    fn satp_mode(self) -> satp::Mode {
+        match self {
+            Self::Sv39 => satp::Mode::Sv39,
+            Self::Sv48 => satp::Mode::Sv48,
+        }
+    }
+}
+
+/// 选择分页模式。需要在页帧分配器初始化之后、构造内核页表之前，由其中一个核调用且仅调用一次。
+///
+/// 如果设置了 `USE_SV48`，则尝试把 satp 写成 Sv48 模式再读回来，硬件不支持时写入无效，此时退回 Sv39
+pub fn init_paging_mode() {
+    if USE_SV48 && probe_sv48() {
+        PAGING_MODE.store(PagingMode::Sv48 as usize, Ordering::Relaxed);
+    }
+    info!("paging mode: {:?}", PagingMode::current());
+}
+
+/// 检查硬件是否支持 Sv48。
+///
+/// 当前的启动页表是 Sv39 的，内核在高地址运行。在 Sv48 中，内核地址的第 \[47:39\] 位是 511，
+/// 而第 \[38:30\] 位的下标和 Sv39 的根页表相同。
+/// 所以只要新建一个根页表，让第 511 项指向当前的根页表，就可以在切换后继续运行
+fn probe_sv48() -> bool {
+    let mut frame = match Frame::new() {
+        Some(frame) => frame,
+        None => return false,
+    };
+    frame.zero();
+    let old_root = PageTable::current_root_paddr();
+    unsafe {
+        get_pte_at(frame.start_paddr(), PTE_COUNT_PER_TABLE - 1).set_all(old_root, PTEFlags::VALID);
+        satp::set(satp::Mode::Sv48, 0, frame.start_paddr() >> 12);
+        sfence_vma_all();
+        let supported = satp::read().mode() == satp::Mode::Sv48;
+        satp::set(satp::Mode::Sv39, 0, old_root >> 12);
+        sfence_vma_all();
+        supported
+    }
+}
+
+/// 页表中的叶子页表项可以映射的页大小。
+///
+/// 除了最后一级页表中的 4KiB 页，倒数第二级和倒数第三级页表中的页表项也可以直接作为叶子，分别映射 2MiB 和 1GiB 的大页。
+/// Sv48 中根页表的页表项还可以映射 512GiB 的页，这里不使用
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PageSize {
+    /// 4KiB 页
+    Size4K,
+    /// 2MiB 大页(megapage)
+    Size2M,
+    /// 1GiB 大页(gigapage)
+    Size1G,
+}
+
+impl PageSize {
+    /// 页的字节数
+    pub const fn bytes(self) -> usize {
+        match self {
+            Self::Size4K => PAGE_SIZE,
+            Self::Size2M => PAGE_SIZE * PTE_COUNT_PER_TABLE,
+            Self::Size1G => PAGE_SIZE * PTE_COUNT_PER_TABLE * PTE_COUNT_PER_TABLE,
+        }
+    }
+    /// 包含多少个 4KiB 页
+    pub const fn page_count(self) -> usize {
+        self.bytes() / PAGE_SIZE
+    }
+    /// 叶子页表项所在的页表级数，最后一级页表为 0
+    const fn level(self) -> usize {
+        match self {
+            Self::Size4K => 0,
+            Self::Size2M => 1,
+            Self::Size1G => 2,
+        }
+    }
+    /// 第 level 级页表中的叶子页表项对应的页大小
+    const fn from_level(level: usize) -> Self {
+        match level {
+            0 => Self::Size4K,
+            1 => Self::Size2M,
+            _ => Self::Size1G,
+        }
+    }
+}
+
 //#[derive(Copy, Clone)]
 #[repr(C)]
 /// 页表项本体
@@ -62,6 +191,10 @@ impl PageTableEntry {
     pub fn executable(&self) -> bool {
         (self.flags() & PTEFlags::EXECUTE) != PTEFlags::empty()
     }
+    /// 是否是叶子页表项。有效且 R/W/X 不全为 0 的页表项直接映射一个页，否则指向下一级页表
+    pub fn is_leaf(&self) -> bool {
+        self.is_valid() && (self.readable() || self.writable() || self.executable())
+    }
 }
 
 /// 页表项(修改部分)
@@ -79,14 +212,12 @@ impl PageTableEntry {
     pub fn set_all(&mut self, paddr: PhysAddr, flags: PTEFlags) {
         self.bits = ((paddr >> 12) << 10) | flags.bits as usize;
     }
-    /// 申请一个页面，并返回申请到的帧
+    /// 申请一个页表页，并返回申请到的页
     /// 如果返回 None 说明内存已满
-    pub fn alloc_and_set(&mut self) -> Option<Frame> {
-        let mut frame = Frame::new()?;
-        //清空页面，可能是比较耗时的一点
-        frame.zero();
-        self.set_all(frame.start_paddr(), PTEFlags::VALID);
-        Some(frame)
+    pub fn alloc_and_set(&mut self) -> Option<PageTablePage> {
+        let page = PageTablePage::new()?;
+        self.set_all(page.start_paddr(), PTEFlags::VALID);
+        Some(page)
     }
     /// 清空表项
     pub fn clear(&mut self) {
@@ -102,18 +233,48 @@ pub unsafe fn get_pte_at(paddr: PhysAddr, idx: usize) -> &'static mut PageTableE
         .unwrap()
 }
 
+/// 一个页表页。从堆中 page_table 的 slab 缓存里分配，Drop 时还回去
+pub struct PageTablePage {
+    ptr: NonNull<u8>,
+}
+
+// 页表页只由持有它的 PageTable 访问
+unsafe impl Send for PageTablePage {}
+unsafe impl Sync for PageTablePage {}
+
+/// 页表页的内存布局，也是 page_table 的 slab 缓存负责的布局
+const PAGE_TABLE_LAYOUT: Layout =
+    unsafe { Layout::from_size_align_unchecked(PAGE_SIZE, PAGE_SIZE) };
+
+impl PageTablePage {
+    /// 申请一个清空的页表页。如果返回 None 说明内存已满
+    pub fn new() -> Option<Self> {
+        //清空页面，可能是比较耗时的一点
+        NonNull::new(unsafe { alloc_zeroed(PAGE_TABLE_LAYOUT) }).map(|ptr| Self { ptr })
+    }
+    /// 页表页的物理地址
+    pub fn start_paddr(&self) -> PhysAddr {
+        virt_to_phys(self.ptr.as_ptr() as VirtAddr)
+    }
+}
+
+impl Drop for PageTablePage {
+    fn drop(&mut self) {
+        unsafe { dealloc(self.ptr.as_ptr(), PAGE_TABLE_LAYOUT) };
+    }
+}
+
 /// page table structure
 pub struct PageTable {
     root_paddr: PhysAddr,
-    frames: Vec<Frame>,
+    frames: Vec<PageTablePage>,
 }
 
 /// 页表数据结构本身操作
 impl PageTable {
     // 建立页表，并申请一个根页面
     pub fn new() -> OSResult<Self> {
-        if let Some(mut frame) = Frame::new() {
-            frame.zero();
+        if let Some(frame) = PageTablePage::new() {
             Ok(PageTable {
                 root_paddr: frame.start_paddr(),
                 frames: vec![frame],
@@ -144,42 +305,78 @@ impl PageTable {
     pub unsafe fn self_as_usize(&self) -> usize {
         self as *const Self as usize
     }
-    /// 查找一个页表项，如为空则新建页面
+    /// 查找一个 4KiB 页的页表项，如为空则新建页面
     fn find_pte_create(&mut self, vaddr: VirtAddr) -> Option<&mut PageTableEntry> {
-        let (line0, line1, line2) = pte_idx_of_virt_addr(vaddr);
-        //查第一级页表
-        let pte = unsafe { get_pte_at(self.get_root_paddr(), line0) };
-        let paddr = self.get_addr_create(pte)?;
-        //println!("pte {:x}, paddr {:x}", pte.bits, paddr);
-        //查第二级页表
-        let pte = unsafe { get_pte_at(paddr, line1) };
-        let paddr = self.get_addr_create(pte)?;
-        //println!("pte {:x}, paddr {:x}", pte.bits, paddr);
-        //查第三级页表
-        unsafe { Some(get_pte_at(paddr, line2)) }
-    }
-    /// 查找一个页表项，不申请新页面
+        self.find_pte_create_at(vaddr, PageSize::Size4K)
+    }
+    /// 查找映射 size 大小的页需要的那一级页表项，如中间的页表为空则新建页面。
+    ///
+    /// 如果路上遇到更大的页，则先把它拆成小页
+    fn find_pte_create_at(
+        &mut self,
+        vaddr: VirtAddr,
+        size: PageSize,
+    ) -> Option<&mut PageTableEntry> {
+        let mut paddr = self.get_root_paddr();
+        // 从根页表往下查，直到 size 对应的那一级
+        for level in (size.level() + 1..PagingMode::current().levels()).rev() {
+            let pte = unsafe { get_pte_at(paddr, pte_idx_of_virt_addr(vaddr, level)) };
+            if pte.is_leaf() {
+                self.split_leaf(pte, PageSize::from_level(level))?;
+            }
+            //println!("pte {:x}, paddr {:x}", pte.bits, paddr);
+            paddr = self.get_addr_create(pte)?;
+        }
+        unsafe { Some(get_pte_at(paddr, pte_idx_of_virt_addr(vaddr, size.level()))) }
+    }
+    /// 查找一个页表项，不申请新页面。
+    ///
+    /// 如果 vaddr 在一个大页中，则返回这个大页的页表项
     fn find_pte(&self, vaddr: VirtAddr) -> Option<*mut PageTableEntry> {
-        let (line0, line1, line2) = pte_idx_of_virt_addr(vaddr);
-        //查第一级页表
-        let pte = unsafe { get_pte_at(self.get_root_paddr(), line0) };
-        if !pte.is_valid() {
-            return None;
+        self.find_leaf(vaddr).map(|(pte, _)| pte)
+    }
+    /// 查找 vaddr 所在页的页表项和页大小，不申请新页面
+    fn find_leaf(&self, vaddr: VirtAddr) -> Option<(*mut PageTableEntry, PageSize)> {
+        let mut paddr = self.get_root_paddr();
+        for level in (1..PagingMode::current().levels()).rev() {
+            let pte = unsafe { get_pte_at(paddr, pte_idx_of_virt_addr(vaddr, level)) };
+            if !pte.is_valid() {
+                return None;
+            }
+            if pte.is_leaf() {
+                return Some((pte as *mut _, PageSize::from_level(level)));
+            }
+            paddr = pte.addr();
         }
-        let paddr = pte.addr();
-        //查第二级页表
-        let pte = unsafe { get_pte_at(paddr, line1) };
-        if !pte.is_valid() {
-            return None;
+        //查最后一级页表
+        unsafe {
+            Some((
+                get_pte_at(paddr, pte_idx_of_virt_addr(vaddr, 0)) as *mut _,
+                PageSize::Size4K,
+            ))
         }
-        let paddr = pte.addr();
-        //查第三级页表
-        unsafe { Some(get_pte_at(paddr, line2)) }
+    }
+    /// 把一个大小为 size 的大页的叶子页表项拆成下一级页表中的 512 个小页，权限不变
+    fn split_leaf(&mut self, pte: &mut PageTableEntry, size: PageSize) -> Option<()> {
+        let sub_size = PageSize::from_level(size.level() - 1).bytes();
+        let frame = PageTablePage::new()?;
+        let flags = pte.flags();
+        for idx in 0..PTE_COUNT_PER_TABLE {
+            unsafe {
+                get_pte_at(frame.start_paddr(), idx).set_all(pte.addr() + idx * sub_size, flags);
+            }
+        }
+        pte.set_all(frame.start_paddr(), PTEFlags::VALID);
+        self.frames.push(frame);
+        Some(())
     }
     /// 映射内核段的页表
     pub unsafe fn map_kernel_regions(&self, kernel_pt: &PageTable) {
-        // 当前内核段在 0xffff_ffff_8000_0000 至 0xffff_ffff_ffff_ffff
-        for line in 510usize..512 {
+        // 当前内核段在 0xffff_ffff_8000_0000 至 0xffff_ffff_ffff_ffff，
+        // 对应 Sv39 根页表的第 510 和 511 项，或者 Sv48 根页表的第 511 项
+        let root_level = PagingMode::current().levels() - 1;
+        let start = pte_idx_of_virt_addr(PHYS_VIRT_OFFSET + PHYS_MEMORY_OFFSET, root_level);
+        for line in start..PTE_COUNT_PER_TABLE {
             let from_pte = get_pte_at(kernel_pt.get_root_paddr(), line);
             let to_pte = get_pte_at(self.get_root_paddr(), line);
             to_pte.bits = from_pte.bits;
@@ -207,10 +404,64 @@ impl PageTable {
             Err(OSError::PageTable_FrameAllocFailed)
         }
     }
-    /// 修改一个页表项的权限
+    /// 映射一个 size 大小的大页，vaddr 和 paddr 都需要按 size 对齐。
+    ///
+    /// 如果这个范围内已经建好了下一级页表但其中还没有任何映射(例如 lazy 分配的地址段)，则回收这个页表页，换成大页
+    pub fn map_huge(
+        &mut self,
+        vaddr: VirtAddr,
+        paddr: PhysAddr,
+        size: PageSize,
+        flags: PTEFlags,
+    ) -> OSResult {
+        if vaddr % size.bytes() != 0 || paddr % size.bytes() != 0 || flags.is_empty() {
+            return Err(OSError::PageTable_InvalidHugePage);
+        }
+        let pte = self
+            .find_pte_create_at(vaddr, size)
+            .ok_or(OSError::PageTable_FrameAllocFailed)? as *mut PageTableEntry;
+        let pte = unsafe { &mut *pte };
+        if pte.is_valid() {
+            let table = pte.addr();
+            let is_empty_table = !pte.is_leaf()
+                && (0..PTE_COUNT_PER_TABLE)
+                    .all(|idx| unsafe { !get_pte_at(table, idx).is_valid() });
+            if !is_empty_table {
+                error!("vaddr {:x} is mapped before mapping huge page", vaddr);
+                return Err(OSError::PageTable_PageAlreadyMapped);
+            }
+            self.frames.retain(|frame| frame.start_paddr() != table);
+        }
+        // 因为 U740 板子不支持处理器设置 A/D，所以需手动设置
+        pte.set_all(
+            paddr,
+            flags | PTEFlags::VALID | PTEFlags::ACCESS | PTEFlags::DIRTY,
+        );
+        Ok(())
+    }
+    /// 把 vaddr 所在的大页拆成 4KiB 页，映射的物理地址和权限都不变。
+    ///
+    /// 只需要修改大页中的一部分时调用。如果 vaddr 不在大页中，则什么也不做
+    pub fn split_huge_page(&mut self, vaddr: VirtAddr) -> OSResult {
+        match self.page_size(vaddr) {
+            Some(PageSize::Size4K) | None => Ok(()),
+            Some(_) => self
+                .find_pte_create(vaddr)
+                .map(|_| ())
+                .ok_or(OSError::PageTable_FrameAllocFailed),
+        }
+    }
+    /// 获取 vaddr 所在页的大小。如果 vaddr 未映射则返回 None
+    pub fn page_size(&self, vaddr: VirtAddr) -> Option<PageSize> {
+        self.find_leaf(vaddr)
+            .filter(|&(pte, _)| unsafe { (*pte).is_valid() })
+            .map(|(_, size)| size)
+    }
+    /// 修改一个页表项的权限。如果 vaddr 在大页中，则修改的是整个大页的权限
     #[allow(unused)]
     pub fn set_flags(&mut self, vaddr: VirtAddr, flags: PTEFlags) -> OSResult {
-        if let Some(pte) = self.find_pte_create(vaddr) {
+        if let Some(pte) = self.find_pte(vaddr) {
+            let pte = unsafe { &mut *pte };
             // 有效时才写入
             if pte.is_valid() {
                 // 因为 U740 板子不支持处理器设置 A/D，所以需手动设置
@@ -220,10 +471,10 @@ impl PageTable {
                 Err(OSError::PageTable_PageNotMapped)
             }
         } else {
-            Err(OSError::PageTable_FrameAllocFailed)
+            Err(OSError::PageTable_PageNotMapped)
         }
     }
-    /// 取消映射
+    /// 取消映射。如果 vaddr 在大页中，则取消整个大页的映射
     #[allow(unused)]
     pub fn unmap(&mut self, vaddr: VirtAddr) -> OSResult {
         if let Some(pte) = self.find_pte(vaddr) {
@@ -246,21 +497,24 @@ impl PageTable {
         self.find_pte(vaddr).map(|pte| *pte)
     }
     */
-    /// 生成该页表对应的 satp 寄存器的值(使用Sv39模式)
+    /// 生成该页表对应的 satp 寄存器的值(使用启动时选定的分页模式)
     pub fn token(&self) -> usize {
-        8usize << 60 | self.root_paddr
+        (PagingMode::current() as usize) << 60 | self.root_paddr
     }
 }
 
 /// 页表的与硬件相关的功能
 impl PageTable {
-    /// 获取 PTE，可直接对其修改
+    /// 获取 PTE，可直接对其修改。
+    ///
+    /// 如果 vaddr 在大页中，拿到的是大页的页表项，调用者需要先用 `split_huge_page` 拆开才能按 4KiB 页修改
     pub fn get_entry(&self, vaddr: VirtAddr) -> Option<*mut PageTableEntry> {
         self.find_pte(vaddr)
     }
-    /// 询问一个虚拟地址对应的物理地址
+    /// 询问一个虚拟地址所在页对应的物理地址。如果在大页中，则返回其中对应的 4KiB 页的地址
     pub fn query(&mut self, vaddr: VirtAddr) -> Option<PhysAddr> {
-        unsafe { Some((*self.find_pte(vaddr)?).addr()) }
+        let (pte, size) = self.find_leaf(vaddr)?;
+        unsafe { Some((*pte).addr() + align_down(vaddr % size.bytes())) }
     }
     /// 获取第一层页表所在的物理页地址
     pub fn current_root_paddr() -> PhysAddr {
@@ -291,7 +545,7 @@ impl PageTable {
     }
     /// 写 satp 寄存器切换页表
     pub unsafe fn set_current_root_paddr(root_paddr: PhysAddr) {
-        satp::set(satp::Mode::Sv39, 0, root_paddr >> 12)
+        satp::set(PagingMode::current().satp_mode(), 0, root_paddr >> 12)
     }
 
     pub fn flush_tlb(&self, vaddr: Option<VirtAddr>) {
@@ -304,6 +558,15 @@ impl PageTable {
         }
     }
 
+    /// 刷新所有核的 TLB。同一个地址空间的其他线程可能正在别的核上运行，
+    /// 修改或删除已有的映射后，要在释放对应页帧之前调用
+    pub fn flush_tlb_all_harts(&self) {
+        self.flush_tlb(None);
+        if !is_single_core() {
+            arch::remote_flush_tlb();
+        }
+    }
+
     pub fn get_root_paddr(&self) -> PhysAddr {
         self.root_paddr
     }
@@ -0,0 +1,254 @@
+//! 共享内存
+//!
+//! `SharedMemory` 是一段可以同时被多个地址段映射的内存，页帧属于它自己而不是某个地址段，
+//! 所以映射它的地址段在 fork 时只需要复制引用，父子进程看到的是同一份数据。它有三种来源：
+//! - 带 MAP_SHARED 的匿名 mmap，每次 mmap 新建一个；
+//! - /dev/shm 下的文件(即 shm_open)，文件本身就是一个 `SharedMemory`；
+//! - System V 共享内存(shmget)，由这里的 `SHM_SEGMENTS` 按 id 保存。
+//!
+//! 锁的顺序为：地址段 -> 共享内存，以及 SHM_SEGMENTS -> 共享内存
+
+use super::{addr::page_count, Frame};
+use crate::{
+    constants::{PAGE_SIZE, SHM_SIZE_LIMIT},
+    error::{OSError, OSResult},
+};
+use alloc::{collections::BTreeMap, sync::Arc, vec::Vec};
+use core::{
+    slice,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+use lock::Mutex;
+
+/// 一段共享内存。页帧在第一次访问时才分配
+pub struct SharedMemory {
+    inner: Mutex<SharedMemoryInner>,
+    /// 通过 shmat 映射了它的地址段数，即 shmctl(IPC_STAT) 返回的 nattch
+    nattch: AtomicUsize,
+}
+
+struct SharedMemoryInner {
+    /// 已分配的页帧。可能比 size 更长，因为 mmap 可以映射超过文件结尾的部分
+    frames: Vec<Option<Arc<Frame>>>,
+    /// 共享内存的字节数，相当于文件的大小
+    size: usize,
+}
+
+impl SharedMemory {
+    /// 新建一段大小为 size 的共享内存，暂不分配页帧
+    pub fn new(size: usize) -> Self {
+        Self {
+            inner: Mutex::new(SharedMemoryInner {
+                frames: Vec::new(),
+                size,
+            }),
+            nattch: AtomicUsize::new(0),
+        }
+    }
+    /// 当前大小
+    pub fn size(&self) -> usize {
+        self.inner.lock().size
+    }
+    /// 修改大小，一般是 ftruncate 要求的。缩小时超出部分的页帧会被丢弃，最后一页的剩余部分置零
+    ///
+    /// 仍映射着被丢弃的页的地址段持有页帧的引用，所以不会访问到已释放的内存
+    pub fn set_size(&self, size: usize) {
+        let mut inner = self.inner.lock();
+        if size < inner.size {
+            inner.frames.truncate(page_count(size));
+            let pgoff = size % PAGE_SIZE;
+            if let Some(Some(frame)) = inner.frames.get(size / PAGE_SIZE) {
+                // 可能有地址段同时映射着这一页，所以只能通过指针修改
+                unsafe {
+                    slice::from_raw_parts_mut(frame.as_mut_ptr().add(pgoff), PAGE_SIZE - pgoff)
+                        .fill(0);
+                }
+            }
+        }
+        inner.size = size;
+    }
+    /// 获取第 idx 页的页帧，未分配时分配一个置零的页帧
+    pub fn get_frame(&self, idx: usize) -> OSResult<Arc<Frame>> {
+        self.inner.lock().get_frame(idx)
+    }
+    /// 从 offset 开始读取数据，不超过共享内存的结尾。返回读取的长度
+    pub fn read(&self, offset: usize, buf: &mut [u8]) -> usize {
+        let inner = self.inner.lock();
+        let end = (offset + buf.len()).min(inner.size);
+        let mut pos = offset;
+        while pos < end {
+            let pgoff = pos % PAGE_SIZE;
+            let n = (PAGE_SIZE - pgoff).min(end - pos);
+            let dst = &mut buf[pos - offset..pos - offset + n];
+            // 未分配的页读出来都是 0
+            match inner.frames.get(pos / PAGE_SIZE) {
+                Some(Some(frame)) => dst.copy_from_slice(&frame.as_slice()[pgoff..pgoff + n]),
+                _ => dst.fill(0),
+            }
+            pos += n;
+        }
+        end.saturating_sub(offset)
+    }
+    /// 把数据写到从 offset 开始的位置，超过结尾时会扩大共享内存。返回写入的长度。
+    ///
+    /// 共享内存最大为 `SHM_SIZE_LIMIT`，超过的部分不写入；内存不足时只写入已经拿到页帧的部分。
+    /// 一个字节都写不进去时返回 error
+    pub fn write(&self, offset: usize, buf: &[u8]) -> OSResult<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        if offset >= SHM_SIZE_LIMIT {
+            return Err(OSError::Shm_TooLarge);
+        }
+        let buf = &buf[..buf.len().min(SHM_SIZE_LIMIT - offset)];
+        let mut inner = self.inner.lock();
+        let mut pos = offset;
+        while pos < offset + buf.len() {
+            let pgoff = pos % PAGE_SIZE;
+            let n = (PAGE_SIZE - pgoff).min(offset + buf.len() - pos);
+            let frame = match inner.get_frame(pos / PAGE_SIZE) {
+                Ok(frame) => frame,
+                // 内存不足时返回已经写入的部分
+                Err(e) if pos == offset => return Err(e),
+                Err(_) => break,
+            };
+            unsafe {
+                slice::from_raw_parts_mut(frame.as_mut_ptr().add(pgoff), n)
+                    .copy_from_slice(&buf[pos - offset..pos - offset + n]);
+            }
+            pos += n;
+        }
+        inner.size = inner.size.max(pos);
+        Ok(pos - offset)
+    }
+    /// 记录一个 shmat 创建的地址段开始映射这段共享内存
+    pub fn attach(&self) {
+        self.nattch.fetch_add(1, Ordering::Relaxed);
+    }
+    /// 记录一个 shmat 创建的地址段不再映射这段共享内存
+    pub fn detach(&self) {
+        self.nattch.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+impl SharedMemoryInner {
+    fn get_frame(&mut self, idx: usize) -> OSResult<Arc<Frame>> {
+        if idx >= self.frames.len() {
+            self.frames.resize(idx + 1, None);
+        }
+        if self.frames[idx].is_none() {
+            let mut frame = Frame::new().ok_or(OSError::Memory_RunOutOfMemory)?;
+            frame.zero();
+            self.frames[idx] = Some(Arc::new(frame));
+        }
+        Ok(self.frames[idx].clone().unwrap())
+    }
+}
+
+/// shmat 映射的地址段的名字。shmdt 只能删除这样的地址段
+pub const SHM_AREA_NAME: &str = "from shmat";
+
+/// 一个 System V 共享内存段
+struct ShmSegment {
+    /// shmget 时给出的 key。IPC_PRIVATE 创建的段没有 key
+    key: Option<usize>,
+    memory: Arc<SharedMemory>,
+    /// 权限位，即 shmget 时 shmflg 的低 9 位
+    mode: u32,
+    /// 创建者的 pid
+    cpid: usize,
+    /// 最后一次 shmat / shmdt 的进程的 pid
+    lpid: usize,
+}
+
+/// 所有 System V 共享内存段，按 id 存放。
+///
+/// IPC_RMID 后的段会立即从这里删除，已经 shmat 的地址段仍持有 `SharedMemory` 的引用，直到 shmdt
+static SHM_SEGMENTS: Mutex<BTreeMap<usize, ShmSegment>> = Mutex::new(BTreeMap::new());
+/// 下一个新建的共享内存段的 id。只增不减，删除的段的 id 不会被重新使用
+static NEXT_SHM_ID: AtomicUsize = AtomicUsize::new(1);
+
+/// 共享内存段的信息，由 shmctl(IPC_STAT) 返回
+pub struct ShmSegmentStat {
+    pub key: usize,
+    pub size: usize,
+    pub mode: u32,
+    pub cpid: usize,
+    pub lpid: usize,
+    /// 通过 shmat 映射了这个段的地址段数
+    pub nattch: usize,
+}
+
+/// 查找或创建一个共享内存段，返回它的 id。
+///
+/// key 为 None 时总是新建。否则如 key 已存在且没有 excl，返回已有的段；如 key 不存在且有 create，则新建
+pub fn shm_get(
+    key: Option<usize>,
+    size: usize,
+    create: bool,
+    excl: bool,
+    mode: u32,
+    pid: usize,
+) -> OSResult<usize> {
+    let mut segments = SHM_SEGMENTS.lock();
+    if let Some(key) = key {
+        if let Some((&id, segment)) = segments.iter().find(|(_, seg)| seg.key == Some(key)) {
+            if create && excl {
+                return Err(OSError::Shm_KeyExists);
+            }
+            if size > segment.memory.size() {
+                return Err(OSError::Shm_InvalidSize);
+            }
+            return Ok(id);
+        }
+        if !create {
+            return Err(OSError::Shm_NotFound);
+        }
+    }
+    if size == 0 || size > SHM_SIZE_LIMIT {
+        return Err(OSError::Shm_InvalidSize);
+    }
+    let id = NEXT_SHM_ID.fetch_add(1, Ordering::Relaxed);
+    segments.insert(
+        id,
+        ShmSegment {
+            key,
+            memory: Arc::new(SharedMemory::new(size)),
+            mode,
+            cpid: pid,
+            lpid: 0,
+        },
+    );
+    Ok(id)
+}
+
+/// 获取 id 对应的共享内存，用于 shmat。pid 是 shmat 的进程
+pub fn shm_attach(id: usize, pid: usize) -> OSResult<Arc<SharedMemory>> {
+    let mut segments = SHM_SEGMENTS.lock();
+    let segment = segments.get_mut(&id).ok_or(OSError::Shm_NotFound)?;
+    segment.lpid = pid;
+    Ok(segment.memory.clone())
+}
+
+/// 获取共享内存段的信息
+pub fn shm_stat(id: usize) -> OSResult<ShmSegmentStat> {
+    let segments = SHM_SEGMENTS.lock();
+    let segment = segments.get(&id).ok_or(OSError::Shm_NotFound)?;
+    Ok(ShmSegmentStat {
+        key: segment.key.unwrap_or(0),
+        size: segment.memory.size(),
+        mode: segment.mode,
+        cpid: segment.cpid,
+        lpid: segment.lpid,
+        nattch: segment.memory.nattch.load(Ordering::Relaxed),
+    })
+}
+
+/// 删除共享内存段。已经映射了它的地址段不受影响
+pub fn shm_remove(id: usize) -> OSResult {
+    SHM_SEGMENTS
+        .lock()
+        .remove(&id)
+        .map(|_| ())
+        .ok_or(OSError::Shm_NotFound)
+}
@@ -0,0 +1,331 @@
+//! 交换区
+//!
+//! 交换区可以是一个块设备，也可以是文件系统中的一个文件，两者都需要先用 mkswap 格式化，再通过 swapon 启用。
+//! 内存不足时，`reclaim_pages` 会先缩小页缓存，然后扫描各个地址空间的页表：
+//! - 页表项中有 ACCESS 位的页说明最近被访问过，清掉 ACCESS 位后跳过(second chance)；
+//! - 没有 ACCESS 位、且只属于一个地址段的匿名页或私有页会被写到交换区中，并从页表中删除。
+//!
+//! 换出的页由 `PmAreaLazy` 记录，之后访问它触发 page fault 时再从交换区读回。
+//!
+//! 锁的顺序为：地址空间 -> 地址段 -> 交换区。交换区的读写不持有交换区的锁
+
+use super::{Frame, MemorySet};
+use crate::{
+    constants::PAGE_SIZE,
+    drivers::{BlockDevice, BLOCK_SIZE},
+    error::{OSError, OSResult},
+    file::{shrink_page_cache, PageCacheIo},
+};
+use alloc::{
+    string::String,
+    sync::{Arc, Weak},
+    vec::Vec,
+};
+use lock::Mutex;
+
+/// mkswap 写在交换区第 0 页末尾的签名
+const SWAP_SIGNATURE: &[u8] = b"SWAPSPACE2";
+/// 交换区头部在第 0 页中的位置。之前的部分是留给引导程序的
+const SWAP_HEADER_OFFSET: usize = 1024;
+/// 头部中坏页列表的位置。它之前依次是 version、last_page、nr_badpages、uuid、卷名和填充
+const SWAP_BAD_PAGES_OFFSET: usize = SWAP_HEADER_OFFSET + 512;
+/// 每个 usize 中存放多少个交换页的占用情况
+const SLOTS_PER_WORD: usize = usize::BITS as usize;
+
+/// 交换区实际存放数据的位置
+#[derive(Clone)]
+pub enum SwapStorage {
+    /// 整个块设备
+    Block(Arc<dyn BlockDevice>),
+    /// 文件系统中的文件。直接读写文件，不经过页缓存
+    File(Arc<dyn PageCacheIo>),
+}
+
+impl SwapStorage {
+    /// 读取第 slot 个交换页
+    fn read_page(&self, slot: usize, buf: &mut [u8]) {
+        match self {
+            Self::Block(device) => {
+                for (i, block) in buf.chunks_mut(BLOCK_SIZE).enumerate() {
+                    device.read_block(slot * (PAGE_SIZE / BLOCK_SIZE) + i, block);
+                }
+            }
+            Self::File(file) => file.read_page(slot, buf),
+        }
+    }
+    /// 写入第 slot 个交换页
+    fn write_page(&self, slot: usize, buf: &[u8]) {
+        match self {
+            Self::Block(device) => {
+                for (i, block) in buf.chunks(BLOCK_SIZE).enumerate() {
+                    device.write_block(slot * (PAGE_SIZE / BLOCK_SIZE) + i, block);
+                }
+            }
+            Self::File(file) => file.write_page(slot, buf),
+        }
+    }
+}
+
+/// 一个已启用的交换区
+struct SwapArea {
+    /// swapon 时给出的路径，swapoff 时用它查找交换区
+    path: String,
+    storage: SwapStorage,
+    /// 每个交换页是否被占用，按 bit 存放。头部、坏页和超出交换区的部分总是被占用的
+    used: Vec<usize>,
+    /// 交换页的总数，包括头部
+    slot_count: usize,
+    /// 可用的交换页数
+    free_count: usize,
+    /// 头部和坏页的个数，它们不能用于存放换出的页
+    reserved: usize,
+    /// 是否正在 swapoff。这时不再分配新的交换页
+    closing: bool,
+}
+
+impl SwapArea {
+    /// 读取 mkswap 写入的头部，创建交换区
+    fn new(path: String, storage: SwapStorage) -> OSResult<Self> {
+        let mut header = Frame::new().ok_or(OSError::Memory_RunOutOfMemory)?;
+        storage.read_page(0, header.as_slice_mut());
+        let header = header.as_slice();
+        if &header[PAGE_SIZE - SWAP_SIGNATURE.len()..] != SWAP_SIGNATURE {
+            return Err(OSError::Swap_InvalidHeader);
+        }
+        let read_u32 =
+            |pos: usize| u32::from_le_bytes(header[pos..pos + 4].try_into().unwrap()) as usize;
+        let version = read_u32(SWAP_HEADER_OFFSET);
+        let last_page = read_u32(SWAP_HEADER_OFFSET + 4);
+        let bad_page_count = read_u32(SWAP_HEADER_OFFSET + 8);
+        let bad_pages_end = SWAP_BAD_PAGES_OFFSET + bad_page_count * 4;
+        if version != 1 || last_page == 0 || bad_pages_end > PAGE_SIZE - SWAP_SIGNATURE.len() {
+            return Err(OSError::Swap_InvalidHeader);
+        }
+        let slot_count = last_page + 1;
+        let mut area = Self {
+            path,
+            storage,
+            used: Vec::new(),
+            slot_count,
+            free_count: slot_count,
+            reserved: 0,
+            closing: false,
+        };
+        area.used
+            .resize((slot_count + SLOTS_PER_WORD - 1) / SLOTS_PER_WORD, 0);
+        for slot in slot_count..area.used.len() * SLOTS_PER_WORD {
+            area.used[slot / SLOTS_PER_WORD] |= 1 << (slot % SLOTS_PER_WORD);
+        }
+        area.mark_used(0);
+        for pos in (SWAP_BAD_PAGES_OFFSET..bad_pages_end).step_by(4) {
+            let slot = read_u32(pos);
+            if slot < slot_count {
+                area.mark_used(slot);
+            }
+        }
+        area.reserved = slot_count - area.free_count;
+        Ok(area)
+    }
+    /// 标记一个交换页不可用
+    fn mark_used(&mut self, slot: usize) {
+        let (word, bit) = (slot / SLOTS_PER_WORD, slot % SLOTS_PER_WORD);
+        if self.used[word] & (1 << bit) == 0 {
+            self.used[word] |= 1 << bit;
+            self.free_count -= 1;
+        }
+    }
+    /// 分配一个交换页
+    fn alloc_slot(&mut self) -> Option<usize> {
+        if self.closing || self.free_count == 0 {
+            return None;
+        }
+        let word = self.used.iter().position(|&word| word != usize::MAX)?;
+        let slot = word * SLOTS_PER_WORD + (!self.used[word]).trailing_zeros() as usize;
+        self.mark_used(slot);
+        Some(slot)
+    }
+    /// 释放一个交换页
+    fn free_slot(&mut self, slot: usize) {
+        self.used[slot / SLOTS_PER_WORD] &= !(1 << (slot % SLOTS_PER_WORD));
+        self.free_count += 1;
+    }
+    /// 是否还有交换页被地址段使用
+    fn in_use(&self) -> bool {
+        self.free_count + self.reserved < self.slot_count
+    }
+}
+
+/// 所有交换区。下标即交换区的编号，swapoff 之后的位置为 None
+static SWAP_AREAS: Mutex<Vec<Option<SwapArea>>> = Mutex::new(Vec::new());
+
+/// 所有用户进程的地址空间，内存不足时从中选择页换出。已经被释放的地址空间会在注册时顺便清理掉
+static MEMORY_SETS: Mutex<Vec<Weak<Mutex<MemorySet>>>> = Mutex::new(Vec::new());
+
+/// 一个被换出的页在交换区中的位置。drop 时释放对应的交换页
+///
+/// fork 时父子进程的地址段可以通过 Arc 共享同一个交换页，和共享页帧一样
+#[derive(Debug)]
+pub struct SwapSlot {
+    area: usize,
+    slot: usize,
+}
+
+impl SwapSlot {
+    /// 把页帧的内容写到一个新的交换页中。没有可用的交换区时返回 None
+    pub fn swap_out(frame: &Frame) -> Option<Self> {
+        let (area, slot, storage) = {
+            let mut areas = SWAP_AREAS.lock();
+            areas.iter_mut().enumerate().find_map(|(id, area)| {
+                let area = area.as_mut()?;
+                area.alloc_slot()
+                    .map(|slot| (id, slot, area.storage.clone()))
+            })?
+        };
+        storage.write_page(slot, frame.as_slice());
+        Some(Self { area, slot })
+    }
+    /// 把交换页的内容读到 frame 中
+    pub fn swap_in(&self, frame: &mut Frame) {
+        let storage = SWAP_AREAS.lock()[self.area]
+            .as_ref()
+            .unwrap()
+            .storage
+            .clone();
+        storage.read_page(self.slot, frame.as_slice_mut());
+    }
+    /// 所在交换区的编号
+    pub fn area(&self) -> usize {
+        self.area
+    }
+}
+
+impl Drop for SwapSlot {
+    fn drop(&mut self) {
+        if let Some(Some(area)) = SWAP_AREAS.lock().get_mut(self.area) {
+            area.free_slot(self.slot);
+        }
+    }
+}
+
+/// 记录一个用户进程的地址空间，之后内存不足时可以从中换出页
+pub fn register_memory_set(vm: &Arc<Mutex<MemorySet>>) {
+    let mut sets = MEMORY_SETS.lock();
+    sets.retain(|ms| ms.strong_count() > 0);
+    sets.push(Arc::downgrade(vm));
+}
+
+/// 是否有可以换出页的交换区
+fn swap_available() -> bool {
+    SWAP_AREAS
+        .lock()
+        .iter()
+        .flatten()
+        .any(|area| !area.closing && area.free_count > 0)
+}
+
+/// 内存不足时回收至多 count 个页帧，返回实际回收的页数。
+///
+/// current 是调用者已经持有锁的地址空间，它一定会被扫描；其他地址空间只在能拿到锁时才会扫描。
+/// 第一轮扫描清掉页表中的 ACCESS 位，第二轮换出在这期间没有被访问过的页
+pub fn reclaim_pages(current: &mut MemorySet, count: usize) -> usize {
+    let mut reclaimed = shrink_page_cache(count);
+    if reclaimed >= count || !swap_available() {
+        return reclaimed;
+    }
+    let others: Vec<Arc<Mutex<MemorySet>>> = MEMORY_SETS
+        .lock()
+        .iter()
+        .filter_map(Weak::upgrade)
+        .collect();
+    for _ in 0..2 {
+        reclaimed += current.reclaim(count - reclaimed);
+        for ms in others.iter() {
+            if reclaimed >= count {
+                break;
+            }
+            // 当前地址空间的锁已经被调用者持有，这里会跳过它
+            if let Some(mut ms) = ms.try_lock() {
+                reclaimed += ms.reclaim(count - reclaimed);
+            }
+        }
+        if reclaimed >= count {
+            break;
+        }
+    }
+    info!("reclaimed {} pages", reclaimed);
+    reclaimed
+}
+
+/// 启用交换区。path 用于之后 swapoff 时查找
+pub fn swap_on(path: String, storage: SwapStorage) -> OSResult {
+    if SWAP_AREAS
+        .lock()
+        .iter()
+        .flatten()
+        .any(|area| area.path == path)
+    {
+        return Err(OSError::Swap_AreaBusy);
+    }
+    // 读头部时不持有锁
+    let area = SwapArea::new(path, storage)?;
+    info!(
+        "swap on {}: {} pages, {} free",
+        area.path, area.slot_count, area.free_count
+    );
+    let mut areas = SWAP_AREAS.lock();
+    match areas.iter().position(Option::is_none) {
+        Some(id) => areas[id] = Some(area),
+        None => areas.push(Some(area)),
+    }
+    Ok(())
+}
+
+/// 停用交换区。其中所有被换出的页都会先换回内存，内存不足时失败
+pub fn swap_off(path: &str) -> OSResult {
+    let id = {
+        let mut areas = SWAP_AREAS.lock();
+        let (id, area) = areas
+            .iter_mut()
+            .enumerate()
+            .find_map(|(id, area)| {
+                area.as_mut()
+                    .filter(|area| area.path == path)
+                    .map(|a| (id, a))
+            })
+            .ok_or(OSError::Swap_AreaNotFound)?;
+        if area.closing {
+            return Err(OSError::Swap_AreaBusy);
+        }
+        area.closing = true;
+        id
+    };
+    let sets: Vec<Arc<Mutex<MemorySet>>> = MEMORY_SETS
+        .lock()
+        .iter()
+        .filter_map(Weak::upgrade)
+        .collect();
+    let result = sets.iter().try_for_each(|ms| ms.lock().swap_in_area(id));
+    let mut areas = SWAP_AREAS.lock();
+    let area = areas[id].as_mut().unwrap();
+    if result.is_err() || area.in_use() {
+        area.closing = false;
+        return result.and(Err(OSError::Swap_AreaBusy));
+    }
+    areas[id] = None;
+    info!("swap off {}", path);
+    Ok(())
+}
+
+/// 所有交换区的总页数和可用页数，不包括头部和坏页
+pub fn swap_stat() -> (usize, usize) {
+    SWAP_AREAS
+        .lock()
+        .iter()
+        .flatten()
+        .fold((0, 0), |(total, free), area| {
+            (
+                total + area.slot_count - area.reserved,
+                free + area.free_count,
+            )
+        })
+}
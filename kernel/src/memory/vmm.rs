@@ -1,25 +1,29 @@
 //! 虚拟地址段映射管理
 
 use super::{
-    addr_to_page_id, cross_page, get_phys_memory_regions, page_count, page_id_to_addr,
-    virt_to_phys, PTEFlags, PageTable, PmAreaLazy, VirtAddr, VmArea,
+    addr_to_page_id, align_down, align_up, cross_page, get_phys_memory_regions, page_count,
+    page_id_to_addr, reclaim_pages, user_virt_addr_limit, virt_to_phys, PTEFlags, PageTable,
+    PmArea, PmAreaLazy, PmAreaShared, SharedLocation, UserLayout, VirtAddr, VmArea,
+    USER_HEAP_AREA_NAME, USER_STACK_AREA_NAME,
 };
 use crate::{
     arch,
+    cmdline::{device_end, is_preloaded_fs_img, is_test_env, report_page_fault},
     constants::{
-        CPU_ID_LIMIT, DEVICE_END, DEVICE_START, IS_PRELOADED_FS_IMG, IS_TEST_ENV, MMIO_REGIONS,
-        PAGE_SIZE, REPORT_PAGE_FAULT, USER_VIRT_ADDR_LIMIT,
+        CPU_ID_LIMIT, DEVICE_START, PAGE_SIZE, PROBE_BLOCK_DEVICE, PROBE_NET_DEVICE,
+        SWAP_RECLAIM_BATCH, USER_STACK_GUARD_GAP, USER_STACK_RLIMIT,
     },
     error::{OSError, OSResult},
+    fdt,
     file::BackEndFile,
 };
-use alloc::{boxed::Box, sync::Arc};
+use alloc::{boxed::Box, sync::Arc, vec::Vec};
 use core::{
     fmt::{Debug, Formatter, Result},
     mem::size_of,
 };
 use lock::Mutex;
-use range_action_map::RangeActionMap;
+use range_action_map::{RangeActionMap, LOWER_LIMIT};
 
 /// 内存段和相关的页表
 pub struct MemorySet {
@@ -29,6 +33,14 @@ pub struct MemorySet {
     pub pt: Box<PageTable>,
     /// 是否是用户态的
     is_user: bool,
+    /// 用户地址空间的布局。在加载用户程序前设置
+    pub layout: UserLayout,
+    /// 用户栈最多可以增长到多大，即 RLIMIT_STACK。exec 时保持不变
+    pub stack_limit: usize,
+    /// 用户地址空间的最大大小，即 RLIMIT_AS。exec 时保持不变
+    pub as_limit: usize,
+    /// 可写的地址段(用户栈除外)的最大总大小，即 RLIMIT_DATA。exec 时保持不变
+    pub data_limit: usize,
 }
 
 impl MemorySet {
@@ -39,6 +51,10 @@ impl MemorySet {
             area_map: RangeActionMap::new(unsafe { pt.self_as_usize() }),
             pt,
             is_user: false,
+            layout: UserLayout::new(false),
+            stack_limit: USER_STACK_RLIMIT,
+            as_limit: usize::MAX,
+            data_limit: usize::MAX,
         }
     }
 
@@ -49,9 +65,13 @@ impl MemorySet {
             area_map: RangeActionMap::new(unsafe { pt.self_as_usize() }),
             pt,
             is_user: true,
+            layout: UserLayout::new(false),
+            stack_limit: USER_STACK_RLIMIT,
+            as_limit: usize::MAX,
+            data_limit: usize::MAX,
         }
     }
-    /// 取消一段内存地址映射
+    /// 取消一段内存地址映射。删除映射时会刷新所有核的 TLB，之后才释放页帧
     pub fn munmap(&mut self, start: VirtAddr, end: VirtAddr) -> bool {
         //error!("munmap start {:x} , end {:x}", start, end);
         self.area_map.unmap(start, end);
@@ -62,6 +82,8 @@ impl MemorySet {
         //error!("mprotect start {:x} , end {:x}", start, end);
         self.area_map
             .mprotect(start, end, new_flags.bits() as usize);
+        // 其他核上的线程可能还在使用旧权限的 TLB 项
+        self.pt.flush_tlb_all_harts();
         true
     }
     /// 将一段区域中的数据同步到和其对应的文件中
@@ -93,22 +115,55 @@ impl MemorySet {
         backend: Option<BackEndFile>,
         anywhere: bool,
     ) -> OSResult<usize> {
-        if !anywhere && end >= USER_VIRT_ADDR_LIMIT {
+        self.push_with_pma(start, end, flags, anywhere, "from mmap", |len| {
+            // 注意实际占用的页数不仅看 data.len()，还要看请求的地址跨越了几页
+            Ok(Arc::new(Mutex::new(PmAreaLazy::new(
+                page_count(len),
+                backend,
+            )?)))
+        })
+    }
+    /// 插入一段映射共享内存的地址段。如插入成功，返回插入后的起始地址
+    ///
+    /// pma 的长度需要和 [start, end) 相同。anywhere 的含义同 `push_with_backend`
+    pub fn push_shared(
+        &mut self,
+        start: VirtAddr,
+        end: VirtAddr,
+        flags: PTEFlags,
+        pma: PmAreaShared,
+        anywhere: bool,
+        name: &'static str,
+    ) -> OSResult<usize> {
+        self.push_with_pma(start, end, flags, anywhere, name, |_| {
+            Ok(Arc::new(Mutex::new(pma)))
+        })
+    }
+    /// 插入一段地址段，其中的物理地址段由 new_pma 按地址段的长度生成
+    fn push_with_pma(
+        &mut self,
+        start: VirtAddr,
+        end: VirtAddr,
+        flags: PTEFlags,
+        anywhere: bool,
+        name: &'static str,
+        new_pma: impl FnOnce(usize) -> OSResult<Arc<Mutex<dyn PmArea>>>,
+    ) -> OSResult<usize> {
+        if !anywhere && end >= user_virt_addr_limit() {
             return Err(OSError::MemorySet_UserMmapIntersectWithKernel);
         }
         let len = end - start;
+        let is_data = flags.contains(PTEFlags::WRITE) && name != USER_STACK_AREA_NAME;
         if anywhere {
-            let start = self
-                .area_map
-                .mmap_anywhere(start, end - start, |start| {
+            let start = self.find_free_range(start, len)?;
+            self.check_rlimit(start, start + len, is_data)?;
+            let pma = new_pma(len)?;
+            self.area_map
+                .mmap_anywhere(start, len, |start| {
                     // 注意此时因为 start 已改变，所以外部的 end 已失效，应该使用 len 计算 end
                     let end = len + start;
                     //error!("mmap anywhere get start {:x} , end {:x}", start, end);
-                    // 注意实际占用的页数不仅看 data.len()，还要看请求的地址跨越了几页
-                    let pma = PmAreaLazy::new(page_count(end - start), backend).unwrap();
-                    let area =
-                        VmArea::new(start, end, flags, Arc::new(Mutex::new(pma)), "from mmap")
-                            .unwrap();
+                    let area = VmArea::new(start, end, flags, pma, name).unwrap();
                     area.map_area(&mut self.pt).unwrap();
                     area
                 })
@@ -116,14 +171,13 @@ impl MemorySet {
             self.flush_tlb();
             Ok(start)
         } else {
+            self.check_rlimit(start, end, is_data)?;
+            let pma = new_pma(len)?;
             let start = self
                 .area_map
                 .mmap_fixed(start, end, || {
                     //error!("mmap fixed get start {:x} , end {:x}", start, end);
-                    let pma = PmAreaLazy::new(page_count(end - start), backend).unwrap();
-                    let area =
-                        VmArea::new(start, end, flags, Arc::new(Mutex::new(pma)), "from mmap")
-                            .unwrap();
+                    let area = VmArea::new(start, end, flags, pma, name).unwrap();
                     area.map_area(&mut self.pt).unwrap();
                     area
                 })
@@ -132,6 +186,283 @@ impl MemorySet {
             Ok(start)
         }
     }
+    /// 删除从 start 开始、名为 name 的地址段，返回它的结尾。一般是 shmdt 要求的
+    pub fn unmap_area_named(&mut self, start: VirtAddr, name: &'static str) -> OSResult<VirtAddr> {
+        let end = match self.area_map.find(start) {
+            Some(area) if area.start == start && area.name() == name => area.end,
+            _ => return Err(OSError::MemorySet_UnmapAreaNotFound),
+        };
+        self.area_map.unmap(start, end);
+        self.flush_tlb();
+        Ok(end)
+    }
+
+    /// 地址段是否计入 RLIMIT_DATA，即可写且不是用户栈
+    fn is_data_area(area: &VmArea) -> bool {
+        area.flags.contains(PTEFlags::WRITE) && area.name() != USER_STACK_AREA_NAME
+    }
+
+    /// 统计 [start, end) 中已映射的用户地址的字节数，以及其中计入 RLIMIT_DATA 的部分
+    fn mapped_size_in(&self, start: VirtAddr, end: VirtAddr) -> (usize, usize) {
+        self.area_map
+            .iter()
+            .filter(|area| area.is_user() && area.is_overlap_with(start, end))
+            .fold((0, 0), |(total, data), area| {
+                let len = area.end.min(end) - area.start.max(start);
+                if Self::is_data_area(area) {
+                    (total + len, data + len)
+                } else {
+                    (total + len, data)
+                }
+            })
+    }
+
+    /// 用户地址空间的总大小，即 VmSize
+    pub fn vm_size(&self) -> usize {
+        self.mapped_size_in(LOWER_LIMIT, usize::MAX).0
+    }
+
+    /// 计入 RLIMIT_DATA 的地址段的总大小，即 VmData
+    pub fn data_size(&self) -> usize {
+        self.mapped_size_in(LOWER_LIMIT, usize::MAX).1
+    }
+
+    /// 用户地址空间中当前在内存中的页数，即 RSS。被换出的页不算在内
+    pub fn resident_pages(&self) -> usize {
+        self.area_map
+            .iter()
+            .filter(|area| area.is_user())
+            .map(|area| area.resident_pages())
+            .sum()
+    }
+
+    /// 检查把 [start, end) 映射为一个新的地址段之后，地址空间是否仍在 RLIMIT_AS 以内。
+    /// 如果新的地址段计入 RLIMIT_DATA，还要检查 RLIMIT_DATA。
+    ///
+    /// 这段地址中原有的映射会被替换掉，所以不重复计算
+    fn check_rlimit(&self, start: VirtAddr, end: VirtAddr, is_data: bool) -> OSResult {
+        let (total, data) = self.mapped_size_in(LOWER_LIMIT, usize::MAX);
+        let (old_total, old_data) = self.mapped_size_in(start, end);
+        let len = end - start;
+        if total - old_total + len > self.as_limit
+            || (is_data && data - old_data + len > self.data_limit)
+        {
+            return Err(OSError::MemorySet_ExceedRlimit);
+        }
+        Ok(())
+    }
+
+    /// 把用户堆的结尾从 old_top 调整到 new_top，两者都不需要按页对齐。一般是 brk 要求的
+    ///
+    /// 用户堆向上增长时不能碰到其他内存段，和用户栈之间还要隔开 `USER_STACK_GUARD_GAP`
+    pub fn set_brk(&mut self, old_top: VirtAddr, new_top: VirtAddr) -> OSResult {
+        let heap_start = self.layout.heap_start;
+        if new_top < heap_start {
+            return Err(OSError::MemorySet_InvalidRange);
+        }
+        let old_end = align_up(old_top);
+        let new_end = align_up(new_top);
+        if new_end <= old_end {
+            // 删除映射时已经刷新了所有核的 TLB
+            self.area_map.unmap(new_end, old_end);
+            return Ok(());
+        }
+        if new_end > user_virt_addr_limit() {
+            return Err(OSError::MemorySet_UserMmapIntersectWithKernel);
+        }
+        if let Some(next) = self.area_map.iter().find(|area| area.start >= old_end) {
+            let gap = if next.name() == USER_STACK_AREA_NAME {
+                USER_STACK_GUARD_GAP
+            } else {
+                0
+            };
+            if new_end + gap > next.start {
+                return Err(OSError::Memory_RunOutOfConsecutiveMemory);
+            }
+        }
+        self.check_rlimit(old_end, new_end, true)?;
+        // 原来的堆的最后一段还在时，直接向上扩展它，不需要修改已有的页表项
+        match self.area_map.find(old_end.wrapping_sub(1)) {
+            Some(area)
+                if old_end > heap_start
+                    && area.end == old_end
+                    && area.name() == USER_HEAP_AREA_NAME =>
+            {
+                let mut heap = self.area_map.take(area.start, old_end).unwrap();
+                let result = heap.grow_up(new_end);
+                self.area_map.mmap_fixed(heap.start, heap.end, || heap);
+                result
+            }
+            _ => {
+                let pma = PmAreaLazy::new(page_count(new_end - old_end), None)?;
+                self.push(VmArea::new(
+                    old_end,
+                    new_end,
+                    PTEFlags::READ | PTEFlags::WRITE | PTEFlags::USER,
+                    Arc::new(Mutex::new(pma)),
+                    USER_HEAP_AREA_NAME,
+                )?)
+            }
+        }
+    }
+
+    /// 如果 vaddr 在用户栈下方，且栈扩展到 vaddr 之后不超过 `stack_limit`，则把用户栈向下扩展到包含 vaddr。
+    /// 返回是否扩展成功
+    ///
+    /// 扩展后的栈和下面的内存段之间至少要隔开 `USER_STACK_GUARD_GAP`，否则视为栈溢出
+    fn try_grow_stack(&mut self, vaddr: VirtAddr) -> bool {
+        let (start, end) = match self.area_map.iter().find(|area| area.start > vaddr) {
+            Some(area) if area.name() == USER_STACK_AREA_NAME => (area.start, area.end),
+            _ => return false,
+        };
+        let new_start = align_down(vaddr);
+        if end - new_start > self.stack_limit {
+            return false;
+        }
+        let below_end = self
+            .area_map
+            .iter()
+            .take_while(|area| area.end <= vaddr)
+            .last()
+            .map_or(LOWER_LIMIT, |area| area.end);
+        if below_end + USER_STACK_GUARD_GAP > new_start
+            || self.check_rlimit(new_start, start, false).is_err()
+        {
+            return false;
+        }
+        let mut stack = self.area_map.take(start, end).unwrap();
+        let result = stack.grow_down(new_start);
+        self.area_map.mmap_fixed(stack.start, stack.end, || stack);
+        result.is_ok()
+    }
+
+    /// 找一段长为 len 的空闲用户地址，返回它的起始地址。
+    ///
+    /// 从 hint 开始往后找，hint 为 0 时从 mmap_base 开始找。如果后面的空间不够，再从头找一次
+    fn find_free_range(&self, hint: VirtAddr, len: usize) -> OSResult<VirtAddr> {
+        let limit = user_virt_addr_limit();
+        let hint = if hint == 0 {
+            self.layout.mmap_base
+        } else {
+            hint
+        };
+        self.area_map
+            .find_free_area(hint, len)
+            .filter(|&start| start + len <= limit)
+            .or_else(|| self.area_map.find_free_area(LOWER_LIMIT, len))
+            .filter(|&start| start + len <= limit)
+            .ok_or(OSError::MemorySet_UserMmapIntersectWithKernel)
+    }
+
+    /// 检查 [start, end) 中的每个地址是否都在某个内存段中
+    fn check_range_mapped(&self, start: VirtAddr, end: VirtAddr) -> OSResult {
+        let mut pos = start;
+        while pos < end {
+            pos = self
+                .area_map
+                .find(pos)
+                .ok_or(OSError::MemorySet_AreaNotMapped)?
+                .end;
+        }
+        Ok(())
+    }
+
+    /// 丢弃一段地址中已分配的页帧，之后访问时再重新分配。这段地址需要全部已映射
+    pub fn discard_areas(&mut self, start: VirtAddr, end: VirtAddr) -> OSResult {
+        self.check_range_mapped(start, end)?;
+        for area in self.area_map.iter() {
+            if area.is_overlap_with(start, end) {
+                area.discard(&mut self.pt, start, end)?;
+            }
+        }
+        // 拆开的大页在其他核上可能还留着旧的 TLB 项
+        self.pt.flush_tlb_all_harts();
+        Ok(())
+    }
+
+    /// 预先分配一段地址中的页帧。这段地址需要全部已映射
+    pub fn prefetch_areas(&mut self, start: VirtAddr, end: VirtAddr) -> OSResult {
+        self.check_range_mapped(start, end)?;
+        for area in self.area_map.iter() {
+            if area.is_overlap_with(start, end) {
+                area.prefetch(&mut self.pt, start, end)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// 获取一段地址中的每一页是否在内存中，每页对应一项。这段地址需要全部已映射
+    pub fn residency(&self, start: VirtAddr, end: VirtAddr) -> OSResult<Vec<bool>> {
+        self.check_range_mapped(start, end)?;
+        Ok((start..end)
+            .step_by(PAGE_SIZE)
+            .map(|vaddr| {
+                self.area_map
+                    .find(vaddr)
+                    .map_or(false, |area| area.is_resident(vaddr))
+            })
+            .collect())
+    }
+
+    /// 把 [old_start, old_start + old_len) 的映射调整为 new_len 长，成功时返回调整后的起始地址。
+    ///
+    /// - 缩小时直接删除多出的部分；
+    /// - 扩大时优先在原地扩展。后面的空间不够时，如有 may_move 则整段移到别处，否则失败；
+    /// - 如指定了 fixed，则一定移动到这个位置，原本在那里的映射会被删除。
+    ///
+    /// 原来的这一段需要落在同一个内存段中。移动时已分配的页帧保持不变，只修改页表
+    pub fn mremap(
+        &mut self,
+        old_start: VirtAddr,
+        old_len: usize,
+        new_len: usize,
+        may_move: bool,
+        fixed: Option<VirtAddr>,
+    ) -> OSResult<VirtAddr> {
+        let old_end = old_start + old_len;
+        let is_data = match self.area_map.find(old_start) {
+            Some(area) if old_end <= area.end => Self::is_data_area(area),
+            _ => return Err(OSError::MemorySet_AreaNotMapped),
+        };
+        let new_start = if let Some(new_start) = fixed {
+            if new_start + new_len > user_virt_addr_limit() {
+                return Err(OSError::MemorySet_UserMmapIntersectWithKernel);
+            }
+            // 新旧两段不能重叠
+            if new_start < old_end && old_start < new_start + new_len {
+                return Err(OSError::MemorySet_InvalidRange);
+            }
+            new_start
+        } else if new_len <= old_len {
+            // 删除映射时已经刷新了所有核的 TLB
+            self.area_map.unmap(old_start + new_len, old_end);
+            return Ok(old_start);
+        } else if old_start + new_len <= user_virt_addr_limit()
+            && self.area_map.find_free_area(old_end, new_len - old_len) == Some(old_end)
+        {
+            // 后面的空间足够，原地扩展
+            old_start
+        } else if may_move {
+            self.find_free_range(0, new_len)?
+        } else {
+            return Err(OSError::Memory_RunOutOfMemory);
+        };
+        // 新增的部分 [new_start + old_len, new_start + new_len) 一定是空闲的或者会被替换掉
+        if new_len > old_len {
+            self.check_rlimit(new_start + old_len, new_start + new_len, is_data)?;
+        }
+        // 上面已经检查过 [old_start, old_end) 在同一个内存段中，所以一定可以取出来
+        let mut vma = self.area_map.take(old_start, old_end).unwrap();
+        if let Err(e) = vma.remap(new_start, new_len, &mut self.pt) {
+            // 失败时页表还没有被修改，原样放回去即可
+            self.area_map.mmap_fixed(old_start, old_end, || vma);
+            return Err(e);
+        }
+        self.push(vma)?;
+        // 旧地址的映射在 remap 中已经刷新了所有核的 TLB，这里只需要让新映射在当前核上生效
+        self.flush_tlb();
+        Ok(new_start)
+    }
 
     /// 插入一段内存段，并将其映射到页表里
     pub fn push(&mut self, vma: VmArea) -> OSResult {
@@ -146,24 +477,80 @@ impl MemorySet {
 
     /// 处理这个映射表对应的错误
     pub fn handle_page_fault(&mut self, vaddr: VirtAddr, access_flags: PTEFlags) -> OSResult {
-        if let Some(area) = self.area_map.find(vaddr) {
-            return area.handle_page_fault(vaddr - area.start, access_flags, &mut self.pt);
-        }
-        if REPORT_PAGE_FAULT {
-            warn!(
-                "unhandled page fault @ {:#x?} with access {:?}",
-                vaddr, access_flags
-            );
-        }
-        Err(OSError::PageFaultHandler_Unhandled)
+        self.retry_after_reclaim(|ms| {
+            if ms.area_map.find(vaddr).is_none() {
+                ms.try_grow_stack(vaddr);
+            }
+            if let Some(area) = ms.area_map.find(vaddr) {
+                return area.handle_page_fault(vaddr - area.start, access_flags, &mut ms.pt);
+            }
+            if report_page_fault() {
+                warn!(
+                    "unhandled page fault @ {:#x?} with access {:?}",
+                    vaddr, access_flags
+                );
+            }
+            Err(OSError::PageFaultHandler_Unhandled)
+        })
     }
 
     /// 检查一个地址是否分配，如果未分配则强制分配它
     pub fn manually_alloc_page(&mut self, vaddr: VirtAddr) -> OSResult {
-        if let Some(area) = self.area_map.find(vaddr) {
-            return area.manually_alloc_page(vaddr - area.start, &mut self.pt);
+        self.retry_after_reclaim(|ms| {
+            if ms.area_map.find(vaddr).is_none() {
+                ms.try_grow_stack(vaddr);
+            }
+            if let Some(area) = ms.area_map.find(vaddr) {
+                return area.manually_alloc_page(vaddr - area.start, &mut ms.pt);
+            }
+            Err(OSError::PageFaultHandler_Unhandled)
+        })
+    }
+
+    /// 检查一个地址是否可以被用户写入，并像 `manually_alloc_page` 一样强制分配它。
+    /// 成功后这一页已经完成写时复制，在释放地址空间的锁之前内核可以直接写入
+    pub fn manually_alloc_writable_page(&mut self, vaddr: VirtAddr) -> OSResult {
+        self.manually_alloc_page(vaddr)?;
+        match self.area_map.find(vaddr) {
+            Some(area) if area.flags.contains(PTEFlags::WRITE) => Ok(()),
+            _ => Err(OSError::PageFaultHandler_AccessDenied),
+        }
+    }
+
+    /// 执行 op，如果因为内存不足失败，则回收一部分页帧后再试一次
+    fn retry_after_reclaim(&mut self, op: impl Fn(&mut Self) -> OSResult) -> OSResult {
+        let result = op(self);
+        if result == Err(OSError::Memory_RunOutOfMemory)
+            && reclaim_pages(self, SWAP_RECLAIM_BATCH) > 0
+        {
+            return op(self);
         }
-        Err(OSError::PageFaultHandler_Unhandled)
+        result
+    }
+
+    /// 换出至多 count 个最近没有被访问的用户页，返回换出的页数
+    pub fn reclaim(&mut self, count: usize) -> usize {
+        let mut reclaimed = 0;
+        for area in self.area_map.iter() {
+            if reclaimed >= count {
+                break;
+            }
+            reclaimed += area.reclaim(&mut self.pt, count - reclaimed);
+        }
+        reclaimed
+    }
+
+    /// 把存放在编号为 area 的交换区中的用户页都换回内存
+    pub fn swap_in_area(&mut self, area: usize) -> OSResult {
+        self.area_map
+            .iter()
+            .try_for_each(|vma| vma.swap_in_area(area))
+    }
+
+    /// 如果 vaddr 位于共享映射(MAP_SHARED)中，则返回它在被映射的对象中的位置，否则返回 None。
+    /// 用于区分跨进程的 futex，调用前需要保证这一页已经分配
+    pub fn shared_location(&self, vaddr: VirtAddr) -> Option<SharedLocation> {
+        self.area_map.find(vaddr)?.shared_location(vaddr)
     }
 
     /// 检查一个放在某个地址上的结构是否分配空间，如果未分配则强制分配它
@@ -209,7 +596,7 @@ impl MemorySet {
             return;
         }
         self.area_map
-            .unmap(range_action_map::LOWER_LIMIT, USER_VIRT_ADDR_LIMIT);
+            .unmap(range_action_map::LOWER_LIMIT, user_virt_addr_limit());
     }
 
     // 清空 TLB
@@ -278,6 +665,8 @@ impl MemorySet {
         access_flags: PTEFlags,
     ) -> OSResult {
         self.read_write(start, len, access_flags, |area, offset, len, processed| {
+            // 先把写时复制的页复制出来，避免改到其他进程的数据
+            area.copy_on_write_range(offset, len, &self.pt)?;
             area.pma
                 .lock()
                 .write(offset, &src[processed..processed + len])?;
@@ -288,14 +677,21 @@ impl MemorySet {
     /// 从已有 MemorySet 按照 fork 的要求复制一个新的 MemorySet 。具体来说：
     ///
     /// 1. 对内核的地址段，所有虚拟地址与物理地址的映射相同
-    /// 2. 对用户的地址段，所有虚拟地址和其中的数据相同，但对应的物理地址与 self 中的不同
-    pub fn copy_as_fork(&self) -> OSResult<MemorySet> {
+    /// 2. 对用户的地址段，所有虚拟地址和其中的数据相同。已分配的页帧以写时复制的方式共享，
+    /// 所以 self 中的可写页也会暂时变为只读
+    pub fn copy_as_fork(&mut self) -> OSResult<MemorySet> {
         let mut ms = new_memory_set_for_task()?;
+        ms.layout = self.layout;
+        ms.stack_limit = self.stack_limit;
+        ms.as_limit = self.as_limit;
+        ms.data_limit = self.data_limit;
         for area in self.area_map.iter() {
             if area.is_user() {
-                ms.push(area.copy_to_new_area_with_data()?)?;
+                ms.push(area.copy_to_new_area_cow(&mut self.pt)?)?;
             }
         }
+        // 同一进程的其他线程可能正在其他核上运行，它们的 TLB 里还留着可写的映射
+        self.pt.flush_tlb_all_harts();
         Ok(ms)
     }
 }
@@ -385,7 +781,7 @@ fn init_kernel_memory_set(ms: &mut MemorySet) -> OSResult {
         )?)?;
     }
 
-    // 插入物理内存映射
+    // 插入物理内存映射。其中按 2MiB 对齐的部分会在 map_area 中用大页映射，减少页表页和 TLB 的占用
     for region in get_phys_memory_regions() {
         info!("init region {:x}, {:x}", region.start, region.end);
         ms.push(VmArea::from_fixed_pma(
@@ -397,49 +793,47 @@ fn init_kernel_memory_set(ms: &mut MemorySet) -> OSResult {
         )?)?;
     }
 
-    if !IS_TEST_ENV {
+    if !is_test_env() || PROBE_NET_DEVICE || PROBE_BLOCK_DEVICE {
         // 插入设备的 MMIO 映射
-        for region in MMIO_REGIONS {
+        for region in fdt::mmio_regions() {
             // 这里选择恒等映射是为了兼容设备
             ms.push(VmArea::from_identical_pma(
-                region.0,
-                region.1,
+                align_down(region.start),
+                align_up(region.end),
                 PTEFlags::READ | PTEFlags::WRITE,
                 "MMIO",
             )?)?;
         }
     }
-    // 测试环境，需要加载文件系统镜像到内存中
-    if IS_TEST_ENV {
-        if !IS_PRELOADED_FS_IMG {
-            extern "C" {
-                fn img_start();
-                fn img_end();
-            }
-            info!(
-                "img start {:x}, img_end {:x}",
-                img_start as usize, img_end as usize
-            );
-            let pstart = virt_to_phys(img_start as usize);
-            let pend = virt_to_phys(img_end as usize);
-            let offset = DEVICE_START - pstart;
-            // 文件系统的内存映射
-            ms.push(VmArea::from_fixed_pma(
-                pstart,
-                pend,
-                PHYS_VIRT_OFFSET + offset,
-                PTEFlags::READ | PTEFlags::WRITE,
-                "fs_in_memory",
-            )?)?;
-        } else {
-            ms.push(VmArea::from_fixed_pma(
-                DEVICE_START,
-                DEVICE_END,
-                PHYS_VIRT_OFFSET,
-                PTEFlags::READ | PTEFlags::WRITE,
-                "fs_in_memory",
-            )?)?;
+    // 把文件系统镜像映射到 DEVICE_START 对应的虚拟地址。镜像来源由启动参数 root= 决定
+    if !is_preloaded_fs_img() {
+        extern "C" {
+            fn img_start();
+            fn img_end();
         }
+        info!(
+            "img start {:x}, img_end {:x}",
+            img_start as usize, img_end as usize
+        );
+        let pstart = virt_to_phys(img_start as usize);
+        let pend = virt_to_phys(img_end as usize);
+        let offset = DEVICE_START - pstart;
+        // 文件系统的内存映射
+        ms.push(VmArea::from_fixed_pma(
+            pstart,
+            pend,
+            PHYS_VIRT_OFFSET + offset,
+            PTEFlags::READ | PTEFlags::WRITE,
+            "fs_in_memory",
+        )?)?;
+    } else {
+        ms.push(VmArea::from_fixed_pma(
+            DEVICE_START,
+            device_end(),
+            PHYS_VIRT_OFFSET,
+            PTEFlags::READ | PTEFlags::WRITE,
+            "fs_in_memory",
+        )?)?;
     }
     //create_mapping(ms)?;
     Ok(())
@@ -447,7 +841,7 @@ fn init_kernel_memory_set(ms: &mut MemorySet) -> OSResult {
 
 /*
 /// 加载 data 段中的文件系统。必须在测试环境且非预载文件系统的情况下调用。
-/// 即 IS_TEST_ENV && !IS_PRELOADED_FS_IMG
+/// 即 !is_preloaded_fs_img()
 ///
 /// 仅用于fs出问题无法加载时进行测试
 fn load_fs_force() {
@@ -479,7 +873,7 @@ lazy_static::lazy_static! {
         // 否则，镜像在 data 段里，需要手动把它加载到 device 对应位置
         // 目前的实现不考虑把修改后的文件系统写回的情况
         /*
-        if IS_TEST_ENV && !IS_PRELOADED_FS_IMG {
+        if !is_preloaded_fs_img() {
             load_fs_force();
         }
         */
@@ -521,3 +915,36 @@ pub fn new_memory_set_for_task() -> OSResult<MemorySet> {
     map_kernel_regions(&mut ms);
     Ok(ms)
 }
+
+/// 测试相邻且权限相同的两段匿名映射会合并为一个内存段
+#[allow(unused)]
+pub fn memory_set_merge_test() {
+    let mut ms = new_memory_set_for_task().unwrap();
+    let flags = PTEFlags::USER | PTEFlags::READ | PTEFlags::WRITE;
+    let start = ms.layout.mmap_base;
+    ms.push_with_backend(start, start + PAGE_SIZE * 2, flags, None, false)
+        .unwrap();
+    ms.push_with_backend(
+        start + PAGE_SIZE * 2,
+        start + PAGE_SIZE * 3,
+        flags,
+        None,
+        false,
+    )
+    .unwrap();
+    assert_eq!(ms.area_map.iter().count(), 1);
+    let area = ms.area_map.find(start).unwrap();
+    assert_eq!((area.start, area.end), (start, start + PAGE_SIZE * 3));
+    // 权限不同的映射不会合并
+    let read_only = PTEFlags::USER | PTEFlags::READ;
+    ms.push_with_backend(
+        start + PAGE_SIZE * 3,
+        start + PAGE_SIZE * 4,
+        read_only,
+        None,
+        false,
+    )
+    .unwrap();
+    assert_eq!(ms.area_map.iter().count(), 2);
+    println!("memory set merge test passed!");
+}
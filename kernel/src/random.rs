@@ -0,0 +1,65 @@
+//! 内核的熵源
+//!
+//! 目前的硬件上没有可用的随机数指令，所以用 time 计数器作为熵：
+//! 启动时用它初始化状态，之后每次取随机数时也会把当前的计数混入状态中，
+//! 这样不同核、不同时刻的调用都会带来一些不可预测的抖动。
+//!
+//! 生成器本身是 xoshiro256**，它足够快，但不是密码学安全的。目前只用于地址随机化和 AT_RANDOM
+
+use crate::arch::get_cpu_id;
+use lock::Mutex;
+use timer::get_time;
+
+/// xoshiro256** 的状态
+struct Xoshiro256 {
+    state: [u64; 4],
+}
+
+impl Xoshiro256 {
+    /// 混入一个 64 位的值。用 splitmix64 把它打散到整个状态上
+    fn mix(&mut self, mut seed: u64) {
+        for word in self.state.iter_mut() {
+            seed = seed.wrapping_add(0x9e37_79b9_7f4a_7c15);
+            let mut z = seed;
+            z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+            z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+            *word ^= z ^ (z >> 31);
+        }
+    }
+
+    /// 生成下一个随机数
+    fn next(&mut self) -> u64 {
+        let s = &mut self.state;
+        let result = s[1].wrapping_mul(5).rotate_left(7).wrapping_mul(9);
+        let t = s[1] << 17;
+        s[2] ^= s[0];
+        s[3] ^= s[1];
+        s[1] ^= s[2];
+        s[0] ^= s[3];
+        s[2] ^= t;
+        s[3] = s[3].rotate_left(45);
+        result
+    }
+}
+
+/// 全局的随机数生成器
+static RNG: Mutex<Xoshiro256> = Mutex::new(Xoshiro256 { state: [0; 4] });
+
+/// 初始化熵源。需由其中一个核调用且仅调用一次
+pub fn init() {
+    let mut rng = RNG.lock();
+    rng.mix(get_time() as u64);
+    rng.mix(get_cpu_id() as u64);
+}
+
+/// 向熵源中加入一些不可预测的数据，如中断发生时用户程序的 pc。同时也会混入当前时刻
+pub fn add_entropy(data: u64) {
+    RNG.lock().mix(data ^ (get_time() as u64).rotate_left(32));
+}
+
+/// 获取一个随机的 u64
+pub fn random_u64() -> u64 {
+    let mut rng = RNG.lock();
+    rng.mix(get_time() as u64);
+    rng.next()
+}
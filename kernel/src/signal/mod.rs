@@ -4,6 +4,7 @@
 //!
 //! 目前的模型中，不采用 ipi 实时发送信号，而是由被目标线程在 trap 时处理。因此需要开启**时钟中断**来保证信号能实际送到
 
+use crate::task::{find_task_by_tid, wake_blocked_task};
 use bitset::Bitset;
 
 mod signal_no;
@@ -100,6 +101,17 @@ impl SignalReceivers {
         })
     }
 
+    /// 是否收到了 SIGKILL
+    pub fn has_sigkill(&self) -> bool {
+        self.sig_received
+            .contain_bit(SignalNo::SIGKILL as usize - 1)
+    }
+
+    /// 是否有需要处理的信号，即收到了 SIGKILL，或者收到了不在 mask 中的信号
+    pub fn has_pending(&self) -> bool {
+        self.has_sigkill() || self.sig_received.find_first_one(self.mask).is_some()
+    }
+
     /// 尝试添加一个 bit 作为信号。发送的信号如果在 mask 中，则仍然会发送，只是可能不触发
     /// 因为 signum 的范围是 \[1,64\]，所以内部要 -1
     ///
@@ -111,9 +123,15 @@ impl SignalReceivers {
 }
 
 /// 发送一个信号给进程 tid
+///
+/// 如果目标线程正阻塞在等待队列上，则唤醒它，让它回到用户态处理信号
 pub fn send_signal(tid: usize, signum: usize) {
     if let Some(signals) = get_signals_from_tid(tid as usize) {
         // 获取目标线程(可以是自己)的 signals 数组
         signals.lock().try_add_bit(signum);
+        // 先设置信号再唤醒。等待的线程在阻塞前会检查信号，所以不会漏掉
+        if let Some(task) = find_task_by_tid(tid) {
+            wake_blocked_task(task);
+        }
     }
 }
@@ -105,6 +105,118 @@ bitflags! {
 #define MAP_FIXED_NOREPLACE 0x100000
 */
 
+bitflags! {
+    /// sys_mremap 用到的选项
+    pub struct MRemapFlags: u32 {
+        /// 原地放不下时，可以移动到其他位置
+        const MAYMOVE = 1 << 0;
+        /// 移动到参数指定的位置，原本在那里的映射会被删除。需要和 MAYMOVE 一起使用
+        const FIXED = 1 << 1;
+        /// 移动后保留原来的映射，这里不支持
+        const DONTUNMAP = 1 << 2;
+    }
+}
+
+numeric_enum_macro::numeric_enum! {
+    #[repr(usize)]
+    #[allow(non_camel_case_types)]
+    #[derive(Debug)]
+    /// sys_madvise 使用的建议
+    pub enum MadviseAdvice {
+        /// 没有特别的建议
+        MADV_NORMAL = 0,
+        /// 会随机访问
+        MADV_RANDOM = 1,
+        /// 会顺序访问
+        MADV_SEQUENTIAL = 2,
+        /// 很快就会访问，可以预先分配
+        MADV_WILLNEED = 3,
+        /// 不再需要这段内存的内容，可以直接丢弃
+        MADV_DONTNEED = 4,
+        /// 和 MADV_DONTNEED 类似，但允许内核推迟到内存不足时再丢弃
+        MADV_FREE = 8,
+    }
+}
+
+/// sys_shmget 的 key 为这个值时，总是新建共享内存段
+pub const IPC_PRIVATE: usize = 0;
+/// 新版 libc 调用 sys_shmctl 时会在 cmd 上加这一位，表示使用 64 位的 shmid_ds
+pub const IPC_64: usize = 0x100;
+
+bitflags! {
+    /// sys_shmget 用到的选项。低 9 位是权限，不在这里
+    pub struct ShmGetFlags: u32 {
+        /// key 不存在时新建
+        const IPC_CREAT = 0o1000;
+        /// 和 IPC_CREAT 一起使用，key 已存在时报错
+        const IPC_EXCL = 0o2000;
+    }
+}
+
+bitflags! {
+    /// sys_shmat 用到的选项
+    pub struct ShmAtFlags: u32 {
+        /// 只读映射
+        const SHM_RDONLY = 0o10000;
+        /// 地址不对齐时向下对齐到页，而不是报错
+        const SHM_RND = 0o20000;
+        /// 可以替换掉给定地址上原有的映射
+        const SHM_REMAP = 0o40000;
+        /// 可执行
+        const SHM_EXEC = 0o100000;
+    }
+}
+
+numeric_enum_macro::numeric_enum! {
+    #[repr(usize)]
+    #[allow(non_camel_case_types)]
+    #[derive(Debug)]
+    /// sys_shmctl 使用的命令
+    pub enum ShmCtlCmd {
+        /// 删除共享内存段
+        IPC_RMID = 0,
+        /// 获取共享内存段的信息
+        IPC_STAT = 2,
+    }
+}
+
+/// sys_shmctl 使用的权限信息，即 `struct ipc_perm`
+#[repr(C)]
+#[derive(Debug, Default)]
+pub struct IpcPerm {
+    pub key: i32,
+    pub uid: u32,
+    pub gid: u32,
+    pub cuid: u32,
+    pub cgid: u32,
+    pub mode: u32,
+    pub seq: u16,
+    pub pad: u16,
+    pub unused: [usize; 2],
+}
+
+/// sys_shmctl(IPC_STAT) 返回的共享内存段信息，即 `struct shmid_ds`
+#[repr(C)]
+#[derive(Debug, Default)]
+pub struct ShmIdDs {
+    pub shm_perm: IpcPerm,
+    /// 段的大小(字节)
+    pub shm_segsz: usize,
+    /// 最后一次 shmat 的时间
+    pub shm_atime: isize,
+    /// 最后一次 shmdt 的时间
+    pub shm_dtime: isize,
+    /// 最后一次修改的时间
+    pub shm_ctime: isize,
+    /// 创建者的 pid
+    pub shm_cpid: i32,
+    /// 最后一次 shmat / shmdt 的进程的 pid
+    pub shm_lpid: i32,
+    /// 映射了这个段的次数
+    pub shm_nattch: usize,
+    pub unused: [usize; 2],
+}
+
 bitflags! {
     pub struct UtimensatFlags: u32 {
         /// 表示更新时间时如果是指向符号链接，则仅更新符号链接本身的时间，不更新其指向文件的时间
@@ -205,6 +317,50 @@ pub struct IoVec {
     pub len: usize,
 }
 
+/// sys_sendmsg / sys_recvmsg 中的消息头，即 `struct msghdr`
+#[repr(C)]
+pub struct MsgHdr {
+    /// 消息的地址，可以为空
+    pub name: *mut u8,
+    /// 地址的长度
+    pub name_len: u32,
+    /// 消息内容所在的一组缓冲区
+    pub iov: *mut IoVec,
+    /// 缓冲区的个数
+    pub iov_len: usize,
+    /// 控制信息(cmsg)所在的缓冲区，可以为空
+    pub control: *mut u8,
+    /// 控制信息缓冲区的长度
+    pub control_len: usize,
+    /// sys_recvmsg 返回时写入的标志，如 MSG_TRUNC 和 MSG_CTRUNC
+    pub flags: i32,
+}
+
+/// 控制信息的头，即 `struct cmsghdr`。数据紧跟在头之后
+#[repr(C)]
+pub struct CMsgHdr {
+    /// 头和数据的总长度
+    pub len: usize,
+    /// 控制信息的 level，如 SOL_SOCKET
+    pub level: i32,
+    /// 控制信息的类型，如 SCM_RIGHTS
+    pub cmsg_type: i32,
+}
+
+impl CMsgHdr {
+    /// 控制信息在缓冲区中按 8 字节对齐，即 CMSG_ALIGN
+    pub fn align(len: usize) -> usize {
+        (len + size_of::<usize>() - 1) & !(size_of::<usize>() - 1)
+    }
+}
+
+/// 控制信息的类型：随消息传递文件描述符
+pub const SCM_RIGHTS: i32 = 1;
+/// 一条 SCM_RIGHTS 控制信息中最多的 fd 数，与 Linux 相同
+pub const SCM_MAX_FD: usize = 253;
+/// 控制信息的类型：发送方的身份，即 `struct ucred`
+pub const SCM_CREDENTIALS: i32 = 2;
+
 // sys_lseek 时对应的条件
 /// 从文件开头
 pub const SEEK_SET: isize = 0;
@@ -238,12 +394,24 @@ pub struct RLimit {
 }
 
 // sys_prlimit64 使用的选项
+/// 数据段(包括堆)的最大大小
+pub const RLIMIT_DATA: i32 = 2;
 /// 用户栈大小
 pub const RLIMIT_STACK: i32 = 3;
 /// 可以打开的 fd 数
 pub const RLIMIT_NOFILE: i32 = 7;
 /// 用户地址空间的最大大小
 pub const RLIMIT_AS: i32 = 9;
+/// 表示没有上限
+pub const RLIM_INFINITY: u64 = u64::MAX;
+
+// sys_setpriority / sys_getpriority 使用的选项
+/// who 是一个进程(线程) id
+pub const PRIO_PROCESS: i32 = 0;
+/// who 是一个进程组 id
+pub const PRIO_PGRP: i32 = 1;
+/// who 是一个用户 id
+pub const PRIO_USER: i32 = 2;
 
 numeric_enum_macro::numeric_enum! {
     #[repr(usize)]
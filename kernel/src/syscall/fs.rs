@@ -11,12 +11,17 @@ use super::{
 };
 use crate::{
     constants::{AT_FDCWD, SENDFILE_BUFFER_SIZE},
+    drivers::BLOCK_DEVICES,
+    error::OSError,
+    file::socket::{absolute_unix_path, unlink_unix_path},
     file::{
         check_dir_exists, check_file_exists, get_dir_entry_iter, mkdir, mount_fat_fs, open_file,
         origin_fs_stat, read_link, rename_or_move, try_add_link, try_remove_link, umount_fat_fs,
+        write_back_all,
     },
-    file::{FatFile, FsStat, Pipe, SeekFrom},
-    task::{get_current_task, TaskControlBlock},
+    file::{FatFile, FsStat, Pipe, SeekFrom, ShmFile},
+    memory::{swap_off, swap_on, SwapStorage},
+    task::{get_current_task, signal_pending, TaskControlBlock},
     utils::raw_ptr_to_ref_str,
 };
 use alloc::{string::String, sync::Arc};
@@ -81,6 +86,10 @@ pub fn sys_read(fd: usize, buf: *mut u8, len: usize) -> SysResult {
             //println!("[kernel] read syscall size {} wanted {}", read_len, len);
             return Ok(read_len);
         }
+        // 阻塞的读被信号打断了
+        if signal_pending() {
+            return Err(ErrorNo::EINTR);
+        }
     }
     Err(ErrorNo::EINVAL)
 }
@@ -113,6 +122,14 @@ pub fn sys_write(fd: usize, buf: *const u8, len: usize) -> SysResult {
         if let Some(write_len) = file.write(slice) {
             return Ok(write_len);
         }
+        // 阻塞的写被信号打断了
+        if signal_pending() {
+            return Err(ErrorNo::EINTR);
+        }
+        // /dev/shm 下的文件写入失败时，区分超过了大小限制和内存不足
+        if let Some(shm_file) = file.as_any().downcast_ref::<ShmFile>() {
+            return Err(shm_file.write_error());
+        }
     }
     Err(ErrorNo::EINVAL)
 }
@@ -258,6 +275,88 @@ pub fn sys_fstat(fd: usize, kstat: *mut Kstat) -> SysResult {
     }
     Err(ErrorNo::EINVAL)
 }
+/// 把文件在页缓存中的脏页写回，fsync 和 fdatasync 都使用它。
+/// 只有 FAT 中的文件有页缓存，其他文件直接返回成功
+pub fn sys_fsync(fd: usize) -> SysResult {
+    let task = get_current_task().unwrap();
+    let file = task
+        .fd_manager
+        .lock()
+        .get_file(fd)
+        .map_err(|_| ErrorNo::EBADF)?;
+    if let Some(fat_file) = file.as_any().downcast_ref::<FatFile>() {
+        fat_file.sync();
+    }
+    Ok(0)
+}
+
+/// 修改文件的大小。目前只有 /dev/shm 下的文件支持，其他文件直接返回成功
+pub fn sys_ftruncate(fd: usize, len: usize) -> SysResult {
+    let task = get_current_task().unwrap();
+    let file = task
+        .fd_manager
+        .lock()
+        .get_file(fd)
+        .map_err(|_| ErrorNo::EBADF)?;
+    if let Some(shm_file) = file.as_any().downcast_ref::<ShmFile>() {
+        shm_file.truncate(len);
+    }
+    Ok(0)
+}
+
+/// 把页缓存中所有的脏页写回
+pub fn sys_sync() -> SysResult {
+    write_back_all();
+    Ok(0)
+}
+
+/// 找到 swapon / swapoff 的路径对应的交换区，返回用于区分交换区的完整路径和存放数据的位置。
+///
+/// /dev/vda、/dev/vdb ... 依次对应每个 virtio-blk 设备，其他路径则是文件系统中的交换文件
+fn resolve_swap_path(path: *const u8) -> Result<(String, SwapStorage), ErrorNo> {
+    let task = get_current_task().unwrap();
+    if task.vm.lock().manually_alloc_page(path as usize).is_err() {
+        return Err(ErrorNo::EFAULT); // 地址不合法
+    }
+    let file_path = unsafe { raw_ptr_to_ref_str(path) };
+    if let Some(name) = file_path.strip_prefix("/dev/vd") {
+        let device = match name.as_bytes() {
+            &[letter @ b'a'..=b'z'] => BLOCK_DEVICES.get((letter - b'a') as usize),
+            _ => None,
+        }
+        .ok_or(ErrorNo::ENOENT)?;
+        return Ok((String::from(file_path), SwapStorage::Block(device.clone())));
+    }
+    let (dir, file) = resolve_path_from_fd(&task, AT_FDCWD, path).ok_or(ErrorNo::EINVAL)?;
+    let file = open_file(dir.as_str(), file, OpenFlags::RDWR).ok_or(ErrorNo::ENOENT)?;
+    // 交换文件只能是 FAT 中的普通文件
+    let fat_file = file
+        .as_any()
+        .downcast_ref::<FatFile>()
+        .ok_or(ErrorNo::EINVAL)?;
+    Ok((fat_file.cache_key(), SwapStorage::File(fat_file.cache_io())))
+}
+
+/// 启用交换区。设备或文件需要先用 mkswap 格式化。
+/// flags 中的优先级和 discard 选项都被忽略，交换区按启用的顺序使用
+pub fn sys_swapon(path: *const u8, _flags: u32) -> SysResult {
+    let (path, storage) = resolve_swap_path(path)?;
+    swap_on(path, storage).map(|_| 0).map_err(|e| match e {
+        OSError::Swap_AreaBusy => ErrorNo::EBUSY,
+        OSError::Memory_RunOutOfMemory => ErrorNo::ENOMEM,
+        _ => ErrorNo::EINVAL,
+    })
+}
+
+/// 停用交换区。其中的页都会先换回内存
+pub fn sys_swapoff(path: *const u8) -> SysResult {
+    let (path, _) = resolve_swap_path(path)?;
+    swap_off(path.as_str()).map(|_| 0).map_err(|e| match e {
+        OSError::Swap_AreaBusy | OSError::Memory_RunOutOfMemory => ErrorNo::ENOMEM,
+        _ => ErrorNo::EINVAL,
+    })
+}
+
 /// 获取文件状态信息，但是给出的是目录 fd 和相对路径。
 pub fn sys_fstatat(dir_fd: i32, path: *const u8, kstat: *mut Kstat) -> SysResult {
     let task = get_current_task().unwrap();
@@ -365,6 +464,13 @@ pub fn sys_linkat(
 /// 删除硬链接，并在链接数为0时实际删除文件。成功时返回0，失败时返回-1
 pub fn sys_unlinkat(dir_fd: i32, path: *const u8, _flags: u32) -> SysResult {
     let task = get_current_task().unwrap();
+    // 绑定在这个路径上的 unix socket 地址不在文件系统中，需要单独删除
+    if let Some(dir) = get_dir_from_fd(&task, dir_fd) {
+        let file_path = unsafe { raw_ptr_to_ref_str(path) };
+        if unlink_unix_path(&absolute_unix_path(&dir, file_path)) {
+            return Ok(0);
+        }
+    }
     if let Some((path, file)) = resolve_path_from_fd(&task, dir_fd, path) {
         if try_remove_link(path, file) {
             return Ok(0);
@@ -762,13 +868,17 @@ pub fn sys_fcntl64(fd: usize, cmd: usize, arg: usize) -> SysResult {
                 }
             }
             Ok(Fcntl64Cmd::F_GETFD) => {
-                if file.get_status().contains(OpenFlags::CLOEXEC) {
+                if file.get_status().contains(OpenFlags::CLOEXEC) || fd_manager.is_fd_cloexec(fd) {
                     Ok(1)
                 } else {
                     Ok(0)
                 }
             }
             Ok(Fcntl64Cmd::F_SETFD) => {
+                // 标记只设置在文件上，但清除时 fd 表项上的标记也要清除
+                if (arg & 1) == 0 {
+                    fd_manager.set_fd_cloexec(fd, false);
+                }
                 if file.set_close_on_exec((arg & 1) != 0) {
                     Ok(0)
                 } else {
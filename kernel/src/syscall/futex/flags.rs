@@ -10,6 +10,20 @@ pub enum Flags {
     WAKE = 1,
     /// 唤醒最多 val 个在等待 uaddr 位置的线程。如果有更多，则将它们转移到 uaddr2 处，至多转移 val2 个
     REQUEUE = 3,
+    /// 同 REQUEUE，但会先检查 uaddr 处的值是否等于 val3，不等则返回 EAGAIN
+    CMP_REQUEUE = 4,
+    /// 修改 uaddr2 处的值，唤醒 uaddr 上的 val 个线程，再根据 uaddr2 处原来的值决定是否唤醒 uaddr2 上的 val2 个线程
+    WAKE_OP = 5,
+    /// 获取 uaddr 处的 PI(优先级继承) futex。如果已被其他线程持有，则等待，并让持有者继承当前线程的优先级
+    LOCK_PI = 6,
+    /// 释放 uaddr 处的 PI futex，并把它交给等待者中优先级最高的线程
+    UNLOCK_PI = 7,
+    /// 同 LOCK_PI，但 futex 已被其他线程持有时直接返回 EAGAIN
+    TRYLOCK_PI = 8,
+    /// 同 WAIT，但只能被 bitset 与 val3 有交集的 WAKE_BITSET 唤醒，且超时时间是绝对时间
+    WAIT_BITSET = 9,
+    /// 同 WAKE，但只唤醒 bitset 与 val3 有交集的线程
+    WAKE_BITSET = 10,
     UNSUPPORTED,
 }
 
@@ -21,7 +35,7 @@ impl FutexFlag {
     pub fn new(val: i32) -> Self {
         Self(val)
     }
-    /// 是否是当前地址空间内的。目前不支持跨进程的 futex
+    /// 是否是当前地址空间内的。不是的话则可能和其他进程共享
     pub fn is_private(&self) -> bool {
         (self.0 & 0x80) > 0
     }
@@ -31,7 +45,69 @@ impl FutexFlag {
             0 => Flags::WAIT,
             1 => Flags::WAKE,
             3 => Flags::REQUEUE,
+            4 => Flags::CMP_REQUEUE,
+            5 => Flags::WAKE_OP,
+            6 => Flags::LOCK_PI,
+            7 => Flags::UNLOCK_PI,
+            8 => Flags::TRYLOCK_PI,
+            9 => Flags::WAIT_BITSET,
+            10 => Flags::WAKE_BITSET,
             _ => Flags::UNSUPPORTED,
         }
     }
 }
+
+/// WAIT 和 WAKE 对应的 bitset，即可以匹配任意的 bitset
+pub const FUTEX_BITSET_MATCH_ANY: u32 = 0xffff_ffff;
+
+/// PI futex 和 robust futex 中，表示有线程在等待这个 futex
+pub const FUTEX_WAITERS: u32 = 0x8000_0000;
+/// PI futex 和 robust futex 中，表示持有这个 futex 的线程已经退出了
+pub const FUTEX_OWNER_DIED: u32 = 0x4000_0000;
+/// PI futex 和 robust futex 中，持有者的 tid 所在的位
+pub const FUTEX_TID_MASK: u32 = 0x3fff_ffff;
+
+/// WAKE_OP 中 val3 编码的操作，格式为 `op:4 | cmp:4 | oparg:12 | cmparg:12`
+pub struct FutexWakeOp(u32);
+
+impl FutexWakeOp {
+    /// 解析 val3
+    pub fn new(val3: u32) -> Self {
+        Self(val3)
+    }
+    /// 把 12 位的有符号数扩展成 i32
+    fn sign_extend_12(val: u32) -> i32 {
+        ((val << 20) as i32) >> 20
+    }
+    /// 根据 uaddr2 处原来的值计算新的值。操作不合法时返回 None
+    pub fn apply(&self, old_val: u32) -> Option<u32> {
+        let op = (self.0 >> 28) & 0x7;
+        let mut oparg = Self::sign_extend_12((self.0 >> 12) & 0xfff) as u32;
+        // FUTEX_OP_OPARG_SHIFT，表示实际的参数是 1 << oparg
+        if (self.0 >> 28) & 0x8 != 0 {
+            oparg = 1u32.checked_shl(oparg)?;
+        }
+        match op {
+            0 => Some(oparg),                       // FUTEX_OP_SET
+            1 => Some(old_val.wrapping_add(oparg)), // FUTEX_OP_ADD
+            2 => Some(old_val | oparg),             // FUTEX_OP_OR
+            3 => Some(old_val & !oparg),            // FUTEX_OP_ANDN
+            4 => Some(old_val ^ oparg),             // FUTEX_OP_XOR
+            _ => None,
+        }
+    }
+    /// 比较 uaddr2 处原来的值，决定是否唤醒 uaddr2 上的线程。比较方式不合法时返回 None
+    pub fn compare(&self, old_val: u32) -> Option<bool> {
+        let old_val = old_val as i32;
+        let cmparg = Self::sign_extend_12(self.0 & 0xfff);
+        match (self.0 >> 24) & 0xf {
+            0 => Some(old_val == cmparg), // FUTEX_OP_CMP_EQ
+            1 => Some(old_val != cmparg), // FUTEX_OP_CMP_NE
+            2 => Some(old_val < cmparg),  // FUTEX_OP_CMP_LT
+            3 => Some(old_val <= cmparg), // FUTEX_OP_CMP_LE
+            4 => Some(old_val > cmparg),  // FUTEX_OP_CMP_GT
+            5 => Some(old_val >= cmparg), // FUTEX_OP_CMP_GE
+            _ => None,
+        }
+    }
+}
@@ -7,18 +7,22 @@
 //! 由于 futex 参数复杂，所以特别开了一个子模块来放和它相关的实现与 flag
 
 mod flags;
-mod waiter;
-pub use waiter::{FutexWaiter, Waiter};
-mod waiting_board;
-pub use waiting_board::{check_thread_blocked, set_waiter_for_thread, wake_thread};
+mod pi;
+mod robust;
+mod table;
+
+pub use robust::{exit_robust_list, sys_get_robust_list, sys_set_robust_list};
 
 use super::{sys_gettid, SysResult};
-use crate::task::{get_current_task, suspend_current_task};
-use alloc::boxed::Box;
-use flags::{Flags, FutexFlag};
+use crate::task::{get_current_task, signal_pending};
+use alloc::sync::Arc;
+use core::sync::atomic::{AtomicU32, Ordering};
+use flags::{Flags, FutexFlag, FutexWakeOp, FUTEX_BITSET_MATCH_ANY};
 use lock::Mutex;
+use pi::{futex_lock_pi, futex_unlock_pi};
 use syscall::ErrorNo;
-use timer::{TimeSpec, TimeVal};
+use table::{lock_futex_table, wake_futex_waiters, FutexKey, FutexWaiter};
+use timer::{get_time_us, TimeSpec, TimeVal};
 
 static FCOUNT: Mutex<usize> = Mutex::new(0);
 
@@ -37,9 +41,7 @@ pub fn sys_futex(
         "futex: uaddr {:x}, op {} val {} val2 {:x} uaddr2 {:x} val3 {}",
         uaddr, futex_op, val, val2, uaddr2, val3
     );
-    if !flag.is_private() { // 不支持跨地址空间
-         //panic!("futex not private");
-    }
+    let is_private = flag.is_private();
 
     *FCOUNT.lock() += 1;
     //if uaddr == 0x85f60 && tid == 3 && *FCOUNT.lock() > 300 {
@@ -47,44 +49,200 @@ pub fn sys_futex(
     //}
     match flag.operation() {
         Flags::WAIT => {
-            //info!("futex wait, suspend---");
-            // 检查 uaddr 处的地址
-            let task = get_current_task().unwrap();
-            let mut task_vm = task.vm.lock();
-            if task_vm.manually_alloc_page(uaddr).is_ok() {
-                let real_val = unsafe { (uaddr as *const u32).read_volatile() };
-                if real_val != val {
-                    Err(ErrorNo::EAGAIN)
-                } else {
-                    // 如果是个表示 timeout 的地址
-                    let timed_out =
-                        if val2 != 0 && task_vm.manually_alloc_page(val2 as usize).is_ok() {
-                            let time_spec: TimeSpec = unsafe { *(val2 as *const TimeSpec) };
-                            let time_us: usize = TimeVal::from(time_spec).into();
-                            info!("futex timed out {time_us} us");
-                            Some(time_us)
-
-                            //panic!("");
-                        } else {
-                            // None，永不通过超时唤醒
-                            Some(0)
-                        };
-                    set_waiter_for_thread(tid, Box::new(FutexWaiter::new(timed_out)));
-                    drop(task_vm); // 切换任务前取消对锁的占用
-                    drop(task);
-                    suspend_current_task();
-                    Ok(0)
-                }
-            } else {
-                // 若地址无效
-                Err(ErrorNo::EFAULT)
+            // 超时时间是相对时间
+            let expire_us = read_timeout(val2)?.map(|time_us| get_time_us() + time_us);
+            futex_wait(uaddr, is_private, val, expire_us, FUTEX_BITSET_MATCH_ANY)
+        }
+        Flags::WAIT_BITSET => {
+            if val3 == 0 {
+                return Err(ErrorNo::EINVAL);
+            }
+            // 超时时间是绝对时间
+            let expire_us = read_timeout(val2)?;
+            futex_wait(uaddr, is_private, val, expire_us, val3)
+        }
+        Flags::WAKE => futex_wake_bitset(uaddr, is_private, val as usize, FUTEX_BITSET_MATCH_ANY),
+        Flags::WAKE_BITSET => {
+            if val3 == 0 {
+                return Err(ErrorNo::EINVAL);
             }
+            futex_wake_bitset(uaddr, is_private, val as usize, val3)
         }
-        Flags::WAKE => {
-            //info!("futex wake");
-            suspend_current_task();
-            Ok(val as usize)
+        // REQUEUE 和 CMP_REQUEUE 中，val2 表示最多转移的线程数
+        Flags::REQUEUE => futex_requeue(uaddr, uaddr2, is_private, val as usize, val2, None),
+        Flags::CMP_REQUEUE => {
+            futex_requeue(uaddr, uaddr2, is_private, val as usize, val2, Some(val3))
         }
+        // WAKE_OP 中，val2 表示 uaddr2 上最多唤醒的线程数
+        Flags::WAKE_OP => futex_wake_op(uaddr, uaddr2, is_private, val as usize, val2, val3),
+        // PI futex 的超时时间是绝对时间
+        Flags::LOCK_PI => futex_lock_pi(uaddr, is_private, read_timeout(val2)?, false),
+        Flags::TRYLOCK_PI => futex_lock_pi(uaddr, is_private, None, true),
+        Flags::UNLOCK_PI => futex_unlock_pi(uaddr, is_private),
         _ => Err(ErrorNo::EINVAL),
     }
 }
+
+/// 唤醒最多 n 个在 uaddr 上等待的线程，返回实际唤醒的个数。
+/// 用于线程退出时唤醒在 clear_child_tid 上等待的线程，此时和 Linux 一样按非私有的 futex 处理
+pub fn futex_wake(uaddr: usize, n: usize) -> usize {
+    futex_wake_bitset(uaddr, false, n, FUTEX_BITSET_MATCH_ANY).unwrap_or(0)
+}
+
+/// 读取用户传入的超时时间，单位为微秒。地址为 0 表示不会超时
+fn read_timeout(timeout: usize) -> Result<Option<usize>, ErrorNo> {
+    if timeout == 0 {
+        return Ok(None);
+    }
+    let task = get_current_task().unwrap();
+    let mut task_vm = task.vm.lock();
+    if task_vm
+        .manually_alloc_type(timeout as *const TimeSpec)
+        .is_err()
+    {
+        return Err(ErrorNo::EFAULT);
+    }
+    let time_spec: TimeSpec = unsafe { *(timeout as *const TimeSpec) };
+    let time_us: usize = TimeVal::from(time_spec).into();
+    info!("futex timed out {time_us} us");
+    Ok(Some(time_us))
+}
+
+/// 获取 uaddr 对应的 futex 的键，同时保证 uaddr 所在的页已经分配
+fn get_futex_key(uaddr: usize, is_private: bool) -> Result<FutexKey, ErrorNo> {
+    // futex 是 4 字节对齐的 u32
+    if uaddr % 4 != 0 {
+        return Err(ErrorNo::EINVAL);
+    }
+    let task = get_current_task().unwrap();
+    let mut task_vm = task.vm.lock();
+    // 如果是写时复制的页，这里会把它复制一份，所以之后这个地址空间独占这一页
+    if task_vm.manually_alloc_page(uaddr).is_err() {
+        // 若地址无效
+        return Err(ErrorNo::EFAULT);
+    }
+    if !is_private {
+        if let Some(location) = task_vm.shared_location(uaddr) {
+            return Ok(FutexKey::Shared(location));
+        }
+    }
+    Ok(FutexKey::Private {
+        mm: Arc::as_ptr(&task.vm) as usize,
+        uaddr,
+    })
+}
+
+/// 读取用户地址 uaddr 处的值
+fn read_futex_value(uaddr: usize) -> u32 {
+    unsafe { (uaddr as *const u32).read_volatile() }
+}
+
+/// 如果 uaddr 处的值等于 val，则等待直到被 bitset 有交集的 wake 唤醒，或者系统时间到达 expire_us
+fn futex_wait(
+    uaddr: usize,
+    is_private: bool,
+    val: u32,
+    expire_us: Option<usize>,
+    bitset: u32,
+) -> SysResult {
+    let key = get_futex_key(uaddr, is_private)?;
+    let waiter = Arc::new(FutexWaiter::new(key, bitset));
+    let mut table = lock_futex_table();
+    // 检查 uaddr 处的值和进入等待队列需要在同一次持有锁时完成，否则可能错过在两者之间发生的 wake
+    if read_futex_value(uaddr) != val {
+        return Err(ErrorNo::EAGAIN);
+    }
+    table.push(waiter.clone());
+    drop(table); // 切换任务前取消对锁的占用
+    if waiter.wait(expire_us) {
+        return Ok(0);
+    }
+    // 超时或被信号打断了，但可能在这之后、删除 waiter 之前又被唤醒了，此时仍然算作被唤醒
+    if lock_futex_table().remove(&waiter) {
+        Err(if signal_pending() {
+            ErrorNo::EINTR
+        } else {
+            ErrorNo::ETIMEDOUT
+        })
+    } else {
+        Ok(0)
+    }
+}
+
+/// 唤醒最多 n 个在 uaddr 上等待、且 bitset 与给定值有交集的线程，返回实际唤醒的个数
+fn futex_wake_bitset(uaddr: usize, is_private: bool, n: usize, bitset: u32) -> SysResult {
+    let key = get_futex_key(uaddr, is_private)?;
+    let waiters = lock_futex_table().take_waiters(key, n, bitset);
+    Ok(wake_futex_waiters(waiters))
+}
+
+/// 唤醒 uaddr 上最多 n_wake 个线程，再把剩下的最多 n_requeue 个线程转移到 uaddr2 上。
+///
+/// 如果 cmp_val 不为 None，则先检查 uaddr 处的值是否与它相等，不等则返回 EAGAIN，
+/// 此时返回值包括唤醒和转移的线程数(CMP_REQUEUE)；否则只包括唤醒的线程数(REQUEUE)
+fn futex_requeue(
+    uaddr: usize,
+    uaddr2: usize,
+    is_private: bool,
+    n_wake: usize,
+    n_requeue: usize,
+    cmp_val: Option<u32>,
+) -> SysResult {
+    let key = get_futex_key(uaddr, is_private)?;
+    let key2 = get_futex_key(uaddr2, is_private)?;
+    let mut table = lock_futex_table();
+    if let Some(cmp_val) = cmp_val {
+        if read_futex_value(uaddr) != cmp_val {
+            return Err(ErrorNo::EAGAIN);
+        }
+    }
+    let waiters = table.take_waiters(key, n_wake, FUTEX_BITSET_MATCH_ANY);
+    let requeued = table.requeue(key, key2, n_requeue);
+    drop(table);
+    let woken = wake_futex_waiters(waiters);
+    if cmp_val.is_some() {
+        Ok(woken + requeued)
+    } else {
+        Ok(woken)
+    }
+}
+
+/// 按 val3 编码的操作原子地修改 uaddr2 处的值，然后唤醒 uaddr 上最多 n_wake 个线程；
+/// 如果 uaddr2 处原来的值满足 val3 编码的比较条件，再唤醒 uaddr2 上最多 n_wake2 个线程
+fn futex_wake_op(
+    uaddr: usize,
+    uaddr2: usize,
+    is_private: bool,
+    n_wake: usize,
+    n_wake2: usize,
+    val3: u32,
+) -> SysResult {
+    let key = get_futex_key(uaddr, is_private)?;
+    let key2 = get_futex_key(uaddr2, is_private)?;
+    let wake_op = FutexWakeOp::new(val3);
+    // 先检查比较方式是否合法，以免修改了 uaddr2 处的值后才发现参数错误
+    if wake_op.compare(0).is_none() {
+        return Err(ErrorNo::EINVAL);
+    }
+    // 修改 uaddr2 处的值时持有地址空间的锁，这一页不会被其他线程 munmap 或 mprotect，所以不会在内核中触发缺页。
+    // 修改在拿表的锁之前进行，即使出错也不会持有表的锁
+    let task = get_current_task().unwrap();
+    let mut task_vm = task.vm.lock();
+    if task_vm.manually_alloc_writable_page(uaddr2).is_err() {
+        return Err(ErrorNo::EFAULT);
+    }
+    let atomic = unsafe { &*(uaddr2 as *const AtomicU32) };
+    let old_val = atomic
+        .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |old_val| {
+            wake_op.apply(old_val)
+        })
+        .map_err(|_| ErrorNo::EINVAL)?;
+    drop(task_vm);
+    let mut table = lock_futex_table();
+    let mut waiters = table.take_waiters(key, n_wake, FUTEX_BITSET_MATCH_ANY);
+    if wake_op.compare(old_val).unwrap() {
+        waiters.extend(table.take_waiters(key2, n_wake2, FUTEX_BITSET_MATCH_ANY));
+    }
+    drop(table);
+    Ok(wake_futex_waiters(waiters))
+}
@@ -0,0 +1,126 @@
+//! 优先级继承(PI) futex
+//!
+//! futex 的值的低 30 位是持有者的 tid，最高位 `FUTEX_WAITERS` 表示有线程在等待。
+//! 用户态在没有竞争时直接用原子操作获取和释放，只有在获取失败或者释放时发现有等待者，才调用 LOCK_PI / UNLOCK_PI。
+//!
+//! 等待的线程会把自己的优先级借给持有者(见 `task/sched_entity.rs` 中的 `pi_nice`)，
+//! 这样低优先级的持有者不会因为分不到 CPU 时间而一直不释放锁。释放时锁会直接交给优先级最高的等待者
+
+use super::{
+    flags::{FUTEX_BITSET_MATCH_ANY, FUTEX_OWNER_DIED, FUTEX_TID_MASK, FUTEX_WAITERS},
+    get_futex_key,
+    table::{lock_futex_table, wake_futex_waiters, FutexWaiter},
+    SysResult,
+};
+use crate::task::{find_task_by_tid, get_current_task, signal_pending};
+use alloc::{sync::Arc, vec};
+use core::sync::atomic::{AtomicU32, Ordering};
+use syscall::ErrorNo;
+
+/// 获取 uaddr 处的 PI futex，直到成功或者系统时间到达 expire_us。
+/// 如果 is_try 为 true，则 futex 已被其他线程持有时直接返回 EAGAIN
+pub fn futex_lock_pi(
+    uaddr: usize,
+    is_private: bool,
+    expire_us: Option<usize>,
+    is_try: bool,
+) -> SysResult {
+    let key = get_futex_key(uaddr, is_private)?;
+    let tid = get_current_task().unwrap().get_tid_num();
+    let atomic = unsafe { &*(uaddr as *const AtomicU32) };
+    loop {
+        let mut table = lock_futex_table();
+        let val = atomic.load(Ordering::SeqCst);
+        let owner = (val & FUTEX_TID_MASK) as usize;
+        if owner == 0 {
+            // 锁是空闲的(可能是上一个持有者退出后留下的)，直接获取。如果还有其他等待者，需要保留 FUTEX_WAITERS
+            let waiters_bit = if table.has_waiters(key) {
+                FUTEX_WAITERS
+            } else {
+                0
+            };
+            let new_val = tid as u32 | (val & FUTEX_OWNER_DIED) | waiters_bit;
+            if atomic
+                .compare_exchange(val, new_val, Ordering::SeqCst, Ordering::SeqCst)
+                .is_err()
+            {
+                continue;
+            }
+            table.set_pi_owner(key, tid);
+            table.update_pi_boost(tid);
+            return Ok(0);
+        }
+        if owner == tid {
+            return Err(ErrorNo::EDEADLK);
+        }
+        if is_try {
+            return Err(ErrorNo::EAGAIN);
+        }
+        if find_task_by_tid(owner).is_none() {
+            // 持有者已经退出，且没有通过 robust list 释放这个锁
+            return Err(ErrorNo::ESRCH);
+        }
+        // 标记有等待者，这样持有者在用户态释放锁时会失败，从而调用 UNLOCK_PI
+        if val & FUTEX_WAITERS == 0
+            && atomic
+                .compare_exchange(val, val | FUTEX_WAITERS, Ordering::SeqCst, Ordering::SeqCst)
+                .is_err()
+        {
+            continue;
+        }
+        let waiter = Arc::new(FutexWaiter::new(key, FUTEX_BITSET_MATCH_ANY));
+        table.push(waiter.clone());
+        table.set_pi_owner(key, owner);
+        table.update_pi_boost(owner);
+        drop(table); // 切换任务前取消对锁的占用
+        if !waiter.wait(expire_us) {
+            let mut table = lock_futex_table();
+            // 超时或被信号打断了，但可能在这之后、删除 waiter 之前锁已经交给了当前线程
+            if table.remove(&waiter) {
+                table.update_pi_boost(owner);
+                return Err(if signal_pending() {
+                    ErrorNo::EINTR
+                } else {
+                    ErrorNo::ETIMEDOUT
+                });
+            }
+        }
+        // 通常是 UNLOCK_PI 直接把锁交给了当前线程。
+        // 但也可能是持有者退出时通过 robust list 唤醒了当前线程，此时需要重新尝试获取
+        if (atomic.load(Ordering::SeqCst) & FUTEX_TID_MASK) as usize == tid {
+            return Ok(0);
+        }
+    }
+}
+
+/// 释放当前线程持有的 uaddr 处的 PI futex。如果有等待者，则把锁交给其中优先级最高的线程
+pub fn futex_unlock_pi(uaddr: usize, is_private: bool) -> SysResult {
+    let key = get_futex_key(uaddr, is_private)?;
+    let tid = get_current_task().unwrap().get_tid_num();
+    let atomic = unsafe { &*(uaddr as *const AtomicU32) };
+    let mut table = lock_futex_table();
+    if (atomic.load(Ordering::SeqCst) & FUTEX_TID_MASK) as usize != tid {
+        return Err(ErrorNo::EPERM);
+    }
+    let next = table.take_pi_waiter(key);
+    match &next {
+        Some(next) => {
+            let waiters_bit = if table.has_waiters(key) {
+                FUTEX_WAITERS
+            } else {
+                0
+            };
+            atomic.store(next.tid() as u32 | waiters_bit, Ordering::SeqCst);
+            table.set_pi_owner(key, next.tid());
+            table.update_pi_boost(next.tid());
+        }
+        None => atomic.store(0, Ordering::SeqCst),
+    }
+    // 不再持有这个锁，重新计算当前线程继承的优先级
+    table.update_pi_boost(tid);
+    drop(table);
+    if let Some(next) = next {
+        wake_futex_waiters(vec![next]);
+    }
+    Ok(0)
+}
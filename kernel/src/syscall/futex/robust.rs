@@ -0,0 +1,120 @@
+//! robust futex 链表
+//!
+//! 用户态(如 pthread_mutex 的 robust 模式)把线程持有的锁串成一个链表，通过 set_robust_list 告诉内核链表头的位置。
+//! 线程退出时，内核遍历这个链表，把其中仍被它持有的 futex 标记为 `FUTEX_OWNER_DIED` 并唤醒一个等待者，
+//! 这样其他线程就不会因为持有者意外退出而永远等下去。
+//!
+//! 详见 `https://www.kernel.org/doc/Documentation/robust-futex-ABI.txt`
+
+use super::{
+    flags::{FUTEX_OWNER_DIED, FUTEX_TID_MASK, FUTEX_WAITERS},
+    futex_wake, SysResult,
+};
+use crate::task::{find_task_by_tid, get_current_task};
+use core::{
+    mem::size_of,
+    sync::atomic::{AtomicU32, Ordering},
+};
+use syscall::ErrorNo;
+
+/// 用户态的 robust futex 链表头
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct RobustListHead {
+    /// 链表中的第一项。链表是循环的，最后一项指回链表头
+    list: usize,
+    /// 每一项的地址加上这个偏移量，就是对应的 futex 的地址
+    futex_offset: isize,
+    /// 正在获取或释放、还没有加入或移出链表的一项
+    list_op_pending: usize,
+}
+
+/// 最多遍历的链表项数，防止用户构造的环形链表让内核无法退出
+const ROBUST_LIST_LIMIT: usize = 2048;
+
+/// 设置当前线程的 robust futex 链表头
+pub fn sys_set_robust_list(head: usize, len: usize) -> SysResult {
+    if len != size_of::<RobustListHead>() {
+        return Err(ErrorNo::EINVAL);
+    }
+    get_current_task().unwrap().set_robust_list(head);
+    Ok(0)
+}
+
+/// 获取线程的 robust futex 链表头，写入 head_ptr，并把链表头的大小写入 len_ptr。
+///
+/// pid 为 0 时表示当前线程
+pub fn sys_get_robust_list(pid: usize, head_ptr: *mut usize, len_ptr: *mut usize) -> SysResult {
+    let task = if pid == 0 {
+        get_current_task().unwrap()
+    } else {
+        find_task_by_tid(pid).ok_or(ErrorNo::ESRCH)?
+    };
+    let head = task.get_robust_list();
+    drop(task);
+    let current = get_current_task().unwrap();
+    let mut task_vm = current.vm.lock();
+    if task_vm.manually_alloc_type(head_ptr).is_err()
+        || task_vm.manually_alloc_type(len_ptr).is_err()
+    {
+        return Err(ErrorNo::EFAULT);
+    }
+    unsafe {
+        *head_ptr = head;
+        *len_ptr = size_of::<RobustListHead>();
+    }
+    Ok(0)
+}
+
+/// 读取当前线程地址空间中 addr 处的值。地址无效时返回 None
+fn read_user<T: Copy>(addr: usize) -> Option<T> {
+    let task = get_current_task().unwrap();
+    let mut task_vm = task.vm.lock();
+    task_vm.manually_alloc_type(addr as *const T).ok()?;
+    Some(unsafe { *(addr as *const T) })
+}
+
+/// 线程退出时，遍历它的 robust futex 链表，释放其中仍被它持有的 futex。
+///
+/// 链表项的最低位表示它是否是 PI futex，这里两者的处理方式相同
+pub fn exit_robust_list(head_addr: usize, tid: usize) {
+    let head = match read_user::<RobustListHead>(head_addr) {
+        Some(head) => head,
+        None => return,
+    };
+    let pending = head.list_op_pending & !1;
+    let mut entry = head.list & !1;
+    let mut count = 0;
+    while entry != head_addr && entry != 0 && count < ROBUST_LIST_LIMIT {
+        // 先读出下一项，因为唤醒等待者后，这一项可能马上被其他线程修改
+        let next = match read_user::<usize>(entry) {
+            Some(next) => next & !1,
+            None => return,
+        };
+        // list_op_pending 放到最后处理，以免处理两次
+        if entry != pending {
+            handle_futex_death(entry.wrapping_add(head.futex_offset as usize), tid);
+        }
+        entry = next;
+        count += 1;
+    }
+    if pending != 0 {
+        handle_futex_death(pending.wrapping_add(head.futex_offset as usize), tid);
+    }
+}
+
+/// 如果 uaddr 处的 futex 仍被退出的线程持有，则标记为 FUTEX_OWNER_DIED，并唤醒一个等待者
+fn handle_futex_death(uaddr: usize, tid: usize) {
+    if uaddr % 4 != 0 || read_user::<u32>(uaddr).is_none() {
+        return;
+    }
+    let atomic = unsafe { &*(uaddr as *const AtomicU32) };
+    let old_val = atomic.fetch_update(Ordering::SeqCst, Ordering::SeqCst, |val| {
+        ((val & FUTEX_TID_MASK) as usize == tid).then_some((val & FUTEX_WAITERS) | FUTEX_OWNER_DIED)
+    });
+    if let Ok(old_val) = old_val {
+        if old_val & FUTEX_WAITERS != 0 {
+            futex_wake(uaddr, 1);
+        }
+    }
+}
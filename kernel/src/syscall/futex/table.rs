@@ -0,0 +1,216 @@
+//! 一张全局的表，记录在每个 futex 上等待的线程
+//!
+//! futex 以 `FutexKey` 区分：
+//! - 私有的 futex 只在一个地址空间内有效，以地址空间和用户地址区分；
+//! - 非私有的 futex 如果位于共享映射(MAP_SHARED)中，则以它在被映射的对象(共享内存或文件)中的位置区分，
+//! 这样不同进程的线程即使把它映射到不同的地址也能互相唤醒。
+//! 否则它和私有的 futex 没有区别，这和 Linux 对私有匿名映射的处理是一致的。
+//!
+//! 每个等待的线程都有自己的 `FutexWaiter`，其中有一个只包含它自己的等待队列。
+//! 检查 uaddr 处的值、把 waiter 放进表里、以及唤醒和 requeue 都是在持有表的锁时进行的，
+//! 所以修改 uaddr 处的值后再 wake 的线程一定能看到在它之前检查了值的 waiter
+//!
+//! 对于 PI futex，表中还记录了每个有等待者的 futex 的持有者，用于计算持有者应该继承的优先级
+
+use crate::memory::SharedLocation;
+use crate::task::{find_task_by_tid, get_current_task, WaitQueue};
+use alloc::{
+    collections::{BTreeMap, VecDeque},
+    sync::Arc,
+    vec::Vec,
+};
+use core::sync::atomic::{AtomicBool, Ordering};
+use lock::{Mutex, MutexGuard};
+
+/// 区分不同 futex 的键
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug)]
+pub enum FutexKey {
+    /// 只在一个地址空间内有效的 futex，mm 是地址空间(MemorySet)的地址
+    Private { mm: usize, uaddr: usize },
+    /// 共享映射中的 futex，以它在被映射的对象中的位置区分
+    Shared(SharedLocation),
+}
+
+/// 一个在 futex 上等待的线程
+pub struct FutexWaiter {
+    /// 只有 bitset 与之有交集的 wake 才能唤醒这个线程
+    bitset: u32,
+    /// 当前等待的 futex。requeue 时会被修改，所以只能在持有表的锁时访问
+    key: Mutex<FutexKey>,
+    /// 是否已被唤醒。只在持有表的锁时修改
+    woken: AtomicBool,
+    /// 等待的线程的 tid
+    tid: usize,
+    /// 等待的线程开始等待时实际的 nice 值。PI futex 的持有者会继承其中最小的一个
+    nice: i32,
+    /// 线程在这里阻塞
+    queue: WaitQueue,
+}
+
+impl FutexWaiter {
+    /// 为当前线程新建一个 waiter
+    pub fn new(key: FutexKey, bitset: u32) -> Self {
+        let task = get_current_task().unwrap();
+        let nice = task.sched.lock().effective_nice();
+        Self {
+            bitset,
+            key: Mutex::new(key),
+            woken: AtomicBool::new(false),
+            tid: task.get_tid_num(),
+            nice,
+            queue: WaitQueue::new(),
+        }
+    }
+    /// 等待的线程的 tid
+    pub fn tid(&self) -> usize {
+        self.tid
+    }
+    /// 阻塞当前线程，直到被唤醒或者系统时间到达 expire_us。返回是否被唤醒
+    pub fn wait(&self, expire_us: Option<usize>) -> bool {
+        self.queue
+            .wait_until(expire_us, || {
+                self.woken.load(Ordering::Acquire).then_some(())
+            })
+            .is_some()
+    }
+}
+
+/// 所有 futex 上的等待线程
+pub struct FutexTable {
+    /// 每个 futex 上的等待线程，按等待的先后排列
+    waiters: BTreeMap<FutexKey, VecDeque<Arc<FutexWaiter>>>,
+    /// 有等待者的 PI futex 的持有者 tid
+    pi_owners: BTreeMap<FutexKey, usize>,
+}
+
+/// 全局的 futex 表
+static FUTEX_TABLE: Mutex<FutexTable> = Mutex::new(FutexTable {
+    waiters: BTreeMap::new(),
+    pi_owners: BTreeMap::new(),
+});
+
+/// 获取 futex 表的锁
+pub fn lock_futex_table() -> MutexGuard<'static, FutexTable> {
+    FUTEX_TABLE.lock()
+}
+
+impl FutexTable {
+    /// 把 waiter 加到它等待的 futex 的队尾
+    pub fn push(&mut self, waiter: Arc<FutexWaiter>) {
+        let key = *waiter.key.lock();
+        self.waiters.entry(key).or_default().push_back(waiter);
+    }
+    /// key 对应的 futex 上是否有等待的线程
+    pub fn has_waiters(&self, key: FutexKey) -> bool {
+        self.waiters.contains_key(&key)
+    }
+    /// 如果 key 对应的 futex 上已经没有等待的线程，则删除它的记录
+    fn remove_if_empty(&mut self, key: FutexKey) {
+        if self
+            .waiters
+            .get(&key)
+            .map_or(false, |waiters| waiters.is_empty())
+        {
+            self.waiters.remove(&key);
+        }
+        if !self.waiters.contains_key(&key) {
+            self.pi_owners.remove(&key);
+        }
+    }
+    /// 把 waiter 从表中删除。如果它已经被唤醒了(所以不在表里)，则返回 false
+    pub fn remove(&mut self, waiter: &Arc<FutexWaiter>) -> bool {
+        if waiter.woken.load(Ordering::Acquire) {
+            return false;
+        }
+        let key = *waiter.key.lock();
+        if let Some(waiters) = self.waiters.get_mut(&key) {
+            waiters.retain(|w| !Arc::ptr_eq(w, waiter));
+        }
+        self.remove_if_empty(key);
+        true
+    }
+    /// 从 key 对应的 futex 上取出最多 n 个 bitset 与给定值有交集的线程，并标记为已唤醒。
+    ///
+    /// 实际的唤醒需要在释放表的锁之后，对返回的每个 waiter 调用 `wake_futex_waiters`
+    pub fn take_waiters(&mut self, key: FutexKey, n: usize, bitset: u32) -> Vec<Arc<FutexWaiter>> {
+        let mut taken = Vec::new();
+        if let Some(waiters) = self.waiters.get_mut(&key) {
+            waiters.retain(|w| {
+                if taken.len() < n && w.bitset & bitset != 0 {
+                    w.woken.store(true, Ordering::Release);
+                    taken.push(w.clone());
+                    false
+                } else {
+                    true
+                }
+            });
+        }
+        self.remove_if_empty(key);
+        taken
+    }
+    /// 把 from 上最多 n 个线程转移到 to 上，返回转移的个数
+    pub fn requeue(&mut self, from: FutexKey, to: FutexKey, n: usize) -> usize {
+        if from == to || n == 0 {
+            return 0;
+        }
+        let moved: Vec<_> = match self.waiters.get_mut(&from) {
+            Some(waiters) => {
+                let count = n.min(waiters.len());
+                waiters.drain(..count).collect()
+            }
+            None => return 0,
+        };
+        self.remove_if_empty(from);
+        let count = moved.len();
+        let target = self.waiters.entry(to).or_default();
+        for waiter in moved {
+            *waiter.key.lock() = to;
+            target.push_back(waiter);
+        }
+        count
+    }
+    /// 从 key 对应的 PI futex 上取出优先级最高(nice 值最小)的线程，并标记为已唤醒。
+    /// 优先级相同时，先等待的线程先被取出
+    pub fn take_pi_waiter(&mut self, key: FutexKey) -> Option<Arc<FutexWaiter>> {
+        let waiters = self.waiters.get_mut(&key)?;
+        let pos = waiters
+            .iter()
+            .enumerate()
+            .min_by_key(|(i, w)| (w.nice, *i))
+            .map(|(i, _)| i)?;
+        let waiter = waiters.remove(pos)?;
+        waiter.woken.store(true, Ordering::Release);
+        self.remove_if_empty(key);
+        Some(waiter)
+    }
+    /// 记录 key 对应的 PI futex 的持有者。只有在 futex 上有等待者时才需要记录
+    pub fn set_pi_owner(&mut self, key: FutexKey, owner: usize) {
+        if self.has_waiters(key) {
+            self.pi_owners.insert(key, owner);
+        } else {
+            self.pi_owners.remove(&key);
+        }
+    }
+    /// 根据 tid 持有的所有 PI futex 上的等待者，重新计算它应该继承的 nice 值
+    pub fn update_pi_boost(&self, tid: usize) {
+        let pi_nice = self
+            .pi_owners
+            .iter()
+            .filter(|&(_, &owner)| owner == tid)
+            .filter_map(|(key, _)| self.waiters.get(key))
+            .flatten()
+            .map(|w| w.nice)
+            .min();
+        if let Some(task) = find_task_by_tid(tid) {
+            task.sched.lock().set_pi_nice(pi_nice);
+        }
+    }
+}
+
+/// 唤醒 `take_waiters` 取出的线程，返回唤醒的个数
+pub fn wake_futex_waiters(waiters: Vec<Arc<FutexWaiter>>) -> usize {
+    for waiter in waiters.iter() {
+        waiter.queue.notify_all();
+    }
+    waiters.len()
+}
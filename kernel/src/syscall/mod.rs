@@ -24,7 +24,7 @@ use epoll::EpollEvent;
 use flags::*;
 use fs::*;
 use futex::*;
-pub use futex::{check_thread_blocked, set_waiter_for_thread, wake_thread};
+pub use futex::{exit_robust_list, futex_wake};
 pub use loops::clear_loop_checker;
 use loops::*;
 use poll::PollFd;
@@ -147,6 +147,10 @@ pub fn syscall(syscall_id: usize, args: [usize; 6]) -> isize {
             args[4],
             args[5] as u32,
         ),
+        SyscallNo::SET_ROBUST_LIST => sys_set_robust_list(args[0], args[1]),
+        SyscallNo::GET_ROBUST_LIST => {
+            sys_get_robust_list(args[0], args[1] as *mut usize, args[2] as *mut usize)
+        }
         SyscallNo::NANOSLEEP => {
             timer::sys_nanosleep(args[0] as *const TimeSpec, args[1] as *mut TimeSpec)
         }
@@ -172,10 +176,13 @@ pub fn syscall(syscall_id: usize, args: [usize; 6]) -> isize {
             args[3],
         ),
         SyscallNo::SIGRETURN => sys_sigreturn(),
+        SyscallNo::SETPRIORITY => sys_setpriority(args[0] as i32, args[1], args[2] as i32),
+        SyscallNo::GETPRIORITY => sys_getpriority(args[0] as i32, args[1]),
         SyscallNo::TIMES => timer::sys_times(args[0] as *mut TMS),
         SyscallNo::UNAME => sys_uname(args[0] as *mut UtsName),
         SyscallNo::GETRUSAGE => timer::sys_getrusage(args[0] as i32, args[1] as *mut TimeVal),
         SyscallNo::UMASK => sys_umask(args[0] as i32),
+        SyscallNo::PERSONALITY => sys_personality(args[0] as u32),
         SyscallNo::GET_TIME_OF_DAY => timer::sys_get_time_of_day(args[0] as *mut TimeVal),
         SyscallNo::GETPID => sys_getpid(),
         SyscallNo::GETPPID => sys_getppid(),
@@ -186,6 +193,7 @@ pub fn syscall(syscall_id: usize, args: [usize; 6]) -> isize {
         SyscallNo::GETTID => sys_gettid(),
         SyscallNo::SYSINFO => sys_sysinfo(args[0] as *mut SysInfo),
         SyscallNo::SOCKET => sys_socket(args[0], args[1], args[2]),
+        SyscallNo::SOCKETPAIR => sys_socketpair(args[0], args[1], args[2], args[3] as *mut i32),
         SyscallNo::SENDTO => sys_sendto(
             args[0],
             args[1] as *const u8,
@@ -217,6 +225,21 @@ pub fn syscall(syscall_id: usize, args: [usize; 6]) -> isize {
             args[2] as *mut u32,
             args[3] as i32,
         ),
+        SyscallNo::SHUTDOWN => sys_shutdown(args[0], args[1]),
+        SyscallNo::GETSOCKNAME => sys_getsockname(args[0], args[1] as *mut u8, args[2] as *mut u32),
+        SyscallNo::GETPEERNAME => sys_getpeername(args[0], args[1] as *mut u8, args[2] as *mut u32),
+        SyscallNo::SETSOCKOPT => {
+            sys_setsockopt(args[0], args[1], args[2], args[3] as *const u8, args[4])
+        }
+        SyscallNo::GETSOCKOPT => sys_getsockopt(
+            args[0],
+            args[1],
+            args[2],
+            args[3] as *mut u8,
+            args[4] as *mut u32,
+        ),
+        SyscallNo::SENDMSG => sys_sendmsg(args[0], args[1] as *const MsgHdr, args[2] as i32),
+        SyscallNo::RECVMSG => sys_recvmsg(args[0], args[1] as *mut MsgHdr, args[2] as i32),
         SyscallNo::BRK => sys_brk(args[0]),
         SyscallNo::MUNMAP => sys_munmap(args[0], args[1]),
         SyscallNo::CLONE => sys_clone(args[0], args[1] as isize, args[2], args[3], args[4]),
@@ -238,6 +261,19 @@ pub fn syscall(syscall_id: usize, args: [usize; 6]) -> isize {
             args[1],
             MSyncFlags::from_bits(args[2] as u32).unwrap(),
         ),
+        SyscallNo::MREMAP => sys_mremap(
+            args[0],
+            args[1],
+            args[2],
+            MRemapFlags::from_bits_truncate(args[3] as u32),
+            args[4],
+        ),
+        SyscallNo::MINCORE => sys_mincore(args[0], args[1], args[2] as *mut u8),
+        SyscallNo::MADVISE => sys_madvise(args[0], args[1], args[2]),
+        SyscallNo::SHMGET => sys_shmget(args[0], args[1], args[2] as u32),
+        SyscallNo::SHMAT => sys_shmat(args[0], args[1], args[2] as u32),
+        SyscallNo::SHMDT => sys_shmdt(args[0]),
+        SyscallNo::SHMCTL => sys_shmctl(args[0], args[1], args[2] as *mut ShmIdDs),
         SyscallNo::EXECVE => sys_execve(
             args[0] as *const u8,
             args[1] as *const usize,
@@ -265,7 +301,11 @@ pub fn syscall(syscall_id: usize, args: [usize; 6]) -> isize {
         //SyscallNo::MPROTECT => 0,
         SyscallNo::SIGTIMEDWAIT => Ok(0),
         SyscallNo::MEMBARRIER => Ok(0),
-        SyscallNo::FSYNC => Ok(0),
+        SyscallNo::SYNC => sys_sync(),
+        SyscallNo::SWAPON => sys_swapon(args[0] as *const u8, args[1] as u32),
+        SyscallNo::SWAPOFF => sys_swapoff(args[0] as *const u8),
+        SyscallNo::FSYNC | SyscallNo::FDATASYNC => sys_fsync(args[0]),
+        SyscallNo::FTRUNCATE => sys_ftruncate(args[0], args[1]),
         _ => {
             //_ => panic!("Unsupported syscall id = {:#?}()", syscall_id, syscall_id as usize);
             warn!(
@@ -1,23 +1,31 @@
 //! 与进程相关的系统调用
 
 use super::{
-    resolve_clone_flags_and_signal, MMAPFlags, MSyncFlags, RLimit, SysResult, UtsName, WaitFlags,
-    MMAPPROT, RLIMIT_AS, RLIMIT_NOFILE, RLIMIT_STACK, SIG_BLOCK, SIG_SETMASK, SIG_UNBLOCK,
+    resolve_clone_flags_and_signal, IpcPerm, MMAPFlags, MRemapFlags, MSyncFlags, MadviseAdvice,
+    RLimit, ShmAtFlags, ShmCtlCmd, ShmGetFlags, ShmIdDs, SysResult, UtsName, WaitFlags, IPC_64,
+    IPC_PRIVATE, MMAPPROT, PRIO_PGRP, PRIO_PROCESS, PRIO_USER, RLIMIT_AS, RLIMIT_DATA,
+    RLIMIT_NOFILE, RLIMIT_STACK, RLIM_INFINITY, SIG_BLOCK, SIG_SETMASK, SIG_UNBLOCK,
 };
 use crate::{
-    constants::{SIGSET_SIZE_IN_BYTE, USER_STACK_SIZE, USER_VIRT_ADDR_LIMIT, USE_MSYNC},
-    file::{BackEndFile, SeekFrom},
-    memory::{align_down, align_up, page_offset},
+    constants::{PAGE_SIZE, SIGSET_SIZE_IN_BYTE, USE_MSYNC},
+    error::OSError,
+    file::{BackEndFile, SeekFrom, ShmFile, SyncPolicy},
+    memory::{
+        align_down, align_up, page_count, page_offset, shm_attach, shm_get, shm_remove, shm_stat,
+        swap_stat, PTEFlags, PmAreaShared, SharedMemory, SHM_AREA_NAME,
+    },
     signal::{send_signal, SigAction, SignalNo},
     syscall::flags::SysInfo,
     task::{
-        exec_new_task, exit_current_task, get_current_task, push_task_to_scheduler, signal_return,
-        suspend_current_task,
+        add_new_task_to_scheduler, exec_new_task, exit_current_task, get_current_task,
+        signal_return, yield_current_task, TaskControlBlock,
     },
     utils::{raw_ptr_to_string, str_ptr_array_to_vec_string},
 };
+use alloc::sync::Arc;
 use bitset::Bitset;
 use core::mem::size_of;
+use core::slice;
 use syscall::ErrorNo;
 use timer::get_time_sec;
 
@@ -30,10 +38,56 @@ pub fn sys_exit(exit_code: i32) -> ! {
 
 /// 进程主动放弃时间片，立即切换到其他进程执行
 pub fn sys_yield() -> SysResult {
-    suspend_current_task();
+    yield_current_task();
+    Ok(0)
+}
+
+/// 找到 sys_setpriority / sys_getpriority 的目标任务。
+///
+/// 内核中没有进程组和用户的概念，所以 PRIO_PGRP / PRIO_USER 只支持 who == 0，即当前任务；
+/// PRIO_PROCESS 支持当前任务和它的子进程
+fn find_priority_target(which: i32, who: usize) -> Result<Arc<TaskControlBlock>, ErrorNo> {
+    let task = get_current_task().unwrap();
+    match which {
+        PRIO_PROCESS => {
+            if who == 0 || who == task.get_pid_num() || who == task.get_tid_num() {
+                return Ok(task);
+            }
+            let inner = task.inner.lock();
+            inner
+                .children
+                .iter()
+                .find(|child| child.get_pid_num() == who)
+                .cloned()
+                .ok_or(ErrorNo::ESRCH)
+        }
+        PRIO_PGRP | PRIO_USER => {
+            if who == 0 {
+                Ok(task)
+            } else {
+                Err(ErrorNo::ESRCH)
+            }
+        }
+        _ => Err(ErrorNo::EINVAL),
+    }
+}
+
+/// 设置任务的 nice 值。超出 \[-20, 19\] 的值会被截断
+pub fn sys_setpriority(which: i32, who: usize, nice: i32) -> SysResult {
+    let task = find_priority_target(which, who)?;
+    task.sched.lock().set_nice(nice);
     Ok(0)
 }
 
+/// 获取任务的 nice 值。
+///
+/// 为了避免返回负数和错误码混淆，系统调用返回的是 20 - nice，即 1 到 40 之间的值，由用户库再转换回 nice
+pub fn sys_getpriority(which: i32, who: usize) -> SysResult {
+    let task = find_priority_target(which, who)?;
+    let nice = task.sched.lock().nice();
+    Ok((20 - nice) as usize)
+}
+
 /// 获取当前进程的 pid。
 /// 如果该核没有正在运行的线程，则直接 panic
 pub fn sys_getpid() -> SysResult {
@@ -69,6 +123,19 @@ pub fn sys_brk(brk: usize) -> SysResult {
     //Err(ErrorNo::ENOMEM)
 }
 
+/// 设置进程的执行域，返回原来的值。如 persona 为 0xffffffff，则只查询不修改。
+///
+/// 目前只有 ADDR_NO_RANDOMIZE 有实际作用，它会在下一次 exec 时关闭地址随机化
+pub fn sys_personality(persona: u32) -> SysResult {
+    let task = get_current_task().unwrap();
+    let mut inner = task.inner.lock();
+    let old = inner.personality;
+    if persona != 0xffff_ffff {
+        inner.personality = persona;
+    }
+    Ok(old as usize)
+}
+
 /// 创建一个子任务，如成功，返回其 tid
 pub fn sys_clone(
     flags: usize,
@@ -101,7 +168,7 @@ pub fn sys_clone(
     // 获取新进程的 pid。必须提前在此拿到 usize 形式的 pid，因为后续 new_task 插入任务队列后就不能调用它的方法了
     let new_task_tid = new_task.get_tid_num();
     // 将新任务加入调度器
-    push_task_to_scheduler(new_task);
+    add_new_task_to_scheduler(new_task);
     //println!("new task {new_task_tid}");
     //println!("create time {}", crate::timer::get_time());
     Ok(new_task_tid)
@@ -163,7 +230,7 @@ fn sys_exec(path: *const u8, args: *const usize) -> SysResult {
     }
 }
 
-/// 等待子进程执行完成。如果它还没完成，则先切换掉
+/// 等待子进程执行完成。如果它还没完成，则在当前任务的 child_exit_queue 上阻塞，直到有子进程退出
 ///
 /// 目前只支持 WNOHANG 选项
 pub fn sys_wait4(pid: isize, exit_code_ptr: *mut i32, option: WaitFlags) -> SysResult {
@@ -171,22 +238,26 @@ pub fn sys_wait4(pid: isize, exit_code_ptr: *mut i32, option: WaitFlags) -> SysR
         "sys_wait4 {}, {:x}, {:#?}",
         pid, exit_code_ptr as usize, option
     );
-    loop {
-        let child_pid = waitpid(pid, exit_code_ptr);
-        // 找不到子进程，直接返回-1
-        if child_pid == -1 {
-            return Err(ErrorNo::EINVAL);
-        } else if child_pid == -2 {
-            if option.contains(WaitFlags::WNOHANG) {
-                return Ok(0);
-            } else {
-                info!("find child but suspend");
-                suspend_current_task();
-            }
-        } else {
-            info!("find child and return {}", child_pid);
-            return Ok(child_pid as usize);
-        }
+    let task = get_current_task().unwrap();
+    let child_pid = if option.contains(WaitFlags::WNOHANG) {
+        waitpid(pid, exit_code_ptr)
+    } else {
+        // 子进程退出时会唤醒父进程的 child_exit_queue
+        task.child_exit_queue
+            .wait_until(None, || match waitpid(pid, exit_code_ptr) {
+                -2 => None,
+                child_pid => Some(child_pid),
+            })
+            .ok_or(ErrorNo::EINTR)?
+    };
+    // 找不到子进程，直接返回-1
+    if child_pid == -1 {
+        Err(ErrorNo::EINVAL)
+    } else if child_pid == -2 {
+        Ok(0)
+    } else {
+        info!("find child and return {}", child_pid);
+        Ok(child_pid as usize)
     }
 }
 
@@ -195,7 +266,7 @@ pub fn sys_wait4(pid: isize, exit_code_ptr: *mut i32, option: WaitFlags) -> SysR
 /// 1. 如果找不到对应 pid 的进程，或者它不是调用进程的子进程，返回 -1
 /// 2. 如果能找到，但该子进程没有运行结束，返回 -2
 /// 3. 否则，返回这个进程的 pid。
-/// 3.1 如果 exit_code_ptr != 0，则将子进程的 exit_code 写入 exit_code_ptr
+/// 3.1 如果 exit_code_ptr != 0，则将子进程的退出状态写入 exit_code_ptr
 fn waitpid(pid: isize, exit_code_ptr: *mut i32) -> isize {
     let request_pid = pid as usize;
     let task = get_current_task().unwrap();
@@ -246,7 +317,7 @@ fn waitpid(pid: isize, exit_code_ptr: *mut i32) -> isize {
         if exit_code_ptr as usize != 0 {
             unsafe {
                 //info!("write exit code {}", exit_code);
-                *exit_code_ptr = exit_code << 8;
+                *exit_code_ptr = exit_code;
             }
         }
         pid_found
@@ -291,26 +362,74 @@ pub fn sys_mmap(
         drop(tcb_inner);
         // 根据linux规范需要 fd 设为 -1 且 offset 设为 0
         if fd == -1 && offset == 0 {
-            if let Some(start) = task.mmap(start, start + len, prot.into(), None, anywhere) {
-                return Ok(start);
+            if flags.contains(MMAPFlags::MAP_SHARED) {
+                // 共享的匿名映射在 fork 后仍要共享，所以数据放在一段单独的共享内存里
+                let memory = Arc::new(SharedMemory::new(len));
+                return PmAreaShared::new(memory, 0, page_count(len))
+                    .and_then(|pma| {
+                        task.vm.lock().push_shared(
+                            start,
+                            start + len,
+                            prot.into(),
+                            pma,
+                            anywhere,
+                            "from mmap",
+                        )
+                    })
+                    .map_err(mmap_error_no);
+            } else {
+                return task
+                    .mmap(start, start + len, prot.into(), None, anywhere)
+                    .map_err(mmap_error_no);
             }
         }
     } else if let Ok(file) = task.fd_manager.lock().get_file(fd as usize) {
+        // /dev/shm 下的文件的共享映射直接映射文件所在的共享内存，不经过 backend
+        if let Some(shm_file) = file.as_any().downcast_ref::<ShmFile>() {
+            if flags.contains(MMAPFlags::MAP_SHARED) && page_offset(offset) == 0 {
+                drop(tcb_inner);
+                return PmAreaShared::new(shm_file.memory(), offset / PAGE_SIZE, page_count(len))
+                    .and_then(|pma| {
+                        task.vm.lock().push_shared(
+                            start,
+                            start + len,
+                            prot.into(),
+                            pma,
+                            anywhere,
+                            "from mmap",
+                        )
+                    })
+                    .map_err(mmap_error_no);
+            }
+        }
         //确认可以seek才获取文件，否则后续 lazy alloc 时不好处理
         if let Some(_off) = file.seek(SeekFrom::Start(offset as u64)) {
-            // file 在从 fd 中拿的时候已经是 clone 了，所以这里可以直接传给 backend
-            let backend = BackEndFile::new(file, offset, prot.into());
+            // file 在从 fd 中拿的时候已经是 clone 了，所以这里可以直接传给 backend。
+            // 私有映射的修改不写回文件，所以只同步读
+            let policy = if flags.contains(MMAPFlags::MAP_SHARED) {
+                prot.into()
+            } else {
+                SyncPolicy::SyncRead
+            };
+            let backend = BackEndFile::new(file, offset, policy);
             drop(tcb_inner);
             // mmap 内部需要拿 inner 锁
-            if let Some(start) = task.mmap(start, start + len, prot.into(), Some(backend), anywhere)
-            {
-                return Ok(start);
-            }
+            return task
+                .mmap(start, start + len, prot.into(), Some(backend), anywhere)
+                .map_err(mmap_error_no);
         }
     }
     Err(ErrorNo::EINVAL)
 }
 
+/// mmap 失败时返回的错误码。超过 RLIMIT_AS / RLIMIT_DATA 或者内存不足时是 ENOMEM
+fn mmap_error_no(e: OSError) -> ErrorNo {
+    match e {
+        OSError::MemorySet_ExceedRlimit | OSError::Memory_RunOutOfMemory => ErrorNo::ENOMEM,
+        _ => ErrorNo::EINVAL,
+    }
+}
+
 /// 取消映射一段内存
 pub fn sys_munmap(start: usize, len: usize) -> SysResult {
     info!("start {:x}, len {}", start, len);
@@ -338,6 +457,108 @@ pub fn sys_mprotect(start: usize, len: usize, prot: MMAPPROT) -> SysResult {
     }
 }
 
+/// 调整一段已有映射的大小，可能会移动到其他位置。成功时返回调整后的起始地址
+pub fn sys_mremap(
+    old_start: usize,
+    old_len: usize,
+    new_len: usize,
+    flags: MRemapFlags,
+    new_start: usize,
+) -> SysResult {
+    info!(
+        "mremap old_start={:x} old_len={:x} new_len={:x} flags=[{:#?}] new_start={:x}",
+        old_start, old_len, new_len, flags, new_start
+    );
+    // old_len 为 0 时 Linux 会复制一份共享映射，这里不支持
+    if page_offset(old_start) != 0
+        || old_len == 0
+        || new_len == 0
+        || flags.contains(MRemapFlags::DONTUNMAP)
+        || (flags.contains(MRemapFlags::FIXED) && !flags.contains(MRemapFlags::MAYMOVE))
+    {
+        return Err(ErrorNo::EINVAL);
+    }
+    let fixed = if flags.contains(MRemapFlags::FIXED) {
+        if page_offset(new_start) != 0 {
+            return Err(ErrorNo::EINVAL);
+        }
+        Some(new_start)
+    } else {
+        None
+    };
+    get_current_task()
+        .unwrap()
+        .vm
+        .lock()
+        .mremap(
+            old_start,
+            align_up(old_len),
+            align_up(new_len),
+            flags.contains(MRemapFlags::MAYMOVE),
+            fixed,
+        )
+        .map_err(|e| match e {
+            OSError::MemorySet_AreaNotMapped => ErrorNo::EFAULT,
+            OSError::Memory_RunOutOfMemory
+            | OSError::MemorySet_UserMmapIntersectWithKernel
+            | OSError::MemorySet_ExceedRlimit => ErrorNo::ENOMEM,
+            _ => ErrorNo::EINVAL,
+        })
+}
+
+/// 给内核关于一段内存之后如何使用的建议。
+///
+/// 目前只处理 MADV_WILLNEED 和 MADV_DONTNEED / MADV_FREE，其他建议直接忽略
+pub fn sys_madvise(start: usize, len: usize, advice: usize) -> SysResult {
+    info!("madvise start={:x} len={:x} advice={}", start, len, advice);
+    if page_offset(start) != 0 {
+        return Err(ErrorNo::EINVAL);
+    }
+    let end = align_up(start + len);
+    let task = get_current_task().unwrap();
+    let mut task_vm = task.vm.lock();
+    let res = match MadviseAdvice::try_from(advice) {
+        Ok(MadviseAdvice::MADV_WILLNEED) => task_vm.prefetch_areas(start, end),
+        // MADV_FREE 允许推迟丢弃，这里直接当作 MADV_DONTNEED 处理
+        Ok(MadviseAdvice::MADV_DONTNEED) | Ok(MadviseAdvice::MADV_FREE) => {
+            task_vm.discard_areas(start, end)
+        }
+        Ok(_) => Ok(()),
+        Err(_) => return Err(ErrorNo::EINVAL),
+    };
+    res.map(|_| 0).map_err(|e| match e {
+        OSError::MemorySet_AreaNotMapped => ErrorNo::ENOMEM,
+        _ => ErrorNo::EINVAL,
+    })
+}
+
+/// 获取一段内存中的每一页是否在内存中，结果写到 vec 里，每页一个字节
+pub fn sys_mincore(start: usize, len: usize, vec: *mut u8) -> SysResult {
+    if page_offset(start) != 0 {
+        return Err(ErrorNo::EINVAL);
+    }
+    let task = get_current_task().unwrap();
+    let mut task_vm = task.vm.lock();
+    // 先查询再检查 vec，避免检查 vec 时分配的页影响结果
+    let residency = task_vm
+        .residency(start, align_up(start + len))
+        .map_err(|_| ErrorNo::ENOMEM)?;
+    if residency.is_empty() {
+        return Ok(0);
+    }
+    if task_vm
+        .manually_alloc_user_str(vec, residency.len())
+        .is_err()
+    {
+        return Err(ErrorNo::EFAULT);
+    }
+    let vec = unsafe { slice::from_raw_parts_mut(vec, residency.len()) };
+    for (byte, &resident) in vec.iter_mut().zip(residency.iter()) {
+        *byte = resident as u8;
+    }
+    Ok(0)
+}
+
 /// 映射一段内存
 pub fn sys_msync(start: usize, len: usize, flags: MSyncFlags) -> SysResult {
     if !USE_MSYNC {
@@ -362,6 +583,101 @@ pub fn sys_msync(start: usize, len: usize, flags: MSyncFlags) -> SysResult {
     }
 }
 
+/// 获取一个 System V 共享内存段，不存在时根据 shmflg 新建。成功时返回段的 id
+pub fn sys_shmget(key: usize, size: usize, shmflg: u32) -> SysResult {
+    info!("shmget key={:x} size={:x} shmflg={:o}", key, size, shmflg);
+    let flags = ShmGetFlags::from_bits_truncate(shmflg);
+    let key = if key == IPC_PRIVATE { None } else { Some(key) };
+    shm_get(
+        key,
+        size,
+        flags.contains(ShmGetFlags::IPC_CREAT),
+        flags.contains(ShmGetFlags::IPC_EXCL),
+        shmflg & 0o777,
+        get_current_task().unwrap().get_pid_num(),
+    )
+    .map_err(|e| match e {
+        OSError::Shm_KeyExists => ErrorNo::EEXIST,
+        OSError::Shm_NotFound => ErrorNo::ENOENT,
+        _ => ErrorNo::EINVAL,
+    })
+}
+
+/// 把 System V 共享内存段映射到地址空间中。addr 为 0 时由内核选择位置，成功时返回映射的起始地址
+///
+/// 给定 addr 时总是替换掉原有的映射，相当于总是带着 SHM_REMAP
+pub fn sys_shmat(id: usize, addr: usize, shmflg: u32) -> SysResult {
+    info!("shmat id={} addr={:x} shmflg={:o}", id, addr, shmflg);
+    let flags = ShmAtFlags::from_bits_truncate(shmflg);
+    let addr = if flags.contains(ShmAtFlags::SHM_RND) {
+        align_down(addr)
+    } else if page_offset(addr) != 0 {
+        return Err(ErrorNo::EINVAL);
+    } else {
+        addr
+    };
+    let task = get_current_task().unwrap();
+    let memory = shm_attach(id, task.get_pid_num()).map_err(|_| ErrorNo::EINVAL)?;
+    let len = align_up(memory.size());
+    let mut prot = PTEFlags::USER | PTEFlags::READ;
+    if !flags.contains(ShmAtFlags::SHM_RDONLY) {
+        prot |= PTEFlags::WRITE;
+    }
+    if flags.contains(ShmAtFlags::SHM_EXEC) {
+        prot |= PTEFlags::EXECUTE;
+    }
+    PmAreaShared::new_attached(memory, 0, page_count(len))
+        .and_then(|pma| {
+            task.vm
+                .lock()
+                .push_shared(addr, addr + len, prot, pma, addr == 0, SHM_AREA_NAME)
+        })
+        .map_err(|_| ErrorNo::ENOMEM)
+}
+
+/// 删除 shmat 在 addr 处映射的共享内存段
+pub fn sys_shmdt(addr: usize) -> SysResult {
+    info!("shmdt addr={:x}", addr);
+    get_current_task()
+        .unwrap()
+        .vm
+        .lock()
+        .unmap_area_named(addr, SHM_AREA_NAME)
+        .map(|_| 0)
+        .map_err(|_| ErrorNo::EINVAL)
+}
+
+/// 操作 System V 共享内存段。目前只支持 IPC_STAT 和 IPC_RMID
+pub fn sys_shmctl(id: usize, cmd: usize, buf: *mut ShmIdDs) -> SysResult {
+    info!("shmctl id={} cmd={:x}", id, cmd);
+    match ShmCtlCmd::try_from(cmd & !IPC_64) {
+        Ok(ShmCtlCmd::IPC_RMID) => shm_remove(id).map(|_| 0).map_err(|_| ErrorNo::EINVAL),
+        Ok(ShmCtlCmd::IPC_STAT) => {
+            let stat = shm_stat(id).map_err(|_| ErrorNo::EINVAL)?;
+            let task = get_current_task().unwrap();
+            if task.vm.lock().manually_alloc_type(buf).is_err() {
+                return Err(ErrorNo::EFAULT);
+            }
+            unsafe {
+                *buf = ShmIdDs {
+                    shm_perm: IpcPerm {
+                        key: stat.key as i32,
+                        mode: stat.mode,
+                        ..Default::default()
+                    },
+                    shm_segsz: stat.size,
+                    shm_cpid: stat.cpid as i32,
+                    shm_lpid: stat.lpid as i32,
+                    shm_nattch: stat.nattch,
+                    ..Default::default()
+                };
+            }
+            Ok(0)
+        }
+        Err(_) => Err(ErrorNo::EINVAL),
+    }
+}
+
 /// 获取系统信息
 pub fn sys_uname(uts: *mut UtsName) -> SysResult {
     unsafe {
@@ -371,15 +687,19 @@ pub fn sys_uname(uts: *mut UtsName) -> SysResult {
 }
 
 /// 获取系统的启动时间和内存信息。
-/// 目前只支持启动时间
+/// 目前只支持启动时间和交换区的大小
 pub fn sys_sysinfo(info: *mut SysInfo) -> SysResult {
     let task = get_current_task().unwrap();
     let mut task_vm = task.vm.lock();
     if task_vm.manually_alloc_type(info).is_err() {
         return Err(ErrorNo::EFAULT);
     }
+    let (total_swap, free_swap) = swap_stat();
     unsafe {
         (*info).uptime = get_time_sec() as isize;
+        (*info).totalswap = total_swap;
+        (*info).freeswap = free_swap;
+        (*info).mem_unit = PAGE_SIZE as u32;
     }
     Ok(0)
 }
@@ -594,14 +914,19 @@ pub fn sys_prlimt64(
 
         match resource {
             RLIMIT_STACK => {
+                let mut vm = task.vm.lock();
                 if old_limit as usize != 0 {
                     unsafe {
                         *old_limit = RLimit {
-                            rlim_cur: USER_STACK_SIZE as u64,
-                            rlim_max: USER_STACK_SIZE as u64,
+                            rlim_cur: vm.stack_limit as u64,
+                            rlim_max: RLIM_INFINITY,
                         };
                     }
                 }
+                if new_limit as usize != 0 {
+                    let new_limit = unsafe { (*new_limit).rlim_cur };
+                    vm.stack_limit = new_limit as usize;
+                }
             }
             RLIMIT_NOFILE => {
                 if old_limit as usize != 0 {
@@ -618,15 +943,24 @@ pub fn sys_prlimt64(
                     fd_manger.modify_limit(new_limit as usize);
                 }
             }
-            RLIMIT_AS => {
+            RLIMIT_AS | RLIMIT_DATA => {
+                let mut vm = task.vm.lock();
+                let limit = if resource == RLIMIT_AS {
+                    &mut vm.as_limit
+                } else {
+                    &mut vm.data_limit
+                };
                 if old_limit as usize != 0 {
                     unsafe {
                         *old_limit = RLimit {
-                            rlim_cur: USER_VIRT_ADDR_LIMIT as u64,
-                            rlim_max: USER_VIRT_ADDR_LIMIT as u64,
+                            rlim_cur: *limit as u64,
+                            rlim_max: RLIM_INFINITY,
                         };
                     }
                 }
+                if new_limit as usize != 0 {
+                    *limit = unsafe { (*new_limit).rlim_cur } as usize;
+                }
             }
             _ => {}
         }
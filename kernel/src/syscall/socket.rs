@@ -1,14 +1,83 @@
 //! 关于 socket 的 syscall
 
-use super::SysResult;
+use super::{CMsgHdr, IoVec, MsgHdr, SysResult, SCM_CREDENTIALS, SCM_MAX_FD, SCM_RIGHTS};
 use crate::file::socket::*;
-use crate::task::suspend_current_task;
 use crate::{file::Socket, task::get_current_task};
-use alloc::sync::Arc;
-use base_file::OpenFlags;
+use alloc::{sync::Arc, vec, vec::Vec};
+use base_file::{File, OpenFlags};
 use core::mem::size_of;
 use syscall::ErrorNo;
 
+/// 控制信息的 level：socket 本身
+const SOL_SOCKET: i32 = 1;
+
+/// 从 socket 的 type 参数或者 accept4 的 flags 参数中取出 SOCK_NONBLOCK 和 SOCK_CLOEXEC
+fn socket_fd_flags(flags: usize) -> OpenFlags {
+    OpenFlags::from_bits_truncate(flags as u32 & !SOCKET_TYPE_MASK)
+        & (OpenFlags::NON_BLOCK | OpenFlags::CLOEXEC)
+}
+
+/// 获取 fd 对应的 socket，然后对它执行 f。
+///
+/// 执行 f 时不持有 fd_manager 的锁，所以 f 中可以阻塞
+fn with_socket<T>(fd: usize, f: impl FnOnce(&Socket) -> Result<T, ErrorNo>) -> Result<T, ErrorNo> {
+    let file = get_current_task()
+        .unwrap()
+        .fd_manager
+        .lock()
+        .get_file(fd)
+        .map_err(|_| ErrorNo::EBADF)?;
+    let socket = file
+        .as_any()
+        .downcast_ref::<Socket>()
+        .ok_or(ErrorNo::ENOTSOCK)?;
+    f(socket)
+}
+
+/// 读取用户传入的地址。unix 域的相对路径会被转换为绝对路径
+fn read_user_addr(addr: *const u8, addr_len: usize) -> Result<SocketAddr, ErrorNo> {
+    let task = get_current_task().unwrap();
+    let mut task_vm = task.vm.lock();
+    if addr_len == 0 || task_vm.manually_alloc_user_str(addr, addr_len).is_err() {
+        return Err(ErrorNo::EFAULT); // 地址不合法
+    }
+    drop(task_vm);
+    match addr_resolution(addr, addr_len)? {
+        SocketAddr::Unix(UnixAddr::Path(path)) => {
+            let cwd = task.inner.lock().dir.clone();
+            Ok(SocketAddr::Unix(UnixAddr::Path(absolute_unix_path(
+                &cwd, &path,
+            ))))
+        }
+        addr => Ok(addr),
+    }
+}
+
+/// 把地址写到用户给的 addr 处。addr_len 处原本是 addr 的空间大小，写入后是地址的实际长度。
+///
+/// 如果空间不够，地址会被截断。addr 为 0 时不写入
+fn write_user_addr(addr: *mut u8, addr_len: *mut u32, sock_addr: &SocketAddr) -> SysResult {
+    if addr.is_null() {
+        return Ok(0);
+    }
+    let task = get_current_task().unwrap();
+    let mut task_vm = task.vm.lock();
+    if task_vm.manually_alloc_type(addr_len).is_err() {
+        return Err(ErrorNo::EFAULT);
+    }
+    let len = unsafe { *addr_len } as usize;
+    let bytes = addr_to_user(sock_addr);
+    let write_len = len.min(bytes.len());
+    if write_len > 0 && task_vm.manually_alloc_user_str(addr, write_len).is_err() {
+        return Err(ErrorNo::EFAULT);
+    }
+    unsafe {
+        core::ptr::copy_nonoverlapping(bytes.as_ptr(), addr, write_len);
+        *addr_len = bytes.len() as u32;
+    }
+    Ok(0)
+}
+
 /// 创建一个 socket
 pub fn sys_socket(domain: usize, s_type: usize, protocol: usize) -> SysResult {
     let domain = match Domain::try_from(domain) {
@@ -29,172 +98,129 @@ pub fn sys_socket(domain: usize, s_type: usize, protocol: usize) -> SysResult {
         "SOCKET domain: {:?}, s_type: {:?}, protocol: {:x}",
         domain, socket_type, protocol
     );
+    let socket = Socket::new(domain, socket_type, protocol, socket_fd_flags(s_type))?;
     let task = get_current_task().unwrap();
     let mut fd_manager = task.fd_manager.lock();
-    if let Ok(fd) = fd_manager.push(Arc::new(Socket::new(domain, socket_type, protocol))) {
+    if let Ok(fd) = fd_manager.push(Arc::new(socket)) {
         Ok(fd)
     } else {
         Err(ErrorNo::EMFILE)
     }
 }
 
-/// 发送消息，目的地在 dest_addr 的信息中
+/// 创建一对互相连接的 socket，fd 写入 sv[0] 和 sv[1]。目前只支持 unix 域 socket
+pub fn sys_socketpair(domain: usize, s_type: usize, protocol: usize, sv: *mut i32) -> SysResult {
+    let domain = Domain::try_from(domain).map_err(|_| ErrorNo::EAFNOSUPPORT)?;
+    let socket_type =
+        SocketType::try_from(s_type & (SOCKET_TYPE_MASK as usize)).map_err(|_| ErrorNo::EINVAL)?;
+    info!(
+        "SOCKETPAIR domain: {:?}, s_type: {:?}, protocol: {:x}",
+        domain, socket_type, protocol
+    );
+    let task = get_current_task().unwrap();
+    if task
+        .vm
+        .lock()
+        .manually_alloc_type(sv as *const [i32; 2])
+        .is_err()
+    {
+        return Err(ErrorNo::EFAULT);
+    }
+    let (first, second) = Socket::new_pair(domain, socket_type, protocol, socket_fd_flags(s_type))?;
+    let mut fd_manager = task.fd_manager.lock();
+    let first_fd = fd_manager
+        .push(Arc::new(first))
+        .map_err(|_| ErrorNo::EMFILE)?;
+    let second_fd = match fd_manager.push(Arc::new(second)) {
+        Ok(fd) => fd,
+        Err(_) => {
+            fd_manager.remove_file(first_fd).unwrap();
+            return Err(ErrorNo::EMFILE);
+        }
+    };
+    unsafe {
+        *sv = first_fd as i32;
+        *sv.add(1) = second_fd as i32;
+    }
+    Ok(0)
+}
+
+/// 发送消息，目的地在 dest_addr 的信息中。dest_addr 为 0 时发送到已连接的地址
 pub fn sys_sendto(
     fd: usize,
     buf: *const u8,
     len: usize,
     flags: i32,
     dest_addr: *const u8,
-    _addr_len: usize,
+    addr_len: usize,
 ) -> SysResult {
+    let dest = if dest_addr.is_null() {
+        None
+    } else {
+        Some(read_user_addr(dest_addr, addr_len)?)
+    };
     let task = get_current_task().unwrap();
     let mut task_vm = task.vm.lock();
-    let fd_manager = task.fd_manager.lock();
-    if task_vm.manually_alloc_user_str(buf, len).is_err() {
+    if len > 0 && task_vm.manually_alloc_user_str(buf, len).is_err() {
         return Err(ErrorNo::EFAULT); // 地址不合法
     }
+    drop(task_vm);
     let slice = unsafe { core::slice::from_raw_parts(buf, len) };
-    if let Ok(file) = fd_manager.get_file(fd) {
-        // 这里不考虑进程切换.                             dest_addr可能为0
-        if let Some(write_len) = file.sendto(slice, flags, dest_addr as usize) {
-            return Ok(write_len);
-        } else {
-            return Err(ErrorNo::EINVAL);
-        }
-    } else {
-        return Err(ErrorNo::EBADF);
-    }
+    let flags = MsgFlags::from_bits_truncate(flags as u32);
+    with_socket(fd, |socket| {
+        socket.send_msg(slice, dest, Ancillary::default(), flags)
+    })
 }
 
-/// 收取消息，消息地址需要解析 dest_addr 获得
+/// 收取消息。如果 src_addr 不为 0，则把消息来源的地址写到 src_addr 处
 ///
-/// 消息的地址信息的长度(注意不是消息长度)将被存放在 src_len_pos 中
+/// 消息的地址信息的长度(注意不是消息长度)将被存放在 src_len_pos 中。
+/// flags 中有 MSG_TRUNC 时，返回数据报的实际长度而不是读到的长度
 pub fn sys_recvfrom(
     fd: usize,
     buf: *mut u8,
     len: usize,
-    _flags: i32,
-    _src_addr: *mut u8,
-    _src_len_pos: *mut u32,
+    flags: i32,
+    src_addr: *mut u8,
+    src_len_pos: *mut u32,
 ) -> SysResult {
     let task = get_current_task().unwrap();
     let mut task_vm = task.vm.lock();
-    if task_vm.manually_alloc_user_str(buf, len).is_err() {
+    if len > 0 && task_vm.manually_alloc_user_str(buf, len).is_err() {
         return Err(ErrorNo::EFAULT); // 地址不合法
     }
     drop(task_vm);
     let slice = unsafe { core::slice::from_raw_parts_mut(buf, len) };
-    loop {
-        let fd_manager = task.fd_manager.lock();
-        if let Ok(file) = fd_manager.get_file(fd) {
-            /* if let Some(read_len) = file.recvfrom(slice, flags, src_addr,
-            unsafe { src_len_pos.as_mut().unwrap() }) */
-            if let Some(read_len) = file.recvfrom(slice, 0, 0, &mut 0) {
-                return Ok(read_len);
-            }
-            let fl = file.get_status();
-            if fl.contains(OpenFlags::NON_BLOCK) {
-                info!("sys_recvfrom flags: {:?}", fl);
-                return Err(ErrorNo::EAGAIN);
-            }
-        } else {
-            return Err(ErrorNo::EBADF);
-        }
-
-        drop(fd_manager);
-        suspend_current_task(); // yield
+    let flags = MsgFlags::from_bits_truncate(flags as u32);
+    let meta = with_socket(fd, |socket| socket.recv_msg(slice, flags))?;
+    if let Some(src) = &meta.src {
+        write_user_addr(src_addr, src_len_pos, src)?;
     }
+    Ok(if flags.contains(MsgFlags::TRUNC) {
+        meta.msg_len
+    } else {
+        meta.len
+    })
 }
 
 /// 绑定socket fd到指定地址的IP和Port
 pub fn sys_bind(fd: usize, addr: *const u8, addr_len: usize) -> SysResult {
     info!("sys_bind: fd: {} addr: {:p} len: {}", fd, addr, addr_len);
-    let task = get_current_task().unwrap();
-    let mut task_vm = task.vm.lock();
-    if task_vm.manually_alloc_user_str(addr, addr_len).is_err() {
-        return Err(ErrorNo::EFAULT); // 地址不合法
-    }
-    let fd_manager = task.fd_manager.lock();
-    if let Ok(file) = fd_manager.get_file(fd) {
-        let sock = file.as_any().downcast_ref::<Socket>().unwrap();
-        if let Some(_p) = sock.set_endpoint(addr, false) {
-            Ok(0)
-        } else {
-            Err(ErrorNo::EINVAL)
-        }
-    } else {
-        return Err(ErrorNo::EBADF);
-    }
+    let endpoint = read_user_addr(addr, addr_len)?;
+    with_socket(fd, |socket| socket.bind(endpoint)).map(|_| 0)
 }
 
 /// 设置socket为监听模式
 pub fn sys_listen(fd: usize, backlog: usize) -> SysResult {
     info!("sys_listen: fd: {} backlog: {}", fd, backlog);
-    let task = get_current_task().unwrap();
-    let fd_manager = task.fd_manager.lock();
-    if let Ok(file) = fd_manager.get_file(fd) {
-        let sock = file.as_any().downcast_ref::<Socket>().unwrap();
-        sock.set_listening(true);
-        Ok(0)
-    } else {
-        Err(ErrorNo::EBADF)
-    }
+    with_socket(fd, |socket| socket.listen(backlog)).map(|_| 0)
 }
 
 /// socket连接给的远程地址. 如完成TCP的三次握手
 pub fn sys_connect(fd: usize, addr: *const u8, addr_len: usize) -> SysResult {
     info!("sys_connect: fd: {} addr: {:p} len: {}", fd, addr, addr_len);
-    let task = get_current_task().unwrap();
-    let mut task_vm = task.vm.lock();
-    if task_vm.manually_alloc_user_str(addr, addr_len).is_err() {
-        return Err(ErrorNo::EFAULT); // 地址不合法
-    }
-
-    let fd_manager = task.fd_manager.lock();
-    if let Ok(file) = fd_manager.get_file(fd) {
-        let sock = file.as_any().downcast_ref::<Socket>().unwrap();
-        let laddr = IpAddr {
-            family: 2,
-            port: 0,
-            addr: 0,
-        };
-        //设置好本地endpoint
-        if let Some(lport) = sock.set_endpoint(&laddr as *const _ as *const u8, false) {
-            // TCP submit a SYN
-            // 注意转换成Big endian
-            let laddr = IpAddr {
-                family: 2,
-                port: u16::to_be(lport),
-                addr: u32::to_be(0x7f000001),
-            };
-            let slice = unsafe {
-                core::slice::from_raw_parts(
-                    &laddr as *const IpAddr as *const u8,
-                    size_of::<IpAddr>(),
-                )
-            };
-            //把本地地址告诉给远端, 暂用端口port+100
-            if let Some(write_len) = file.sendto(slice, 100, addr as usize) {
-                //设置好远程endpoint
-                let rport = sock.set_endpoint(addr, true).unwrap_or(0);
-                info!(
-                    "sys_connect sent IpAddr {} from {} to {} len: {}",
-                    size_of::<IpAddr>(),
-                    lport,
-                    rport,
-                    write_len
-                );
-                // 这里没loop, 直接返回成功
-                return Ok(0);
-            } else {
-                return Err(ErrorNo::ECONNREFUSED);
-            }
-        } else {
-            return Err(ErrorNo::EINVAL);
-        }
-    } else {
-        Err(ErrorNo::EBADF)
-    }
+    let endpoint = read_user_addr(addr, addr_len)?;
+    with_socket(fd, |socket| socket.connect(endpoint)).map(|_| 0)
 }
 
 /// 监听着的SOCK_STREAM类型的socket, 接受连接, 原socket不受影响，创建一个新的socket返回
@@ -203,60 +229,321 @@ pub fn sys_accept4(fd: usize, addr: *mut u8, addr_len: *mut u32, flags: i32) ->
         "sys_accept: fd: {} addr: {:p} len: {:p}",
         fd, addr, addr_len
     );
+    let (socket, remote) =
+        with_socket(fd, |socket| socket.accept(socket_fd_flags(flags as usize)))?;
+    write_user_addr(addr, addr_len, &remote)?;
+    let task = get_current_task().unwrap();
+    let mut fd_manager = task.fd_manager.lock();
+    fd_manager
+        .push(Arc::new(socket))
+        .map_err(|_| ErrorNo::EMFILE)
+}
+
+/// 关闭 socket 连接的读端和/或写端
+pub fn sys_shutdown(fd: usize, how: usize) -> SysResult {
+    info!("sys_shutdown: fd: {} how: {}", fd, how);
+    with_socket(fd, |socket| socket.shutdown(how)).map(|_| 0)
+}
+
+/// 获取 socket 的本地地址
+pub fn sys_getsockname(fd: usize, addr: *mut u8, addr_len: *mut u32) -> SysResult {
+    let sock_addr = with_socket(fd, |socket| Ok(socket.local_addr()))?;
+    write_user_addr(addr, addr_len, &sock_addr)
+}
+
+/// 获取 socket 连接的对方的地址
+pub fn sys_getpeername(fd: usize, addr: *mut u8, addr_len: *mut u32) -> SysResult {
+    let sock_addr = with_socket(fd, |socket| socket.peer_addr())?;
+    write_user_addr(addr, addr_len, &sock_addr)
+}
+
+/// 设置 socket 的选项，选项的值在 optval 处，长度为 optlen
+pub fn sys_setsockopt(
+    fd: usize,
+    level: usize,
+    optname: usize,
+    optval: *const u8,
+    optlen: usize,
+) -> SysResult {
     let task = get_current_task().unwrap();
     let mut task_vm = task.vm.lock();
-    if task_vm.manually_alloc_page(addr as usize).is_err()
-        || task_vm.manually_alloc_page(addr_len as usize).is_err()
-    {
-        return Err(ErrorNo::EINVAL);
+    if optlen > 0 && task_vm.manually_alloc_user_str(optval, optlen).is_err() {
+        return Err(ErrorNo::EFAULT);
     }
     drop(task_vm);
-    loop {
-        let mut fd_manager = task.fd_manager.lock();
-        if let Ok(file) = fd_manager.get_file(fd) {
-            //if file.ready_to_read()
-            let mut buffer = [0u8; 64];
-            //获取新连接的远端地址
-            if let Some(read_len) = file.recvfrom(&mut buffer, 100, 0, &mut 0) {
-                info!("accept recv: {}", read_len);
-                if read_len != size_of::<IpAddr>() {
-                    warn!("accept unknown IpAddr");
-                }
-                unsafe {
-                    let taddr = core::slice::from_raw_parts_mut(addr as *mut u8, read_len);
-                    taddr.copy_from_slice(&buffer[..read_len]);
-                    *addr_len = read_len as u32;
+    let optval = unsafe { core::slice::from_raw_parts(optval, optlen) };
+    with_socket(fd, |socket| socket.set_option(level, optname, optval)).map(|_| 0)
+}
 
-                    let recv_addr = addr as *const IpAddr;
-                    info!(
-                        "sys_accept got IpAddr {} family: {:?}, IP: {:x}, Port: {}",
-                        *addr_len,
-                        (*recv_addr).family,
-                        u32::from_be((*recv_addr).addr),
-                        u16::from_be((*recv_addr).port)
-                    );
-                }
+/// 获取 socket 的选项。optlen 处原本是 optval 的空间大小，写入后是选项的实际长度
+pub fn sys_getsockopt(
+    fd: usize,
+    level: usize,
+    optname: usize,
+    optval: *mut u8,
+    optlen: *mut u32,
+) -> SysResult {
+    let task = get_current_task().unwrap();
+    let mut task_vm = task.vm.lock();
+    if task_vm.manually_alloc_type(optlen).is_err() {
+        return Err(ErrorNo::EFAULT);
+    }
+    let len = unsafe { *optlen } as usize;
+    if len > 0 && task_vm.manually_alloc_user_str(optval, len).is_err() {
+        return Err(ErrorNo::EFAULT);
+    }
+    drop(task_vm);
+    let optval = unsafe { core::slice::from_raw_parts_mut(optval, len) };
+    let write_len = with_socket(fd, |socket| socket.get_option(level, optname, optval))?;
+    unsafe {
+        *optlen = write_len as u32;
+    }
+    Ok(0)
+}
 
-                //设置好远程endpoint
-                let sock = file.as_any().downcast_ref::<Socket>().unwrap();
-                sock.set_endpoint(addr, true).unwrap_or(0);
+/// 检查 iov 指向的一组缓冲区是否都在用户地址空间中，返回它们的地址和长度
+fn read_user_iovec(iov: *const IoVec, iov_len: usize) -> Result<Vec<(*mut u8, usize)>, ErrorNo> {
+    let task = get_current_task().unwrap();
+    let mut task_vm = task.vm.lock();
+    let mut bufs = Vec::with_capacity(iov_len);
+    for i in 0..iov_len {
+        let io_vec = unsafe { iov.add(i) };
+        if task_vm.manually_alloc_type(io_vec).is_err() {
+            return Err(ErrorNo::EFAULT);
+        }
+        let (base, len) = unsafe { ((*io_vec).base, (*io_vec).len) };
+        if len > 0 && task_vm.manually_alloc_user_str(base, len).is_err() {
+            return Err(ErrorNo::EFAULT);
+        }
+        bufs.push((base, len));
+    }
+    Ok(bufs)
+}
+
+/// 检查 msg 及其中的控制信息缓冲区是否在用户地址空间中
+fn check_user_msg(msg: *const MsgHdr) -> Result<(), ErrorNo> {
+    let task = get_current_task().unwrap();
+    let mut task_vm = task.vm.lock();
+    if task_vm.manually_alloc_type(msg).is_err() {
+        return Err(ErrorNo::EFAULT);
+    }
+    let (control, control_len) = unsafe { ((*msg).control, (*msg).control_len) };
+    if !control.is_null()
+        && control_len > 0
+        && task_vm
+            .manually_alloc_user_str(control, control_len)
+            .is_err()
+    {
+        return Err(ErrorNo::EFAULT);
+    }
+    Ok(())
+}
 
-                // New Socket
-                if let Ok(new_fd) = fd_manager.push(Arc::new(sock.clonew())) {
-                    return Ok(new_fd);
-                } else {
-                    return Err(ErrorNo::EMFILE);
+/// 解析 sendmsg 的控制信息，取出 SCM_RIGHTS 中的 fd 对应的文件和 SCM_CREDENTIALS 中的身份
+fn read_ancillary(control: *const u8, control_len: usize) -> Result<Ancillary, ErrorNo> {
+    let task = get_current_task().unwrap();
+    let fd_manager = task.fd_manager.lock();
+    let mut ancillary = Ancillary::default();
+    let mut pos = 0;
+    while !control.is_null() && pos + size_of::<CMsgHdr>() <= control_len {
+        let cmsg = unsafe { &*(control.add(pos) as *const CMsgHdr) };
+        if cmsg.len < size_of::<CMsgHdr>() || pos + cmsg.len > control_len {
+            return Err(ErrorNo::EINVAL);
+        }
+        let data = unsafe { control.add(pos + size_of::<CMsgHdr>()) };
+        let data_len = cmsg.len - size_of::<CMsgHdr>();
+        match (cmsg.level, cmsg.cmsg_type) {
+            (SOL_SOCKET, SCM_RIGHTS) => {
+                let fds = data as *const i32;
+                for i in 0..data_len / size_of::<i32>() {
+                    let fd = unsafe { fds.add(i).read_unaligned() };
+                    let file = fd_manager
+                        .get_file(fd as usize)
+                        .map_err(|_| ErrorNo::EBADF)?;
+                    ancillary.rights.push(file);
                 }
-            } else if let Some(fl) = OpenFlags::from_bits((flags as u32) & !SOCKET_TYPE_MASK) {
-                if fl.contains(OpenFlags::NON_BLOCK) {
-                    info!("sys_accept flags: {:?}", fl);
-                    return Err(ErrorNo::EAGAIN);
+            }
+            (SOL_SOCKET, SCM_CREDENTIALS) => {
+                if data_len < size_of::<UCred>() {
+                    return Err(ErrorNo::EINVAL);
                 }
+                ancillary.creds = Some(unsafe { (data as *const UCred).read_unaligned() });
             }
-        } else {
-            return Err(ErrorNo::EBADF);
+            _ => return Err(ErrorNo::EINVAL),
+        }
+        pos += CMsgHdr::align(cmsg.len);
+    }
+    Ok(ancillary)
+}
+
+/// 依次往 recvmsg 的控制信息缓冲区中写入控制信息
+struct ControlWriter {
+    /// 缓冲区，可以为空
+    control: *mut u8,
+    /// 缓冲区的长度
+    control_len: usize,
+    /// 已经写入的长度
+    pos: usize,
+    /// 是否有控制信息因为缓冲区放不下而被截断
+    truncated: bool,
+}
+
+impl ControlWriter {
+    fn new(control: *mut u8, control_len: usize) -> Self {
+        Self {
+            control,
+            control_len: if control.is_null() { 0 } else { control_len },
+            pos: 0,
+            truncated: false,
+        }
+    }
+    /// 下一条控制信息中还能放下多少字节的数据
+    fn data_space(&self) -> usize {
+        self.control_len
+            .saturating_sub(self.pos + size_of::<CMsgHdr>())
+    }
+    /// 写入一条控制信息。数据放不下时会被截断
+    fn push(&mut self, cmsg_type: i32, data: &[u8]) {
+        if self.pos + size_of::<CMsgHdr>() > self.control_len {
+            self.truncated = true;
+            return;
+        }
+        let data_len = data.len().min(self.data_space());
+        self.truncated |= data_len < data.len();
+        let cmsg_len = size_of::<CMsgHdr>() + data_len;
+        unsafe {
+            let cmsg = self.control.add(self.pos);
+            (cmsg as *mut CMsgHdr).write_unaligned(CMsgHdr {
+                len: cmsg_len,
+                level: SOL_SOCKET,
+                cmsg_type,
+            });
+            core::ptr::copy_nonoverlapping(data.as_ptr(), cmsg.add(size_of::<CMsgHdr>()), data_len);
+        }
+        self.pos = (self.pos + CMsgHdr::align(cmsg_len)).min(self.control_len);
+    }
+    /// 把收到的文件放进当前任务预留的 fd 中，并写入 SCM_RIGHTS。未用到的预留 fd 会被释放。
+    ///
+    /// 缓冲区或者预留的 fd 放不下的文件会被丢弃，此时设置 MSG_CTRUNC。
+    /// cloexec 为 true 时只给接收方的 fd 加上 CLOEXEC，不影响发送方和文件本身
+    fn push_rights(&mut self, rights: Vec<Arc<dyn File>>, reserved: Vec<usize>, cloexec: bool) {
+        let fit_count = (self.data_space() / size_of::<i32>()).min(reserved.len());
+        if fit_count < rights.len() {
+            self.truncated = true;
+        }
+        let task = get_current_task().unwrap();
+        let mut fd_manager = task.fd_manager.lock();
+        let mut fds: Vec<i32> = Vec::new();
+        let mut reserved = reserved.into_iter();
+        for (file, fd) in rights.into_iter().take(fit_count).zip(&mut reserved) {
+            fd_manager.install_reserved(fd, file, cloexec);
+            fds.push(fd as i32);
+        }
+        for fd in reserved {
+            fd_manager.unreserve(fd);
         }
         drop(fd_manager);
-        suspend_current_task(); // yield
+        if !fds.is_empty() {
+            let bytes: Vec<u8> = fds.iter().flat_map(|fd| fd.to_ne_bytes()).collect();
+            self.push(SCM_RIGHTS, &bytes);
+        }
+    }
+}
+
+/// 为 recvmsg 可能收到的 SCM_RIGHTS 预留 fd，个数取控制信息缓冲区最多能放下的 fd 数。
+///
+/// 预留要在取出消息之前进行，否则 fd 不够时消息已经从队列中取出，数据就丢失了
+fn reserve_rights_fds(control: *const u8, control_len: usize) -> Vec<usize> {
+    if control.is_null() {
+        return Vec::new();
+    }
+    let count =
+        (control_len.saturating_sub(size_of::<CMsgHdr>()) / size_of::<i32>()).min(SCM_MAX_FD);
+    get_current_task().unwrap().fd_manager.lock().reserve(count)
+}
+
+/// 释放预留但没有用到的 fd
+fn unreserve_fds(fds: Vec<usize>) {
+    let task = get_current_task().unwrap();
+    let mut fd_manager = task.fd_manager.lock();
+    for fd in fds {
+        fd_manager.unreserve(fd);
+    }
+}
+
+/// 发送消息。消息内容由 msg 中的一组缓冲区拼接而成，
+/// 控制信息中可以用 SCM_RIGHTS 传递 fd，用 SCM_CREDENTIALS 指定发送方的身份
+pub fn sys_sendmsg(fd: usize, msg: *const MsgHdr, flags: i32) -> SysResult {
+    check_user_msg(msg)?;
+    let msg = unsafe { &*msg };
+    let dest = if msg.name.is_null() || msg.name_len == 0 {
+        None
+    } else {
+        Some(read_user_addr(msg.name, msg.name_len as usize)?)
+    };
+    let mut data = Vec::new();
+    for (base, len) in read_user_iovec(msg.iov, msg.iov_len)? {
+        data.extend_from_slice(unsafe { core::slice::from_raw_parts(base, len) });
+    }
+    let ancillary = read_ancillary(msg.control, msg.control_len)?;
+    let flags = MsgFlags::from_bits_truncate(flags as u32);
+    with_socket(fd, |socket| socket.send_msg(&data, dest, ancillary, flags))
+}
+
+/// 接收消息，内容依次填入 msg 中的一组缓冲区。
+///
+/// 收到的 fd 和发送方的身份分别以 SCM_RIGHTS 和 SCM_CREDENTIALS 控制信息的形式返回，
+/// 数据报或者控制信息被截断时，在 msg.flags 中设置 MSG_TRUNC 或 MSG_CTRUNC
+pub fn sys_recvmsg(fd: usize, msg: *mut MsgHdr, flags: i32) -> SysResult {
+    check_user_msg(msg)?;
+    let msg = unsafe { &mut *msg };
+    let bufs = read_user_iovec(msg.iov, msg.iov_len)?;
+    let mut data = vec![0u8; bufs.iter().map(|&(_, len)| len).sum()];
+    let flags = MsgFlags::from_bits_truncate(flags as u32);
+    let reserved = reserve_rights_fds(msg.control, msg.control_len);
+    let meta = match with_socket(fd, |socket| socket.recv_msg(&mut data, flags)) {
+        Ok(meta) => meta,
+        Err(err) => {
+            unreserve_fds(reserved);
+            return Err(err);
+        }
+    };
+    let mut pos = 0;
+    for (base, len) in bufs {
+        let len = len.min(meta.len - pos);
+        unsafe { core::slice::from_raw_parts_mut(base, len) }
+            .copy_from_slice(&data[pos..pos + len]);
+        pos += len;
+    }
+    match &meta.src {
+        Some(src) if !msg.name.is_null() => {
+            if let Err(err) = write_user_addr(msg.name, &mut msg.name_len as *mut u32, src) {
+                unreserve_fds(reserved);
+                return Err(err);
+            }
+        }
+        _ => msg.name_len = 0,
+    }
+    let mut writer = ControlWriter::new(msg.control, msg.control_len);
+    if let Some(cred) = meta.ancillary.creds {
+        let bytes = unsafe {
+            core::slice::from_raw_parts(&cred as *const UCred as *const u8, size_of::<UCred>())
+        };
+        writer.push(SCM_CREDENTIALS, bytes);
     }
+    writer.push_rights(
+        meta.ancillary.rights,
+        reserved,
+        flags.contains(MsgFlags::CMSG_CLOEXEC),
+    );
+    msg.control_len = writer.pos;
+    let mut msg_flags = MsgFlags::empty();
+    msg_flags.set(MsgFlags::TRUNC, meta.msg_len > meta.len);
+    msg_flags.set(MsgFlags::CTRUNC, writer.truncated);
+    msg.flags = msg_flags.bits() as i32;
+    Ok(if flags.contains(MsgFlags::TRUNC) {
+        meta.msg_len
+    } else {
+        meta.len
+    })
 }
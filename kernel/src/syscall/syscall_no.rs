@@ -21,6 +21,7 @@ numeric_enum_macro::numeric_enum! {
         UMOUNT = 39,
         MOUNT = 40,
         STATFS = 43,
+        FTRUNCATE = 46,
         ACCESS = 48,
         CHDIR = 49,
         CHMOD = 53,
@@ -40,9 +41,11 @@ numeric_enum_macro::numeric_enum! {
         READLINKAT = 78,
         FSTATAT = 79,
         FSTAT = 80,
+        SYNC = 81,
         FSYNC = 82,
         FDATASYNC = 83,
         UTIMENSAT = 88,
+        PERSONALITY = 92,
         EXIT = 93,
         EXIT_GROUP = 94,
         SET_TID_ADDRESS = 96,
@@ -61,6 +64,8 @@ numeric_enum_macro::numeric_enum! {
         SIGPROCMASK = 135,
         SIGTIMEDWAIT = 137,
         SIGRETURN = 139,
+        SETPRIORITY = 140,
+        GETPRIORITY = 141,
         TIMES = 153,
         UNAME = 160,
         GETRUSAGE = 165,
@@ -75,7 +80,12 @@ numeric_enum_macro::numeric_enum! {
         GETEGID = 177,
         GETTID = 178,
         SYSINFO = 179,
+        SHMGET = 194,
+        SHMCTL = 195,
+        SHMAT = 196,
+        SHMDT = 197,
         SOCKET = 198,
+        SOCKETPAIR = 199,
         BIND = 200,
         LISTEN = 201,
         ACCEPT = 202,
@@ -86,16 +96,20 @@ numeric_enum_macro::numeric_enum! {
         RECVFROM = 207,
         SETSOCKOPT = 208,
         GETSOCKOPT = 209,
-        SHUDOWN = 210,
+        SHUTDOWN = 210,
         SENDMSG = 211,
         RECVMSG = 212,
         BRK = 214,
         MUNMAP = 215,
+        MREMAP = 216,
         CLONE = 220,
         EXECVE = 221,
         MMAP = 222,
+        SWAPON = 224,
+        SWAPOFF = 225,
         MPROTECT = 226,
         MSYNC = 227,
+        MINCORE = 232,
         MADVISE = 233,
         ACCEPT4 = 242,
         WAIT4 = 260,
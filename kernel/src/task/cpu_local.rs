@@ -1,20 +1,23 @@
 //! 每个核当前正在运行的任务及上下文信息
 
+use super::oom::{handle_pending_oom, oom_kill};
 use super::{
-    TaskContext, TaskControlBlock, TaskStatus, __move_to_context, __switch,
-    fetch_task_from_scheduler, push_task_to_scheduler, ORIGIN_USER_PROC,
+    TaskContext, TaskControlBlock, TaskStatus, __move_to_context, __switch, expire_timers,
+    fetch_task_from_scheduler, push_task_to_scheduler, remove_exited_task_from_scheduler,
+    wait_for_task, yield_task_in_scheduler, ORIGIN_USER_PROC,
 };
 use crate::{
     arch::get_cpu_id,
-    constants::{CPU_ID_LIMIT, IS_TEST_ENV, NO_PARENT, USER_STACK_RED_ZONE},
+    cmdline::is_test_env,
+    constants::{CPU_ID_LIMIT, NO_PARENT, USER_STACK_RED_ZONE},
     error::{OSError, OSResult},
-    file::show_testcase_result,
+    file::{poll_nic, show_testcase_result},
     memory::{enable_kernel_page_table, PTEFlags, VirtAddr},
     signal::{
         global_logoff_signals, send_signal, SigActionDefault, SigActionFlags, SigInfo, SignalNo,
         SignalUserContext, SIG_IGN,
     },
-    syscall::{check_thread_blocked, clear_loop_checker},
+    syscall::{clear_loop_checker, exit_robust_list, futex_wake},
 };
 use alloc::{sync::Arc, vec::Vec};
 use core::mem::size_of;
@@ -68,13 +71,13 @@ lazy_static::lazy_static! {
 pub fn run_tasks() -> ! {
     let cpu_id = get_cpu_id();
     loop {
+        // 唤醒等待超时的任务
+        expire_timers();
+        // 把网卡收到的帧交给协议栈，唤醒等待网络的任务。没有网卡或刚推进过时直接返回
+        poll_nic();
+        // 处理内核分配内存失败时请求的 OOM kill
+        handle_pending_oom();
         if let Some(task) = fetch_task_from_scheduler() {
-            let tid = task.get_tid_num();
-            // 如果线程正在等待，则不进入
-            if check_thread_blocked(tid) {
-                push_task_to_scheduler(task);
-                continue;
-            }
             let mut cpu_local = CPU_CONTEXTS[cpu_id].lock();
             //let mut task_inner = task.lock();
             let idle_task_cx_ptr = cpu_local.get_idle_task_cx_ptr();
@@ -89,6 +92,7 @@ pub fn run_tasks() -> ! {
             }
             // 标记内核态进入任务的时间
             task.time.lock().switch_into_task();
+            task.sched.lock().switch_into_task();
             cpu_local.current = Some(task);
             // 清空计数器
             clear_loop_checker();
@@ -102,7 +106,10 @@ pub fn run_tasks() -> ! {
             // 在其中会修改 current.task_status 和 exit_code，但任务本身还在被当前 CPU 占用，需要下面再将其插入队列或
             let mut cpu_local = CPU_CONTEXTS[cpu_id].lock();
             // 标记内核态退出任务的时间
-            cpu_local.current().unwrap().time.lock().switch_out_task();
+            let current = cpu_local.current().unwrap();
+            current.time.lock().switch_out_task();
+            current.sched.lock().switch_out_task();
+            drop(current);
             // 切换回只有内核的页表。在此之后就不能再访问该任务用户空间的内容
             enable_kernel_page_table();
             // 此时已切回空闲任务
@@ -114,8 +121,16 @@ pub fn run_tasks() -> ! {
                         // 将暂停的用户程序塞回任务队列
                         push_task_to_scheduler(task);
                     }
+                    TaskStatus::Blocking => {
+                        // 阻塞的任务留在等待队列里，由唤醒它的任务放回就绪队列。
+                        // 但如果它在切出之前就已经被唤醒了，则需要在这里放回
+                        if !task.block_if_not_woken() {
+                            push_task_to_scheduler(task);
+                        }
+                    }
                     TaskStatus::Dying => {
-                        if !IS_TEST_ENV && task.get_pid_num() == 0 {
+                        remove_exited_task_from_scheduler(&task);
+                        if !is_test_env() && task.get_pid_num() == 0 {
                             // 这是初始进程，且不在测试环境
                             panic!("origin user proc exited, All applications completed.");
                         } else {
@@ -133,6 +148,9 @@ pub fn run_tasks() -> ! {
             // 因为 task 是 task_current() 得到的，所以如果 task 不是 ORIGIN_USER_PROC，它在上面的 if 结束时就已经没有了 Arc 引用
             // 其内部的 Pid, MemorySet 等应在此时被 Drop
             drop(cpu_local);
+        } else {
+            // 没有任务可以执行，等待其他核插入新任务
+            wait_for_task();
         }
     }
 }
@@ -159,6 +177,42 @@ pub fn suspend_current_task() {
     }
 }
 
+/// 阻塞当前用户程序，回到 idle 状态。
+///
+/// 调用前需要已经把它放进某个等待队列，并标记为 `TaskStatus::Blocking`，见 `wait_queue.rs`。
+/// 切出后它不会回到就绪队列，直到被等待队列唤醒
+pub fn block_current_task() {
+    let cpu_id = get_cpu_id();
+    let mut cpu_local = CPU_CONTEXTS[cpu_id].lock();
+    let task = cpu_local.current().unwrap();
+    trace!("[cpu {}] tid {} block", cpu_id, task.get_tid_num());
+    let current_task_cx_ptr = task.get_task_cx_ptr() as *mut TaskContext;
+    let idle_task_cx_ptr = cpu_local.get_idle_task_cx_ptr();
+    drop(task);
+    drop(cpu_local);
+    // 切换回 run_tasks() 中
+    unsafe {
+        __switch(current_task_cx_ptr, idle_task_cx_ptr);
+    }
+}
+
+/// 当前用户程序主动让出 CPU，回到 idle 状态。
+///
+/// 和 suspend_current_task 的区别在于，调度器会把它排在当前所有就绪任务之后
+pub fn yield_current_task() {
+    let task = get_current_task().unwrap();
+    yield_task_in_scheduler(&task);
+    drop(task);
+    suspend_current_task();
+}
+
+/// 当前用户程序被信号杀死。和 shell 一样把 exit_code 设为 128 + 信号编号，
+/// 这样测例被杀死时也会被统计为失败
+fn kill_current_task(signum: usize) {
+    get_current_task().unwrap().set_term_signal(signum);
+    exit_current_task(128 + signum as i32);
+}
+
 /// 终止当前用户程序，回到 idle 状态
 pub fn exit_current_task(exit_code: i32) {
     let cpu_id = get_cpu_id();
@@ -169,6 +223,8 @@ pub fn exit_current_task(exit_code: i32) {
     task.set_exit_code(exit_code);
     // clear_child_tid 的值不为 0，则将这个用户地址处的值写为0
     let addr = task.inner.lock().clear_child_tid;
+    let robust_list = task.get_robust_list();
+    let tid = task.get_tid_num();
     if addr != 0 {
         // 确认这个地址在用户地址空间中。如果没有也不需要报错，因为线程马上就退出了
         if task.vm.lock().manually_alloc_page(addr).is_ok() {
@@ -189,6 +245,14 @@ pub fn exit_current_task(exit_code: i32) {
     //drop(task_inner);
     drop(task);
     drop(cpu_local);
+    if robust_list != 0 {
+        // 释放线程退出时仍然持有的 robust futex
+        exit_robust_list(robust_list, tid);
+    }
+    if addr != 0 {
+        // 唤醒在 clear_child_tid 上等待的线程，如 pthread_join
+        futex_wake(addr, 1);
+    }
 
     // 切换回 run_tasks() 中
     unsafe {
@@ -237,7 +301,7 @@ fn handle_zombie_task(_cpu_local: &mut CpuLocal, task: Arc<TaskControlBlock>) {
             // 因为每个进程在进这个函数时都拿着自己的锁，所以此时只有子进程先执行完成，父进程才能继续执行。
             // 为了防止父进程反复抢 start_proc 的锁又不得不释放，所以把获取子进程的锁放在外层
             if let Some(mut child_inner) = child.inner.try_lock() {
-                if tcb_inner.ppid == NO_PARENT || IS_TEST_ENV {
+                if tcb_inner.ppid == NO_PARENT || is_test_env() {
                     child_inner.ppid = NO_PARENT;
                     break;
                 } else if let Some(mut start_proc_tcb_inner) =
@@ -253,10 +317,11 @@ fn handle_zombie_task(_cpu_local: &mut CpuLocal, task: Arc<TaskControlBlock>) {
             // 只要没拿到任意一个锁，就继续循环
         }
     }
+    let has_orphans = !tcb_inner.children.is_empty();
     tcb_inner.children.clear();
     tcb_inner.task_status = TaskStatus::Zombie;
     // 在测试环境中时，手动检查退出时的 exit_code
-    if IS_TEST_ENV && task.pid == task.tid.0 {
+    if is_test_env() && task.pid == task.tid.0 {
         show_testcase_result(tcb_inner.exit_code);
     }
     //println!("tid {} is dead", task.tid.0);
@@ -274,17 +339,32 @@ fn handle_zombie_task(_cpu_local: &mut CpuLocal, task: Arc<TaskControlBlock>) {
     if Arc::strong_count(&task.vm) == 1 {
         task.vm.lock().clear_user_pages();
     }
+    // 唤醒在 wait4 中等待的父进程。这里要先释放当前任务的锁，因为父进程的 wait4 会拿着自己的锁来查看子进程
+    let parent = tcb_inner
+        .parent
+        .as_ref()
+        .and_then(|parent| parent.upgrade());
+    drop(tcb_inner);
+    if let Some(parent) = parent {
+        parent.child_exit_queue.notify_all();
+    }
+    // 子进程交给了初始进程，其中可能有已经退出的
+    if has_orphans && !is_test_env() {
+        ORIGIN_USER_PROC.child_exit_queue.notify_all();
+    }
 }
 
 /// 处理用户程序的缺页异常
+///
+/// 回收页帧之后仍然内存不足时，由 OOM killer 杀掉一个进程。
+/// 这时不算缺页失败，等被杀掉的进程退出后，用户程序会重新执行这条指令，再次缺页
 pub fn handle_user_page_fault(vaddr: VirtAddr, access_flags: PTEFlags) -> OSResult {
-    let cpu_id = get_cpu_id();
-    let cpu_local = CPU_CONTEXTS[cpu_id].lock();
-    if let Some(task) = cpu_local.current() {
-        task.vm.lock().handle_page_fault(vaddr, access_flags)
-    } else {
-        Err(OSError::Task_NoTrapHandler)
+    let task = get_current_task().ok_or(OSError::Task_NoTrapHandler)?;
+    let result = task.vm.lock().handle_page_fault(vaddr, access_flags);
+    if result == Err(OSError::Memory_RunOutOfMemory) && oom_kill() {
+        return Ok(());
     }
+    result
 }
 
 /// 获取当前核正在运行的进程的TCB。
@@ -318,6 +398,14 @@ pub fn handle_signals() {
     // 如果其他线程正在向这里发送信号，则当前线程在此被阻塞
     let mut sig_inner = task.signal_receivers.lock();
     let handler = task.signal_handlers.lock();
+    // SIGKILL 不能被屏蔽或捕获，即使正在处理其他信号也要立即退出
+    if sig_inner.has_sigkill() {
+        drop(handler);
+        drop(sig_inner);
+        drop(task);
+        kill_current_task(SignalNo::SIGKILL as usize);
+        return;
+    }
     if let Some(signum) = sig_inner.get_one_signal() {
         let signal = SignalNo::from(signum);
         //println!("tid {} handling signal: {:#?}", task.get_tid_num(), signal);
@@ -375,7 +463,7 @@ pub fn handle_signals() {
                         // 这里不需要 drop(task)，因为当前函数没有用到 task_inner，在 task.save_trap... 内部用过后已经 drop 了
                         drop(handler);
                         drop(sig_inner);
-                        exit_current_task(0);
+                        kill_current_task(signum);
                     }
                     SigActionDefault::Ignore => {
                         // 忽略信号时，要将已保存的上下文删除
@@ -385,7 +473,9 @@ pub fn handle_signals() {
             }
         } else if signal == SignalNo::SIGSEGV || signal == SignalNo::SIGBUS {
             //在处理信号的过程中又触发 SIGSEGV 或 SIGBUS，那么说明该直接结束了，否则会无限递归触发
-            exit_current_task(-1);
+            drop(handler);
+            drop(sig_inner);
+            kill_current_task(signum);
         }
     }
     //info!("signal handler finish");
@@ -8,31 +8,43 @@ mod clone_flags;
 mod context;
 mod cpu_local;
 mod kernel_stack;
+mod oom;
+mod sched_entity;
 mod scheduler;
 mod switch;
 mod task;
 mod time_stat;
+mod timer_wheel;
+mod wait_queue;
 
-use crate::constants::{ORIGIN_USER_PROC_NAME, ROOT_DIR};
+use crate::{cmdline::init_argv, constants::ROOT_DIR};
 use alloc::sync::Arc;
 use switch::{__move_to_context, __switch};
 
 pub use clone_flags::CloneFlags;
 pub use context::TaskContext;
 pub use cpu_local::{
-    exec_new_task, exit_current_task, get_current_task, handle_signals, handle_user_page_fault,
-    run_tasks, signal_return, suspend_current_task, timer_kernel_to_user, timer_user_to_kernel,
+    block_current_task, exec_new_task, exit_current_task, get_current_task, handle_signals,
+    handle_user_page_fault, run_tasks, signal_return, suspend_current_task, timer_kernel_to_user,
+    timer_user_to_kernel, yield_current_task,
 };
 pub use kernel_stack::KernelStack;
-pub use scheduler::Scheduler;
-pub use scheduler::{fetch_task_from_scheduler, push_task_to_scheduler};
+pub use oom::request_oom_kill;
+pub use sched_entity::SchedEntity;
+pub use scheduler::{
+    add_new_task_to_scheduler, fetch_task_from_scheduler, find_task_by_tid, push_task_to_scheduler,
+    remove_exited_task_from_scheduler, wait_for_task, yield_task_in_scheduler,
+};
+pub use scheduler::{CfsScheduler, RoundRobinScheduler, Scheduler};
 pub use task::{TaskControlBlock, TaskControlBlockInner, TaskStatus};
 pub use time_stat::TimeStat;
+pub use timer_wheel::{add_timer, cancel_timer, expire_timers, next_timer_expire_us, TimerId};
+pub use wait_queue::{signal_pending, sleep_current_task_until, wake_blocked_task, WaitQueue};
 
 lazy_static::lazy_static! {
     /// 第一个用户程序
     /// 任务调度器启动时会自动在队列中插入它作为第一个用户程序
     pub static ref ORIGIN_USER_PROC: Arc<TaskControlBlock> = Arc::new(
-        TaskControlBlock::from_app_name(ROOT_DIR, 0, vec![ORIGIN_USER_PROC_NAME.into()]).unwrap()
+        TaskControlBlock::from_app_name(ROOT_DIR, 0, init_argv()).unwrap()
     );
 }
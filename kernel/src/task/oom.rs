@@ -0,0 +1,100 @@
+//! 内存不足时选择进程杀掉(OOM killer)
+//!
+//! 缺页时分配不到页帧，并且换出页、收缩页缓存之后仍然不够时，从所有进程中选一个占用内存最多的进程，
+//! 向它的所有线程发送 SIGKILL。它退出时会释放用户段的页帧，之后缺页的进程重新执行时就可以分配到了。
+//!
+//! 内核堆和页缓存分配不到内存时，可能正持有各种锁，所以只通过 `request_oom_kill` 做个标记，
+//! 由调度循环调用 `handle_pending_oom` 来选择进程。
+//!
+//! 没有父进程的任务(初始进程和每个测例)以及和它们共用地址空间的线程不会被选中，
+//! 所以内存不足时它们仍能继续运行、回收子进程并统计结果
+
+use super::scheduler::alive_tasks;
+use super::{find_task_by_tid, TaskControlBlock, TaskStatus, ORIGIN_USER_PROC};
+use crate::{
+    cmdline::is_test_env,
+    constants::NO_PARENT,
+    memory::{refill_heap_reserve, MemorySet},
+    signal::{send_signal, SignalNo},
+};
+use alloc::{sync::Arc, vec::Vec};
+use core::sync::atomic::{AtomicBool, Ordering};
+use lock::Mutex;
+
+/// 已经被发送 SIGKILL 但可能还没有退出的任务的 tid。它们都退出之前不会再选择新的进程
+static OOM_VICTIMS: Mutex<Vec<usize>> = Mutex::new(Vec::new());
+
+/// 内核自身分配内存失败时设置，等待调度循环处理
+static OOM_PENDING: AtomicBool = AtomicBool::new(false);
+
+/// 进程的 badness，越大越优先被杀掉。目前就是它的 RSS 页数，被换出的页不算
+fn badness(vm: &MemorySet) -> usize {
+    vm.resident_pages()
+}
+
+/// 任务是否可以被 OOM killer 杀掉
+///
+/// 阻塞中的任务也可以被选中，发送 SIGKILL 时会唤醒它。已经在退出的任务不需要再杀
+fn is_killable(task: &Arc<TaskControlBlock>) -> bool {
+    let inner = task.inner.lock();
+    inner.ppid != NO_PARENT
+        && !matches!(inner.task_status, TaskStatus::Dying | TaskStatus::Zombie)
+        && (is_test_env() || !Arc::ptr_eq(task, &ORIGIN_USER_PROC))
+}
+
+/// 请求 OOM killer 杀掉一个进程。不分配内存也不拿锁，所以可以在内核堆和页缓存中调用
+pub fn request_oom_kill() {
+    OOM_PENDING.store(true, Ordering::Release);
+}
+
+/// 处理之前的 `request_oom_kill`，并补上内核堆用掉的预留页帧。由调度循环调用，此时不持有任何锁
+pub fn handle_pending_oom() {
+    if OOM_PENDING.swap(false, Ordering::AcqRel) {
+        oom_kill();
+    }
+    refill_heap_reserve();
+}
+
+/// 内存不足时调用，选择一个进程杀掉。调用时不能持有任何任务或地址空间的锁
+///
+/// 如果之前杀掉的进程还没有退出，或者这次选出了新的进程，则返回 true，调用者可以稍后再试；
+/// 如果没有可以杀掉的进程，则返回 false
+pub fn oom_kill() -> bool {
+    let mut victims = OOM_VICTIMS.lock();
+    victims.retain(|&tid| find_task_by_tid(tid).is_some());
+    if !victims.is_empty() {
+        return true;
+    }
+    // 按地址空间分组，同一个进程的线程共用一个地址空间，需要一起杀掉
+    let mut groups: Vec<Vec<Arc<TaskControlBlock>>> = Vec::new();
+    for task in alive_tasks() {
+        match groups
+            .iter_mut()
+            .find(|group| Arc::ptr_eq(&group[0].vm, &task.vm))
+        {
+            Some(group) => group.push(task),
+            None => groups.push(vec![task]),
+        }
+    }
+    let victim = groups
+        .into_iter()
+        .filter(|group| group.iter().all(is_killable))
+        .map(|group| (badness(&group[0].vm.lock()), group))
+        .filter(|&(score, _)| score > 0)
+        .max_by_key(|&(score, _)| score);
+    if let Some((score, group)) = victim {
+        warn!(
+            "out of memory: kill pid {} with {} resident pages",
+            group[0].get_pid_num(),
+            score
+        );
+        for task in group {
+            send_signal(task.get_tid_num(), SignalNo::SIGKILL as usize);
+            victims.push(task.get_tid_num());
+        }
+        true
+    } else {
+        warn!("out of memory: no process to kill");
+        false
+    }
+}
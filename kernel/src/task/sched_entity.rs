@@ -0,0 +1,106 @@
+//! 任务的调度信息，包括 nice 值和加权后的运行时间 vruntime
+//!
+//! - 在 `cpu_local.rs: run_tasks()` 中切换进入/切出任务时，开始/停止统计运行时间
+//! - vruntime = 实际运行时间 * NICE_0_WEIGHT / 当前 nice 值对应的权重
+//!
+//! 只有 `CfsScheduler` 会用到 vruntime，但 nice 值无论使用哪种调度器都会保存，以便 sys_getpriority 读取
+//!
+//! 持有 PI futex 的任务会临时继承等待者中最高的优先级(见 `syscall/futex/pi.rs`)，此时 CfsScheduler 按继承后的 nice 值计算权重，
+//! RoundRobinScheduler 则让它排在其他任务之前
+
+use timer::get_time_us;
+
+/// nice 值的下限，对应最高优先级
+pub const NICE_MIN: i32 = -20;
+/// nice 值的上限，对应最低优先级
+pub const NICE_MAX: i32 = 19;
+/// nice 值为 0 时的权重
+const NICE_0_WEIGHT: usize = 1024;
+
+/// nice 值从 -20 到 19 对应的权重，与 Linux 的 sched_prio_to_weight 相同。
+/// 相邻两级之间的权重约为 1.25 倍，即 nice 值每差 1，分到的 CPU 时间约差 10%
+const NICE_TO_WEIGHT: [usize; 40] = [
+    88761, 71755, 56483, 46273, 36291, // -20 ~ -16
+    29154, 23254, 18705, 14949, 11916, // -15 ~ -11
+    9548, 7620, 6100, 4904, 3906, // -10 ~ -6
+    3121, 2501, 1991, 1586, 1277, // -5 ~ -1
+    1024, 820, 655, 526, 423, // 0 ~ 4
+    335, 272, 215, 172, 137, // 5 ~ 9
+    110, 87, 70, 56, 45, // 10 ~ 14
+    36, 29, 23, 18, 15, // 15 ~ 19
+];
+
+/// 任务的调度信息
+pub struct SchedEntity {
+    /// nice 值，在 [NICE_MIN, NICE_MAX] 之间，越小优先级越高
+    nice: i32,
+    /// 通过 PI futex 从等待者那里继承的 nice 值。只有比自己的 nice 值小时才生效
+    pi_nice: Option<i32>,
+    /// 按权重缩放后的运行时间，单位为微秒
+    vruntime: usize,
+    /// 切换进入任务时标记当前系统时间，切出时累加统计
+    exec_start: usize,
+}
+
+impl SchedEntity {
+    /// 新任务的 nice 值为 0
+    pub fn new() -> Self {
+        Self {
+            nice: 0,
+            pi_nice: None,
+            vruntime: 0,
+            exec_start: 0,
+        }
+    }
+    /// clone 出的任务继承 nice 值和 vruntime，但不继承 PI futex 带来的优先级
+    pub fn new_from_parent(parent: &Self) -> Self {
+        Self {
+            nice: parent.nice,
+            pi_nice: None,
+            vruntime: parent.vruntime,
+            exec_start: 0,
+        }
+    }
+    /// 获取 nice 值，不包括继承来的优先级
+    pub fn nice(&self) -> i32 {
+        self.nice
+    }
+    /// 实际用于调度的 nice 值，即自己的 nice 值和继承的 nice 值中较小的一个
+    pub fn effective_nice(&self) -> i32 {
+        self.pi_nice
+            .map_or(self.nice, |pi_nice| pi_nice.min(self.nice))
+    }
+    /// 是否从 PI futex 等待者那里继承了更高的优先级
+    pub fn is_pi_boosted(&self) -> bool {
+        self.pi_nice.map_or(false, |pi_nice| pi_nice < self.nice)
+    }
+    /// 设置从 PI futex 等待者那里继承的 nice 值，None 表示不再继承
+    pub fn set_pi_nice(&mut self, pi_nice: Option<i32>) {
+        self.pi_nice = pi_nice;
+    }
+    /// 设置 nice 值，超出范围的部分会被截断
+    pub fn set_nice(&mut self, nice: i32) {
+        self.nice = nice.clamp(NICE_MIN, NICE_MAX);
+    }
+    /// 当前实际 nice 值对应的权重
+    pub fn weight(&self) -> usize {
+        NICE_TO_WEIGHT[(self.effective_nice() - NICE_MIN) as usize]
+    }
+    /// 获取 vruntime
+    pub fn vruntime(&self) -> usize {
+        self.vruntime
+    }
+    /// 设置 vruntime，由调度器调用
+    pub fn set_vruntime(&mut self, vruntime: usize) {
+        self.vruntime = vruntime;
+    }
+    /// 统计时间：(内核态)切换进入当前任务
+    pub fn switch_into_task(&mut self) {
+        self.exec_start = get_time_us();
+    }
+    /// 统计时间：(内核态)切出当前任务，按权重累加 vruntime
+    pub fn switch_out_task(&mut self) {
+        let delta = get_time_us() - self.exec_start;
+        self.vruntime += delta * NICE_0_WEIGHT / self.weight();
+    }
+}
@@ -0,0 +1,66 @@
+//! 按权重公平分配时间的调度器，参考 Linux 的 CFS
+//!
+//! 每个任务的 vruntime 是它实际运行时间按 nice 值对应的权重缩放后的结果(见 `sched_entity.rs`)，
+//! 调度器每次选出 vruntime 最小的任务执行。这样权重越大(nice 越小)的任务，vruntime 增长越慢，
+//! 也就能分到越多的运行时间；而批处理任务跑得越久，就越排在交互式任务后面
+
+use super::{Scheduler, TaskControlBlock};
+use crate::constants::SCHED_WAKEUP_GRANULARITY_US;
+use alloc::{collections::BTreeMap, sync::Arc};
+
+/// 按 vruntime 排序的任务调度器
+pub struct CfsScheduler {
+    /// 就绪队列，以 (vruntime, 插入序号) 为键。
+    /// 插入序号保证键不重复，且 vruntime 相同时先插入的先执行
+    ready_queue: BTreeMap<(usize, usize), Arc<TaskControlBlock>>,
+    /// 下一个插入序号
+    next_seq: usize,
+    /// 队列中出现过的最小 vruntime，只增不减
+    min_vruntime: usize,
+}
+
+impl CfsScheduler {
+    /// 新建一个空的调度器
+    pub fn new() -> Self {
+        Self {
+            ready_queue: BTreeMap::new(),
+            next_seq: 0,
+            min_vruntime: 0,
+        }
+    }
+}
+
+impl Scheduler for CfsScheduler {
+    fn push(&mut self, task: Arc<TaskControlBlock>) {
+        let mut sched = task.sched.lock();
+        // 长时间睡眠的任务 vruntime 会远小于其他任务，如果不加限制，它醒来后会一直占着 CPU。
+        // 所以最多只让它比当前最小值提前一个粒度
+        let floor = self
+            .min_vruntime
+            .saturating_sub(SCHED_WAKEUP_GRANULARITY_US);
+        if sched.vruntime() < floor {
+            sched.set_vruntime(floor);
+        }
+        let key = (sched.vruntime(), self.next_seq);
+        drop(sched);
+        self.next_seq += 1;
+        self.ready_queue.insert(key, task);
+    }
+    fn pop(&mut self) -> Option<Arc<TaskControlBlock>> {
+        let (&key, _) = self.ready_queue.iter().next()?;
+        self.min_vruntime = self.min_vruntime.max(key.0);
+        self.ready_queue.remove(&key)
+    }
+    fn size(&self) -> usize {
+        self.ready_queue.len()
+    }
+    fn yield_task(&mut self, task: &Arc<TaskControlBlock>) {
+        // 把它的 vruntime 推到队列中最大的值，这样重新插入时会排在所有任务之后
+        if let Some((&(max_vruntime, _), _)) = self.ready_queue.iter().next_back() {
+            let mut sched = task.sched.lock();
+            if sched.vruntime() < max_vruntime {
+                sched.set_vruntime(max_vruntime);
+            }
+        }
+    }
+}
@@ -0,0 +1,215 @@
+//! 任务调度器
+//!
+//! 调度器统一实现 `Scheduler` trait，目前有两种：
+//! - `RoundRobinScheduler`：按到达顺序轮流执行，只有通过 PI futex 继承了更高优先级的任务会插队
+//! - `CfsScheduler`：按 nice 值加权，总是选择 vruntime 最小的任务执行
+//!
+//! 具体使用哪一种由 `constants.rs` 中的 `USE_CFS_SCHEDULER` 决定
+//!
+//! 每个核有自己的就绪队列 `RUN_QUEUES[cpu_id]`，任务默认放回它上一次运行的核的队列中。
+//! 如果一个核的队列空了，它会从任务最多的其他核的队列里"偷"一个任务来执行；
+//! 如果所有队列都是空的，它会在 `wait_for_task` 中停下来，直到其他核插入新任务时通过核间中断(IPI)唤醒它，
+//! 或者时间轮中有定时器到期
+
+mod cfs;
+mod round_robin;
+
+use super::{next_timer_expire_us, TaskControlBlock, ORIGIN_USER_PROC};
+use crate::{
+    arch::{get_cpu_id, send_ipi, wait_for_ipi, wait_for_ipi_or_timer},
+    cmdline::is_test_env,
+    constants::{CPU_ID_LIMIT, USE_CFS_SCHEDULER},
+    file::load_next_testcase,
+};
+use alloc::{
+    boxed::Box,
+    collections::BTreeMap,
+    sync::{Arc, Weak},
+    vec::Vec,
+};
+use core::sync::atomic::{AtomicUsize, Ordering};
+use lock::Mutex;
+use timer::machine_ticks_per_usec;
+
+pub use cfs::CfsScheduler;
+pub use round_robin::RoundRobinScheduler;
+
+/// 任务调度器需要实现的接口。
+/// 在调度器外部会加一个 Mutex 锁，所以内部不需要考虑并发
+pub trait Scheduler: Send {
+    /// 添加一个任务到队列中
+    fn push(&mut self, task: Arc<TaskControlBlock>);
+    /// 从队列中获取下一个要执行的任务
+    fn pop(&mut self) -> Option<Arc<TaskControlBlock>>;
+    /// 返回队列中元素个数
+    fn size(&self) -> usize;
+    /// 正在运行的任务通过 sched_yield 主动让出 CPU。
+    ///
+    /// 此时任务不在队列中，调用后它会被挂起并重新 push 回来，
+    /// 调度器需要保证它排在当前队列中所有任务之后
+    fn yield_task(&mut self, _task: &Arc<TaskControlBlock>) {}
+}
+
+/// 按 `USE_CFS_SCHEDULER` 生成一个空的调度器
+fn new_scheduler() -> Box<dyn Scheduler> {
+    if USE_CFS_SCHEDULER {
+        Box::new(CfsScheduler::new())
+    } else {
+        Box::new(RoundRobinScheduler::new())
+    }
+}
+
+lazy_static::lazy_static! {
+    /// 每个核的就绪队列，下标为 cpu_id。
+    ///
+    /// 它没有放在 CpuLocal 里，因为核在切换任务时会一直持有自己的 CpuLocal，
+    /// 而其他核偷任务或者插入任务时需要访问这个队列，分开加锁可以避免互相等待。
+    ///
+    /// 它启动时会自动在第一个访问它的核的队列中插入 ORIGIN_USER_PROC(或第一个测例)作为第一个用户程序
+    pub static ref RUN_QUEUES: Vec<Mutex<Box<dyn Scheduler>>> = {
+        let mut queues: Vec<Mutex<Box<dyn Scheduler>>> = Vec::new();
+        for _ in 0..CPU_ID_LIMIT {
+            queues.push(Mutex::new(new_scheduler()));
+        }
+        let first_task = if is_test_env() { // 评测环境下，输入测例
+            load_next_testcase().unwrap()
+        } else { // 正常情况下，启动初始进程
+            ORIGIN_USER_PROC.clone()
+        };
+        register_alive_task(&first_task);
+        queues[get_cpu_id()].lock().push(first_task);
+        queues
+    };
+
+    /// 测试环境下，是否已经加载完所有测例
+    static ref ALL_TESTCASES_LOADED: Mutex<bool> = Mutex::new(false);
+}
+
+/// 正在 wait_for_task 中等待的空闲核，第 i 位表示 cpu_id 为 i 的核
+static IDLE_CPUS: AtomicUsize = AtomicUsize::new(0);
+
+/// 还没有退出的任务数，包括在就绪队列中的、正在运行的和被阻塞的任务。
+/// 测试环境下，只有它归零时才加载下一个测例，保证测例依次执行
+static ALIVE_TASKS: AtomicUsize = AtomicUsize::new(0);
+
+/// 所有还没有退出的任务，以 tid 为键。这里只存弱引用，不影响任务的回收
+static TASK_TABLE: Mutex<BTreeMap<usize, Weak<TaskControlBlock>>> = Mutex::new(BTreeMap::new());
+
+/// 记录一个新加入调度的任务
+fn register_alive_task(task: &Arc<TaskControlBlock>) {
+    ALIVE_TASKS.fetch_add(1, Ordering::SeqCst);
+    TASK_TABLE
+        .lock()
+        .insert(task.get_tid_num(), Arc::downgrade(task));
+}
+
+/// 按 tid 查找一个还没有退出的任务
+pub fn find_task_by_tid(tid: usize) -> Option<Arc<TaskControlBlock>> {
+    TASK_TABLE.lock().get(&tid)?.upgrade()
+}
+
+/// 所有还没有退出的任务
+pub fn alive_tasks() -> Vec<Arc<TaskControlBlock>> {
+    TASK_TABLE
+        .lock()
+        .values()
+        .filter_map(Weak::upgrade)
+        .collect()
+}
+
+/// 如果有空闲的核，则发送 IPI 唤醒其中一个，让它来偷任务
+fn wake_idle_cpu() {
+    let idle_cpus = IDLE_CPUS.load(Ordering::SeqCst) & !(1 << get_cpu_id());
+    if idle_cpus != 0 {
+        send_ipi(idle_cpus.trailing_zeros() as usize);
+    }
+}
+
+/// 向当前核的任务队列里插入一个任务。一般是把刚切换出来的任务放回队列
+pub fn push_task_to_scheduler(task: Arc<TaskControlBlock>) {
+    RUN_QUEUES[get_cpu_id()].lock().push(task);
+    wake_idle_cpu();
+}
+
+/// 向任务队列里插入一个新创建的任务
+pub fn add_new_task_to_scheduler(task: Arc<TaskControlBlock>) {
+    register_alive_task(&task);
+    push_task_to_scheduler(task);
+}
+
+/// 通知调度器，一个任务已经退出，不会再回到队列中
+pub fn remove_exited_task_from_scheduler(task: &TaskControlBlock) {
+    TASK_TABLE.lock().remove(&task.get_tid_num());
+    ALIVE_TASKS.fetch_sub(1, Ordering::SeqCst);
+}
+
+/// 通知调度器，这个(正在运行的)任务主动让出了 CPU
+pub fn yield_task_in_scheduler(task: &Arc<TaskControlBlock>) {
+    RUN_QUEUES[get_cpu_id()].lock().yield_task(task)
+}
+
+/// 从任务最多的其他核的队列中偷一个任务
+fn steal_task(cpu_id: usize) -> Option<Arc<TaskControlBlock>> {
+    let victim = (0..CPU_ID_LIMIT)
+        .filter(|&id| id != cpu_id)
+        .max_by_key(|&id| RUN_QUEUES[id].lock().size())?;
+    RUN_QUEUES[victim].lock().pop()
+}
+
+/// 测试环境下，如果上一个测例的所有任务都已退出，则加载下一个测例
+fn try_load_next_testcase() -> Option<Arc<TaskControlBlock>> {
+    let mut all_loaded = ALL_TESTCASES_LOADED.lock();
+    if *all_loaded || ALIVE_TASKS.load(Ordering::SeqCst) != 0 {
+        return None;
+    }
+    if let Some(new_tcb) = load_next_testcase() {
+        register_alive_task(&new_tcb);
+        Some(new_tcb)
+    } else {
+        // load_next_testcase 在测例用完时会输出测试结果，所以只能调用一次
+        *all_loaded = true;
+        None
+    }
+}
+
+/// 从任务队列中拿一个任务，返回其TCB。
+/// 非阻塞，即如果没有任务可取，则直接返回 None
+pub fn fetch_task_from_scheduler() -> Option<Arc<TaskControlBlock>> {
+    let cpu_id = get_cpu_id();
+    if let Some(task) = RUN_QUEUES[cpu_id].lock().pop() {
+        return Some(task);
+    }
+    if let Some(task) = steal_task(cpu_id) {
+        trace!("[cpu {}] steal tid {}", cpu_id, task.get_tid_num());
+        return Some(task);
+    }
+    if is_test_env() {
+        // 测试环境下，测例执行完就加载下一个
+        return try_load_next_testcase();
+    }
+    None
+}
+
+/// 是否有任务可以执行，或者(测试环境下)可以加载下一个测例
+fn has_task_to_run() -> bool {
+    RUN_QUEUES.iter().any(|queue| queue.lock().size() > 0)
+        || (is_test_env()
+            && ALIVE_TASKS.load(Ordering::SeqCst) == 0
+            && !*ALL_TESTCASES_LOADED.lock())
+}
+
+/// 当前核没有任务可执行时调用，停下当前核，直到其他核插入新任务时唤醒它，或者最近的定时器到期
+pub fn wait_for_task() {
+    let cpu_id = get_cpu_id();
+    IDLE_CPUS.fetch_or(1 << cpu_id, Ordering::SeqCst);
+    // 标记空闲之后要再检查一次，否则在标记之前插入的任务不会发送 IPI，当前核可能就一直睡下去了
+    if !has_task_to_run() {
+        info!("[cpu {}] is idle now", cpu_id);
+        if let Some(expire_us) = next_timer_expire_us() {
+            wait_for_ipi_or_timer(expire_us.saturating_mul(machine_ticks_per_usec()) as u64);
+        } else {
+            wait_for_ipi();
+        }
+    }
+    IDLE_CPUS.fetch_and(!(1 << cpu_id), Ordering::SeqCst);
+}
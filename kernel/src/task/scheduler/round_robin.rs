@@ -0,0 +1,51 @@
+//! Round-Robin 调度器
+
+use super::{Scheduler, TaskControlBlock};
+use alloc::{collections::VecDeque, sync::Arc};
+
+/// 任务调度器，采用 Round-Robin 算法，不考虑任务的 nice 值。
+///
+/// 唯一的例外是通过 PI futex 继承了更高优先级的任务，它们放在单独的队列中，总是先于其他任务执行，
+/// 这样等待它释放锁的高优先级任务不会被其他任务拖延
+pub struct RoundRobinScheduler {
+    ready_queue: VecDeque<Arc<TaskControlBlock>>,
+    /// 继承了更高优先级的任务
+    boosted_queue: VecDeque<Arc<TaskControlBlock>>,
+    /// 正在主动让出 CPU 的任务的 tid。它重新插入时总是放到普通队列的队尾
+    yielding: Option<usize>,
+}
+
+impl RoundRobinScheduler {
+    /// 新建一个空的调度器
+    pub fn new() -> Self {
+        Self {
+            ready_queue: VecDeque::new(),
+            boosted_queue: VecDeque::new(),
+            yielding: None,
+        }
+    }
+}
+
+impl Scheduler for RoundRobinScheduler {
+    fn push(&mut self, task: Arc<TaskControlBlock>) {
+        if self.yielding == Some(task.get_tid_num()) {
+            self.yielding = None;
+            self.ready_queue.push_back(task);
+        } else if task.sched.lock().is_pi_boosted() {
+            self.boosted_queue.push_back(task);
+        } else {
+            self.ready_queue.push_back(task);
+        }
+    }
+    fn pop(&mut self) -> Option<Arc<TaskControlBlock>> {
+        self.boosted_queue
+            .pop_front()
+            .or_else(|| self.ready_queue.pop_front())
+    }
+    fn size(&self) -> usize {
+        self.boosted_queue.len() + self.ready_queue.len()
+    }
+    fn yield_task(&mut self, task: &Arc<TaskControlBlock>) {
+        self.yielding = Some(task.get_tid_num());
+    }
+}
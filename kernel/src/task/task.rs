@@ -3,13 +3,17 @@
 
 //#![deny(missing_docs)]
 
-use super::{CloneFlags, KernelStack, TaskContext, TimeStat};
+use super::{CloneFlags, KernelStack, SchedEntity, TaskContext, TimeStat, WaitQueue};
 use crate::{
     arch::get_cpu_id,
-    constants::{NO_PARENT, USER_STACK_OFFSET},
+    constants::{ADDR_NO_RANDOMIZE, NO_PARENT},
+    error::OSResult,
     file::{check_file_exists, BackEndFile, FdManager},
     loaders::parse_user_app,
-    memory::{new_memory_set_for_task, phys_to_virt, MemorySet, PTEFlags, Tid, VirtAddr},
+    memory::{
+        new_memory_set_for_task, phys_to_virt, register_memory_set, MemorySet, PTEFlags, Tid,
+        UserLayout, VirtAddr,
+    },
     signal::{global_register_signals, SignalHandlers, SignalReceivers, SignalUserContext},
     trap::TrapContext,
 };
@@ -49,6 +53,10 @@ pub struct TaskControlBlock {
     pub fd_manager: Arc<Mutex<FdManager>>,
     /// 任务的运行时间信息
     pub time: Mutex<TimeStat>,
+    /// 任务的调度信息，包括 nice 值和 vruntime
+    pub sched: Mutex<SchedEntity>,
+    /// 在 wait4 中等待这个任务的子进程退出的任务
+    pub child_exit_queue: WaitQueue,
     /// 任务的状态信息
     pub inner: Arc<Mutex<TaskControlBlockInner>>,
 }
@@ -64,9 +72,11 @@ pub struct TaskControlBlockInner {
     /// - 又因为它可能会在父进程结束时被修改为初始进程，所以是可变的。
     pub ppid: usize,
     /// 用户堆的堆顶。
-    /// 用户堆和用户栈共用空间，反向增长，即从 USER_STACK_OFFSET 开始往上增加。
+    /// 用户堆从 vm.layout.heap_start 开始往上增加，由若干个名为 `USER_HEAP_AREA_NAME` 的地址段组成。
     /// 本来不应该由内存记录的，但 brk() 系统调用要用
     pub user_heap_top: usize,
+    /// 由 sys_personality 设置的执行域。目前只关心其中的 ADDR_NO_RANDOMIZE，fork 和 exec 时保留
+    pub personality: u32,
     /// 任务执行状态
     pub task_status: TaskStatus,
     /// 上下文信息，用于切换，包含所有必要的寄存器
@@ -78,11 +88,15 @@ pub struct TaskControlBlockInner {
     pub children: Vec<Arc<TaskControlBlock>>,
     /// sys_exit 时输出的值
     pub exit_code: i32,
+    /// 被信号杀死时为信号编号，正常退出时为 0
+    pub term_signal: usize,
     /// 子线程初始化时，存放 tid 的地址。当且仅当创建时包含 CLONE_CHILD_SETTID 才非0
     pub set_child_tid: usize,
     /// 子线程初始化时，将这个地址清空；子线程退出时，触发这里的 futex。
     /// 在创建时包含 CLONE_CHILD_SETTID 时才非0，但可以被 sys_set_tid_address 修改
     pub clear_child_tid: usize,
+    /// 由 sys_set_robust_list 设置的 robust futex 链表头的用户地址。线程退出时会遍历这个链表
+    pub robust_list: usize,
     /// 处理信号时，保存的之前的用户线程的上下文信息
     trap_cx_before_signal: Option<TrapContext>,
     /// 保存信息时，处理函数是否设置了 SIGINFO 选项
@@ -119,6 +133,8 @@ impl TaskControlBlock {
         }
         // 新建页表，包含内核段
         let mut vm = new_memory_set_for_task().unwrap();
+        vm.layout = UserLayout::new(true);
+        let user_heap_top = vm.layout.heap_start;
         // 找到用户名对应的文件，将用户地址段信息插入页表和 VmArea
         parse_user_app(app_dir, app_name, &mut vm, args)
             .map(|(user_entry, user_stack)| {
@@ -134,6 +150,8 @@ impl TaskControlBlock {
                 let signal_handlers = Arc::new(Mutex::new(SignalHandlers::new()));
                 let signal_receivers = Arc::new(Mutex::new(SignalReceivers::new()));
                 global_register_signals(tid.0, signal_receivers.clone());
+                let vm = Arc::new(Mutex::new(vm));
+                register_memory_set(&vm);
                 //println!("tid = {}", tid.0);
                 TaskControlBlock {
                     kernel_stack: kernel_stack,
@@ -142,20 +160,25 @@ impl TaskControlBlock {
                     send_sigchld_when_exit: true,
                     signal_handlers: signal_handlers,
                     signal_receivers: signal_receivers,
-                    vm: Arc::new(Mutex::new(vm)),
+                    vm: vm,
                     fd_manager: Arc::new(Mutex::new(FdManager::new(0))), // 初始 umask 设为 0
                     time: Mutex::new(TimeStat::new(tid_raw)),
+                    sched: Mutex::new(SchedEntity::new()),
+                    child_exit_queue: WaitQueue::new(),
                     inner: Arc::new(Mutex::new(TaskControlBlockInner {
                         dir: String::from(app_dir),
                         ppid: ppid,
-                        user_heap_top: USER_STACK_OFFSET,
+                        user_heap_top: user_heap_top,
+                        personality: 0,
                         task_cx: TaskContext::goto_restore(stack_top),
                         task_status: TaskStatus::Ready,
                         parent: None,
                         children: Vec::new(),
                         exit_code: 0,
+                        term_signal: 0,
                         set_child_tid: 0,
                         clear_child_tid: 0,
+                        robust_list: 0,
                         trap_cx_before_signal: None,
                         signal_set_siginfo: false,
                     })),
@@ -189,7 +212,9 @@ impl TaskControlBlock {
         let vm = if flags.contains(CloneFlags::CLONE_VM) {
             self.vm.clone()
         } else {
-            Arc::new(Mutex::new(self.vm.lock().copy_as_fork().unwrap()))
+            let vm = Arc::new(Mutex::new(self.vm.lock().copy_as_fork().unwrap()));
+            register_memory_set(&vm);
+            vm
         };
         // 是否共享文件描述符
         let fd_manager = if flags.contains(CloneFlags::CLONE_FILES) {
@@ -292,16 +317,20 @@ impl TaskControlBlock {
             vm: vm,
             fd_manager: fd_manager,
             time: Mutex::new(TimeStat::new(tid_raw)), // fork 出的任务不继承时间
+            sched: Mutex::new(SchedEntity::new_from_parent(&self.sched.lock())), // 但继承 nice 值
+            child_exit_queue: WaitQueue::new(),
             inner: {
                 Arc::new(Mutex::new(TaskControlBlockInner {
                     dir: dir,
                     ppid: ppid,
-                    user_heap_top: USER_STACK_OFFSET,
+                    user_heap_top: inner.user_heap_top, // 子进程的地址空间和父进程相同，堆顶也相同
+                    personality: inner.personality,
                     task_cx: TaskContext::goto_restore(stack_top),
                     task_status: TaskStatus::Ready,
                     parent: Some(Arc::downgrade(self)),
                     children: Vec::new(),
                     exit_code: 0,
+                    term_signal: 0,
                     set_child_tid: if flags.contains(CloneFlags::CLONE_CHILD_SETTID) {
                         ctid
                     } else {
@@ -312,6 +341,7 @@ impl TaskControlBlock {
                     } else {
                         0
                     },
+                    robust_list: 0,
                     trap_cx_before_signal: None,
                     signal_set_siginfo: false,
                 }))
@@ -342,10 +372,15 @@ impl TaskControlBlock {
         if !check_file_exists(inner.dir.as_str(), app_name) {
             return false;
         }
+        // 原来的 robust list 在新的地址空间中已经没有意义了
+        inner.robust_list = 0;
+        // 清空 MemorySet 中用户段的地址，并重新生成地址空间的布局
+        let mut vm = self.vm.lock();
+        vm.clear_user_pages_and_save_kernel();
+        vm.layout = UserLayout::new(inner.personality & ADDR_NO_RANDOMIZE == 0);
         // 清空用户堆
-        inner.user_heap_top = USER_STACK_OFFSET;
-        // 清空 MemorySet 中用户段的地址
-        self.vm.lock().clear_user_pages_and_save_kernel();
+        inner.user_heap_top = vm.layout.heap_start;
+        drop(vm);
         // 清空信号模块
         self.signal_handlers.lock().clear();
         self.signal_receivers.lock().clear();
@@ -409,11 +444,10 @@ impl TaskControlBlock {
         flags: PTEFlags,
         backend: Option<BackEndFile>,
         anywhere: bool,
-    ) -> Option<usize> {
+    ) -> OSResult<usize> {
         self.vm
             .lock()
             .push_with_backend(start, end, flags, backend, anywhere)
-            .ok()
     }
     /// 取消一段内存地址映射
     pub fn munmap(&self, start: VirtAddr, end: VirtAddr) -> bool {
@@ -442,6 +476,35 @@ impl TaskControlBlock {
         let inner = self.inner.lock();
         inner.task_status
     }
+    /// 任务切出后调用。如果它仍处于 Blocking 状态，则改为 Blocked 并返回 true，之后只有等待队列能唤醒它；
+    /// 否则说明它在切出之前就已经被唤醒了，返回 false
+    pub fn block_if_not_woken(&self) -> bool {
+        let mut inner = self.inner.lock();
+        if inner.task_status == TaskStatus::Blocking {
+            inner.task_status = TaskStatus::Blocked;
+            true
+        } else {
+            false
+        }
+    }
+    /// 唤醒阻塞中的任务，返回是否需要由调用者把它放回就绪队列。
+    ///
+    /// 如果任务还没有切出(Blocking)，只需要把它改回 Ready，`run_tasks` 切出它之后会自己放回队列；
+    /// 如果已经切出(Blocked)，则需要调用者放回。其他状态说明它已经被唤醒过了，不需要处理
+    pub fn wake_up(&self) -> bool {
+        let mut inner = self.inner.lock();
+        match inner.task_status {
+            TaskStatus::Blocking => {
+                inner.task_status = TaskStatus::Ready;
+                false
+            }
+            TaskStatus::Blocked => {
+                inner.task_status = TaskStatus::Ready;
+                true
+            }
+            _ => false,
+        }
+    }
     /// 读取任务上下文
     pub fn get_task_cx_ptr(&self) -> *const TaskContext {
         let inner = self.inner.lock();
@@ -469,22 +532,29 @@ impl TaskControlBlock {
         self.inner.lock().user_heap_top
     }
     /// 重新设置堆顶地址，如成功则返回设置后的堆顶地址，否则保持不变，并返回之前的堆顶地址。
-    /// 新地址需要在用户栈内，并且不能碰到目前的栈
+    /// 新地址不能低于堆的起始地址，扩展后的堆不能碰到其他地址段，和用户栈之间还要留出保护间隔
     pub fn set_user_heap_top(&self, new_top: usize) -> usize {
-        let user_sp = unsafe { (*self.kernel_stack.get_first_context()).get_sp() };
         let mut inner = self.inner.lock();
-        if new_top >= USER_STACK_OFFSET && new_top < user_sp {
-            inner.user_heap_top = new_top;
-            new_top
-        } else {
-            inner.user_heap_top
+        match self.vm.lock().set_brk(inner.user_heap_top, new_top) {
+            Ok(()) => {
+                inner.user_heap_top = new_top;
+                new_top
+            }
+            Err(_) => inner.user_heap_top,
         }
     }
-    /// 如果当前进程已是运行结束，则获取其 exit_code，否则返回 None
+    /// 记录任务被哪个信号杀死
+    pub fn set_term_signal(&self, signum: usize) {
+        self.inner.lock().term_signal = signum;
+    }
+    /// 如果当前进程已是运行结束，则获取 wait 返回给父进程的状态，否则返回 None
+    ///
+    /// 正常退出时 exit_code 在第 8~15 位，被信号杀死时低 7 位是信号编号
     pub fn get_code_if_exit(&self) -> Option<i32> {
         let inner = self.inner.try_lock()?;
         match inner.task_status {
-            TaskStatus::Zombie => Some(inner.exit_code),
+            TaskStatus::Zombie if inner.term_signal != 0 => Some(inner.term_signal as i32 & 0x7f),
+            TaskStatus::Zombie => Some((inner.exit_code & 0xff) << 8),
             _ => None,
         }
     }
@@ -492,6 +562,14 @@ impl TaskControlBlock {
     pub fn set_tid_address(&self, addr: usize) {
         self.inner.lock().clear_child_tid = addr;
     }
+    /// 设置 robust futex 链表头的地址
+    pub fn set_robust_list(&self, head: usize) {
+        self.inner.lock().robust_list = head;
+    }
+    /// 获取 robust futex 链表头的地址
+    pub fn get_robust_list(&self) -> usize {
+        self.inner.lock().robust_list
+    }
     /// 如果当前没有在信号处理函数中，则保存当前用户上下文信息，返回true。
     /// 否则不保存并返回false
     pub fn save_trap_cx_if_not_handling_signals(&self) -> bool {
@@ -546,6 +624,10 @@ pub enum TaskStatus {
     Ready,
     /// 正在被一个核执行
     Running,
+    /// 已经进入等待队列，但还没有切换出去
+    Blocking,
+    /// 在等待队列中阻塞，不在任何就绪队列里
+    Blocked,
     /// 进程在用户端已退出，但内核端还有些工作要处理，例如把它的所有子进程交给初始进程
     Dying,
     /// 僵尸进程，已退出，但其资源还在等待回收
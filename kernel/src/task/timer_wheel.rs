@@ -0,0 +1,128 @@
+//! 时间轮，用于等待队列的超时唤醒
+//!
+//! 时间轮有 `TIMER_WHEEL_SLOTS` 个槽，每个槽对应 `TIMER_WHEEL_TICK_US` 微秒。
+//! 到期时间为 t 的定时器放在第 `(t / TIMER_WHEEL_TICK_US) % TIMER_WHEEL_SLOTS` 个槽里，
+//! 每次检查时只需要扫描从上次检查到现在经过的槽。到期时间超过一圈的定时器会留在槽里，等时间轮转到它那一圈时再触发。
+//!
+//! 内核目前没有打开时钟中断，所以时间轮在 `run_tasks` 每次调度之前检查；
+//! 核空闲时，`wait_for_task` 会把时钟设为最近的到期时间，以便按时醒来
+
+use super::{wake_blocked_task, TaskControlBlock};
+use crate::constants::{TIMER_WHEEL_SLOTS, TIMER_WHEEL_TICK_US};
+use alloc::{sync::Arc, vec::Vec};
+use lock::Mutex;
+use timer::get_time_us;
+
+/// 定时器的编号，用于取消定时器
+#[derive(Clone, Copy)]
+pub struct TimerId {
+    /// 定时器所在的槽
+    slot: usize,
+    /// 全局唯一的序号
+    id: usize,
+}
+
+/// 一个定时器，到期时唤醒对应的任务
+struct TimerEntry {
+    id: usize,
+    expire_us: usize,
+    task: Arc<TaskControlBlock>,
+}
+
+/// 时间轮
+struct TimerWheel {
+    slots: Vec<Vec<TimerEntry>>,
+    /// 上次检查到的槽的绝对序号，即上次检查时的 `时间 / TIMER_WHEEL_TICK_US`
+    current_tick: usize,
+    /// 下一个定时器的序号
+    next_id: usize,
+}
+
+impl TimerWheel {
+    fn new() -> Self {
+        Self {
+            slots: (0..TIMER_WHEEL_SLOTS).map(|_| Vec::new()).collect(),
+            current_tick: get_time_us() / TIMER_WHEEL_TICK_US,
+            next_id: 0,
+        }
+    }
+    /// 添加一个定时器。已经过期的定时器会放在当前槽里，下次检查时触发
+    fn add(&mut self, expire_us: usize, task: Arc<TaskControlBlock>) -> TimerId {
+        let slot = (expire_us / TIMER_WHEEL_TICK_US).max(self.current_tick) % TIMER_WHEEL_SLOTS;
+        let id = self.next_id;
+        self.next_id += 1;
+        self.slots[slot].push(TimerEntry {
+            id,
+            expire_us,
+            task,
+        });
+        TimerId { slot, id }
+    }
+    /// 取消一个定时器。如果它还没有触发，则返回 true
+    fn cancel(&mut self, timer: TimerId) -> bool {
+        let slot = &mut self.slots[timer.slot];
+        if let Some(pos) = slot.iter().position(|entry| entry.id == timer.id) {
+            slot.swap_remove(pos);
+            true
+        } else {
+            false
+        }
+    }
+    /// 取出所有在 now_us 之前到期的定时器对应的任务
+    fn expire(&mut self, now_us: usize) -> Vec<Arc<TaskControlBlock>> {
+        let now_tick = now_us / TIMER_WHEEL_TICK_US;
+        // 距离上次检查超过一圈时，每个槽也只需要扫描一次
+        let end_tick = now_tick.min(self.current_tick + TIMER_WHEEL_SLOTS - 1);
+        let mut expired = Vec::new();
+        for tick in self.current_tick..=end_tick {
+            let slot = &mut self.slots[tick % TIMER_WHEEL_SLOTS];
+            let mut i = 0;
+            while i < slot.len() {
+                if slot[i].expire_us <= now_us {
+                    expired.push(slot.swap_remove(i).task);
+                } else {
+                    i += 1;
+                }
+            }
+        }
+        self.current_tick = self.current_tick.max(now_tick);
+        expired
+    }
+    /// 最近的到期时间
+    fn next_expire(&self) -> Option<usize> {
+        self.slots
+            .iter()
+            .flat_map(|slot| slot.iter())
+            .map(|entry| entry.expire_us)
+            .min()
+    }
+}
+
+lazy_static::lazy_static! {
+    /// 全局的时间轮
+    static ref TIMER_WHEEL: Mutex<TimerWheel> = Mutex::new(TimerWheel::new());
+}
+
+/// 添加一个定时器，在系统时间到达 expire_us 时唤醒 task
+pub fn add_timer(expire_us: usize, task: Arc<TaskControlBlock>) -> TimerId {
+    TIMER_WHEEL.lock().add(expire_us, task)
+}
+
+/// 取消一个定时器。如果它还没有触发，则返回 true
+pub fn cancel_timer(timer: TimerId) -> bool {
+    TIMER_WHEEL.lock().cancel(timer)
+}
+
+/// 检查时间轮，唤醒所有定时器已到期的任务
+pub fn expire_timers() {
+    let expired = TIMER_WHEEL.lock().expire(get_time_us());
+    // 唤醒时会访问任务的锁和就绪队列，所以要在释放时间轮的锁之后进行
+    for task in expired {
+        wake_blocked_task(task);
+    }
+}
+
+/// 时间轮中最近的到期时间，单位为微秒。没有定时器时返回 None
+pub fn next_timer_expire_us() -> Option<usize> {
+    TIMER_WHEEL.lock().next_expire()
+}
@@ -0,0 +1,145 @@
+//! 等待队列
+//!
+//! 任务在等待某个事件(如 futex 被唤醒、管道中有数据、子进程退出、睡眠时间到达)时，会进入对应的 `WaitQueue`，
+//! 并且不再放回就绪队列，直到其他任务通过 `notify_one` / `notify_all` 唤醒它，或者等待超时。
+//!
+//! 为了不漏掉唤醒，任务会先进入等待队列并标记为 `TaskStatus::Blocking`，然后再检查一次等待的条件，最后才切换出去。
+//! 如果在这期间它已经被唤醒，它的状态会被改回 `Ready`，`run_tasks` 在它切出后就会直接把它放回就绪队列。
+//! 超时由时间轮 `timer_wheel.rs` 负责。
+//!
+//! 发送信号时也会唤醒目标任务。任务在阻塞前和被唤醒后都会检查是否有待处理的信号，有则不再等待，
+//! 由调用者返回 EINTR，回到用户态处理信号
+
+use super::{
+    add_timer, block_current_task, cancel_timer, get_current_task, push_task_to_scheduler,
+    TaskControlBlock, TaskStatus,
+};
+use alloc::{collections::VecDeque, sync::Arc};
+use lock::Mutex;
+use timer::get_time_us;
+
+/// 等待某个事件的任务队列
+pub struct WaitQueue {
+    queue: Mutex<VecDeque<Arc<TaskControlBlock>>>,
+}
+
+impl WaitQueue {
+    /// 新建一个空的等待队列
+    pub const fn new() -> Self {
+        Self {
+            queue: Mutex::new(VecDeque::new()),
+        }
+    }
+    /// 把任务放进等待队列，并标记为即将阻塞
+    fn prepare_to_wait(&self, task: &Arc<TaskControlBlock>) {
+        let mut queue = self.queue.lock();
+        queue.push_back(task.clone());
+        task.set_status(TaskStatus::Blocking);
+    }
+    /// 任务结束等待，重新开始运行。
+    /// 如果它已经不在队列中，说明它是被 notify 取出的，此时返回 true
+    fn finish_wait(&self, task: &Arc<TaskControlBlock>) -> bool {
+        let mut queue = self.queue.lock();
+        let notified = match queue.iter().position(|t| Arc::ptr_eq(t, task)) {
+            Some(pos) => {
+                queue.remove(pos);
+                false
+            }
+            None => true,
+        };
+        task.set_status(TaskStatus::Running);
+        notified
+    }
+    /// 阻塞当前任务，直到被 notify 唤醒，或者系统时间到达 expire_us(单位为微秒，None 表示不会超时)。
+    ///
+    /// 进入等待队列之后、切换出去之前，会调用 `should_block` 再检查一次等待的条件，
+    /// 如果它返回 false，则不阻塞，直接返回。
+    ///
+    /// 返回是否是被 notify 唤醒的。超时或者没有阻塞时返回 false
+    pub fn wait_timeout_if(
+        &self,
+        expire_us: Option<usize>,
+        should_block: impl FnOnce() -> bool,
+    ) -> bool {
+        let task = get_current_task().unwrap();
+        self.prepare_to_wait(&task);
+        if !should_block() || task.signal_receivers.lock().has_pending() {
+            self.finish_wait(&task);
+            return false;
+        }
+        let timer = expire_us.map(|expire_us| add_timer(expire_us, task.clone()));
+        block_current_task();
+        if let Some(timer) = timer {
+            cancel_timer(timer);
+        }
+        self.finish_wait(&task)
+    }
+    /// 阻塞当前任务，直到 `cond` 返回 Some，或者系统时间到达 expire_us(单位为微秒，None 表示不会超时)。
+    ///
+    /// `cond` 每次被唤醒后都会重新检查，它在返回 None 时不应该产生任何副作用。
+    /// 超时或者被信号打断时返回 None，调用者可以用 `signal_pending` 区分这两种情况
+    pub fn wait_until<T>(
+        &self,
+        expire_us: Option<usize>,
+        mut cond: impl FnMut() -> Option<T>,
+    ) -> Option<T> {
+        loop {
+            if let Some(ret) = cond() {
+                return Some(ret);
+            }
+            if expire_us.map_or(false, |expire_us| get_time_us() >= expire_us) || signal_pending() {
+                return None;
+            }
+            let mut ret = None;
+            self.wait_timeout_if(expire_us, || {
+                ret = cond();
+                ret.is_none()
+            });
+            if ret.is_some() {
+                return ret;
+            }
+        }
+    }
+    /// 唤醒最多 n 个等待中的任务，返回实际唤醒的个数
+    pub fn notify_n(&self, n: usize) -> usize {
+        let mut queue = self.queue.lock();
+        let count = n.min(queue.len());
+        let tasks: VecDeque<_> = queue.drain(..count).collect();
+        // 唤醒时会访问任务的锁和就绪队列，所以先释放等待队列的锁
+        drop(queue);
+        for task in tasks {
+            wake_blocked_task(task);
+        }
+        count
+    }
+    /// 唤醒一个等待中的任务，返回是否有任务被唤醒
+    pub fn notify_one(&self) -> bool {
+        self.notify_n(1) > 0
+    }
+    /// 唤醒所有等待中的任务，返回唤醒的个数
+    pub fn notify_all(&self) -> usize {
+        self.notify_n(usize::MAX)
+    }
+}
+
+/// 当前任务是否有待处理的信号。此时 `WaitQueue` 上的等待会提前结束
+pub fn signal_pending() -> bool {
+    get_current_task().map_or(false, |task| task.signal_receivers.lock().has_pending())
+}
+
+/// 唤醒一个正在阻塞的任务。如果它已经被切出，则把它放回就绪队列
+pub fn wake_blocked_task(task: Arc<TaskControlBlock>) {
+    if task.wake_up() {
+        push_task_to_scheduler(task);
+    }
+}
+
+/// 所有睡眠中的任务。没有任务会 notify 它，睡眠的任务只会通过超时醒来
+static SLEEP_QUEUE: WaitQueue = WaitQueue::new();
+
+/// 让当前任务睡眠，直到系统时间到达 expire_us(单位为微秒)。
+/// 如果被信号打断，则提前返回 false
+pub fn sleep_current_task_until(expire_us: usize) -> bool {
+    SLEEP_QUEUE.wait_until(Some(expire_us), || None::<()>);
+    get_time_us() >= expire_us
+}
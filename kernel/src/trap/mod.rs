@@ -25,6 +25,7 @@ use crate::{
     arch::get_cpu_id,
     constants::SIGNAL_RETURN_TRAP,
     memory::PTEFlags,
+    random,
     signal::{send_signal, SignalNo},
     syscall::syscall,
     task::{
@@ -183,6 +184,8 @@ pub fn user_trap_handler(cx: &mut TrapContext) -> &mut TrapContext {
                 get_cpu_id(),
                 cx.sepc
             );
+            // 时钟中断打断用户程序的位置是难以预测的，可以作为熵
+            random::add_entropy(cx.sepc as u64);
 
             // 之后需要判断如果是在内核态，则不切换任务
             set_timer(get_next_trigger());
@@ -109,20 +109,6 @@ pub trait File: Send + Sync + AsAny {
     fn get_status(&self) -> OpenFlags {
         OpenFlags::empty()
     }
-    /// 发送消息，当且仅当这个文件是 socket 时可用
-    fn sendto(&self, _buf: &[u8], _flags: i32, _dest_addr: usize) -> Option<usize> {
-        None
-    }
-    /// 收取消息，当且仅当这个文件是 socket 时可用
-    fn recvfrom(
-        &self,
-        _buf: &mut [u8],
-        _flags: i32,
-        _src_addr: usize,
-        _src_len: &mut u32,
-    ) -> Option<usize> {
-        None
-    }
 }
 
 /// `File` 需要满足 `AsAny` 的要求，即可以转化为 `Any` 类型，从而能够进行向下类型转换。
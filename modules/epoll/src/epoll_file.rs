@@ -6,7 +6,7 @@ use alloc::sync::Arc;
 use alloc::collections::{BTreeMap, BTreeSet};
 use alloc::vec::Vec;
 use syscall::ErrorNo;
-use task_trampoline::{get_file, suspend_current_task};
+use task_trampoline::{get_file, wait_for_file_event};
 use crate::{EpollEvent, EpollCtl, EpollEventType};
 
 /// 用作 epoll 的文件
@@ -101,10 +101,19 @@ impl EpollFile {
     }
 
     /// 实现 epoll_wait 系统调用，返回的第一个参数 0 表示超时，正数表示响应的事件个数，第二个参数表示响应后的 `epoll_events`
+    ///
+    /// expire_time 是以毫秒计的超时时间，为 usize::MAX 时表示不会超时
     pub fn epoll_wait(&self, expire_time: usize) -> Vec<EpollEvent> {
         let epoll_events = self.get_epoll_events();
         let mut ret_events: Vec<EpollEvent> = Vec::new();
-        loop {
+        let expire_us = if expire_time == usize::MAX {
+            None
+        } else {
+            Some(expire_time * 1000)
+        };
+        // 没有 fd 触发时暂时 block 住，直到有文件的状态发生变化再检查。超时则返回空数组
+        wait_for_file_event(expire_us, || {
+            ret_events.clear();
             // 已触发的 fd
             for req_fd in &epoll_events {
                 if let Some(file) = get_file(req_fd.data as usize) {
@@ -122,17 +131,9 @@ impl EpollFile {
                     });
                 }
             }
-            if !ret_events.is_empty() {
-                // 正常返回响应了事件的fd个数
-                return ret_events;
-            }
-            // 否则暂时 block 住
-            if timer::get_time_ms() > expire_time {
-                // 超时返回0
-                return ret_events;
-            }
-            suspend_current_task();
-        }
+            !ret_events.is_empty()
+        });
+        ret_events
     }
 }
 
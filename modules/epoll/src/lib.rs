@@ -136,6 +136,18 @@ mod tests {
         ) -> bool {
             true
         }
+
+        fn sleep_until(&self, expire_us: usize) -> bool {
+            true
+        }
+
+        fn wait_for_file_event(
+            &self,
+            expire_us: Option<usize>,
+            ready: &mut dyn FnMut() -> bool,
+        ) -> bool {
+            ready()
+        }
     }
 
     struct FakeFileInner {
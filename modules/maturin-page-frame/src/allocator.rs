@@ -1,18 +1,100 @@
 //! 全局只有一个的页帧分配器。
 //!
+//! 默认使用位图分配器，打开 `buddy` feature 时换成伙伴分配器。
+//! 如果 `PageFrameConfig::use_cpu_cache()` 为 true，单个页帧的分配和回收会先经过每个核的缓存，见 `cache.rs`
 
 use lock::Mutex;
 
+use super::buddy::for_each_block;
+use super::cache::{CpuCache, CPU_CACHE_COUNT};
 use super::defs::PageFrameConfig;
+use super::stat::{FrameStat, FRAME_ORDERS};
 use super::{PhantomData, Range, Vec};
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use bitmap_allocator::BitAllocCascade16;
 
 // 16M bit * (4K per page) = max 64G
 // 即最大可适用 64G 内存的分配
+#[cfg(not(feature = "buddy"))]
 type FrameAllocatorImpl = bitmap_allocator::BitAlloc16M;
-use bitmap_allocator::BitAlloc;
+// 2^22 个页 * (4K per page) = max 16G，各阶的位图一共需要 2^23 位
+#[cfg(feature = "buddy")]
+type FrameAllocatorImpl = super::buddy::BuddyAllocator<bitmap_allocator::BitAlloc16M, 23>;
 
 /// 分配器全局只有一个，用互斥锁保护
 static FRAME_ALLOCATOR: Mutex<FrameAllocatorImpl> = Mutex::new(FrameAllocatorImpl::DEFAULT);
+/// 每个核的页帧缓存
+static CPU_CACHES: [Mutex<CpuCache>; CPU_CACHE_COUNT] = {
+    #[allow(clippy::declare_interior_mutable_const)]
+    const EMPTY: Mutex<CpuCache> = Mutex::new(CpuCache::DEFAULT);
+    [EMPTY; CPU_CACHE_COUNT]
+};
+/// 加入分配器的页帧总数
+static TOTAL_FRAMES: AtomicUsize = AtomicUsize::new(0);
+
+/// 页帧分配器需要实现的操作，参数和返回值都是页帧编号
+pub trait FrameAlloc {
+    /// 一个空的分配器
+    const DEFAULT: Self;
+    /// 能管理的页帧编号上限
+    const CAP: usize;
+    /// 加入一段空闲页帧，编号都必须小于 `CAP`
+    fn insert(&mut self, range: Range<usize>);
+    /// 分配一个页帧
+    fn alloc(&mut self) -> Option<usize>;
+    /// 分配 count 个连续的页帧，起始编号按 2^align_log2 对齐
+    fn alloc_contiguous(&mut self, count: usize, align_log2: usize) -> Option<usize>;
+    /// 回收一个页帧
+    fn dealloc(&mut self, idx: usize);
+    /// 回收一段连续的页帧
+    fn dealloc_contiguous(&mut self, range: Range<usize>) {
+        for idx in range {
+            self.dealloc(idx)
+        }
+    }
+    /// 各阶的空闲块数，含义见 `FrameStat::free_blocks`
+    fn free_blocks(&self) -> [usize; FRAME_ORDERS];
+}
+
+// 只通过 FrameAlloc 使用位图，避免和 BitAlloc 中的同名方法混淆
+impl<T: bitmap_allocator::BitAlloc> FrameAlloc for BitAllocCascade16<T> {
+    const DEFAULT: Self = <Self as bitmap_allocator::BitAlloc>::DEFAULT;
+    const CAP: usize = <Self as bitmap_allocator::BitAlloc>::CAP;
+
+    fn insert(&mut self, range: Range<usize>) {
+        bitmap_allocator::BitAlloc::insert(self, range)
+    }
+
+    fn alloc(&mut self) -> Option<usize> {
+        bitmap_allocator::BitAlloc::alloc(self)
+    }
+
+    fn alloc_contiguous(&mut self, count: usize, align_log2: usize) -> Option<usize> {
+        bitmap_allocator::BitAlloc::alloc_contiguous(self, count, align_log2)
+    }
+
+    fn dealloc(&mut self, idx: usize) {
+        bitmap_allocator::BitAlloc::dealloc(self, idx)
+    }
+
+    /// 需要扫描所有空闲页帧，只应在调试时使用
+    fn free_blocks(&self) -> [usize; FRAME_ORDERS] {
+        let mut blocks = [0; FRAME_ORDERS];
+        let mut key = 0;
+        while let Some(start) = bitmap_allocator::BitAlloc::next(self, key) {
+            let mut end = start + 1;
+            while end < <Self as bitmap_allocator::BitAlloc>::CAP
+                && bitmap_allocator::BitAlloc::test(self, end)
+            {
+                end += 1;
+            }
+            for_each_block(start..end, FRAME_ORDERS - 1, |_, order| blocks[order] += 1);
+            key = end;
+        }
+        blocks
+    }
+}
 
 /// 使用特定 Config 定义的页帧分配器
 #[derive(Debug)]
@@ -24,28 +106,52 @@ impl<Config: PageFrameConfig> FrameAllocatorWrapper<Config> {
     /// 指定页帧分配器对应的物理地址区间，
     /// 必须至少在启动时调用一次。
     ///
+    /// 超出分配器容量的页帧会被忽略，返回被忽略的页帧数
+    ///
     /// # Safety
     /// 除了该分配器分配出的页帧之外， `regions` 指定的区间不应以其他任何方式读写。
     /// 这一点需要调用者来保证，因而是 unsafe 的。
-    pub unsafe fn init(regions: Vec<Range<usize>>) {
+    pub unsafe fn init(regions: Vec<Range<usize>>) -> usize {
         let mut ba = FRAME_ALLOCATOR.lock();
+        let mut ignored = 0;
         for region in regions {
             let frame_start = Config::phys_addr_to_frame_idx(region.start);
             let frame_end = Config::phys_addr_to_frame_idx(region.end - 1) + 1;
             assert!(frame_start < frame_end, "illegal range for frame allocator");
-            ba.insert(frame_start..frame_end);
+            let end = frame_end.min(FrameAllocatorImpl::CAP);
+            if frame_start < end {
+                ba.insert(frame_start..end);
+                TOTAL_FRAMES.fetch_add(end - frame_start, Ordering::Relaxed);
+            }
+            ignored += frame_end - end.max(frame_start);
         }
         //println!("frame allocator init end.");
+        ignored
     }
 
-    /// 分配一个页帧
-    pub unsafe fn alloc_frame() -> Option<usize> {
-        let ret = FRAME_ALLOCATOR
-            .lock()
-            .alloc()
-            .map(Config::frame_idx_to_phys_addr);
-        //println!("Allocate frame: {:x?}", ret);
-        ret
+    /// 当前核的页帧缓存。不使用缓存或当前核没有缓存时返回 None
+    fn cpu_cache() -> Option<&'static Mutex<CpuCache>> {
+        if Config::use_cpu_cache() {
+            CPU_CACHES.get(Config::cpu_id())
+        } else {
+            None
+        }
+    }
+
+    /// 分配一个页帧。cold 为 true 时优先取不在 CPU 缓存中的页帧
+    pub unsafe fn alloc_frame(cold: bool) -> Option<usize> {
+        match Self::cpu_cache() {
+            Some(cache) => {
+                let ret = cache.lock().alloc(&FRAME_ALLOCATOR, cold);
+                // 全局分配器中已经没有页帧了，但其他核的缓存中可能还有
+                ret.or_else(|| {
+                    Self::drain_cpu_caches();
+                    FRAME_ALLOCATOR.lock().alloc()
+                })
+            }
+            None => FRAME_ALLOCATOR.lock().alloc(),
+        }
+        .map(Config::frame_idx_to_phys_addr)
     }
 
     /// 分配一段连续的页帧，并要求偏移为 PAGE_SIZE * (1 << align_log2)
@@ -53,10 +159,16 @@ impl<Config: PageFrameConfig> FrameAllocatorWrapper<Config> {
     /// 在 OS 中一般的类型只要求虚拟地址连续，但是一些设备，如 virt 块设备的 buffer 需要物理地址连续，
     /// 所以需要有这个函数
     pub unsafe fn alloc_frame_contiguous(frame_count: usize, align_log2: usize) -> Option<usize> {
-        let ret = FRAME_ALLOCATOR
+        let mut ret = FRAME_ALLOCATOR
             .lock()
-            .alloc_contiguous(frame_count, align_log2)
-            .map(Config::frame_idx_to_phys_addr);
+            .alloc_contiguous(frame_count, align_log2);
+        if ret.is_none() && Config::use_cpu_cache() {
+            // 缓存中的页帧可能正好挡住了连续的空闲段
+            Self::drain_cpu_caches();
+            ret = FRAME_ALLOCATOR
+                .lock()
+                .alloc_contiguous(frame_count, align_log2);
+        }
         /*
         println!(
             "Allocate {} frames with alignment {}: {:x?}",
@@ -65,24 +177,51 @@ impl<Config: PageFrameConfig> FrameAllocatorWrapper<Config> {
             ret
         );
         */
-        ret
+        ret.map(Config::frame_idx_to_phys_addr)
     }
 
-    /// 回收一个页帧
-    pub unsafe fn dealloc_frame(target: usize) {
+    /// 回收一个页帧。cold 为 true 时表示这个页帧最近没有被 CPU 访问过
+    pub unsafe fn dealloc_frame(target: usize, cold: bool) {
         //println!("Deallocate frame: {:x}", target);
-        FRAME_ALLOCATOR
-            .lock()
-            .dealloc(Config::phys_addr_to_frame_idx(target))
+        let idx = Config::phys_addr_to_frame_idx(target);
+        match Self::cpu_cache() {
+            Some(cache) => cache.lock().dealloc(&FRAME_ALLOCATOR, idx, cold),
+            None => FRAME_ALLOCATOR.lock().dealloc(idx),
+        }
     }
 
     /// 回收一段连续的页帧
     pub unsafe fn dealloc_frame_contiguous(target: usize, frame_count: usize) {
         //println!("Deallocate {} frames: {:x}", frame_count, target);
         let start_idx = Config::phys_addr_to_frame_idx(target);
-        let mut ba = FRAME_ALLOCATOR.lock();
-        for i in start_idx..start_idx + frame_count {
-            ba.dealloc(i)
+        FRAME_ALLOCATOR
+            .lock()
+            .dealloc_contiguous(start_idx..start_idx + frame_count);
+    }
+
+    /// 把所有核的缓存中的页帧都还给全局分配器
+    pub fn drain_cpu_caches() {
+        // 每次只持有一个核的缓存的锁，避免和其他核互相等待
+        for cache in CPU_CACHES.iter() {
+            cache.lock().drain(&FRAME_ALLOCATOR);
+        }
+    }
+
+    /// 页帧分配器的使用情况
+    pub fn stat() -> FrameStat {
+        let cached_frames: usize = CPU_CACHES.iter().map(|cache| cache.lock().len()).sum();
+        let free_blocks = FRAME_ALLOCATOR.lock().free_blocks();
+        let free_frames = free_blocks
+            .iter()
+            .enumerate()
+            .map(|(order, count)| count << order)
+            .sum::<usize>()
+            + cached_frames;
+        FrameStat {
+            total_frames: TOTAL_FRAMES.load(Ordering::Relaxed),
+            free_frames,
+            cached_frames,
+            free_blocks,
         }
     }
 }
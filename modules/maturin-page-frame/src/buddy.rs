@@ -0,0 +1,147 @@
+//! 伙伴分配器。
+//!
+//! 大小为 2^k 个页、且按自身大小对齐的空闲块称为 k 阶块。分配时从不小于所需阶数的空闲块中取出一块，
+//! 把多余的部分逐级对半拆开放回去；回收时如果一个块的"伙伴"(和它组成上一阶块的另一半)也空闲，
+//! 就合并成上一阶的块，直到不能再合并。
+//!
+//! 每一阶的空闲块用一段位图记录，所有阶的位图拼在同一个 `BitAlloc` 里：
+//! 第 k 阶的第 i 块对应第 `2N - 2N/2^k + i` 位，其中 N 是分配器能管理的页帧数。
+//! `BitAlloc` 本身是多层的线段树，找下一个空闲块只需 O(log N)，所以分配和回收都是 O(log N) 的。
+//!
+//! 为了和位图分配器的行为保持一致，分配时总是选地址最低的可用块，而不是阶数最小的块
+
+use super::allocator::FrameAlloc;
+use super::stat::FRAME_ORDERS;
+use super::Range;
+use bitmap_allocator::BitAlloc;
+
+/// 伙伴分配器，最大的块为 `ORDERS - 1` 阶，能管理 `2^(ORDERS - 1)` 个页帧。
+///
+/// `B` 是存放各阶位图的数据结构，它的容量至少要有 `2^ORDERS - 1` 位
+pub struct BuddyAllocator<B: BitAlloc, const ORDERS: usize> {
+    free: B,
+}
+
+impl<B: BitAlloc, const ORDERS: usize> BuddyAllocator<B, ORDERS> {
+    /// 能管理的页帧数
+    pub const FRAMES: usize = 1 << (ORDERS - 1);
+
+    /// 第 order 阶的第 block 块在位图中的位置
+    fn bit(order: usize, block: usize) -> usize {
+        2 * Self::FRAMES - ((2 * Self::FRAMES) >> order) + block
+    }
+
+    /// 取出第 order 阶中地址最低的空闲块，返回它的起始页帧编号
+    fn first_free(&self, order: usize) -> Option<usize> {
+        let start = Self::bit(order, 0);
+        let end = start + (Self::FRAMES >> order);
+        self.free
+            .next(start)
+            .filter(|&bit| bit < end)
+            .map(|bit| (bit - start) << order)
+    }
+
+    /// 页帧 idx 开头的 order 阶块是否空闲
+    fn is_free(&self, idx: usize, order: usize) -> bool {
+        self.free.test(Self::bit(order, idx >> order))
+    }
+
+    /// 回收一个 order 阶块，并尽量和伙伴合并
+    fn free_block(&mut self, mut idx: usize, mut order: usize) {
+        while order + 1 < ORDERS {
+            let buddy = idx ^ (1 << order);
+            if !self.is_free(buddy, order) {
+                break;
+            }
+            let bit = Self::bit(order, buddy >> order);
+            self.free.remove(bit..bit + 1);
+            idx &= !(1 << order);
+            order += 1;
+        }
+        self.free.dealloc(Self::bit(order, idx >> order));
+    }
+
+    /// 回收一段页帧，它可以不按 2 的幂对齐
+    fn free_range(&mut self, range: Range<usize>) {
+        for_each_block(range, ORDERS - 1, |idx, order| self.free_block(idx, order));
+    }
+}
+
+impl<B: BitAlloc, const ORDERS: usize> FrameAlloc for BuddyAllocator<B, ORDERS> {
+    const DEFAULT: Self = {
+        assert!(
+            ORDERS <= FRAME_ORDERS,
+            "too many orders for buddy allocator"
+        );
+        assert!(
+            2 * Self::FRAMES - 1 <= B::CAP,
+            "bitmap too small for buddy allocator"
+        );
+        Self { free: B::DEFAULT }
+    };
+    const CAP: usize = Self::FRAMES;
+
+    fn insert(&mut self, range: Range<usize>) {
+        assert!(range.end <= Self::FRAMES, "frame out of buddy allocator");
+        self.free_range(range);
+    }
+
+    fn alloc(&mut self) -> Option<usize> {
+        self.alloc_contiguous(1, 0)
+    }
+
+    fn alloc_contiguous(&mut self, count: usize, align_log2: usize) -> Option<usize> {
+        let order = (count.next_power_of_two().trailing_zeros() as usize).max(align_log2);
+        // 在所有不小于 order 阶的空闲块中找地址最低的
+        let (idx, found) = (order..ORDERS)
+            .filter_map(|found| self.first_free(found).map(|idx| (idx, found)))
+            .min()?;
+        let bit = Self::bit(found, idx >> found);
+        self.free.remove(bit..bit + 1);
+        // 拆开的块中，后一半逐级放回去
+        for sub in (order..found).rev() {
+            self.free.dealloc(Self::bit(sub, (idx >> sub) + 1));
+        }
+        // 请求的页数不是 2 的幂时，多出的部分也放回去
+        self.free_range(idx + count..idx + (1 << order));
+        Some(idx)
+    }
+
+    fn dealloc(&mut self, idx: usize) {
+        self.free_block(idx, 0);
+    }
+
+    fn dealloc_contiguous(&mut self, range: Range<usize>) {
+        self.free_range(range);
+    }
+
+    fn free_blocks(&self) -> [usize; FRAME_ORDERS] {
+        let mut blocks = [0; FRAME_ORDERS];
+        for (order, count) in blocks.iter_mut().enumerate().take(ORDERS) {
+            let start = Self::bit(order, 0);
+            let end = start + (Self::FRAMES >> order);
+            let mut bit = start;
+            while let Some(next) = self.free.next(bit).filter(|&next| next < end) {
+                *count += 1;
+                bit = next + 1;
+            }
+        }
+        blocks
+    }
+}
+
+/// 把一段页帧拆成若干个按自身大小对齐的块，块最大为 max_order 阶。对每一块调用 f(起始编号, 阶数)
+pub fn for_each_block(range: Range<usize>, max_order: usize, mut f: impl FnMut(usize, usize)) {
+    let Range { mut start, end } = range;
+    while start < end {
+        let align = if start == 0 {
+            max_order
+        } else {
+            start.trailing_zeros() as usize
+        };
+        let fit = (usize::BITS - 1 - (end - start).leading_zeros()) as usize;
+        let order = align.min(fit).min(max_order);
+        f(start, order);
+        start += 1 << order;
+    }
+}
@@ -0,0 +1,117 @@
+//! 每个核的页帧缓存。
+//!
+//! 每个核都有一个小的页帧缓存，单个页帧的分配和回收先在缓存中进行，
+//! 缓存空了或满了时才批量地从全局分配器中取或还回去，这样大部分时候不需要获取全局分配器的锁。
+//!
+//! 缓存是一个双端队列，头部是"热"页帧，尾部是"冷"页帧。刚回收的页帧大概率还在 CPU 的缓存里，
+//! 所以默认放在头部，下次分配时优先使用；不会马上被 CPU 访问的页帧(如给设备做 DMA 的缓冲区)则从尾部取还。
+//! 缓存满时，从尾部把最冷的一批还给全局分配器
+
+use super::allocator::FrameAlloc;
+use lock::Mutex;
+
+/// 最多有多少个核可以有自己的缓存。编号更大的核直接使用全局分配器
+pub const CPU_CACHE_COUNT: usize = 16;
+/// 每个核的缓存最多存放的页帧数
+pub const CPU_CACHE_CAPACITY: usize = 64;
+/// 缓存空了或满了时，一次从全局分配器中取出或还回去的页帧数
+pub const CPU_CACHE_BATCH: usize = 16;
+
+/// 一个核的页帧缓存，存的是页帧编号
+pub struct CpuCache {
+    frames: [usize; CPU_CACHE_CAPACITY],
+    /// 头部在 frames 中的下标
+    head: usize,
+    len: usize,
+}
+
+impl CpuCache {
+    /// 空的缓存
+    pub const DEFAULT: Self = Self {
+        frames: [0; CPU_CACHE_CAPACITY],
+        head: 0,
+        len: 0,
+    };
+
+    /// 缓存中的页帧数
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    fn push_front(&mut self, idx: usize) {
+        self.head = (self.head + CPU_CACHE_CAPACITY - 1) % CPU_CACHE_CAPACITY;
+        self.frames[self.head] = idx;
+        self.len += 1;
+    }
+
+    fn push_back(&mut self, idx: usize) {
+        self.frames[(self.head + self.len) % CPU_CACHE_CAPACITY] = idx;
+        self.len += 1;
+    }
+
+    fn pop_front(&mut self) -> Option<usize> {
+        if self.len == 0 {
+            return None;
+        }
+        let idx = self.frames[self.head];
+        self.head = (self.head + 1) % CPU_CACHE_CAPACITY;
+        self.len -= 1;
+        Some(idx)
+    }
+
+    fn pop_back(&mut self) -> Option<usize> {
+        if self.len == 0 {
+            return None;
+        }
+        self.len -= 1;
+        Some(self.frames[(self.head + self.len) % CPU_CACHE_CAPACITY])
+    }
+
+    /// 分配一个页帧。cold 为 true 时取最冷的页帧。
+    ///
+    /// 缓存空时从 global 中取一批，global 也没有页帧时返回 None
+    pub fn alloc(&mut self, global: &Mutex<impl FrameAlloc>, cold: bool) -> Option<usize> {
+        if self.len == 0 {
+            let mut global = global.lock();
+            // 取出的页帧按地址从低到高排列，下次优先使用地址低的
+            while self.len < CPU_CACHE_BATCH {
+                match global.alloc() {
+                    Some(idx) => self.push_back(idx),
+                    None => break,
+                }
+            }
+        }
+        if cold {
+            self.pop_back()
+        } else {
+            self.pop_front()
+        }
+    }
+
+    /// 回收一个页帧。cold 为 true 时放到尾部，否则放到头部。
+    ///
+    /// 缓存满时先把尾部的一批还给 global
+    pub fn dealloc(&mut self, global: &Mutex<impl FrameAlloc>, idx: usize, cold: bool) {
+        if self.len == CPU_CACHE_CAPACITY {
+            let mut global = global.lock();
+            for _ in 0..CPU_CACHE_BATCH {
+                if let Some(idx) = self.pop_back() {
+                    global.dealloc(idx);
+                }
+            }
+        }
+        if cold {
+            self.push_back(idx)
+        } else {
+            self.push_front(idx)
+        }
+    }
+
+    /// 把缓存中的页帧全部还给 global
+    pub fn drain(&mut self, global: &Mutex<impl FrameAlloc>) {
+        let mut global = global.lock();
+        while let Some(idx) = self.pop_back() {
+            global.dealloc(idx);
+        }
+    }
+}
@@ -9,6 +9,9 @@ const PAGE_SIZE: usize = 0x1000; // 4 KB
 /// 即：内核可以通过访问 (x + PHYS_VIRT_OFFSET) 拿到物理地址 x 处的值
 const PHYS_VIRT_OFFSET: usize = 0xFFFF_FFFF_0000_0000;
 
+use super::allocator::FrameAllocatorWrapper;
+use super::stat::FrameStat;
+
 pub trait PageFrameConfig {
     /// 页面大小
     fn get_page_size() -> usize {
@@ -28,4 +31,22 @@ pub trait PageFrameConfig {
     fn frame_idx_to_phys_addr(idx: usize) -> usize {
         idx * PAGE_SIZE
     }
+
+    /// 当前核的编号，用于选择每个核自己的页帧缓存
+    fn cpu_id() -> usize {
+        0
+    }
+
+    /// 是否使用每个核的页帧缓存。默认不使用，此时分配结果和只有一个全局分配器时相同
+    fn use_cpu_cache() -> bool {
+        false
+    }
+
+    /// 页帧分配器的使用情况，包括空闲页帧数和各阶空闲块数。一般不需要重写
+    fn stat() -> FrameStat
+    where
+        Self: Sized,
+    {
+        FrameAllocatorWrapper::<Self>::stat()
+    }
 }
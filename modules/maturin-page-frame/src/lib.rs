@@ -46,6 +46,15 @@
 //! assert!(MyFrame::new().is_some());
 //! 
 //! ```
+//! 
+//! ## 可选功能
+//! 
+//! - 默认使用位图分配器。打开 `buddy` feature 后换成伙伴分配器，分配连续页帧只需 O(log n)，
+//!   但最多只能管理 16G 物理内存，更高地址的页帧会被忽略；
+//! - 在 `PageFrameConfig` 中让 `use_cpu_cache()` 返回 true，并用 `cpu_id()` 返回当前核的编号，
+//!   则每个核会有自己的页帧缓存，单个页帧的分配和回收大部分时候不需要获取全局的锁；
+//! - `PageFrameConfig::stat()` 返回空闲页帧数和各阶空闲块数，可以用来查看碎片化程度。
+//! 
 //! ## 测试
 //! 
 //! 本项目来自 `https://github.com/scPointer/maturin`。
@@ -84,8 +93,13 @@ extern crate bitmap_allocator;
 extern crate lock;
 
 mod allocator;
+#[cfg_attr(not(feature = "buddy"), allow(dead_code))]
+mod buddy;
+mod cache;
 mod defs;
+mod stat;
 pub use defs::PageFrameConfig;
+pub use stat::{FrameStat, FRAME_ORDERS};
 #[cfg(test)]
 mod tests;
 
@@ -104,15 +118,29 @@ pub struct Frame<Config: PageFrameConfig> {
 impl<Config: PageFrameConfig> Frame<Config> {
     /// 初始化页帧分配器。
     ///
-    /// 必须在启动时只由一个核调用且全局仅调用一次
-    pub unsafe fn init(regions: Vec<Range<usize>>) {
+    /// 必须在启动时只由一个核调用且全局仅调用一次。
+    /// 返回超出分配器容量而被忽略的页帧数
+    pub unsafe fn init(regions: Vec<Range<usize>>) -> usize {
         Allocator::<Config>::init(regions)
     }
 
     /// 获取并保存一个页帧
     pub fn new() -> Option<Self> {
         unsafe {
-            Allocator::<Config>::alloc_frame().map(|start_paddr| Self {
+            Allocator::<Config>::alloc_frame(false).map(|start_paddr| Self {
+                start_paddr,
+                frame_count: 1,
+                _marker: PhantomData,
+            })
+        }
+    }
+
+    /// 获取一个"冷"页帧，即最近最久没有被回收过的页帧。
+    ///
+    /// 适用于不会马上被 CPU 访问的页帧，如给设备做 DMA 的缓冲区。不使用每个核的缓存时和 `new` 相同
+    pub fn new_cold() -> Option<Self> {
+        unsafe {
+            Allocator::<Config>::alloc_frame(true).map(|start_paddr| Self {
                 start_paddr,
                 frame_count: 1,
                 _marker: PhantomData,
@@ -120,6 +148,20 @@ impl<Config: PageFrameConfig> Frame<Config> {
         }
     }
 
+    /// 回收一个最近没有被 CPU 访问过的页帧，它在每个核的缓存中会被排在最后使用。
+    ///
+    /// 一般的页帧在 Drop 时回收，视为刚被访问过
+    pub fn free_cold(self) {
+        let this = ManuallyDrop::new(self);
+        unsafe {
+            if this.frame_count == 1 {
+                Allocator::<Config>::dealloc_frame(this.start_paddr, true)
+            } else {
+                Allocator::<Config>::dealloc_frame_contiguous(this.start_paddr, this.frame_count)
+            }
+        }
+    }
+
     /// 获取并保存一段连续的页为一个页帧
     pub fn new_contiguous(frame_count: usize, align_log2: usize) -> Option<Self> {
         unsafe {
@@ -133,6 +175,32 @@ impl<Config: PageFrameConfig> Frame<Config> {
         }
     }
 
+    /// 获取一段连续且按自身大小对齐的页为一个页帧，用于映射大页。
+    ///
+    /// `frame_count` 必须是 2 的幂，如 Sv39 下 2MiB 大页对应 512 个页
+    pub fn new_huge(frame_count: usize) -> Option<Self> {
+        assert!(frame_count.is_power_of_two());
+        let frame = Self::new_contiguous(frame_count, frame_count.trailing_zeros() as usize)?;
+        // 分配器只保证页帧编号对齐，如果 Config 中的编号与物理地址间有偏移，还需要检查物理地址
+        if frame.start_paddr % frame.size() == 0 {
+            Some(frame)
+        } else {
+            None
+        }
+    }
+
+    /// 把一个由多个页组成的页帧拆成单个页的页帧，它们各自在 Drop 时单独回收
+    pub fn split(self) -> Vec<Self> {
+        let this = ManuallyDrop::new(self);
+        (0..this.frame_count)
+            .map(|i| Self {
+                start_paddr: this.start_paddr + i * Config::get_page_size(),
+                frame_count: 1,
+                _marker: PhantomData,
+            })
+            .collect()
+    }
+
     /// 从物理地址直接构造一个页帧
     ///
     /// 它在 Drop 时不会回收这个页帧，因为它不是从 new 构造的，也就没有分配过
@@ -190,7 +258,7 @@ impl<Config: PageFrameConfig> Drop for Frame<Config> {
         unsafe {
             if self.frame_count == 1 {
                 //println!("dealloc page {:x}", self.start_paddr);
-                Allocator::<Config>::dealloc_frame(self.start_paddr)
+                Allocator::<Config>::dealloc_frame(self.start_paddr, false)
             } else {
                 Allocator::<Config>::dealloc_frame_contiguous(self.start_paddr, self.frame_count)
             }
@@ -0,0 +1,50 @@
+//! 页帧分配器的统计信息。
+//!
+
+/// 统计空闲块时区分的阶数。k 阶块有 2^k 个页，最大的 22 阶块在 4K 页下是 16G
+pub const FRAME_ORDERS: usize = 23;
+
+/// 页帧分配器的使用情况，由 `PageFrameConfig::stat()` 获取
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FrameStat {
+    /// 加入分配器的页帧总数
+    pub total_frames: usize,
+    /// 空闲的页帧数，包括每个核的缓存中的页帧
+    pub free_frames: usize,
+    /// 每个核的缓存中的页帧数
+    pub cached_frames: usize,
+    /// 全局分配器中各阶的空闲块数，不包括每个核的缓存。
+    ///
+    /// 这里的 k 阶块指按 2^k 个页对齐的、最大的连续空闲段，超过最大阶数的部分按最大阶拆开计数
+    pub free_blocks: [usize; FRAME_ORDERS],
+}
+
+impl FrameStat {
+    /// 全局分配器中能满足的最大连续分配的阶数。没有空闲页帧时返回 None
+    pub fn largest_free_order(&self) -> Option<usize> {
+        self.free_blocks.iter().rposition(|&count| count > 0)
+    }
+
+    /// 全局分配器的空闲页帧中，不能用于 order 阶分配的比例，以千分之一为单位。
+    ///
+    /// 即碎片化程度：0 表示所有空闲页帧都在足够大的块中，1000 表示没有任何一块能满足这个分配
+    pub fn fragmentation(&self, order: usize) -> usize {
+        let free: usize = self
+            .free_blocks
+            .iter()
+            .enumerate()
+            .map(|(k, count)| count << k)
+            .sum();
+        if free == 0 {
+            return 0;
+        }
+        let usable: usize = self
+            .free_blocks
+            .iter()
+            .enumerate()
+            .skip(order)
+            .map(|(k, count)| count << k)
+            .sum();
+        (free - usable) * 1000 / free
+    }
+}
@@ -25,4 +25,181 @@ fn test_default_config() {
     frames.clear();
     let frame = MyFrame::new().unwrap();
     assert!(frame.start_paddr() < 0x8000);
+    drop(frame);
+
+    let huge = MyFrame::new_huge(2).unwrap();
+    assert_eq!(huge.start_paddr() % (2 * PAGE_SIZE), 0);
+    assert_eq!(huge.size(), 2 * PAGE_SIZE);
+    let start = huge.start_paddr();
+    let mut pieces = huge.split();
+    assert_eq!(pieces.len(), 2);
+    assert_eq!(pieces[1].start_paddr(), start + PAGE_SIZE);
+    assert!(MyFrame::new_huge(4).is_none());
+    // 拆开后的页帧单独回收
+    pieces.pop();
+    let frame = MyFrame::new().unwrap();
+    assert_eq!(frame.start_paddr(), start + PAGE_SIZE);
+
+    // 还有 frame 和 pieces[0] 两个页帧在使用
+    let stat = DefaultConfig::stat();
+    assert_eq!(stat.total_frames, 5);
+    assert_eq!(stat.free_frames, 3);
+    assert_eq!(stat.cached_frames, 0);
+    assert_eq!(stat.largest_free_order(), Some(1));
+}
+
+#[test]
+fn test_init_beyond_capacity() {
+    struct DefaultConfig;
+    impl PageFrameConfig for DefaultConfig {}
+    type MyFrame = Frame<DefaultConfig>;
+
+    // 这段页帧完全超出了分配器的容量，会被忽略，不影响同时运行的其他测试
+    let ignored = unsafe { MyFrame::init(vec![1 << 40..(1 << 40) + 0x4000]) };
+    assert_eq!(ignored, 4);
+}
+
+#[test]
+fn test_frame_stat() {
+    let mut stat = FrameStat {
+        total_frames: 16,
+        free_frames: 0,
+        cached_frames: 0,
+        free_blocks: [0; FRAME_ORDERS],
+    };
+    assert_eq!(stat.largest_free_order(), None);
+    assert_eq!(stat.fragmentation(0), 0);
+    // 4 个 0 阶块和 1 个 2 阶块
+    stat.free_blocks[0] = 4;
+    stat.free_blocks[2] = 1;
+    assert_eq!(stat.largest_free_order(), Some(2));
+    assert_eq!(stat.fragmentation(0), 0);
+    assert_eq!(stat.fragmentation(1), 500);
+    assert_eq!(stat.fragmentation(3), 1000);
+}
+
+#[test]
+fn test_for_each_block() {
+    let mut blocks = vec![];
+    buddy::for_each_block(3..13, 10, |idx, order| blocks.push((idx, order)));
+    assert_eq!(blocks, vec![(3, 0), (4, 2), (8, 2), (12, 0)]);
+    blocks.clear();
+    buddy::for_each_block(0..16, 2, |idx, order| blocks.push((idx, order)));
+    assert_eq!(blocks, vec![(0, 2), (4, 2), (8, 2), (12, 2)]);
+}
+
+/// 最大 11 阶，能管理 2048 个页帧
+type TestBuddy = buddy::BuddyAllocator<bitmap_allocator::BitAlloc4K, 12>;
+
+#[test]
+fn test_buddy_allocator() {
+    use allocator::FrameAlloc;
+    let mut buddy = TestBuddy::DEFAULT;
+    buddy.insert(3..13);
+    buddy.insert(64..128);
+    let blocks = buddy.free_blocks();
+    assert_eq!(&blocks[..7], &[2, 0, 2, 0, 0, 0, 1]);
+
+    // 总是分配地址最低的块
+    assert_eq!(buddy.alloc(), Some(3));
+    assert_eq!(buddy.alloc(), Some(4));
+    // 4..8 被拆开，5 在 0 阶，6..8 在 1 阶
+    assert_eq!(&buddy.free_blocks()[..3], &[2, 1, 1]);
+    // 对齐的连续分配
+    assert_eq!(buddy.alloc_contiguous(4, 0), Some(8));
+    assert_eq!(buddy.alloc_contiguous(2, 4), Some(64));
+    // 不是 2 的幂时，多出的部分放回去
+    assert_eq!(buddy.alloc_contiguous(3, 0), Some(68));
+    assert_eq!(buddy.alloc(), Some(5));
+    assert_eq!(buddy.alloc(), Some(6));
+    assert_eq!(buddy.alloc(), Some(7));
+    assert_eq!(buddy.alloc(), Some(12));
+    assert_eq!(buddy.alloc(), Some(66));
+    assert_eq!(buddy.alloc(), Some(67));
+    assert_eq!(buddy.alloc(), Some(71));
+    assert_eq!(buddy.alloc_contiguous(64, 0), None);
+
+    // 回收后和伙伴合并
+    buddy.dealloc_contiguous(64..66);
+    buddy.dealloc_contiguous(68..71);
+    for idx in [71, 66, 67] {
+        buddy.dealloc(idx);
+    }
+    assert_eq!(buddy.free_blocks()[6], 1);
+    assert_eq!(buddy.alloc_contiguous(64, 6), Some(64));
+    buddy.dealloc_contiguous(64..128);
+    for idx in [3, 4, 5, 6, 7, 12] {
+        buddy.dealloc(idx);
+    }
+    buddy.dealloc_contiguous(8..12);
+    assert_eq!(&buddy.free_blocks()[..7], &[2, 0, 2, 0, 0, 0, 1]);
+}
+
+#[test]
+fn test_bitmap_free_blocks() {
+    use allocator::FrameAlloc;
+    let mut bitmap = <bitmap_allocator::BitAlloc4K as bitmap_allocator::BitAlloc>::DEFAULT;
+    FrameAlloc::insert(&mut bitmap, 3..13);
+    FrameAlloc::insert(&mut bitmap, 64..128);
+    // 和伙伴分配器统计出的结果相同
+    let mut buddy = TestBuddy::DEFAULT;
+    buddy.insert(3..13);
+    buddy.insert(64..128);
+    assert_eq!(bitmap.free_blocks(), buddy.free_blocks());
+    assert_eq!(FrameAlloc::alloc(&mut bitmap), Some(3));
+    assert_eq!(buddy.alloc(), Some(3));
+    assert_eq!(FrameAlloc::alloc_contiguous(&mut bitmap, 4, 5), Some(64));
+    assert_eq!(buddy.alloc_contiguous(4, 5), Some(64));
+    assert_eq!(bitmap.free_blocks(), buddy.free_blocks());
+}
+
+#[test]
+fn test_cpu_cache() {
+    use allocator::FrameAlloc;
+    use cache::{CpuCache, CPU_CACHE_BATCH, CPU_CACHE_CAPACITY};
+    let global = lock::Mutex::new(TestBuddy::DEFAULT);
+    global.lock().insert(0..1024);
+    let mut cache = CpuCache::DEFAULT;
+
+    // 缓存空时从全局分配器中取一批
+    assert_eq!(cache.alloc(&global, false), Some(0));
+    assert_eq!(cache.len(), CPU_CACHE_BATCH - 1);
+    // 冷页帧从尾部取
+    assert_eq!(cache.alloc(&global, true), Some(CPU_CACHE_BATCH - 1));
+    // 热页帧回收后马上被再次使用
+    cache.dealloc(&global, 0, false);
+    assert_eq!(cache.alloc(&global, false), Some(0));
+    // 冷页帧回收后最后才被使用
+    cache.dealloc(&global, 0, true);
+    assert_eq!(cache.alloc(&global, false), Some(1));
+    assert_eq!(cache.alloc(&global, true), Some(0));
+
+    // 缓存满时，尾部的一批还给全局分配器
+    let before = cache.len();
+    let frames: Vec<usize> = (0..CPU_CACHE_CAPACITY + 1)
+        .map(|_| global.lock().alloc().unwrap())
+        .collect();
+    for &idx in &frames {
+        cache.dealloc(&global, idx, false);
+    }
+    assert_eq!(
+        cache.len(),
+        before + CPU_CACHE_CAPACITY + 1 - CPU_CACHE_BATCH
+    );
+    let free_in_global: usize = global
+        .lock()
+        .free_blocks()
+        .iter()
+        .enumerate()
+        .map(|(order, count)| count << order)
+        .sum();
+    // 0、1 和 CPU_CACHE_BATCH - 1 三个页帧还在使用
+    assert_eq!(free_in_global + cache.len(), 1024 - 3);
+    // 全部还回去后又合并成一整块
+    cache.drain(&global);
+    assert_eq!(cache.len(), 0);
+    for idx in [0, 1, CPU_CACHE_BATCH - 1] {
+        global.lock().dealloc(idx);
+    }
+    assert_eq!(global.lock().free_blocks()[10], 1);
 }
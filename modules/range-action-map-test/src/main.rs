@@ -17,7 +17,7 @@ use resource::Frame;
 mod pteflags;
 pub use pteflags::*;
 
-use range_action_map::{ArgsType, IdentType, RangeActionMap, Segment};
+use range_action_map::{ArgsType, IdentType, RangeActionMap, Segment, LOWER_LIMIT};
 
 /// 测试用的下层区间结构。
 /// 
@@ -79,6 +79,15 @@ impl Segment for Seg {
     fn modify(&mut self, new_flag: IdentType, _args: ArgsType) {
         self.flags = new_flag.into();
     }
+    /// 权限相同的相邻区间可以合并
+    fn merge(&mut self, mut right: Self, _args: ArgsType) -> Result<(), Self> {
+        if self.end != right.start || self.flags != right.flags {
+            return Err(right);
+        }
+        self.frames.append(&mut right.frames);
+        self.end = right.end;
+        Ok(())
+    }
 }
 
 /// find 接口测试
@@ -115,6 +124,41 @@ pub fn test_get_flag_at(ram: &mut RangeActionMap<Seg>, pos: usize) -> PTEFlags {
     ram.find(pos).unwrap().flags
 }
 
+/// 逐个遍历区间来寻找空位，作为 `find_free_area` 的参照
+pub fn find_free_area_by_walk(ram: &RangeActionMap<Seg>, hint: usize, len: usize) -> Option<usize> {
+    let mut last_seg_end = hint.max(LOWER_LIMIT);
+    for seg in ram.iter() {
+        if last_seg_end + len <= seg.start {
+            return Some(last_seg_end);
+        }
+        last_seg_end = last_seg_end.max(seg.end);
+    }
+    last_seg_end.checked_add(len).map(|_| last_seg_end)
+}
+
+/// 检查区间树中的区间互不相交，且每个区间的页帧数和长度一致
+pub fn check_segments(ram: &RangeActionMap<Seg>) {
+    let mut last_end = 0;
+    for seg in ram.iter() {
+        assert!(last_end <= seg.start && seg.start < seg.end);
+        assert_eq!(seg.frames.len(), seg.end - seg.start);
+        last_end = seg.end;
+    }
+}
+
+/// 测试用的伪随机数，保证每次测试的操作序列相同
+pub struct Lcg(pub u64);
+
+impl Lcg {
+    pub fn below(&mut self, bound: usize) -> usize {
+        self.0 = self
+            .0
+            .wrapping_mul(6364136223846793005)
+            .wrapping_add(1442695040888963407);
+        (self.0 >> 33) as usize % bound
+    }
+}
+
 fn main() {}
 
 #[test]
@@ -194,3 +238,120 @@ fn test_seg() {
     assert_eq!(rrseg.flags, PTE_RXU());
     assert_eq!(rrseg.start, 55);
 }
+
+#[test]
+/// 对 take 接口的测试，模拟 mremap 移动和扩展区间
+fn test_take() {
+    let mut ram = RangeActionMap::<Seg>::new(ArgsType::default());
+    test_mmap_fixed(&mut ram, 0x3000, 0x7000, PTE_RU());
+    // 跨过区间末尾，或者不在任何区间内时取不出来，区间树也不变
+    assert!(ram.take(0x6000, 0x8000).is_none());
+    assert!(ram.take(0x1000, 0x2000).is_none());
+    assert!(test_find(&mut ram, 0x6fff));
+    // 从中间取出一段，两边留在区间树中
+    let mut seg = ram.take(0x4000, 0x5000).unwrap();
+    assert_eq!(seg.start, 0x4000);
+    assert_eq!(seg.end, 0x5000);
+    assert!(test_find(&mut ram, 0x3fff));
+    assert!(!test_find(&mut ram, 0x4000));
+    assert!(!test_find(&mut ram, 0x4fff));
+    assert!(test_find(&mut ram, 0x5000));
+    // 移动到空位上再放回去
+    assert_eq!(ram.find_free_area(0x8000, 0x1000), Some(0x8000));
+    seg.start = 0x8000;
+    seg.end = 0x9000;
+    ram.mmap_fixed(0x8000, 0x9000, || seg);
+    assert_eq!(test_get_flag_at(&mut ram, 0x8fff), PTE_RU());
+    // 取出整个区间
+    let seg = ram.take(0x5000, 0x7000).unwrap();
+    assert_eq!(seg.start, 0x5000);
+    assert_eq!(seg.end, 0x7000);
+    assert!(!test_find(&mut ram, 0x5000));
+    assert_eq!(ram.iter().count(), 2);
+}
+
+#[test]
+/// 对 mmap / mprotect 后合并相邻区间的测试
+fn test_merge() {
+    let mut ram = RangeActionMap::<Seg>::new(ArgsType::default());
+    // 权限相同的相邻区间合并成一个
+    test_mmap_fixed(&mut ram, 0x3000, 0x4000, PTE_RU());
+    test_mmap_fixed(&mut ram, 0x4000, 0x5000, PTE_RU());
+    assert_eq!(ram.iter().count(), 1);
+    assert_eq!(ram.find(0x3000).unwrap().end, 0x5000);
+    // 权限不同时不合并
+    assert_eq!(
+        ram.mmap_anywhere(0x5000, 0x1000, |start| Seg::new(
+            start,
+            start + 0x1000,
+            PTE_RWU()
+        )),
+        Some(0x5000)
+    );
+    assert_eq!(ram.iter().count(), 2);
+    // mprotect 成相同的权限后合并
+    ram.mprotect(0x5000, 0x6000, PTE_RU().bits());
+    assert_eq!(ram.iter().count(), 1);
+    assert_eq!(ram.find(0x5fff).unwrap().start, 0x3000);
+    // 从中间修改再改回来，拆出的三段会重新合并
+    ram.mprotect(0x4000, 0x4800, PTE_RWU().bits());
+    assert_eq!(ram.iter().count(), 3);
+    assert_eq!(test_get_flag_at(&mut ram, 0x47ff), PTE_RWU());
+    ram.mprotect(0x3800, 0x5000, PTE_RU().bits());
+    assert_eq!(ram.iter().count(), 1);
+    check_segments(&ram);
+    // 不相邻的区间不合并
+    ram.unmap(0x4000, 0x4001);
+    test_mmap_fixed(&mut ram, 0x6001, 0x7000, PTE_RU());
+    assert_eq!(ram.iter().count(), 3);
+    // 取出后再放回原处，也会和两边合并
+    let seg = ram.take(0x3000, 0x4000).unwrap();
+    assert!(ram.find(0x3000).is_none());
+    ram.mmap_fixed(0x3000, 0x4000, || seg);
+    test_mmap_fixed(&mut ram, 0x4000, 0x4001, PTE_RU());
+    assert_eq!(ram.iter().count(), 2);
+    assert_eq!(ram.find(0x3000).unwrap().end, 0x6000);
+    check_segments(&ram);
+}
+
+#[test]
+/// 随机地修改区间树，检查 find_free_area 和逐个遍历区间的结果相同
+fn test_find_free_area() {
+    let mut ram = RangeActionMap::<Seg>::new(ArgsType::default());
+    let mut rng = Lcg(2022);
+    let flags = [PTE_RU(), PTE_RWU(), PTE_RXU()];
+    for _ in 0..2000 {
+        let start = LOWER_LIMIT + rng.below(0x400);
+        let len = 1 + rng.below(0x40);
+        let flag = flags[rng.below(flags.len())];
+        match rng.below(5) {
+            0 => test_mmap_fixed(&mut ram, start, start + len, flag),
+            1 => test_mmap_anywhere(&mut ram, start, len, flag),
+            2 => ram.unmap(start, start + len),
+            3 => ram.mprotect(start, start + len, flag.bits()),
+            _ => {
+                if let Some(seg) = ram.find(start) {
+                    let (seg_start, seg_end) = (seg.start, seg.end);
+                    let end = (start + len).min(seg_end);
+                    let seg = ram.take(start, end).unwrap();
+                    assert_eq!((seg.start, seg.end), (start, end));
+                    // 一半放回原处，一半丢掉
+                    if rng.below(2) == 0 {
+                        ram.mmap_fixed(start, end, || seg);
+                    }
+                    assert!(seg_start <= start);
+                }
+            }
+        }
+        check_segments(&ram);
+        for _ in 0..4 {
+            let hint = rng.below(0x500);
+            let len = 1 + rng.below(0x80);
+            assert_eq!(
+                ram.find_free_area(hint, len),
+                find_free_area_by_walk(&ram, hint, len)
+            );
+        }
+        assert_eq!(ram.find_free_area(0, usize::MAX), None);
+    }
+}
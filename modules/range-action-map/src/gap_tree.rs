@@ -0,0 +1,247 @@
+//! 记录区间之间空位的平衡树。
+//!
+//! 每个空位是一个左闭右开区间，树按空位左端点排序，是一棵 AVL 树。
+//! 每个节点额外记录子树中最长的空位长度，这样查找"左端点不小于 from 且长度不小于 len 的第一个空位"时，
+//! 可以跳过所有最长空位都不够长的子树，只需要 O(log n) 的时间
+
+use super::external::Box;
+use core::cmp::Ordering;
+
+type Link = Option<Box<Node>>;
+
+struct Node {
+    /// 空位左端点
+    start: usize,
+    /// 空位右端点
+    end: usize,
+    /// 子树中最长的空位长度
+    max_len: usize,
+    /// 子树高度，叶子为 1
+    height: usize,
+    left: Link,
+    right: Link,
+}
+
+impl Node {
+    fn new(start: usize, end: usize) -> Box<Self> {
+        Box::new(Self {
+            start,
+            end,
+            max_len: end - start,
+            height: 1,
+            left: None,
+            right: None,
+        })
+    }
+    /// 子节点改变后，重新计算高度和最长空位
+    fn update(&mut self) {
+        self.height = height(&self.left).max(height(&self.right)) + 1;
+        self.max_len = (self.end - self.start)
+            .max(max_len(&self.left))
+            .max(max_len(&self.right));
+    }
+}
+
+fn height(link: &Link) -> usize {
+    link.as_ref().map_or(0, |node| node.height)
+}
+
+fn max_len(link: &Link) -> usize {
+    link.as_ref().map_or(0, |node| node.max_len)
+}
+
+/// 右旋，node 的左子节点成为新的根
+fn rotate_right(mut node: Box<Node>) -> Box<Node> {
+    let mut left = node.left.take().unwrap();
+    node.left = left.right.take();
+    node.update();
+    left.right = Some(node);
+    left.update();
+    left
+}
+
+/// 左旋，node 的右子节点成为新的根
+fn rotate_left(mut node: Box<Node>) -> Box<Node> {
+    let mut right = node.right.take().unwrap();
+    node.right = right.left.take();
+    node.update();
+    right.left = Some(node);
+    right.update();
+    right
+}
+
+/// 更新 node 的信息，并在左右子树高度差超过 1 时旋转，返回新的根
+fn balance(mut node: Box<Node>) -> Box<Node> {
+    node.update();
+    let (lh, rh) = (height(&node.left), height(&node.right));
+    if lh > rh + 1 {
+        let left = node.left.take().unwrap();
+        node.left = Some(if height(&left.left) < height(&left.right) {
+            rotate_left(left)
+        } else {
+            left
+        });
+        rotate_right(node)
+    } else if rh > lh + 1 {
+        let right = node.right.take().unwrap();
+        node.right = Some(if height(&right.right) < height(&right.left) {
+            rotate_right(right)
+        } else {
+            right
+        });
+        rotate_left(node)
+    } else {
+        node
+    }
+}
+
+fn insert(link: Link, start: usize, end: usize) -> Box<Node> {
+    match link {
+        None => Node::new(start, end),
+        Some(mut node) => {
+            match start.cmp(&node.start) {
+                Ordering::Less => node.left = Some(insert(node.left.take(), start, end)),
+                Ordering::Greater => node.right = Some(insert(node.right.take(), start, end)),
+                Ordering::Equal => node.end = end,
+            }
+            balance(node)
+        }
+    }
+}
+
+/// 取出子树中左端点最小的节点，返回剩下的子树和这个节点
+fn remove_min(mut node: Box<Node>) -> (Link, Box<Node>) {
+    match node.left.take() {
+        None => (node.right.take(), node),
+        Some(left) => {
+            let (rest, min) = remove_min(left);
+            node.left = rest;
+            (Some(balance(node)), min)
+        }
+    }
+}
+
+/// 删除左端点为 start 的空位，返回剩下的子树和被删除空位的右端点
+fn remove(link: Link, start: usize) -> (Link, Option<usize>) {
+    let mut node = match link {
+        Some(node) => node,
+        None => return (None, None),
+    };
+    match start.cmp(&node.start) {
+        Ordering::Less => {
+            let (rest, end) = remove(node.left.take(), start);
+            node.left = rest;
+            (Some(balance(node)), end)
+        }
+        Ordering::Greater => {
+            let (rest, end) = remove(node.right.take(), start);
+            node.right = rest;
+            (Some(balance(node)), end)
+        }
+        Ordering::Equal => {
+            let rest = match (node.left.take(), node.right.take()) {
+                (left, None) => left,
+                (None, right) => right,
+                (left, Some(right)) => {
+                    let (rest, mut min) = remove_min(right);
+                    min.left = left;
+                    min.right = rest;
+                    Some(balance(min))
+                }
+            };
+            (rest, Some(node.end))
+        }
+    }
+}
+
+/// 子树中左端点不小于 from 且长度不小于 len 的第一个空位
+fn first_fit(link: &Link, from: usize, len: usize) -> Option<&Node> {
+    let node = link.as_deref()?;
+    if node.max_len < len {
+        return None;
+    }
+    if node.start < from {
+        // 左子树中的空位都在 from 之前
+        return first_fit(&node.right, from, len);
+    }
+    first_fit(&node.left, from, len)
+        .or(if node.end - node.start >= len {
+            Some(node)
+        } else {
+            None
+        })
+        .or_else(|| first_fit(&node.right, from, len))
+}
+
+/// 区间之间的空位。空位之间互不相交也不相邻
+pub struct GapTree {
+    root: Link,
+}
+
+impl GapTree {
+    /// 只有 `[start, end)` 一个空位
+    pub fn new(start: usize, end: usize) -> Self {
+        let mut tree = Self { root: None };
+        tree.insert(start, end);
+        tree
+    }
+    fn insert(&mut self, start: usize, end: usize) {
+        if start < end {
+            self.root = Some(insert(self.root.take(), start, end));
+        }
+    }
+    fn remove(&mut self, start: usize) -> Option<usize> {
+        let (rest, end) = remove(self.root.take(), start);
+        self.root = rest;
+        end
+    }
+    /// 包含 pos 的空位
+    pub fn containing(&self, pos: usize) -> Option<(usize, usize)> {
+        let mut link = &self.root;
+        let mut found = None;
+        while let Some(node) = link {
+            if node.start <= pos {
+                found = Some((node.start, node.end));
+                link = &node.right;
+            } else {
+                link = &node.left;
+            }
+        }
+        found.filter(|&(_, end)| pos < end)
+    }
+    /// 左端点不小于 from 且长度不小于 len 的第一个空位的左端点
+    pub fn first_fit(&self, from: usize, len: usize) -> Option<usize> {
+        first_fit(&self.root, from, len).map(|node| node.start)
+    }
+    /// `[start, end)` 被区间占用。它必须完整地落在一个空位中
+    pub fn occupy(&mut self, start: usize, end: usize) {
+        if start >= end {
+            return;
+        }
+        let (gap_start, gap_end) = self
+            .containing(start)
+            .filter(|&(_, gap_end)| end <= gap_end)
+            .expect("occupied range is not free");
+        self.remove(gap_start);
+        self.insert(gap_start, start);
+        self.insert(end, gap_end);
+    }
+    /// `[start, end)` 不再被占用，和两边相邻的空位合并
+    pub fn release(&mut self, mut start: usize, mut end: usize) {
+        if start >= end {
+            return;
+        }
+        if let Some((gap_start, _)) = start
+            .checked_sub(1)
+            .and_then(|pos| self.containing(pos))
+            .filter(|&(_, gap_end)| gap_end == start)
+        {
+            self.remove(gap_start);
+            start = gap_start;
+        }
+        if let Some(gap_end) = self.remove(end) {
+            end = gap_end;
+        }
+        self.insert(start, end);
+    }
+}
@@ -41,6 +41,7 @@
 //! 
 //! 还提供以下接口：
 //! - `find(pos: usize)`：查询一个点是否在某个区间在，如果在，返回它的引用。
+//! - `take(start, end)`：把落在同一个区间内的 `[start, end)` 拆出来并从区间树中取出，但不删除它。
 //! - `.iter()` `.iter_mut()`：迭代器支持。
 //! - `impl Debug`：可以用 Debug 属性输出所有区间信息(需要用户提供的底层 `SegmentType` 实现 `Debug`)。
 //! 
@@ -55,6 +56,9 @@
 //! - `split(pos)`：从`pos`位置把当前区间拆成两段区间(pos 参数为全局的绝对位置而非区间内位置)
 //! - `modify(new_flags)`：修改区间的属性
 //! 
+//! 可选地实现 `merge(right)`：把紧挨在右边的区间合并进来。实现后，`mmap_*` 和 `mprotect` 会自动合并属性相同的相邻区间，
+//! 避免长时间运行的进程中区间越来越碎。默认不合并
+//! 
 //! 一些约定：
 //! - 删除区间时需要用户底层结构完成返还页帧、修改页表等操作，但不需要 `Drop` 结构本身
 //! - 其中每个区间有一个 usize 大小的可修改的属性，在用于内存管理时，它一般是 PTEFlags
@@ -67,12 +71,14 @@
 
 #[cfg(feature = "std")]
 mod external {
+    pub use std::boxed::Box;
     pub use std::collections::btree_map::{Iter, IterMut};
     pub use std::{collections::BTreeMap, fmt::Debug, iter::Iterator, vec::Vec};
 }
 #[cfg(not(feature = "std"))]
 mod external {
     extern crate alloc;
+    pub use alloc::boxed::Box;
     pub use alloc::collections::btree_map::{Iter, IterMut};
     pub use alloc::collections::BTreeMap;
     pub use alloc::vec::Vec;
@@ -88,6 +94,8 @@ mod set;
 pub use set::{CutSet, DiffSet};
 mod range_area;
 pub use range_area::RangeArea;
+mod gap_tree;
+use gap_tree::GapTree;
 mod defs;
 pub use defs::{ArgsType, IdentType, LOWER_LIMIT, UPPER_LIMIT};
 
@@ -103,6 +111,7 @@ pub use defs::{ArgsType, IdentType, LOWER_LIMIT, UPPER_LIMIT};
 /// 
 /// 还提供以下接口：
 /// - `find(pos: usize)`：查询一个点是否在某个区间在，如果在，返回它的引用。
+/// - `take(start, end)`：把落在同一个区间内的 `[start, end)` 拆出来并从区间树中取出，但不删除它。
 /// - `.iter()` `.iter_mut()`：迭代器支持。
 /// - `impl Debug`：可以用 Debug 属性输出所有区间信息(需要用户提供的底层 `SegmentType` 实现 `Debug`)。
 /// 
@@ -111,6 +120,10 @@ pub use defs::{ArgsType, IdentType, LOWER_LIMIT, UPPER_LIMIT};
 /// - `split(pos)`：从`pos`位置把当前区间拆成两段区间(pos 参数为全局的绝对位置而非区间内位置)
 /// - `modify(new_flags)`：修改区间的属性
 /// 
+/// 如果实现了 `merge(right)`，`mmap_fixed` `mmap_anywhere` `mprotect` 之后会尝试合并操作范围两端及内部的相邻区间。
+/// 
+/// 区间之间的空位另外用一棵按最长空位增强的平衡树维护，`mmap_anywhere` 和 `find_free_area` 只需要 O(log n) 的时间。
+/// 
 /// **此外，`RangeActionMap`创建时要求传入一个 `ArgsType`，它实际上是一个 usize。这个值会在每次操作时传递给底层区间**
 /// 
 /// # Example
@@ -140,7 +153,12 @@ pub use defs::{ArgsType, IdentType, LOWER_LIMIT, UPPER_LIMIT};
 /// ```
 /// 
 pub struct RangeActionMap<SegmentType: Segment> {
-    pub segments: BTreeMap<usize, RangeArea<SegmentType>>,
+    /// 所有区间，按左端点排序。
+    ///
+    /// 区间之间的空位另外记录在 `gaps` 中，所以只能通过上层接口插入或删除其中的区间。外部可以用 `iter` 只读地访问它们
+    segments: BTreeMap<usize, RangeArea<SegmentType>>,
+    /// `[LOWER_LIMIT, UPPER_LIMIT)` 中没有被区间占用的空位
+    gaps: GapTree,
     args: ArgsType,
 }
 
@@ -154,11 +172,13 @@ impl<SegmentType: Segment> RangeActionMap<SegmentType> {
     pub fn new(args: ArgsType) -> Self {
         Self {
             segments: BTreeMap::new(),
+            gaps: GapTree::new(LOWER_LIMIT, UPPER_LIMIT),
             args,
         }
     }
     /// 插入一段区间，不检查
     fn insert_raw(&mut self, start: usize, end: usize, segment: SegmentType) {
+        self.gaps.occupy(start, end);
         self.segments.insert(
             start,
             RangeArea {
@@ -177,7 +197,7 @@ impl<SegmentType: Segment> RangeActionMap<SegmentType> {
         }
         None
     }
-    /// 通过迭代器访问每个区间的引用
+    /// 通过迭代器按左端点顺序访问每个区间的引用
     pub fn iter<'a>(&'a self) -> RangeActionMapIter<'a, SegmentType> {
         RangeActionMapIter {
             map_iter: self.segments.iter(),
@@ -228,6 +248,7 @@ impl<SegmentType: Segment> RangeActionMap<SegmentType> {
     ) -> Option<usize> {
         self.find_free_area(hint, len).map(|start| {
             self.insert_raw(start, start + len, f(start));
+            self.merge_range(start, start + len);
             start
         })
     }
@@ -251,6 +272,7 @@ impl<SegmentType: Segment> RangeActionMap<SegmentType> {
         // 需要 unmap 掉原本相交的区间
         self.unmap(start, end);
         self.insert_raw(start, end, f());
+        self.merge_range(start, end);
         Some(start)
     }
     /// 删除映射，空出 [start, end) 这段区间。
@@ -265,6 +287,7 @@ impl<SegmentType: Segment> RangeActionMap<SegmentType> {
             .map(|(_, v)| v)
             .collect();
         for mut area in areas_to_be_modified {
+            self.gaps.release(area.start.max(start), area.end.min(end));
             match area.shrink_or_split_if_overlap(start, end, self.args) {
                 DiffSet::Shrinked => {
                     self.segments.insert(area.start, area);
@@ -306,21 +329,87 @@ impl<SegmentType: Segment> RangeActionMap<SegmentType> {
                 _ => {} // 未相交时，就不需要再管了
             }
         }
+        self.merge_range(start, end);
     }
+    /// 从区间树中取出 `[start, end)` 这一段，返回对应的区间。
+    ///
+    /// 要求 `[start, end)` 完整地落在同一个区间内，否则返回 None 且不修改区间树。
+    /// 区间两端多出来的部分会被拆分出来留在区间树中。
+    /// 取出的区间**不会**执行 `remove`，可以修改后再通过 `mmap_fixed` 放回去，如移动或者扩展一段映射
+    pub fn take(&mut self, start: usize, end: usize) -> Option<SegmentType> {
+        let key = match self.segments.range(..=start).last() {
+            Some((&key, area)) if start < end && area.contains(start) && end <= area.end => key,
+            _ => return None,
+        };
+        let mut area = self.segments.remove(&key).unwrap();
+        self.gaps.release(start, end);
+        if area.start < start {
+            let middle = RangeArea {
+                start,
+                end: area.end,
+                segment: area.segment.split(start, self.args),
+            };
+            area.end = start;
+            self.segments.insert(area.start, area);
+            area = middle;
+        }
+        if end < area.end {
+            let right = RangeArea {
+                start: end,
+                end: area.end,
+                segment: area.segment.split(end, self.args),
+            };
+            area.end = end;
+            self.segments.insert(right.start, right);
+        }
+        Some(area.segment)
+    }
+    /// 寻找一个长为 len 且左端点不小于 hint 的空位，返回它的左端点。不修改区间树
+    ///
+    /// 如果 hint 所在的空位放得下，就从 hint 开始；否则返回 hint 之后第一个足够长的空位的左端点。
+    /// 空位记录在按最长空位增强的平衡树中，所以只需要 O(log n) 的时间
     pub fn find_free_area(&self, hint: usize, len: usize) -> Option<usize> {
-        // 上一段区间的末尾
-        let mut last_seg_end = hint.max(LOWER_LIMIT);
-        for (start, seg) in self.segments.iter() {
-            // 现在检查从上一段末尾到这一段开头能不能塞下一个长为 len 的段
-            if last_seg_end + len <= *start {
-                return Some(last_seg_end);
+        let hint = hint.max(LOWER_LIMIT);
+        if let Some((_, gap_end)) = self.gaps.containing(hint) {
+            if hint.checked_add(len).map_or(false, |end| end <= gap_end) {
+                return Some(hint);
             }
-            last_seg_end = last_seg_end.max(seg.end);
         }
-        if last_seg_end + len <= UPPER_LIMIT {
-            Some(last_seg_end)
-        } else {
-            None
+        self.gaps.first_fit(hint, len)
+    }
+    /// 尝试把左端点在 `[start, end]` 中的每个区间和紧挨在它左边的区间合并，见 `Segment::merge`
+    fn merge_range(&mut self, start: usize, end: usize) {
+        let keys: Vec<usize> = self.segments.range(start..=end).map(|(&key, _)| key).collect();
+        for key in keys {
+            self.merge_at(key);
+        }
+    }
+    /// 尝试把左端点为 pos 的区间合并到右端点为 pos 的区间中
+    fn merge_at(&mut self, pos: usize) {
+        let right = match self.segments.remove(&pos) {
+            Some(right) => right,
+            None => return,
+        };
+        let left = match self.segments.range_mut(..pos).next_back() {
+            Some((_, left)) if left.end == pos => left,
+            _ => {
+                self.segments.insert(pos, right);
+                return;
+            }
+        };
+        let end = right.end;
+        match left.segment.merge(right.segment, self.args) {
+            Ok(()) => left.end = end,
+            Err(segment) => {
+                self.segments.insert(
+                    pos,
+                    RangeArea {
+                        start: pos,
+                        end,
+                        segment,
+                    },
+                );
+            }
         }
     }
 }
@@ -10,6 +10,13 @@ pub trait Segment: Sized {
     fn split(&mut self, pos: usize, args: ArgsType) -> Self;
     /// 修改区间的属性
     fn modify(&mut self, new_flag: IdentType, args: ArgsType);
+    /// 把紧接在这段区间右边的 right 合并进来，成功时 self 变成合并后的区间。
+    ///
+    /// 不能合并时原样返回 right。默认不合并任何区间，
+    /// 需要在 mmap / mprotect 后合并属性和底层资源都相同的相邻区间时再实现它
+    fn merge(&mut self, right: Self, _args: ArgsType) -> Result<(), Self> {
+        Err(right)
+    }
 
     /// 按 pos 拆分区间，只保留左半边
     ///
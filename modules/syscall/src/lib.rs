@@ -12,6 +12,8 @@ pub enum ErrorNo {
     ENOENT = -2,
     /// 找不到对应进程
     ESRCH = -3,
+    /// 系统调用在阻塞时被信号打断
+    EINTR = -4,
     /// 错误的文件描述符
     EBADF = -9,
     /// 资源暂时不可用。也可因为 futex_wait 时对应用户地址处的值与给定值不符
@@ -32,14 +34,48 @@ pub enum ErrorNo {
     EINVAL = -22,
     /// fd（文件描述符）已满
     EMFILE = -24,
+    /// 文件太大，超过了文件大小的上限
+    EFBIG = -27,
+    /// 存储空间不足
+    ENOSPC = -28,
     /// 对文件进行了无效的 seek
     ESPIPE = -29,
+    /// 管道或者 socket 的另一端已经关闭，不能再写入
+    EPIPE = -32,
     /// 超过范围。例如用户提供的buffer不够长
     ERANGE = -34,
+    /// 会导致死锁。例如线程试图获取自己已经持有的 PI futex
+    EDEADLK = -35,
+    /// 对非 socket 的文件描述符进行了 socket 操作
+    ENOTSOCK = -88,
+    /// 发送数据报时没有指定目的地址，且 socket 没有连接
+    EDESTADDRREQ = -89,
+    /// 数据报太长，超过了发送缓冲区的大小
+    EMSGSIZE = -90,
+    /// 不支持的 socket 选项
+    ENOPROTOOPT = -92,
+    /// 不支持的 socket 协议
+    EPROTONOSUPPORT = -93,
+    /// socket 不支持这个操作
+    EOPNOTSUPP = -95,
     /// 不支持的协议
     EPFNOSUPPORT = -96,
     /// 不支持的地址
     EAFNOSUPPORT = -97,
+    /// 地址已被占用
+    EADDRINUSE = -98,
+    /// 连接被对方重置
+    ECONNRESET = -104,
+    /// socket 已经连接
+    EISCONN = -106,
+    /// socket 没有连接
+    ENOTCONN = -107,
+    /// 等待超时，如 futex_wait 超过了给定的时间
+    ETIMEDOUT = -110,
     /// 拒绝连接
     ECONNREFUSED = -111,
+    /// socket 的上一次非阻塞连接还没有完成
+    EALREADY = -114,
+    /// socket 正在连接，之后再检查是否连接成功
+    EINPROGRESS = -115,
 }
@@ -49,6 +49,8 @@ pub trait TaskTrampoline: Sync {
     fn raw_time(&self) -> (usize, usize);
     fn raw_timer(&self) -> (usize, usize);
     fn set_timer(&self, timer_interval_us: usize, timer_remained_us: usize, timer_type: usize) -> bool;
+    fn sleep_until(&self, expire_us: usize) -> bool;
+    fn wait_for_file_event(&self, expire_us: Option<usize>, ready: &mut dyn FnMut() -> bool) -> bool;
 }
 
 static TASK: Once<&'static dyn TaskTrampoline> = Once::new();
@@ -103,4 +105,14 @@ pub fn raw_timer() -> (usize, usize) {
 /// 以 TimeVal 字段格式形式读入计时器信息，返回是否设置成功(类型参数对就算设置成功)
 pub fn set_timer(timer_interval_us: usize, timer_remained_us: usize, timer_type: usize) -> bool {
     TASK.get().unwrap().set_timer(timer_interval_us, timer_remained_us, timer_type)
-}
\ No newline at end of file
+}
+/// 阻塞当前任务，直到系统时间(微秒)到达 expire_us。如果被信号打断，则提前返回 false
+pub fn sleep_until(expire_us: usize) -> bool {
+    TASK.get().unwrap().sleep_until(expire_us)
+}
+
+/// 阻塞当前任务，直到 ready 返回 true，或者系统时间(微秒)到达 expire_us。
+/// 每当有文件的读写状态改变时，都会重新检查 ready。返回是否是因为 ready 返回 true 而结束等待
+pub fn wait_for_file_event(expire_us: Option<usize>, mut ready: impl FnMut() -> bool) -> bool {
+    TASK.get().unwrap().wait_for_file_event(expire_us, &mut ready)
+}
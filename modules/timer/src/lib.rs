@@ -4,27 +4,30 @@
 
 #![no_std]
 
-use core::ops::Add;
+use core::{
+    ops::Add,
+    sync::atomic::{AtomicUsize, Ordering},
+};
 use riscv::register::time;
 use syscall::ErrorNo;
-use task_trampoline::{manually_alloc_type, raw_time, raw_timer, set_timer, suspend_current_task};
+use task_trampoline::{manually_alloc_type, raw_time, raw_timer, set_timer, sleep_until};
 
 /* Constants */
 
-/// 时钟频率，和平台有关
-pub const CLOCK_FREQ: usize = if cfg!(feature = "sifive") { 100_0000 } else { 1250_0000 };
+/// 默认的时钟频率，和平台有关。启动时如果从设备树中读到了 timebase-frequency，会用它替换
+pub const DEFAULT_CLOCK_FREQ: usize = if cfg!(feature = "sifive") {
+    100_0000
+} else {
+    1250_0000
+};
 /// 每秒的时钟中断数
 pub const INTERRUPT_PER_SEC: usize = 10;
-/// 每微秒的时钟周期数
-pub const MACHINE_TICKS_PER_USEC: usize = CLOCK_FREQ / USEC_PER_SEC;
 /// 每秒有多少微秒
 const USEC_PER_SEC: usize = 1_000_000;
 /// 每个时钟中断占多少微秒
 pub const USEC_PER_INTERRUPT: usize = USEC_PER_SEC / INTERRUPT_PER_SEC;
 /// 每秒的纳秒数
 pub const NSEC_PER_SEC: usize = 1_000_000_000;
-/// 每个时钟周期需要多少纳秒(取整)
-pub const NSEC_PER_MACHINE_TICKS: usize = NSEC_PER_SEC / CLOCK_FREQ;
 /// 当 nsec 为这个特殊值时，指示修改时间为现在
 pub const UTIME_NOW: usize = 0x3fffffff;
 /// 当 nsec 为这个特殊值时，指示不修改时间
@@ -38,8 +41,28 @@ pub const RUSAGE_CHILDREN: i32 = -1;
 /// 获取当前线程的资源统计
 pub const RUSAGE_THREAD: i32 = 1;
 
+/// 当前使用的时钟频率
+static CLOCK_FREQ: AtomicUsize = AtomicUsize::new(DEFAULT_CLOCK_FREQ);
+
 /* Methods */
 
+/// 设置时钟频率。需在启动时、其他核读取时间之前调用
+pub fn set_clock_freq(freq: usize) {
+    if freq != 0 {
+        CLOCK_FREQ.store(freq, Ordering::Relaxed);
+    }
+}
+
+/// 时钟频率，即每秒的时钟周期数
+pub fn clock_freq() -> usize {
+    CLOCK_FREQ.load(Ordering::Relaxed)
+}
+
+/// 每微秒的时钟周期数
+pub fn machine_ticks_per_usec() -> usize {
+    (clock_freq() / USEC_PER_SEC).max(1)
+}
+
 /// 读 mtime 计时器的值
 pub fn get_time() -> usize {
     time::read()
@@ -47,27 +70,29 @@ pub fn get_time() -> usize {
 
 /// 获取毫秒格式的时间值。注意这不一定代表进程经过的时间值
 pub fn get_time_ms() -> usize {
-    (time::read() * 1000) / CLOCK_FREQ
+    (time::read() * 1000) / clock_freq()
 }
 
 /// 获取秒格式的时间值。注意这不一定代表进程经过的时间值
 pub fn get_time_sec() -> usize {
-    time::read() / CLOCK_FREQ
+    time::read() / clock_freq()
 }
 
 /// 获取微秒格式的时间值。注意这不一定代表进程经过的时间值
 pub fn get_time_us() -> usize {
-    time::read() / MACHINE_TICKS_PER_USEC
+    time::read() / machine_ticks_per_usec()
 }
 
 /// 当前时间为多少秒(浮点数格式)
 pub fn get_time_f64() -> f64 {
-    get_time() as f64 / CLOCK_FREQ as f64
+    get_time() as f64 / clock_freq() as f64
 }
 
 /// 获取下一次中断时间
 pub fn get_next_trigger() -> u64 {
-    (get_time() + CLOCK_FREQ / INTERRUPT_PER_SEC).try_into().unwrap()
+    (get_time() + clock_freq() / INTERRUPT_PER_SEC)
+        .try_into()
+        .unwrap()
 }
 
 /* Structs */
@@ -113,7 +138,7 @@ impl TimeSpec {
     /// 获取时钟周期数
     /// 考虑到 usize 有 64 位，这里应该不会溢出
     pub fn get_ticks(&self) -> usize {
-        self.tv_sec * CLOCK_FREQ + self.tv_nsec / NSEC_PER_MACHINE_TICKS
+        self.tv_sec * clock_freq() + self.tv_nsec * clock_freq() / NSEC_PER_SEC
     }
 }
 
@@ -166,7 +191,7 @@ impl From<TimeSpec> for TimeVal {
     fn from(spec: TimeSpec) -> Self {
         Self {
             sec: spec.tv_sec,
-            usec: spec.tv_nsec / USEC_PER_SEC,
+            usec: spec.tv_nsec / (NSEC_PER_SEC / USEC_PER_SEC),
         }
     }
 }
@@ -241,21 +266,25 @@ pub fn sys_clock_gettime(_clockid: usize, time_spec: *mut TimeSpec) -> Result<us
 
 /// sys_nanosleep 系统调用实现
 ///
-/// 该进程休眠一段时间
+/// 该进程休眠一段时间。休眠期间它不在就绪队列中，到时间后由内核的时间轮唤醒。
+/// 被信号打断时返回 EINTR，并把剩余的时间写到 rem 中
 pub fn sys_nanosleep(req: *const TimeSpec, rem: *mut TimeSpec) -> Result<usize, ErrorNo> {
-    let end_time = unsafe { get_time_f64() + (*req).time_in_sec() };
-    //let now = get_time_f64();
-    //info!("now {} end time {}", now, end_time);
-    while get_time_f64() < end_time {
-        suspend_current_task();
-    }
+    let sleep_us: usize = TimeVal::from(unsafe { *req }).into();
+    let end_time = get_time_us() + sleep_us;
+    //info!("now {} end time {}", get_time_us(), end_time);
+    let finished = sleep_until(end_time);
     // 如果用户提供了 rem 数组，则需要修改它
     if rem as usize != 0 {
+        let remained_us = end_time.saturating_sub(get_time_us());
         unsafe {
-            (*rem) = TimeSpec::new(0.0);
+            (*rem) = TimeSpec::new(remained_us as f64 / USEC_PER_SEC as f64);
         }
     }
-    Ok(0)
+    if finished {
+        Ok(0)
+    } else {
+        Err(ErrorNo::EINTR)
+    }
 }
 
 /// sys_settimer 系统调用实现
@@ -332,11 +361,14 @@ pub fn sys_settimer(
         };
     }
     let (timer_interval_us, timer_remained_us) = unsafe {
-        ((*new_value).it_interval.into(), (*new_value).it_value.into())
+        (
+            (*new_value).it_interval.into(),
+            (*new_value).it_value.into(),
+        )
     };
     if set_timer(timer_interval_us, timer_remained_us, which) {
         Ok(0)
     } else {
         Err(ErrorNo::EFAULT) // 设置不成功，说明参数 which 错误
     }
-}
\ No newline at end of file
+}